@@ -0,0 +1,412 @@
+//! Alert rules checked against every update, and the notifiers told when one fires.<br>
+//! A rule is either a fixed threshold on one field, a category of the location's AQI standard or, with the "scripting" feature, a short rhai script that returns true when the alert should fire.
+//! Rules only fire when they start matching for a location, not on every poll they keep matching.
+//! The same rules can be checked against the hourly forecast to warn ahead of time, with the forecast tracked apart from the readings so each can fire on its own.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+use chrono::{DateTime, Duration, Local, NaiveDate, Utc};
+use serde::Deserialize;
+use crate::i18n;
+use crate::plugin::PluginError;
+use crate::standard::AqiStandard;
+use crate::{report, schema, PollUpdate};
+
+/// How far ahead OpenWeatherMaps forecasts, and so the most hours OPENWEATHER_FORECAST_ALERT_HOURS can look ahead
+pub const MAX_FORECAST_HOURS: u32 = 96;
+
+/// An alert rule as written in a configuration file or OPENWEATHER_ALERTS
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct AlertRule {
+    pub name: String,
+    #[serde(default)]
+    pub field: Option<String>,
+    #[serde(default)]
+    pub above: Option<f32>,
+    #[serde(default)]
+    pub condition: Option<String>,
+    #[serde(default)]
+    pub category: Option<String>,
+}
+
+/// A rule that has started matching an update, or a forecast hour with time set to that hour<br>
+/// field and value are what a threshold or category rule checked, and category is the update's category under the AQI standard, when they can be worked out.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Alert {
+    pub rule: String,
+    pub location: String,
+    pub time: DateTime<Utc>,
+    pub message: String,
+    pub field: Option<String>,
+    pub value: Option<f32>,
+    pub category: Option<String>,
+}
+
+/// Told about every alert that fires, ex: to print it or send it on
+pub trait Notifier: Send + Sync {
+    /// Get a name for the notifier to use in messages
+    fn name(&self) -> &str;
+    /// Pass an alert on
+    ///
+    /// # Errors
+    /// Any error delivering the alert. It is logged and does not stop polling.
+    fn notify(&self, alert: &Alert) -> Result<(), PluginError>;
+}
+
+/// Prints alerts to the console
+pub struct ConsoleNotifier;
+
+impl Notifier for ConsoleNotifier {
+    fn name(&self) -> &str {
+        "console"
+    }
+    fn notify(&self, alert: &Alert) -> Result<(), PluginError> {
+        report::warn(&format!("ALERT {}: {}", alert.rule, alert.message));
+        Ok(())
+    }
+}
+
+/// How a rule decides if it matches
+enum Condition {
+    Threshold { field: String, above: f32 },
+    /// Reaching a category of the AQI standard, ex: "Unhealthy", or any worse one
+    Category(String),
+    #[cfg(feature = "scripting")]
+    Script(Box<crate::scripting::Script>),
+}
+
+/// Checks every rule against updates and tells the notifiers about any that start matching
+pub struct AlertEngine {
+    rules: Vec<(String, Condition)>,
+    notifiers: Vec<Box<dyn Notifier>>,
+    active: Mutex<BTreeSet<(String, String)>>,
+    forecast_active: Mutex<BTreeSet<(String, String)>>,
+    standard: AqiStandard,
+    location_standards: BTreeMap<String, AqiStandard>,
+}
+
+impl AlertEngine {
+    /// Build the engine for a set of rules, printing alerts to the console and judging categories on OpenWeatherMaps' own scale
+    ///
+    /// # Errors
+    /// Returns a message naming the rule if it has neither a field and threshold nor a condition, if its condition cannot be compiled or if its category is not one of any AQI standard
+    pub fn new(rules: &[AlertRule]) -> Result<AlertEngine, String> {
+        let compiled: Vec<(String, Condition)> = rules.iter().map(|rule| Ok((rule.name.clone(), compile_rule(rule)?))).collect::<Result<Vec<(String, Condition)>, String>>()?;
+        Ok(AlertEngine { rules: compiled, notifiers: vec![Box::new(ConsoleNotifier)], active: Mutex::new(BTreeSet::new()), forecast_active: Mutex::new(BTreeSet::new()), standard: AqiStandard::Owm, location_standards: BTreeMap::new() })
+    }
+    /// Set the AQI standard category rules are judged on for locations without their own
+    ///
+    /// # Errors
+    /// Returns a message naming the rule if its category is not one of the standard's
+    pub fn set_standard(&mut self, standard: AqiStandard) -> Result<(), String> {
+        self.check_categories(standard)?;
+        self.standard = standard;
+        Ok(())
+    }
+    /// Set the AQI standard category rules are judged on for one location by name
+    ///
+    /// # Errors
+    /// Returns a message naming the rule and location if the rule's category is not one of the standard's
+    pub fn set_location_standard(&mut self, location: &str, standard: AqiStandard) -> Result<(), String> {
+        self.check_categories(standard).map_err(|e| format!("{} Its standard is used for {}.", e, location))?;
+        self.location_standards.insert(location.to_string(), standard);
+        Ok(())
+    }
+    /// Check every category rule names one of a standard's categories
+    fn check_categories(&self, standard: AqiStandard) -> Result<(), String> {
+        for (name, condition) in &self.rules {
+            if let Condition::Category(category) = condition {
+                if standard.category_level(category).is_none() {
+                    return Err(format!("Alert rule \"{}\" has category \"{}\", which is not a category of the {} AQI standard. Expected one of: {}.", name, category, standard, standard.categories().iter().map(|(_, category)| *category).collect::<Vec<&str>>().join(", ")));
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Get the AQI standard an update's category rules are judged on, its location's own or the one for every location
+    fn standard_for(&self, update: &PollUpdate) -> AqiStandard {
+        self.location_standards.get(update.get_location()).copied().unwrap_or(self.standard)
+    }
+    /// Add a notifier to tell about alerts alongside the console
+    pub fn add_notifier(&mut self, notifier: Box<dyn Notifier>) {
+        self.notifiers.push(notifier);
+    }
+    /// Check if there are any rules to evaluate
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+    /// Check every rule against an update and notify for each that has just started matching at its location<br>
+    /// A rule that fails to evaluate is logged and treated as not matching
+    pub fn evaluate(&self, update: &PollUpdate) -> Vec<Alert> {
+        let mut active = self.active.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut fired: Vec<Alert> = Vec::new();
+        for (name, condition) in &self.rules {
+            let key: (String, String) = (name.clone(), update.get_series());
+            let message: Option<String> = match check(condition, update, self.standard_for(update)) {
+                Ok(message) => message,
+                Err(e) => {
+                    report::warn(&format!("Unable to evaluate alert {}: {}", name, e));
+                    None
+                },
+            };
+            match message {
+                Some(message) => {
+                    if active.insert(key) {
+                        fired.push(self.alert(name, condition, update, message));
+                    }
+                },
+                None => {
+                    active.remove(&key);
+                },
+            }
+        }
+        drop(active);
+        self.notify_all(&fired);
+        fired
+    }
+    /// Check every rule against the forecast hours after now and up to hours ahead, notifying for each that has just started matching at a location<br>
+    /// The alert is for the first hour the rule matches, with its message saying when, ex: "Forecast for tomorrow at 14:00: ...". A rule that fails to evaluate is logged and treated as not matching.
+    pub fn evaluate_forecast(&self, forecast: &[PollUpdate], now: DateTime<Utc>, hours: u32) -> Vec<Alert> {
+        let upcoming: Vec<&PollUpdate> = forecast.iter().filter(|update| update.get_time() > now && update.get_time() <= now + Duration::hours(hours as i64)).collect();
+        let series: BTreeSet<String> = upcoming.iter().map(|update| update.get_series()).collect();
+        let today: NaiveDate = now.with_timezone(&Local).date_naive();
+        let mut active = self.forecast_active.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut fired: Vec<Alert> = Vec::new();
+        for (name, condition) in &self.rules {
+            for location in &series {
+                let key: (String, String) = (name.clone(), location.clone());
+                let first: Option<(&PollUpdate, String)> = upcoming.iter().filter(|update| update.get_series() == *location).find_map(|update| match check(condition, update, self.standard_for(update)) {
+                    Ok(message) => message.map(|message| (*update, message)),
+                    Err(e) => {
+                        report::warn(&format!("Unable to evaluate alert {} against the forecast: {}", name, e));
+                        None
+                    },
+                });
+                match first {
+                    Some((update, message)) => {
+                        if active.insert(key) {
+                            let when: String = i18n::forecast_time(i18n::current(), update.get_time().with_timezone(&Local).naive_local(), today);
+                            fired.push(self.alert(name, condition, update, i18n::forecast_message(i18n::current(), &when, &message)));
+                        }
+                    },
+                    None => {
+                        active.remove(&key);
+                    },
+                }
+            }
+        }
+        drop(active);
+        self.notify_all(&fired);
+        fired
+    }
+    /// Build the alert for a rule that matched an update, with the field it checked and the update's AQI category
+    fn alert(&self, name: &str, condition: &Condition, update: &PollUpdate, message: String) -> Alert {
+        let standard: AqiStandard = self.standard_for(update);
+        let field: Option<String> = match condition {
+            Condition::Threshold { field, .. } => Some(field.clone()),
+            Condition::Category(_) => Some(standard.field().to_string()),
+            #[cfg(feature = "scripting")]
+            Condition::Script(_) => None,
+        };
+        let value: Option<f32> = field.as_deref().and_then(|field| update.get_field(field));
+        let category: Option<String> = standard.value(update).map(|value| standard.category(value).1.to_string());
+        Alert { rule: name.to_string(), location: update.get_location().to_string(), time: update.get_time(), message, field, value, category }
+    }
+    /// Tell every notifier about each alert, logging any that cannot be sent
+    fn notify_all(&self, fired: &[Alert]) {
+        for alert in fired {
+            for notifier in &self.notifiers {
+                if let Err(e) = notifier.notify(alert) {
+                    report::warn(&format!("Notifier {} was unable to send alert {}: {}", notifier.name(), alert.rule, e));
+                }
+            }
+        }
+    }
+}
+
+/// Turn a rule into something that can be checked, preferring the condition, then the category, when more than one is given
+fn compile_rule(rule: &AlertRule) -> Result<Condition, String> {
+    if let Some(condition) = &rule.condition {
+        return compile_condition(&rule.name, condition);
+    }
+    if let Some(category) = &rule.category {
+        if !AqiStandard::ALL.iter().any(|standard| standard.category_level(category).is_some()) {
+            return Err(format!("Alert rule \"{}\" has category \"{}\", which is not a category of any AQI standard", rule.name, category));
+        }
+        return Ok(Condition::Category(category.clone()));
+    }
+    match (&rule.field, rule.above) {
+        (Some(field), Some(above)) => Ok(Condition::Threshold { field: field.clone(), above }),
+        _ => Err(format!("Alert rule \"{}\" needs either a field and above, a category or a condition", rule.name)),
+    }
+}
+
+#[cfg(feature = "scripting")]
+fn compile_condition(name: &str, condition: &str) -> Result<Condition, String> {
+    crate::scripting::Script::compile(condition).map(|script| Condition::Script(Box::new(script))).map_err(|e| format!("Alert rule \"{}\" has an invalid condition: {}", name, e))
+}
+
+/// Without the scripting feature there is no way to run a condition, so it is an error rather than silently never firing
+#[cfg(not(feature = "scripting"))]
+fn compile_condition(name: &str, _condition: &str) -> Result<Condition, String> {
+    Err(format!("Alert rule \"{}\" has a condition but this build does not include the \"scripting\" feature.", name))
+}
+
+/// Check one condition, returning the alert message when it matches<br>
+/// A threshold rule does not match while its pollutant was not measured, and a category rule does not match while the standard's index cannot be worked out for the update, ex: before enough pollutants have been read.
+fn check(condition: &Condition, update: &PollUpdate, standard: AqiStandard) -> Result<Option<String>, PluginError> {
+    match condition {
+        Condition::Threshold { field, above } => {
+            let value: f32 = match update.get_field(field) {
+                Some(value) => value,
+                None if schema::FIELDS.contains(&field.as_str()) => return Ok(None),
+                None => return Err(format!("unknown field {}", field).into()),
+            };
+            if value > *above {
+                Ok(Some(i18n::threshold_message(i18n::current(), field, update.get_location(), value, *above)))
+            } else {
+                Ok(None)
+            }
+        },
+        Condition::Category(category) => {
+            let wanted: usize = standard.category_level(category).ok_or(format!("{} is not a category of the {} AQI standard", category, standard))?;
+            match standard.value(update).map(|value| (value, standard.category(value))) {
+                Some((value, (level, reached))) if level >= wanted => Ok(Some(i18n::category_message(i18n::current(), standard.field(), update.get_location(), value, reached))),
+                _ => Ok(None),
+            }
+        },
+        #[cfg(feature = "scripting")]
+        Condition::Script(script) => {
+            if script.matches(update)? {
+                Ok(Some(i18n::condition_message(i18n::current(), script.source(), update.get_location())))
+            } else {
+                Ok(None)
+            }
+        },
+    }
+}
+
+/// Parse threshold rules formatted as "field>value" separated by commas, ex: "pm2_5>35,aqi>3"<br>
+/// Each rule is named after its own text. Malformed entries are skipped with a message.
+pub fn parse_alert_rules(raw_rules: &str) -> Vec<AlertRule> {
+    raw_rules.split(',').filter(|rule| !rule.trim().is_empty()).filter_map(|rule| {
+        let rule: &str = rule.trim();
+        match rule.split_once('>') {
+            Some((field, above)) => match above.trim().parse::<f32>() {
+                Ok(above) => Some(AlertRule { name: rule.to_string(), field: Some(field.trim().to_string()), above: Some(above), condition: None, category: None }),
+                Err(_) => {
+                    report::warn(&format!("Ignoring alert rule with an invalid threshold: {}", rule));
+                    None
+                },
+            },
+            None => {
+                report::warn(&format!("Ignoring malformed alert rule: {}", rule));
+                None
+            },
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_update(pm2_5: f32) -> PollUpdate {
+        PollUpdate { aqi: 2, pm2_5: Some(pm2_5), ..PollUpdate::fixture("Home", 1700000000) }
+    }
+
+    #[test]
+    fn parse_alert_rules_thresholds() {
+        let rules: Vec<AlertRule> = parse_alert_rules("pm2_5>35, aqi > 3,bad,pm10>lots");
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0], AlertRule { name: "pm2_5>35".to_string(), field: Some("pm2_5".to_string()), above: Some(35.0), condition: None, category: None });
+        assert_eq!(rules[1].field, Some("aqi".to_string()));
+    }
+
+    #[test]
+    fn threshold_fires_once_until_cleared() {
+        let engine: AlertEngine = AlertEngine::new(&parse_alert_rules("pm2_5>35")).unwrap();
+        assert!(engine.evaluate(&test_update(10.0)).is_empty());
+        let fired: Vec<Alert> = engine.evaluate(&test_update(40.0));
+        assert_eq!(fired.len(), 1);
+        assert_eq!(fired[0].message, "Fine Particulate Matter at Home is 40, above 35".to_string());
+        assert_eq!((fired[0].field.as_deref(), fired[0].value, fired[0].category.as_deref()), (Some("pm2_5"), Some(40.0), Some("Fair")));
+        assert!(engine.evaluate(&test_update(50.0)).is_empty());
+        assert!(engine.evaluate(&test_update(20.0)).is_empty());
+        assert_eq!(engine.evaluate(&test_update(45.0)).len(), 1);
+    }
+
+    #[test]
+    fn forecast_fires_for_first_matching_hour() {
+        let engine: AlertEngine = AlertEngine::new(&parse_alert_rules("pm2_5>35")).unwrap();
+        let now: DateTime<Utc> = DateTime::from_timestamp(1700000000, 0).unwrap();
+        let forecast: Vec<PollUpdate> = [10.0, 20.0, 40.0, 50.0].iter().enumerate().map(|(hour, pm2_5)| {
+            let mut update: PollUpdate = test_update(*pm2_5);
+            update.time = now + Duration::hours(hour as i64);
+            update
+        }).collect();
+        assert!(engine.evaluate_forecast(&forecast, now, 1).is_empty());
+        let fired: Vec<Alert> = engine.evaluate_forecast(&forecast, now, 48);
+        assert_eq!(fired.len(), 1);
+        assert_eq!(fired[0].time, now + Duration::hours(2));
+        assert!(fired[0].message.starts_with("Forecast for "));
+        assert!(fired[0].message.ends_with(": Fine Particulate Matter at Home is 40, above 35"));
+        assert!(engine.evaluate_forecast(&forecast, now, 48).is_empty());
+        assert_eq!(engine.evaluate(&test_update(40.0)).len(), 1);
+        assert!(engine.evaluate_forecast(&forecast[..2], now, 48).is_empty());
+        assert_eq!(engine.evaluate_forecast(&forecast, now, 48).len(), 1);
+    }
+
+    #[test]
+    fn rule_without_threshold_is_rejected() {
+        let rule: AlertRule = AlertRule { name: "empty".to_string(), field: Some("pm10".to_string()), above: None, condition: None, category: None };
+        assert!(AlertEngine::new(&[rule]).is_err());
+    }
+
+    #[test]
+    fn category_fires_at_or_above() {
+        let rule: AlertRule = AlertRule { name: "unhealthy".to_string(), field: None, above: None, condition: None, category: Some("unhealthy".to_string()) };
+        let mut engine: AlertEngine = AlertEngine::new(&[rule]).unwrap();
+        engine.set_standard(AqiStandard::Us).unwrap();
+        let mut update: PollUpdate = test_update(40.0);
+        assert!(engine.evaluate(&update).is_empty());
+        update.set_index("aqi_us", 120.0);
+        assert!(engine.evaluate(&update).is_empty());
+        update.set_index("aqi_us", 210.0);
+        assert_eq!(engine.evaluate(&update)[0].message, "Air quality at Home is Very Unhealthy (aqi_us 210)".to_string());
+    }
+
+    #[test]
+    fn category_judged_on_location_standard() {
+        let rule: AlertRule = AlertRule { name: "moderate".to_string(), field: None, above: None, condition: None, category: Some("moderate".to_string()) };
+        let mut engine: AlertEngine = AlertEngine::new(&[rule]).unwrap();
+        engine.set_standard(AqiStandard::Us).unwrap();
+        engine.set_location_standard("Home", AqiStandard::Uk).unwrap();
+        let mut update: PollUpdate = test_update(40.0);
+        update.set_index("aqi_us", 120.0);
+        assert!(engine.evaluate(&update).is_empty());
+        update.set_index("daqi", 5.0);
+        let fired: Vec<Alert> = engine.evaluate(&update);
+        assert_eq!((fired[0].field.as_deref(), fired[0].category.as_deref()), (Some("daqi"), Some("Moderate")));
+    }
+
+    #[test]
+    fn unknown_categories_are_rejected() {
+        let rule: AlertRule = AlertRule { name: "unhealthy".to_string(), field: None, above: None, condition: None, category: Some("unhealthy".to_string()) };
+        let typo: AlertRule = AlertRule { category: Some("unhelthy".to_string()), ..rule.clone() };
+        assert!(AlertEngine::new(&[typo]).err().unwrap().contains("not a category of any AQI standard"));
+        let mut engine: AlertEngine = AlertEngine::new(&[rule]).unwrap();
+        assert!(engine.set_standard(AqiStandard::Uk).unwrap_err().contains("Expected one of: Low, Moderate, High, Very High."));
+        assert!(engine.set_location_standard("Toronto", AqiStandard::Ca).unwrap_err().ends_with("Its standard is used for Toronto."));
+        assert!(engine.set_location_standard("Denver", AqiStandard::Us).is_ok());
+    }
+
+    #[cfg(feature = "scripting")]
+    #[test]
+    fn script_condition_fires() {
+        let rule: AlertRule = AlertRule { name: "smoggy".to_string(), field: None, above: None, condition: Some("pm2_5 > 30.0 && aqi >= 2".to_string()), category: None };
+        let engine: AlertEngine = AlertEngine::new(&[rule]).unwrap();
+        assert!(engine.evaluate(&test_update(20.0)).is_empty());
+        assert_eq!(engine.evaluate(&test_update(31.0))[0].rule, "smoggy".to_string());
+    }
+}
@@ -0,0 +1,128 @@
+//! Optional read-only HTTP endpoint serving the in-memory history, pipeline stats and health checks as JSON.<br>
+//! Runs on its own thread so it never holds up the polling loop.
+
+use std::thread;
+use tiny_http::{Header, Response, Server};
+use crate::history::SharedBuffer;
+use crate::{health, pipeline, report};
+
+/// Start serving the buffer on the given address, ex: "0.0.0.0:8080"<br>
+/// GET /readings returns every location, GET /readings/{location} returns just that one and GET /pipeline returns the stats for each pipeline stage and queue.
+/// GET /healthz and GET /readyz return the liveness and readiness of the polling loop, with a 503 when the check fails.
+///
+/// # Errors
+/// Returns a message if the address cannot be bound
+pub fn serve(bind: &str, buffer: SharedBuffer) -> Result<thread::JoinHandle<()>, String> {
+    let server: Server = Server::http(bind).map_err(|e| format!("Unable to start HTTP endpoint on {}: {}", bind, e))?;
+    report::info(&format!("HTTP endpoint listening on {}", bind));
+    let handle: thread::JoinHandle<()> = thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let (status, body) = if request.method() == &tiny_http::Method::Get {
+                route(request.url(), &buffer)
+            } else {
+                (405, "{\"error\":\"method not allowed\"}".to_string())
+            };
+            let content_type: Header = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap();
+            let response = Response::from_string(body).with_status_code(status).with_header(content_type);
+            if let Err(e) = request.respond(response) {
+                report::warn(&format!("Unable to respond to HTTP request: {}", e));
+            }
+        }
+    });
+    Ok(handle)
+}
+
+/// Work out the status and JSON body for a path
+pub(crate) fn route(url: &str, buffer: &SharedBuffer) -> (u16, String) {
+    let path: &str = url.split('?').next().unwrap_or("");
+    if path == "/pipeline" {
+        return (200, serde_json::to_string(&pipeline::stats()).unwrap_or_default());
+    }
+    if path == "/healthz" || path == "/readyz" {
+        let (status, report) = if path == "/healthz" { health::liveness() } else { health::readiness() };
+        return (status, serde_json::to_string(&report).unwrap_or_default());
+    }
+    let readings = buffer.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if path == "/readings" || path == "/readings/" {
+        return (200, serde_json::to_string(&readings.all()).unwrap_or_default());
+    }
+    match path.strip_prefix("/readings/") {
+        Some(location) => {
+            let location: String = percent_decode(location);
+            if readings.locations().contains(&location) {
+                (200, serde_json::to_string(&readings.recent(&location)).unwrap_or_default())
+            } else {
+                (404, format!("{{\"error\":\"no readings for {}\"}}", location.replace('"', "")))
+            }
+        },
+        None => (404, "{\"error\":\"not found\"}".to_string()),
+    }
+}
+
+/// Decode %XX escapes in a path segment so locations with spaces can be requested
+pub(crate) fn percent_decode(segment: &str) -> String {
+    let bytes: &[u8] = segment.as_bytes();
+    let mut decoded: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut index: usize = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'%' && index + 2 < bytes.len() {
+            // The two characters after % are read as bytes, as either may be part of a multi-byte character
+            if let Some(value) = std::str::from_utf8(&bytes[index + 1..index + 3]).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                decoded.push(value);
+                index += 3;
+                continue;
+            }
+        }
+        decoded.push(if bytes[index] == b'+' { b' ' } else { bytes[index] });
+        index += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{DateTime, Utc};
+    use crate::history::ReadingBuffer;
+    use crate::standard::AqiStandard;
+    use crate::PollUpdate;
+
+    #[test]
+    fn percent_decode_spaces() {
+        assert_eq!(percent_decode("Beverly%20Hills"), "Beverly Hills".to_string());
+        assert_eq!(percent_decode("San+Jose"), "San Jose".to_string());
+        assert_eq!(percent_decode("100%"), "100%".to_string());
+        assert_eq!(percent_decode("%aé"), "%aé".to_string());
+        assert_eq!(percent_decode("%éa/%C3%A9"), "%éa/é".to_string());
+    }
+
+    #[test]
+    fn route_serves_location() {
+        let buffer: SharedBuffer = ReadingBuffer::shared(5);
+        let time: DateTime<Utc> = DateTime::from_timestamp(1700000000, 0).unwrap();
+        buffer.lock().unwrap().push(PollUpdate { aqi: 2, co: Some(1.0), no: Some(1.0), no2: Some(1.0), o3: Some(1.0), so2: Some(1.0), pm2_5: Some(1.0), pm10: Some(1.0), nh3: Some(1.0), health: AqiStandard::Owm.assess(&PollUpdate::from_measured(time, &[("pm2_5", 15.0)])), ..PollUpdate::fixture("Beverly Hills", 1700000000) });
+        let (status, body) = route("/readings/Beverly%20Hills", &buffer);
+        assert_eq!(status, 200);
+        assert!(body.contains("\"aqi\":2"));
+        assert!(body.contains("\"health\":{\"standard\":\"owm\",\"category\":\"Fair\",\"advice\":\"Enjoy your usual outdoor activities.\"}"));
+        assert_eq!(route("/readings", &buffer).0, 200);
+        assert_eq!(route("/readings/Nowhere", &buffer).0, 404);
+        assert_eq!(route("/other", &buffer).0, 404);
+    }
+
+    #[test]
+    fn route_serves_pipeline_stats() {
+        let (status, body) = route("/pipeline", &ReadingBuffer::shared(1));
+        assert_eq!(status, 200);
+        assert!(body.contains("\"stages\":{\"processor\":{\"items_in\":"));
+        assert!(body.contains("\"queues\":{\"polled\":"));
+    }
+
+    #[test]
+    fn route_serves_health_checks() {
+        let (status, body) = route("/healthz", &ReadingBuffer::shared(1));
+        assert_eq!(status, health::liveness().0);
+        assert!(body.contains("\"error_count\":"));
+        assert!(route("/readyz", &ReadingBuffer::shared(1)).1.contains("\"last_write\":"));
+    }
+}
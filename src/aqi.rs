@@ -0,0 +1,119 @@
+//! The US EPA's AQI from 0 to 500 and the European Air Quality Index from 1 to 6, worked out from OpenWeatherMaps' raw component concentrations rather than its coarse 1 to 5 aqi.<br>
+//! The indices module averages the readings over each pollutant's window and writes the result, ex: aqi_us or aqi_eu, alongside the aqi.
+
+use crate::indices::{sub_index, worst_band, Band};
+use crate::reference;
+
+/// EPA breakpoints for PM2.5 over 24 hours in µg/m³, as revised in 2024
+pub const EPA_PM2_5: [Band; 6] = [(0.0, 9.0, 0.0, 50.0), (9.1, 35.4, 51.0, 100.0), (35.5, 55.4, 101.0, 150.0), (55.5, 125.4, 151.0, 200.0), (125.5, 225.4, 201.0, 300.0), (225.5, 325.4, 301.0, 500.0)];
+/// EPA breakpoints for PM10 over 24 hours in µg/m³
+pub const EPA_PM10: [Band; 6] = [(0.0, 54.0, 0.0, 50.0), (55.0, 154.0, 51.0, 100.0), (155.0, 254.0, 101.0, 150.0), (255.0, 354.0, 151.0, 200.0), (355.0, 424.0, 201.0, 300.0), (425.0, 604.0, 301.0, 500.0)];
+/// EPA breakpoints for O3 over 8 hours in ppb, which only goes up to 200
+pub const EPA_O3_8H: [Band; 5] = [(0.0, 54.0, 0.0, 50.0), (55.0, 70.0, 51.0, 100.0), (71.0, 85.0, 101.0, 150.0), (86.0, 105.0, 151.0, 200.0), (106.0, 200.0, 201.0, 300.0)];
+/// EPA breakpoints for O3 over 1 hour in ppb, used from 125 up when it gives a higher index than the 8 hour average
+pub const EPA_O3_1H: [Band; 4] = [(125.0, 164.0, 101.0, 150.0), (165.0, 204.0, 151.0, 200.0), (205.0, 404.0, 201.0, 300.0), (405.0, 604.0, 301.0, 500.0)];
+/// EPA breakpoints for CO over 8 hours in ppm
+pub const EPA_CO: [Band; 6] = [(0.0, 4.4, 0.0, 50.0), (4.5, 9.4, 51.0, 100.0), (9.5, 12.4, 101.0, 150.0), (12.5, 15.4, 151.0, 200.0), (15.5, 30.4, 201.0, 300.0), (30.5, 50.4, 301.0, 500.0)];
+/// EPA breakpoints for SO2 over 1 hour in ppb, which only goes up to 304. Above that the 24 hour average is used.
+pub const EPA_SO2_1H: [Band; 4] = [(0.0, 35.0, 0.0, 50.0), (36.0, 75.0, 51.0, 100.0), (76.0, 185.0, 101.0, 150.0), (186.0, 304.0, 151.0, 200.0)];
+/// EPA breakpoints for SO2 over 24 hours in ppb, from 305 up
+pub const EPA_SO2_24H: [Band; 2] = [(305.0, 604.0, 201.0, 300.0), (605.0, 1004.0, 301.0, 500.0)];
+/// EPA breakpoints for NO2 over 1 hour in ppb
+pub const EPA_NO2: [Band; 6] = [(0.0, 53.0, 0.0, 50.0), (54.0, 100.0, 51.0, 100.0), (101.0, 360.0, 101.0, 150.0), (361.0, 649.0, 151.0, 200.0), (650.0, 1249.0, 201.0, 300.0), (1250.0, 2049.0, 301.0, 500.0)];
+
+/// EEA bands for PM2.5 over 24 hours in µg/m³, each mapping to its level from 1, Good, to 6, Extremely Poor
+pub const EAQI_PM2_5: [Band; 6] = [(0.0, 10.0, 1.0, 1.0), (10.0, 20.0, 2.0, 2.0), (20.0, 25.0, 3.0, 3.0), (25.0, 50.0, 4.0, 4.0), (50.0, 75.0, 5.0, 5.0), (75.0, 800.0, 6.0, 6.0)];
+/// EEA bands for PM10 over 24 hours in µg/m³
+pub const EAQI_PM10: [Band; 6] = [(0.0, 20.0, 1.0, 1.0), (20.0, 40.0, 2.0, 2.0), (40.0, 50.0, 3.0, 3.0), (50.0, 100.0, 4.0, 4.0), (100.0, 150.0, 5.0, 5.0), (150.0, 1200.0, 6.0, 6.0)];
+/// EEA bands for NO2 over 1 hour in µg/m³
+pub const EAQI_NO2: [Band; 6] = [(0.0, 40.0, 1.0, 1.0), (40.0, 90.0, 2.0, 2.0), (90.0, 120.0, 3.0, 3.0), (120.0, 230.0, 4.0, 4.0), (230.0, 340.0, 5.0, 5.0), (340.0, 1000.0, 6.0, 6.0)];
+/// EEA bands for O3 over 1 hour in µg/m³
+pub const EAQI_O3: [Band; 6] = [(0.0, 50.0, 1.0, 1.0), (50.0, 100.0, 2.0, 2.0), (100.0, 130.0, 3.0, 3.0), (130.0, 240.0, 4.0, 4.0), (240.0, 380.0, 5.0, 5.0), (380.0, 800.0, 6.0, 6.0)];
+/// EEA bands for SO2 over 1 hour in µg/m³
+pub const EAQI_SO2: [Band; 6] = [(0.0, 100.0, 1.0, 1.0), (100.0, 200.0, 2.0, 2.0), (200.0, 350.0, 3.0, 3.0), (350.0, 500.0, 4.0, 4.0), (500.0, 750.0, 5.0, 5.0), (750.0, 1250.0, 6.0, 6.0)];
+
+/// Cut a value down to a number of decimal places, as the EPA does to concentrations before looking them up
+fn truncate(value: f32, decimals: i32) -> f32 {
+    let scale: f32 = 10f32.powi(decimals);
+    (value * scale).floor() / scale
+}
+
+/// The US EPA's AQI from hourly values and averages over each pollutant's window in µg/m³ by field, the worst sub-index rounded to a whole number<br>
+/// Gases are turned into ppb, or ppm for CO, and every concentration truncated as the EPA does. Hourly SO2 over 304 ppb is off the hourly table, so the 24 hour average is used once it reaches 305 ppb and the sub-index is held at 200 until then.
+/// Returns None if none of the pollutants were given.
+pub fn us_aqi(hourly: &[(&str, f32)], averaged: &[(&str, f32)]) -> Option<f32> {
+    let value = |values: &[(&str, f32)], field: &str| values.iter().find(|(name, _)| *name == field).map(|(_, value)| *value);
+    let ppb = |values: &[(&str, f32)], field: &str| value(values, field).and_then(|value| reference::to_ppb(field, value)).map(|ppb| truncate(ppb, 0));
+    let o3_hourly: Option<f32> = ppb(hourly, "o3").filter(|o3| *o3 >= 125.0).and_then(|o3| sub_index(&EPA_O3_1H, o3));
+    let so2_daily: Option<f32> = ppb(averaged, "so2").filter(|so2| *so2 >= 305.0);
+    let so2: Option<f32> = match ppb(hourly, "so2") {
+        Some(so2) if so2 <= 304.0 || so2_daily.is_none() => sub_index(&EPA_SO2_1H, so2),
+        _ => so2_daily.and_then(|so2| sub_index(&EPA_SO2_24H, so2)),
+    };
+    [
+        value(averaged, "pm2_5").and_then(|pm2_5| sub_index(&EPA_PM2_5, truncate(pm2_5, 1))),
+        value(averaged, "pm10").and_then(|pm10| sub_index(&EPA_PM10, truncate(pm10, 0))),
+        ppb(averaged, "o3").filter(|o3| *o3 <= 200.0).and_then(|o3| sub_index(&EPA_O3_8H, o3)),
+        o3_hourly,
+        value(averaged, "co").and_then(|co| reference::to_ppb("co", co)).and_then(|co| sub_index(&EPA_CO, truncate(co / 1000.0, 1))),
+        so2,
+        ppb(hourly, "no2").and_then(|no2| sub_index(&EPA_NO2, no2)),
+    ].into_iter().flatten().map(f32::round).reduce(f32::max)
+}
+
+/// The European Air Quality Index from each pollutant's average over its window in µg/m³ by field, the worst level from 1 to 6<br>
+/// Returns None if none of the pollutants were given.
+pub fn eu_aqi(averaged: &[(&str, f32)]) -> Option<f32> {
+    worst_band(averaged, &[("pm2_5", &EAQI_PM2_5), ("pm10", &EAQI_PM10), ("no2", &EAQI_NO2), ("o3", &EAQI_O3), ("so2", &EAQI_SO2)])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{DateTime, Utc};
+    use crate::{indices, PollUpdate};
+
+    fn test_update(hour: i64, no2: f32, o3: f32, pm2_5: f32) -> PollUpdate {
+        let time: DateTime<Utc> = DateTime::from_timestamp(1700000000 + hour * 3600, 0).unwrap();
+        PollUpdate::from_measured(time, &[("no2", no2), ("o3", o3), ("pm2_5", pm2_5)])
+    }
+
+    #[test]
+    fn us_aqi_uses_epa_breakpoints() {
+        assert_eq!(sub_index(&EPA_PM2_5, 35.4), Some(100.0));
+        assert_eq!(us_aqi(&[], &[("pm2_5", 35.45), ("pm10", 20.0)]), Some(100.0));
+        assert_eq!(us_aqi(&[("no2", reference::to_micrograms("no2", 100.0, "ppb").unwrap())], &[("pm2_5", 5.0)]), Some(100.0));
+        assert_eq!(us_aqi(&[("o3", reference::to_micrograms("o3", 180.0, "ppb").unwrap())], &[("o3", reference::to_micrograms("o3", 60.0, "ppb").unwrap())]), Some(170.0));
+        assert_eq!(us_aqi(&[], &[]), None);
+    }
+
+    #[test]
+    fn us_aqi_band_edges() {
+        assert_eq!(us_aqi(&[], &[("pm2_5", 9.0)]), Some(50.0));
+        assert_eq!(us_aqi(&[], &[("pm2_5", 9.1)]), Some(51.0));
+        assert_eq!(us_aqi(&[], &[("pm2_5", 55.5)]), Some(151.0));
+        assert_eq!(us_aqi(&[], &[("pm2_5", 325.4)]), Some(500.0));
+        assert_eq!(us_aqi(&[], &[("pm10", 1000.0)]), Some(500.0));
+        assert_eq!(us_aqi(&[("so2", reference::to_micrograms("so2", 400.0, "ppb").unwrap())], &[("so2", reference::to_micrograms("so2", 305.0, "ppb").unwrap())]), Some(201.0));
+        assert_eq!(us_aqi(&[("so2", reference::to_micrograms("so2", 400.0, "ppb").unwrap())], &[("so2", reference::to_micrograms("so2", 200.0, "ppb").unwrap())]), Some(200.0));
+        assert_eq!(us_aqi(&[("so2", reference::to_micrograms("so2", 400.0, "ppb").unwrap())], &[]), Some(200.0));
+        let update: PollUpdate = PollUpdate::from_measured(DateTime::<Utc>::from_timestamp(1700000000, 0).unwrap(), &[("pm2_5", 12.0)]);
+        assert_eq!(indices::Index::AqiUs.compute(&[], &update), Some(56.0));
+    }
+
+    #[test]
+    fn eu_aqi_takes_worst_level() {
+        assert_eq!(eu_aqi(&[("pm2_5", 22.0), ("no2", 10.0)]), Some(3.0));
+        assert_eq!(eu_aqi(&[("o3", 900.0)]), Some(6.0));
+        assert_eq!(eu_aqi(&[("pm2_5", 10.0)]), Some(1.0));
+        assert_eq!(eu_aqi(&[("pm2_5", 10.1)]), Some(2.0));
+        assert_eq!(eu_aqi(&[]), None);
+    }
+
+    #[test]
+    fn eu_aqi_averages_particles_not_gases() {
+        let recent: Vec<PollUpdate> = vec![test_update(0, 10.0, 10.0, 40.0), test_update(1, 10.0, 10.0, 40.0)];
+        assert_eq!(indices::Index::AqiEu.compute(&recent, &test_update(2, 100.0, 10.0, 10.0)), Some(4.0));
+        assert_eq!(indices::Index::AqiEu.compute(&recent, &test_update(2, 250.0, 10.0, 10.0)), Some(5.0));
+    }
+}
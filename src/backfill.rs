@@ -0,0 +1,104 @@
+//! Seeding InfluxDB with OpenWeatherMaps' own past readings, so a new deployment does not start from zero.<br>
+//! Run with the binary's backfill subcommand, ex: `pollutionclient_rs backfill --from 2024-01-01`.
+//! The range is fetched from the air_pollution/history endpoint a week at a time, and every hourly reading is written with its original timestamp through the same transforms and sinks as a poll.
+
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use crate::client::PollutionClient;
+use crate::report;
+
+/// How many days of history are asked for at a time
+pub const BACKFILL_CHUNK_DAYS: i64 = 7;
+/// Time between requests to OpenWeatherMaps, to stay within the free plan's rate limit of 60 a minute
+pub const BACKFILL_DELAY: Duration = Duration::from_millis(1100);
+/// Unix timestamp of the earliest hour OpenWeatherMaps has history for, 2020-11-27 00:00 UTC
+pub const HISTORY_START: i64 = 1606435200;
+
+/// What to backfill, from the backfill subcommand's flags
+#[derive(Clone, Debug, PartialEq)]
+pub struct BackfillArgs {
+    /// Earliest hour to backfill
+    pub from: DateTime<Utc>,
+    /// Latest hour to backfill, or up to now if None
+    pub to: Option<DateTime<Utc>>,
+}
+
+/// How a backfill went
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BackfillSummary {
+    /// Ranges asked for from the history endpoint
+    pub chunks: usize,
+    /// Hourly readings written, leaving out any already written or dropped by a transform
+    pub written: usize,
+}
+
+/// Split a range into consecutive ranges of at most BACKFILL_CHUNK_DAYS, oldest first
+pub fn chunks(from: DateTime<Utc>, to: DateTime<Utc>) -> Vec<(DateTime<Utc>, DateTime<Utc>)> {
+    let step: chrono::Duration = chrono::Duration::days(BACKFILL_CHUNK_DAYS);
+    let mut ranges: Vec<(DateTime<Utc>, DateTime<Utc>)> = Vec::new();
+    let mut start: DateTime<Utc> = from;
+    while start < to {
+        let end: DateTime<Utc> = (start + step).min(to);
+        ranges.push((start, end));
+        start = end;
+    }
+    ranges
+}
+
+/// Move the start of a range up to HISTORY_START if it is earlier, as OpenWeatherMaps has nothing before then
+pub fn clamp_start(from: DateTime<Utc>) -> DateTime<Utc> {
+    let earliest: DateTime<Utc> = DateTime::from_timestamp(HISTORY_START, 0).unwrap_or(from);
+    from.max(earliest)
+}
+
+/// Fetch the hourly history for the range the backfill asks for and write it, printing progress after each chunk<br>
+/// The client should have been prepared first, so the database and any retention policies exist.
+///
+/// # Errors
+/// Returns a message if the range is empty or a chunk cannot be fetched or written. Readings written before then stay written.
+pub async fn run(pollution_client: &PollutionClient, backfill: &BackfillArgs) -> Result<BackfillSummary, String> {
+    let to: DateTime<Utc> = backfill.to.unwrap_or_else(Utc::now);
+    let from: DateTime<Utc> = clamp_start(backfill.from);
+    if from != backfill.from {
+        report::warn(&format!("OpenWeatherMaps has no history before {}, backfilling from then instead of {}.", from, backfill.from));
+    }
+    if from >= to {
+        return Err(format!("backfill needs --from before --to, got {} and {}.", from, to));
+    }
+    let ranges: Vec<(DateTime<Utc>, DateTime<Utc>)> = chunks(from, to);
+    report::info(&format!("Backfilling history between {} and {} in {} requests.", from, to, ranges.len()));
+    let mut summary: BackfillSummary = BackfillSummary::default();
+    for (start, end) in ranges {
+        if summary.chunks > 0 {
+            tokio::time::sleep(BACKFILL_DELAY).await;
+        }
+        summary.written += pollution_client.backfill(start, end).await
+            .map_err(|e| format!("Unable to backfill readings between {} and {}: {}", start, end, e))?;
+        summary.chunks += 1;
+        report::info(&format!("Backfilled up to {}, {} readings written so far.", end, summary.written));
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_cover_range() {
+        let from: DateTime<Utc> = DateTime::from_timestamp(1700000000, 0).unwrap();
+        let ranges: Vec<(DateTime<Utc>, DateTime<Utc>)> = chunks(from, from + chrono::Duration::days(16));
+        assert_eq!(ranges.len(), 3);
+        assert_eq!(ranges[0], (from, from + chrono::Duration::days(7)));
+        assert_eq!(ranges[2], (from + chrono::Duration::days(14), from + chrono::Duration::days(16)));
+        assert!(chunks(from, from).is_empty());
+    }
+
+    #[test]
+    fn clamp_to_history_start() {
+        let early: DateTime<Utc> = DateTime::from_timestamp(1500000000, 0).unwrap();
+        assert_eq!(clamp_start(early), DateTime::from_timestamp(HISTORY_START, 0).unwrap());
+        let late: DateTime<Utc> = DateTime::from_timestamp(1700000000, 0).unwrap();
+        assert_eq!(clamp_start(late), late);
+    }
+}
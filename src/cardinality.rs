@@ -0,0 +1,164 @@
+//! A guard against configurations that would write far more InfluxDB series than intended, ex: one location per raw latitude and longitude.<br>
+//! Before the client starts, the series it will write are estimated from the locations and tags the Config uses and compared to OPENWEATHER_CARDINALITY_BUDGET.
+//! Going over the budget, or a location named after raw coordinates, is warned about loudly or refused outright as OPENWEATHER_CARDINALITY_POLICY says.
+
+use std::fmt;
+use std::str::FromStr;
+use crate::grid::GridCell;
+use crate::trend;
+use crate::{report, Config};
+
+/// Default number of series the client may write before the guard steps in
+pub const DEFAULT_CARDINALITY_BUDGET: u64 = 1000;
+/// Fields written for every reading including schema_version, each its own series on InfluxDB v2
+pub const FIELDS_PER_READING: u64 = 10;
+
+/// What to do when the Config goes over the budget, set by OPENWEATHER_CARDINALITY_POLICY
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CardinalityPolicy {
+    /// Print a warning and start anyway
+    Warn,
+    /// Refuse to start
+    Refuse,
+}
+
+impl FromStr for CardinalityPolicy {
+    type Err = String;
+    fn from_str(raw_policy: &str) -> Result<Self, Self::Err> {
+        match raw_policy.trim().to_lowercase().as_str() {
+            "warn" => Ok(CardinalityPolicy::Warn),
+            "refuse" => Ok(CardinalityPolicy::Refuse),
+            _ => Err(format!("Unknown cardinality policy \"{}\". Expected warn or refuse.", raw_policy)),
+        }
+    }
+}
+
+impl fmt::Display for CardinalityPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CardinalityPolicy::Warn => write!(f, "warn"),
+            CardinalityPolicy::Refuse => write!(f, "refuse"),
+        }
+    }
+}
+
+/// Every location name the Config will write as a location tag, one for each grid point when OPENWEATHER_GRID_BBOX is set or each location in OPENWEATHER_POLL_ZIP otherwise
+pub fn locations(current_config: &Config) -> Vec<String> {
+    if !current_config.location_is_set() {
+        return Vec::new();
+    }
+    let cells: Vec<GridCell> = current_config.get_grid_cells();
+    if cells.is_empty() {
+        current_config.get_locations().iter().map(|location| location.to_string()).collect()
+    } else {
+        cells.iter().map(|cell| cell.location(current_config.get_location())).collect()
+    }
+}
+
+/// Estimate the series the Config will write: every location, times each value of the other tags, times the fields and any indices, trends and collected_at, in each measurement, plus a forecast series of each when it is written
+pub fn estimate_series(current_config: &Config) -> u64 {
+    let mut locations: Vec<String> = locations(current_config);
+    if locations.is_empty() {
+        locations.push(current_config.get_location().to_string());
+    }
+    let interpolated: u64 = if current_config.get_interpolate_gaps() > 0 { 2 } else { 1 };
+    let sources: u64 = if current_config.get_reference_station().is_some() { 2 } else { 1 };
+    let measurements: u64 = if current_config.get_downsample() { 2 } else { 1 };
+    let trends: u64 = if current_config.get_trend_hours() > 0 { 2 * trend::POLLUTANTS.len() as u64 } else { 0 };
+    let forecasts: u64 = if current_config.get_write_forecast() { 1 } else { 0 };
+    let collected: u64 = if current_config.get_write_collected_at() { 1 } else { 0 };
+    let fields: u64 = locations.iter().map(|location| FIELDS_PER_READING + current_config.get_indices(location).len() as u64 + trends + collected).sum();
+    fields * (interpolated * sources * measurements + forecasts)
+}
+
+/// Check if a tag value is made of raw coordinates, ex: "47.6062,-122.3321", which gives a new series whenever they change in the last decimal place
+pub fn looks_like_coordinates(value: &str) -> bool {
+    let parts: Vec<&str> = value.split([',', ' ', ';', '/']).filter(|part| !part.is_empty()).collect();
+    parts.len() == 2 && parts.iter().all(|part| part.contains('.') && part.parse::<f64>().is_ok())
+}
+
+/// Find everything about the Config that breaks the cardinality budget
+pub fn problems(current_config: &Config) -> Vec<String> {
+    let mut problems: Vec<String> = locations(current_config).into_iter()
+        .filter(|location| looks_like_coordinates(location))
+        .map(|location| format!("Location \"{}\" is raw coordinates. Every change in them creates new series, so name the location instead.", location))
+        .collect();
+    let estimate: u64 = estimate_series(current_config);
+    if estimate > current_config.get_cardinality_budget() {
+        problems.push(format!("This configuration writes an estimated {} series, over the OPENWEATHER_CARDINALITY_BUDGET of {}.", estimate, current_config.get_cardinality_budget()));
+    }
+    problems
+}
+
+/// Check the Config against the budget, printing a warning for every problem
+///
+/// # Errors
+/// Returns the problems as one message if there are any and OPENWEATHER_CARDINALITY_POLICY is "refuse"
+pub fn check(current_config: &Config) -> Result<(), String> {
+    let problems: Vec<String> = problems(current_config);
+    if problems.is_empty() {
+        return Ok(());
+    }
+    match current_config.get_cardinality_policy() {
+        CardinalityPolicy::Warn => {
+            problems.iter().for_each(|problem| report::warn(problem));
+            Ok(())
+        },
+        CardinalityPolicy::Refuse => Err(format!("Refusing to start as OPENWEATHER_CARDINALITY_POLICY is \"refuse\": {}", problems.join(" "))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grid::BoundingBox;
+    use crate::indices::Index;
+    use crate::reference::ReferenceStation;
+    use crate::Location;
+
+    #[test]
+    fn coordinates_detected() {
+        assert!(looks_like_coordinates("47.6062,-122.3321"));
+        assert!(looks_like_coordinates("47.6 -122.3"));
+        assert!(!looks_like_coordinates("Seattle"));
+        assert!(!looks_like_coordinates("90210"));
+        assert!(!looks_like_coordinates("Route 66, 2"));
+    }
+
+    #[test]
+    fn budget_is_checked() {
+        let test_config: Config = Config::default();
+        assert_eq!(estimate_series(&test_config), FIELDS_PER_READING);
+        assert!(problems(&test_config).is_empty());
+        assert!(check(&test_config).is_ok());
+        assert_eq!(estimate_series(&Config { indices: vec![Index::Aqhi], ..Config::default() }), FIELDS_PER_READING + 1);
+        assert_eq!(estimate_series(&Config { trend_hours: 3, ..Config::default() }), FIELDS_PER_READING + 16);
+        let tight: Config = Config { cardinality_budget: 10, interpolate_gaps: 2, cardinality_policy: CardinalityPolicy::Refuse, ..Config::default() };
+        assert_eq!(estimate_series(&tight), 2 * FIELDS_PER_READING);
+        assert!(check(&tight).unwrap_err().contains("estimated 20 series"));
+        assert_eq!("Refuse".parse::<CardinalityPolicy>(), Ok(CardinalityPolicy::Refuse));
+        assert!("ignore".parse::<CardinalityPolicy>().is_err());
+    }
+
+    #[test]
+    fn grid_points_are_locations() {
+        let mut gridded: Config = Config { grid_bbox: Some(BoundingBox { south: 0.0, west: 0.0, north: 0.2, east: 0.2 }), grid_spacing: 11.132, ..Config::default() };
+        assert!(locations(&gridded).is_empty());
+        gridded.location = Some(Location::Zip(serde_json::from_str(r#"{"zip":"170150","name":"Quito","lat":0.1,"lon":0.1,"country":"EC"}"#).unwrap()));
+        assert_eq!(locations(&gridded), vec!["Quito r00c00", "Quito r00c01", "Quito r01c00", "Quito r01c01"]);
+        assert_eq!(estimate_series(&gridded), 4 * FIELDS_PER_READING);
+        gridded.reference_station = Some(ReferenceStation::Nearest);
+        assert_eq!(estimate_series(&gridded), 8 * FIELDS_PER_READING);
+    }
+
+    #[test]
+    fn every_zip_is_a_location() {
+        let beverly_hills: Location = Location::Zip(serde_json::from_str(r#"{"zip":"90210","name":"Beverly Hills","lat":34.1,"lon":-118.4,"country":"US"}"#).unwrap());
+        let new_york: Location = Location::Coords { name: "New York".to_string(), lat: 40.7, lon: -74.0, country: "US".to_string() };
+        let mut sites: Config = Config { location: Some(beverly_hills), more_locations: vec![new_york], ..Config::default() };
+        assert_eq!(locations(&sites), vec!["Beverly Hills", "New York"]);
+        assert_eq!(estimate_series(&sites), 2 * FIELDS_PER_READING);
+        sites.location_standards = std::collections::BTreeMap::from([("New York".to_string(), crate::standard::AqiStandard::Us)]);
+        assert_eq!(estimate_series(&sites), 2 * FIELDS_PER_READING + 1);
+    }
+}
@@ -0,0 +1,424 @@
+//! Command line flags for the bundled binary, parsed with clap.<br>
+//! Everything about what to poll and where to write it still comes from the environment or FILE_POLL_CONFIG; flags only change how the binary runs and how much it prints.
+
+use std::str::FromStr;
+use std::time::Duration;
+use chrono::{DateTime, NaiveDate, Utc};
+use clap::{ArgGroup, Parser, Subcommand};
+use crate::backfill::BackfillArgs;
+use crate::dryrun::DryRunFormat;
+use crate::export::{ExportArgs, ExportFormat};
+use crate::geocode::GeocodeArgs;
+use crate::import::ImportArgs;
+use crate::{country, influx, POLLUTION_MEASUREMENT};
+use crate::migrate::MigrateArgs;
+use crate::reference::ReferenceStation;
+use crate::schema::{self, FieldNames};
+use crate::report::Verbosity;
+
+/// Flags given on the command line
+#[derive(Clone, Debug, PartialEq)]
+pub struct Args {
+    /// Show the live dashboard instead of printing readings (needs the "tui" feature)
+    pub tui: bool,
+    /// How much to print to the console
+    pub verbosity: Verbosity,
+    /// Stop cleanly once this long has passed since starting
+    pub run_for: Option<Duration>,
+    /// Stop cleanly after this many successful polls
+    pub polls: Option<u64>,
+    /// Print what would be written in this format instead of writing it, from --dry-run
+    pub dry_run: Option<DryRunFormat>,
+    /// Fetch and write a single reading for every location and exit, from the once subcommand
+    pub once: bool,
+    /// Check the configuration without looking up locations or connecting to anything, from the validate subcommand
+    pub validate: bool,
+    /// Export stored readings instead of polling, from the export subcommand
+    pub export: Option<ExportArgs>,
+    /// Migrate points to the current schema instead of polling, from the migrate-schema subcommand
+    pub migrate: Option<MigrateArgs>,
+    /// Look up the coordinates of many sites instead of polling, from the geocode subcommand
+    pub geocode: Option<GeocodeArgs>,
+    /// Import a station's history from OpenAQ instead of polling, from the import subcommand
+    pub import: Option<ImportArgs>,
+    /// Write OpenWeatherMaps' past readings instead of polling, from the backfill subcommand
+    pub backfill: Option<BackfillArgs>,
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Args { tui: false, verbosity: Verbosity::Normal, run_for: None, polls: None, dry_run: None, once: false, validate: false, export: None, migrate: None, geocode: None, import: None, backfill: None }
+    }
+}
+
+/// Exit code when the configuration is invalid, the flags are not recognised or setting up fails, ex: the location cannot be found
+pub const EXIT_ERROR: i32 = 1;
+/// Exit code when OpenWeatherMaps rejects the API key, so a supervisor can tell it apart from a crash and stop restarting
+pub const EXIT_API_KEY_REJECTED: i32 = 3;
+
+/// Printed under the flags in --help
+const AFTER_HELP: &str = "TIME is an RFC 3339 time, a date like 2024-03-01 or a duration back from now like 30d.
+
+Exits with code 1 if the flags or configuration are invalid or setting up fails, and with code 3 if OpenWeatherMaps rejects the API key.
+Everything else is configured with OPENWEATHER_ environmental variables or a file named by FILE_POLL_CONFIG.";
+
+/// Polls OpenWeatherMaps for air pollution readings and writes them to InfluxDB
+#[derive(Debug, Parser)]
+#[command(name = "pollutionclient_rs", version, after_help = AFTER_HELP, args_conflicts_with_subcommands = true, disable_help_subcommand = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    run: RunFlags,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Poll and write every OPENWEATHER_POLL_TIMING until stopped, the same as giving no subcommand
+    Run(RunFlags),
+    /// Fetch and write a single reading for every location, then exit. Exits with code 1 if that fails, so it suits cron.
+    Once(OutputFlags),
+    /// Load the configuration without looking up locations or connecting to anything and print every problem found. Exits with code 1 if there are any.
+    Validate(OutputFlags),
+    /// Write the readings stored in InfluxDB between two times to a file, or the console without --output
+    Export(ExportFlags),
+    /// Rewrite points from an older schema into the current one in a new measurement, printing progress as it goes
+    MigrateSchema(MigrateFlags),
+    /// Look up the coordinates of sites and write them as OPENWEATHER_POLL_LOCATIONS for a configuration file, or to the console without --output
+    Geocode(GeocodeFlags),
+    /// Write a station's hourly history from OpenAQ to InfluxDB as reference readings, tagged source=reference
+    Import(ImportFlags),
+    /// Write OpenWeatherMaps' hourly history for the configured locations between two times, each reading with its original timestamp
+    Backfill(BackfillFlags),
+}
+
+/// Flags for how much to print, taken by every subcommand that polls
+#[derive(Debug, clap::Args)]
+struct OutputFlags {
+    /// Only print warnings and errors
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Also print every field of each update as it is fetched, transformed and written
+    #[arg(short, long)]
+    verbose: bool,
+    /// Print the line protocol, or JSON with --dry-run=json, that would have been written instead of writing it
+    #[arg(long, value_name = "FORMAT", num_args = 0..=1, require_equals = true, value_parser = DryRunFormat::from_str)]
+    dry_run: Option<Option<DryRunFormat>>,
+}
+
+/// Flags for polling until stopped
+#[derive(Debug, clap::Args)]
+struct RunFlags {
+    #[command(flatten)]
+    output: OutputFlags,
+    /// Show a live dashboard of every location instead of printing readings (needs the "tui" feature)
+    #[arg(long)]
+    tui: bool,
+    /// Stop cleanly and exit with code 0 after this long, ex: 24h or 1h30m
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    run_for: Option<Duration>,
+    /// Stop cleanly and exit with code 0 after N successful polls
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    polls: Option<u64>,
+}
+
+#[derive(Debug, clap::Args)]
+struct ExportFlags {
+    /// Earliest reading to export
+    #[arg(long, value_name = "TIME", value_parser = parse_time_from_now)]
+    from: DateTime<Utc>,
+    /// Latest reading to export. Defaults to now.
+    #[arg(long, value_name = "TIME", value_parser = parse_time_from_now)]
+    to: Option<DateTime<Utc>>,
+    /// Location to export. Defaults to the configured one.
+    #[arg(long, value_name = "NAME")]
+    location: Option<String>,
+    /// File format to write, csv or json
+    #[arg(long, default_value = "csv", value_parser = ExportFormat::from_str)]
+    format: ExportFormat,
+    /// File to write the readings to
+    #[arg(long, value_name = "FILE")]
+    output: Option<String>,
+}
+
+#[derive(Debug, clap::Args)]
+struct MigrateFlags {
+    /// Measurement to write the migrated points to
+    #[arg(long, value_name = "MEASUREMENT")]
+    to: String,
+    /// Measurement holding the old points
+    #[arg(long, value_name = "MEASUREMENT", default_value = POLLUTION_MEASUREMENT)]
+    from: String,
+    /// Database, or bucket on InfluxDB v2, to write to. Defaults to the configured one.
+    #[arg(long, value_name = "NAME")]
+    database: Option<String>,
+    /// Earliest point to migrate. Defaults to every point.
+    #[arg(long, value_name = "TIME", value_parser = parse_time_from_now)]
+    since: Option<DateTime<Utc>>,
+    /// Location for points without a location tag. Defaults to the configured one.
+    #[arg(long, value_name = "NAME")]
+    location: Option<String>,
+    /// Names the old points store fields under, ex: pm2_5=pm25. New points use OPENWEATHER_INFLUXDB_FIELD_NAMES.
+    #[arg(long, value_name = "FIELD=NAME,...", value_parser = schema::parse_field_names)]
+    field_names: Option<FieldNames>,
+}
+
+#[derive(Debug, clap::Args)]
+#[command(group(ArgGroup::new("sites").required(true).args(["input", "zip"])))]
+struct GeocodeFlags {
+    /// CSV file of sites, with a header naming any of the name, zip, city and country columns and a zip or a city for every site. Lookups are spaced to stay within OpenWeatherMaps' rate limit.
+    #[arg(long, value_name = "FILE")]
+    input: Option<String>,
+    /// Single zip code to look up instead of a CSV file
+    #[arg(long)]
+    zip: Option<String>,
+    /// Country of --zip as a code or name. Defaults to US.
+    #[arg(long, conflicts_with = "input", value_parser = country::normalize)]
+    country: Option<String>,
+    /// File to write the locations to
+    #[arg(long, value_name = "FILE")]
+    output: Option<String>,
+}
+
+#[derive(Debug, clap::Args)]
+struct ImportFlags {
+    /// Earliest hour to import
+    #[arg(long, value_name = "TIME", value_parser = parse_time_from_now)]
+    from: DateTime<Utc>,
+    /// Latest hour to import. Defaults to now.
+    #[arg(long, value_name = "TIME", value_parser = parse_time_from_now)]
+    to: Option<DateTime<Utc>>,
+    /// OpenAQ location ID of the station, or nearest. Defaults to OPENWEATHER_REFERENCE_STATION, or the nearest monitor if that is not set.
+    #[arg(long, value_name = "nearest|ID", value_parser = ReferenceStation::from_str)]
+    station: Option<ReferenceStation>,
+    /// Location to write the readings under. Defaults to the configured one.
+    #[arg(long, value_name = "NAME")]
+    location: Option<String>,
+}
+
+#[derive(Debug, clap::Args)]
+struct BackfillFlags {
+    /// Earliest hour to backfill. History starts on 2020-11-27.
+    #[arg(long, value_name = "TIME", value_parser = parse_time_from_now)]
+    from: DateTime<Utc>,
+    /// Latest hour to backfill. Defaults to now. Readings already written are skipped as OPENWEATHER_DUPLICATES says.
+    #[arg(long, value_name = "TIME", value_parser = parse_time_from_now)]
+    to: Option<DateTime<Utc>>,
+}
+
+/// Parse a duration given on the command line, ex: "24h", "90m" or "1h30m", using the same units as InfluxDB durations
+///
+/// # Errors
+/// Returns a message if the duration is malformed or zero
+pub fn parse_duration(raw_duration: &str) -> Result<Duration, String> {
+    match influx::duration_to_nanos(raw_duration.trim()) {
+        Some(nanos) if nanos > 0 => Ok(Duration::from_nanos(u64::try_from(nanos).unwrap_or(u64::MAX))),
+        _ => Err(format!("Invalid duration \"{}\". Expected a number and unit, ex: 30s, 90m, 24h or 1h30m.", raw_duration)),
+    }
+}
+
+/// Parse a time given on the command line: an RFC 3339 time, a date meaning midnight UTC, ex: "2024-03-01", or a duration back from now, ex: "30d"
+///
+/// # Errors
+/// Returns a message if the time is none of those
+pub fn parse_time(raw_time: &str, now: DateTime<Utc>) -> Result<DateTime<Utc>, String> {
+    let raw_time: &str = raw_time.trim();
+    if let Ok(time) = DateTime::parse_from_rfc3339(raw_time) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    if let Some(midnight) = NaiveDate::parse_from_str(raw_time, "%Y-%m-%d").ok().and_then(|date| date.and_hms_opt(0, 0, 0)) {
+        return Ok(midnight.and_utc());
+    }
+    match parse_duration(raw_time).ok().and_then(|ago| chrono::Duration::from_std(ago).ok()) {
+        Some(ago) => Ok(now - ago),
+        None => Err(format!("Invalid time \"{}\". Expected an RFC 3339 time, a date like 2024-03-01 or a duration back from now like 30d.", raw_time)),
+    }
+}
+
+/// Parse a time given on the command line against the current time
+fn parse_time_from_now(raw_time: &str) -> Result<DateTime<Utc>, String> {
+    parse_time(raw_time, Utc::now())
+}
+
+/// Set the verbosity and dry run from the flags every polling subcommand takes
+fn apply_output(parsed: &mut Args, output: OutputFlags) {
+    parsed.verbosity = match (output.quiet, output.verbose) {
+        (true, _) => Verbosity::Quiet,
+        (_, true) => Verbosity::Verbose,
+        _ => Verbosity::Normal,
+    };
+    parsed.dry_run = output.dry_run.map(|format| format.unwrap_or(DryRunFormat::LineProtocol));
+}
+
+/// Parse the command line, starting with the program name. Flags taking a value accept it as the next argument or after an equals sign, ex: --run-for=24h<br>
+/// The run subcommand is the same as giving no subcommand, and once and validate take the same flags apart from --tui, --run-for and --polls.
+///
+/// # Errors
+/// Returns clap's error if a flag is not recognised, is missing its value or cannot be used with another, or if --help or --version was given, which should be printed instead
+pub fn parse_args<I: IntoIterator<Item = String>>(args: I) -> Result<Args, clap::Error> {
+    let cli: Cli = Cli::try_parse_from(args)?;
+    let mut parsed: Args = Args::default();
+    match cli.command.unwrap_or(Command::Run(cli.run)) {
+        Command::Run(run) => {
+            apply_output(&mut parsed, run.output);
+            parsed.tui = run.tui;
+            parsed.run_for = run.run_for;
+            parsed.polls = run.polls;
+        },
+        Command::Once(output) => {
+            apply_output(&mut parsed, output);
+            parsed.once = true;
+        },
+        Command::Validate(output) => {
+            apply_output(&mut parsed, output);
+            parsed.validate = true;
+        },
+        Command::Export(export) => {
+            parsed.export = Some(ExportArgs { location: export.location, from: export.from, to: export.to, format: export.format, output: export.output });
+        },
+        Command::MigrateSchema(migrate) => {
+            parsed.migrate = Some(MigrateArgs { from: migrate.from, to: migrate.to, database: migrate.database, location: migrate.location, since: migrate.since, field_names: migrate.field_names.unwrap_or_default() });
+        },
+        Command::Geocode(geocode) => {
+            parsed.geocode = Some(GeocodeArgs { input: geocode.input, zip: geocode.zip, country: geocode.country, output: geocode.output });
+        },
+        Command::Import(import) => {
+            parsed.import = Some(ImportArgs { station: import.station, from: import.from, to: import.to, location: import.location });
+        },
+        Command::Backfill(backfill) => {
+            parsed.backfill = Some(BackfillArgs { from: backfill.from, to: backfill.to });
+        },
+    }
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::error::ErrorKind;
+
+    fn args(raw: &[&str]) -> Vec<String> {
+        std::iter::once("pollutionclient_rs").chain(raw.iter().copied()).map(|arg| arg.to_string()).collect()
+    }
+
+    fn error_kind(raw: &[&str]) -> ErrorKind {
+        parse_args(args(raw)).unwrap_err().kind()
+    }
+
+    #[test]
+    fn parse_args_flags() {
+        assert_eq!(parse_args(args(&[])).unwrap(), Args::default());
+        assert!(parse_args(args(&["--tui"])).unwrap().tui);
+        assert_eq!(error_kind(&["-h"]), ErrorKind::DisplayHelp);
+        assert_eq!(error_kind(&["export", "--help"]), ErrorKind::DisplayHelp);
+    }
+
+    #[test]
+    fn parse_args_verbosity() {
+        assert_eq!(parse_args(args(&["-q"])).unwrap().verbosity, Verbosity::Quiet);
+        assert_eq!(parse_args(args(&["--verbose"])).unwrap().verbosity, Verbosity::Verbose);
+        assert_eq!(error_kind(&["--quiet", "--verbose"]), ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn parse_args_run_for() {
+        assert_eq!(parse_args(args(&["--run-for", "24h"])).unwrap().run_for, Some(Duration::from_secs(86_400)));
+        assert_eq!(parse_args(args(&["--run-for=1h30m", "-q"])).unwrap().run_for, Some(Duration::from_secs(5_400)));
+        assert!(parse_args(args(&["--run-for"])).is_err());
+        assert_eq!(error_kind(&["--run-for", "0s"]), ErrorKind::ValueValidation);
+        assert_eq!(error_kind(&["--run-for", "tomorrow"]), ErrorKind::ValueValidation);
+    }
+
+    #[test]
+    fn parse_args_polls() {
+        assert_eq!(parse_args(args(&["--polls", "3"])).unwrap().polls, Some(3));
+        assert_eq!(parse_args(args(&["--polls=1"])).unwrap().polls, Some(1));
+        assert!(parse_args(args(&["--polls", "0"])).is_err());
+        assert!(parse_args(args(&["--polls", "-2"])).is_err());
+    }
+
+    #[test]
+    fn parse_time_forms() {
+        let now: DateTime<Utc> = DateTime::from_timestamp(1700000000, 0).unwrap();
+        assert_eq!(parse_time("2d", now), Ok(now - chrono::Duration::days(2)));
+        assert_eq!(parse_time("2023-11-14", now), Ok(DateTime::from_timestamp(1699920000, 0).unwrap()));
+        assert_eq!(parse_time("2023-11-14T01:00:00+01:00", now), Ok(DateTime::from_timestamp(1699920000, 0).unwrap()));
+        assert!(parse_time("yesterday", now).is_err());
+    }
+
+    #[test]
+    fn parse_args_export() {
+        let export: ExportArgs = parse_args(args(&["export", "--from", "2023-11-01", "--to=2023-11-14", "--format", "json", "--location", "Home"])).unwrap().export.unwrap();
+        assert_eq!(export.from, DateTime::from_timestamp(1698796800, 0).unwrap());
+        assert_eq!(export.to, Some(DateTime::from_timestamp(1699920000, 0).unwrap()));
+        assert_eq!(export.format, ExportFormat::Json);
+        assert_eq!(export.location, Some("Home".to_string()));
+        assert_eq!(parse_args(args(&["export", "--from", "30d"])).unwrap().export.unwrap().format, ExportFormat::Csv);
+        assert_eq!(error_kind(&["export", "--to", "1d"]), ErrorKind::MissingRequiredArgument);
+        assert_eq!(error_kind(&["export", "--from", "yesterday"]), ErrorKind::ValueValidation);
+        assert!(parse_args(args(&["--tui", "export"])).is_err());
+    }
+
+    #[test]
+    fn parse_args_migrate() {
+        let migrate: MigrateArgs = parse_args(args(&["migrate-schema", "--to", "pollution_v2", "--since=2023-11-07", "--field-names", "pm2_5=pm25", "--database", "archive"])).unwrap().migrate.unwrap();
+        assert_eq!(migrate.from, "pollution");
+        assert_eq!(migrate.to, "pollution_v2");
+        assert_eq!(migrate.since, Some(DateTime::from_timestamp(1699315200, 0).unwrap()));
+        assert_eq!(migrate.database, Some("archive".to_string()));
+        assert_eq!(schema::field_name(&migrate.field_names, "pm2_5"), "pm25");
+        assert_eq!(error_kind(&["migrate-schema", "--from", "old"]), ErrorKind::MissingRequiredArgument);
+        assert_eq!(error_kind(&["migrate-schema", "--to", "new", "--field-names", "pm1=x"]), ErrorKind::ValueValidation);
+    }
+
+    #[test]
+    fn parse_args_geocode() {
+        let geocode: GeocodeArgs = parse_args(args(&["geocode", "--input", "sites.csv", "--output=locations.toml"])).unwrap().geocode.unwrap();
+        assert_eq!(geocode, GeocodeArgs { input: Some("sites.csv".to_string()), zip: None, country: None, output: Some("locations.toml".to_string()) });
+        assert_eq!(error_kind(&["geocode", "--output", "locations.toml"]), ErrorKind::MissingRequiredArgument);
+        let geocode: GeocodeArgs = parse_args(args(&["geocode", "--zip", "10115", "--country=Germany"])).unwrap().geocode.unwrap();
+        assert_eq!((geocode.zip, geocode.country), (Some("10115".to_string()), Some("DE".to_string())));
+        assert_eq!(error_kind(&["geocode", "--zip", "10115", "--input", "sites.csv"]), ErrorKind::ArgumentConflict);
+        assert_eq!(error_kind(&["geocode", "--input", "sites.csv", "--country", "DE"]), ErrorKind::ArgumentConflict);
+        assert_eq!(error_kind(&["geocode", "--zip", "10115", "--country", "Atlantis"]), ErrorKind::ValueValidation);
+    }
+
+    #[test]
+    fn parse_args_import() {
+        let import: ImportArgs = parse_args(args(&["import", "--from", "2023-01-01", "--station=8118", "--location", "Seattle"])).unwrap().import.unwrap();
+        assert_eq!(import.from, DateTime::from_timestamp(1672531200, 0).unwrap());
+        assert_eq!(import.station, Some(ReferenceStation::Id(8118)));
+        assert_eq!(import.to, None);
+        assert_eq!(error_kind(&["import", "--station", "nearest"]), ErrorKind::MissingRequiredArgument);
+        assert_eq!(error_kind(&["import", "--from", "30d", "--station", "closest"]), ErrorKind::ValueValidation);
+    }
+
+    #[test]
+    fn parse_args_backfill() {
+        let backfill: BackfillArgs = parse_args(args(&["backfill", "--from", "2023-01-01", "--to=2023-11-14"])).unwrap().backfill.unwrap();
+        assert_eq!(backfill, BackfillArgs { from: DateTime::from_timestamp(1672531200, 0).unwrap(), to: Some(DateTime::from_timestamp(1699920000, 0).unwrap()) });
+        assert_eq!(error_kind(&["backfill", "--to", "1d"]), ErrorKind::MissingRequiredArgument);
+        assert_eq!(error_kind(&["backfill", "--from", "30d", "--location", "Home"]), ErrorKind::UnknownArgument);
+    }
+
+    #[test]
+    fn parse_args_subcommands() {
+        assert_eq!(parse_args(args(&["run", "--polls", "2"])).unwrap(), parse_args(args(&["--polls", "2"])).unwrap());
+        let once: Args = parse_args(args(&["once", "-q"])).unwrap();
+        assert!(once.once && !once.validate);
+        assert_eq!(once.verbosity, Verbosity::Quiet);
+        assert!(parse_args(args(&["validate"])).unwrap().validate);
+        assert_eq!(error_kind(&["once", "--polls", "2"]), ErrorKind::UnknownArgument);
+        assert_eq!(error_kind(&["validate", "--tui"]), ErrorKind::UnknownArgument);
+        assert!(parse_args(args(&["--polls", "2", "once"])).is_err());
+        assert_eq!(parse_args(args(&["once", "--dry-run"])).unwrap().dry_run, Some(DryRunFormat::LineProtocol));
+        assert_eq!(parse_args(args(&["--dry-run=json"])).unwrap().dry_run, Some(DryRunFormat::Json));
+        assert_eq!(error_kind(&["--dry-run=csv"]), ErrorKind::ValueValidation);
+    }
+
+    #[test]
+    fn parse_args_rejects_unknown() {
+        assert_eq!(error_kind(&["--tiu"]), ErrorKind::UnknownArgument);
+        assert_eq!(error_kind(&["status"]), ErrorKind::InvalidSubcommand);
+    }
+}
@@ -0,0 +1,1194 @@
+//! PollutionClient ties a Config, its InfluxDB client and the in-memory history together for applications embedding this crate.<br>
+//! It runs the same poll, transform and write loop as the bundled binary so other programs can reuse it.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::path::Path;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use std::collections::BTreeMap;
+use std::fmt;
+use chrono::{DateTime, Local, SecondsFormat, Utc};
+use influxdb::{Client, Error};
+use tokio::time::{self, Interval};
+use tracing::Instrument;
+use crate::alerts::{AlertEngine, Notifier};
+use crate::dedup::{DuplicatePolicy, SharedState, WrittenState};
+use crate::dryrun::{DryRunFormat, DryRunSink};
+use crate::error::PollutionError;
+use crate::ha::{Lease, Role};
+use crate::history::{ReadingBuffer, SharedBuffer};
+use crate::indices::Index;
+use crate::influx::DailyAverage;
+use crate::plugin::{PluginError, Registry, Sink, Source, Transform, WriteEvent};
+use crate::pipeline::{self, Handled, Polled};
+use crate::queue::{Queue, QueuePolicy};
+use crate::reference::ReferenceMonitor;
+use crate::schedule::Backoff;
+use crate::slack::SlackNotifier;
+use crate::report::LOG_TARGET;
+use crate::{build_client, cardinality, health, i18n, indices, influx, interpolate, metrics, quiet, report, schema, spool, stale, trend, Config, PollUpdate};
+
+/// Longest single sleep between checks for a shutdown request
+const SHUTDOWN_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// How far the wall clock can move beyond the time slept before it is treated as a jump, ex: from a suspend
+const CLOCK_JUMP_THRESHOLD: Duration = Duration::from_secs(60);
+/// How often the forecast is checked against the alert rules when OPENWEATHER_FORECAST_ALERT_HOURS is set, and written when OPENWEATHER_WRITE_FORECAST is on, as it only changes hourly
+const FORECAST_CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Reasons a polling cycle can fail
+#[derive(Debug)]
+pub enum CycleError {
+    /// The source could not be reached or returned an error
+    Fetch(PluginError),
+    /// The update could not be written to a sink
+    Write(PluginError),
+    /// A transform failed on the update
+    Transform(PluginError),
+}
+
+impl fmt::Display for CycleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CycleError::Fetch(e) => write!(f, "Unable to fetch pollution: {}", e),
+            CycleError::Write(e) => write!(f, "Unable to write pollution: {}", e),
+            CycleError::Transform(e) => write!(f, "Unable to transform pollution: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CycleError {}
+
+impl CycleError {
+    /// Check if the cycle could succeed when tried again. Only fetches are retried, and not when the error is one retrying cannot fix.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            CycleError::Fetch(e) => is_retryable_fetch(e),
+            CycleError::Write(_) | CycleError::Transform(_) => false,
+        }
+    }
+}
+
+/// Run a blocking request, ex: a source's fetch through ureq, on tokio's blocking threads and wait for it without holding up the runtime<br>
+/// A request that panics is returned as an error rather than taking the polling loop down with it.
+async fn off_runtime<T, E, F>(request: F) -> Result<T, PluginError>
+where
+    T: Send + 'static,
+    E: Into<PluginError> + Send + 'static,
+    F: FnOnce() -> Result<T, E> + Send + 'static,
+{
+    match tokio::task::spawn_blocking(request).await {
+        Ok(response) => response.map_err(Into::into),
+        Err(e) => Err(format!("Request stopped unexpectedly: {}", e).into()),
+    }
+}
+
+/// Check if a failed fetch was OpenWeatherMaps rejecting the API key, a 401 or 403 status
+pub fn is_auth_failure(e: &PluginError) -> bool {
+    matches!(e.downcast_ref::<ureq::Error>(), Some(ureq::Error::Status(401 | 403, _)))
+}
+
+/// Sort a failed fetch into one worth retrying, ex: a 5xx, a timeout or a dropped connection, or one that will fail the same way every time, ex: a 400 or a 404 from a bad location<br>
+/// Statuses 408 and 429 are retried as they ask the client to come back later. Errors that are not from the HTTP client are retried, as the source may recover.
+pub fn is_retryable_fetch(e: &PluginError) -> bool {
+    match e.downcast_ref::<ureq::Error>() {
+        Some(ureq::Error::Status(code, _)) => *code >= 500 || *code == 408 || *code == 429,
+        Some(ureq::Error::Transport(transport)) => !matches!(transport.kind(),
+            ureq::ErrorKind::InvalidUrl | ureq::ErrorKind::UnknownScheme | ureq::ErrorKind::InsecureRequestHttpsOnly
+            | ureq::ErrorKind::InvalidProxyUrl | ureq::ErrorKind::ProxyUnauthorized | ureq::ErrorKind::TooManyRedirects),
+        None => true,
+    }
+}
+
+/// Called with each update, ex: to refresh a display
+pub type ReadingHook = Box<dyn Fn(&PollUpdate) + Send + Sync>;
+/// Called with each failed cycle, ex: to raise an alarm
+pub type ErrorHook = Box<dyn Fn(&CycleError) + Send + Sync>;
+/// Called after every write to a sink, ex: to ship throughput to a fleet dashboard
+pub type WriteEventHook = Box<dyn Fn(&WriteEvent) + Send + Sync>;
+
+/// Holds everything needed to collect and look up pollution for a Config
+pub struct PollutionClient {
+    config: Config,
+    dbclient: Client,
+    readings: SharedBuffer,
+    written: SharedState,
+    source: Arc<dyn Source>,
+    reference: Option<Arc<ReferenceMonitor>>,
+    sinks: Vec<Box<dyn Sink>>,
+    transforms: Vec<Box<dyn Transform>>,
+    alerts: AlertEngine,
+    forecast_checked: Mutex<Option<Instant>>,
+    stopping: Arc<AtomicBool>,
+    poll_limit: Option<u64>,
+    dry_run: bool,
+    lease: Option<Lease>,
+    reading_hooks: Vec<ReadingHook>,
+    write_hooks: Vec<ReadingHook>,
+    error_hooks: Vec<ErrorHook>,
+    write_event_hooks: Vec<WriteEventHook>,
+}
+
+impl PollutionClient {
+    /// Create a client from a Config using the built in source and sinks, building the InfluxDB client and an empty history sized from OPENWEATHER_HISTORY_SIZE
+    ///
+    /// # Errors
+    /// Returns an error in the same situations as with_registry, including when OPENWEATHER_SOURCE or OPENWEATHER_SINKS name something that is not built in
+    pub fn new(config: Config) -> Result<PollutionClient, PollutionError> {
+        PollutionClient::with_registry(config, &Registry::default())
+    }
+    /// Create a client from a Config, building the source and sinks it names from a registry
+    ///
+    /// # Errors
+    /// Returns PollutionError::Config if a source or sink is not registered or cannot be built, if the Config breaks OPENWEATHER_CARDINALITY_BUDGET and OPENWEATHER_CARDINALITY_POLICY is "refuse", or in the same situations as build_client
+    pub fn with_registry(config: Config, registry: &Registry) -> Result<PollutionClient, PollutionError> {
+        cardinality::check(&config).map_err(PollutionError::Config)?;
+        let source: Arc<dyn Source> = Arc::from(registry.build_source(&config).map_err(PollutionError::Config)?);
+        let sinks: Vec<Box<dyn Sink>> = registry.build_sinks(&config).map_err(PollutionError::Config)?;
+        let mut transforms: Vec<Box<dyn Transform>> = load_wasm_transforms(&config).map_err(PollutionError::Config)?;
+        transforms.extend(load_script_transforms(&config).map_err(PollutionError::Config)?);
+        let mut alerts: AlertEngine = AlertEngine::new(config.get_alerts()).map_err(PollutionError::Config)?;
+        alerts.set_standard(config.get_aqi_standard(config.get_location())).map_err(PollutionError::Config)?;
+        for location in config.get_locations() {
+            alerts.set_location_standard(location, config.get_aqi_standard(location)).map_err(PollutionError::Config)?;
+        }
+        if let Some(webhook) = config.get_slack_webhook() {
+            alerts.add_notifier(Box::new(SlackNotifier::new(webhook, config.get_slack_interval())));
+        }
+        i18n::set_locale(config.get_locale());
+        report::set_mode(config.get_output());
+        let dbclient: Client = build_client(&config)?;
+        let readings: SharedBuffer = ReadingBuffer::shared(config.get_history_size());
+        let written: SharedState = WrittenState::shared(config.get_state_file());
+        let lease: Option<Lease> = config.get_ha_lock_file().map(|path| Lease::new(Path::new(path), config.get_instance_id(), Duration::from_secs(config.get_ha_stale_after())));
+        let dry_run: Option<DryRunFormat> = config.get_dry_run();
+        let mut client: PollutionClient = PollutionClient { reference: ReferenceMonitor::from_config(&config).map(Arc::new), config, dbclient, readings, written, source, sinks, transforms, alerts, forecast_checked: Mutex::new(None), stopping: Arc::new(AtomicBool::new(false)), poll_limit: None, dry_run: false, lease, reading_hooks: Vec::new(), write_hooks: Vec::new(), error_hooks: Vec::new(), write_event_hooks: Vec::new() };
+        if let Some(format) = dry_run {
+            client.dry_run(format);
+        }
+        Ok(client)
+    }
+    /// Get the Config this client was created with
+    pub fn get_config(&self) -> &Config {
+        &self.config
+    }
+    /// Get the InfluxDB client used for writes and queries
+    pub fn get_dbclient(&self) -> &Client {
+        &self.dbclient
+    }
+    /// Get a handle to the in-memory history, ex: to serve it over HTTP
+    pub fn get_readings(&self) -> SharedBuffer {
+        self.readings.clone()
+    }
+    /// Add an update to the in-memory history under its location
+    pub fn record(&self, update: PollUpdate) {
+        self.readings.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push(update);
+    }
+    /// The latest reading for every location in the history, including the current one even when no history is kept
+    fn latest_readings(&self, current: &PollUpdate) -> Vec<PollUpdate> {
+        let buffer = self.readings.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut latest: Vec<PollUpdate> = buffer.locations().iter().filter_map(|location| buffer.latest(location).cloned()).collect();
+        if !latest.iter().any(|reading| reading.get_series() == current.get_series()) {
+            latest.push(current.clone());
+        }
+        latest
+    }
+    /// Add a transform to run on every update after any already added
+    pub fn add_transform(&mut self, transform: Box<dyn Transform>) {
+        self.transforms.push(transform);
+    }
+    /// Add a notifier to tell about alerts alongside the console
+    pub fn add_notifier(&mut self, notifier: Box<dyn Notifier>) {
+        self.alerts.add_notifier(notifier);
+    }
+    /// Make run stop after this many successful polls, writing what is queued before returning as it does on shutdown
+    pub fn stop_after_polls(&mut self, polls: u64) {
+        self.poll_limit = Some(polls);
+    }
+    /// Print what would have been written in a format instead of writing it, as OPENWEATHER_DRY_RUN does<br>
+    /// Every sink is replaced by a dryrun::DryRunSink, prepare leaves the database alone, and the spool and state files are neither read nor changed.
+    pub fn dry_run(&mut self, format: DryRunFormat) {
+        if !self.dry_run {
+            report::info(&format!("Dry run: printing readings as {} instead of writing them to {}.", format, self.config.get_sinks().join(", ")));
+        }
+        self.sinks = vec![Box::new(DryRunSink::new(&self.config, format))];
+        self.written = WrittenState::shared(None);
+        self.dry_run = true;
+    }
+    /// Check if this client prints readings instead of writing them
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+    /// Get this instance's role in leader and standby mode as of its last heartbeat, or None when OPENWEATHER_HA_LOCK_FILE is not set
+    pub fn get_role(&self) -> Option<Role> {
+        self.lease.as_ref().map(|lease| lease.role())
+    }
+    /// Add a hook called with every update once it has been fetched and transformed, before it is written
+    pub fn on_reading<F: Fn(&PollUpdate) + Send + Sync + 'static>(&mut self, hook: F) {
+        self.reading_hooks.push(Box::new(hook));
+    }
+    /// Add a hook called with every update once it has been written to InfluxDB
+    pub fn on_write_success<F: Fn(&PollUpdate) + Send + Sync + 'static>(&mut self, hook: F) {
+        self.write_hooks.push(Box::new(hook));
+    }
+    /// Add a hook called whenever a cycle fails to fetch or write
+    pub fn on_error<F: Fn(&CycleError) + Send + Sync + 'static>(&mut self, hook: F) {
+        self.error_hooks.push(Box::new(hook));
+    }
+    /// Add a hook called with the WriteEvent for every write to a sink, whether it worked or not
+    pub fn on_write_event<F: Fn(&WriteEvent) + Send + Sync + 'static>(&mut self, hook: F) {
+        self.write_event_hooks.push(Box::new(hook));
+    }
+    /// Create the bucket, database, retention policies and downsampling continuous query or task the Config asks for, and on InfluxDB v2 the DBRP mapping the v1 compatibility API needs<br>
+    /// Then checks the schema version of stored points against the one this client writes, printing what changed if they differ. Does nothing on a dry run.
+    ///
+    /// # Errors
+    /// Returns PollutionError::Db with any errors generated by the underlying influxdb crate
+    pub async fn prepare(&self) -> Result<(), PollutionError> {
+        if self.dry_run {
+            return Ok(());
+        }
+        if self.config.get_create_bucket() {
+            influx::ensure_bucket(&self.config)?;
+        }
+        if influx::is_v2(&self.config) {
+            influx::ensure_dbrp(&self.config)?;
+        }
+        if self.config.get_create_db() {
+            influx::ensure_database(&self.dbclient, &self.config).await?;
+        }
+        if !self.config.get_retention_policies().is_empty() {
+            influx::ensure_retention_policies(&self.dbclient, &self.config).await?;
+        }
+        if self.config.get_downsample() {
+            influx::ensure_downsampling(&self.dbclient, &self.config).await?;
+        }
+        match influx::stored_schema_version(&self.dbclient, &self.config).await {
+            Ok(stored) => if let Some(message) = schema::compatibility(stored) { report::warn(&message) },
+            Err(e) => report::debug(&format!("Unable to check the schema version of stored points: {}", e)),
+        };
+        Ok(())
+    }
+    /// Fetch, transform and write a single update, then add it to the in-memory history<br>
+    /// Hooks are called along the way, with the error hooks called before an error is returned.
+    /// Returns None when a transform dropped the update, or when the reading was already written and OPENWEATHER_DUPLICATES is "skip".
+    ///
+    /// # Errors
+    /// Returns CycleError::Fetch if the source fails, CycleError::Transform if a transform fails and CycleError::Write if a sink fails
+    pub async fn run_once(&self) -> Result<Option<PollUpdate>, CycleError> {
+        let cycle: Result<Option<PollUpdate>, CycleError> = self.cycle().instrument(self.poll_span()).await;
+        if let Err(e) = &cycle {
+            self.error_hooks.iter().for_each(|hook| hook(e));
+        }
+        cycle
+    }
+    /// Span around a poll and what it logs, carrying the locations polled
+    fn poll_span(&self) -> tracing::Span {
+        tracing::info_span!(target: LOG_TARGET, "poll", locations = %self.config.get_locations().join(", "))
+    }
+    /// The steps of run_once without the error hooks, running each stage's work in turn for every update fetched<br>
+    /// Returns the last update written when a poll fetches several, ex: one for each grid point
+    async fn cycle(&self) -> Result<Option<PollUpdate>, CycleError> {
+        let mut delivered: Option<PollUpdate> = None;
+        for fetched in self.fetch().await.map_err(CycleError::Fetch)? {
+            if let Some(results) = self.process(fetched)? {
+                delivered = Some(self.deliver(results).await?);
+            }
+        }
+        Ok(delivered)
+    }
+    /// The source stage's work: fetch the updates for a poll, set the location of any the source left unnamed and note when they were fetched if OPENWEATHER_WRITE_COLLECTED_AT is on<br>
+    /// The reference station's latest values are added after them when OPENWEATHER_REFERENCE_STATION is set. Failing to get those is printed but does not fail the poll.
+    /// Both requests run on tokio's blocking threads, so the other stages, signal handling and the HTTP endpoints carry on while they wait.
+    async fn fetch(&self) -> Result<Vec<PollUpdate>, PluginError> {
+        let fetch_start: Instant = Instant::now();
+        let source: Arc<dyn Source> = Arc::clone(&self.source);
+        let response: Result<Vec<PollUpdate>, PluginError> = off_runtime(move || source.fetch_all()).await;
+        metrics::record_stage("fetch", fetch_start.elapsed());
+        let mut fetched: Vec<PollUpdate> = response?;
+        let collected: DateTime<Utc> = Utc::now();
+        for results in fetched.iter_mut() {
+            report::debug(&format!("Fetched from {}: {}", self.source.name(), report::describe_fields(results)));
+            name_location(results, self.config.get_location());
+            if self.config.get_write_collected_at() {
+                results.set_collected(collected);
+            }
+            stale::check(results, Utc::now(), self.config.get_stale_limit());
+        }
+        if let Some(reference) = &self.reference {
+            let reference: Arc<ReferenceMonitor> = Arc::clone(reference);
+            match off_runtime(move || reference.fetch()).await {
+                Ok(mut update) => {
+                    report::debug(&format!("Fetched from reference station: {}", report::describe_fields(&update)));
+                    name_location(&mut update, self.config.get_location());
+                    fetched.push(update);
+                },
+                Err(e) => report::warn(&format!("Unable to get reference station readings: {}", e)),
+            }
+        }
+        Ok(fetched)
+    }
+    /// The processor stage's work: drop it if it is older than OPENWEATHER_SKIP_STALE_AFTER, then work out its indices and trends, transform an update, add its health advice, call the reading hooks and alerts, and return it if it still needs writing
+    fn process(&self, mut results: PollUpdate) -> Result<Option<PollUpdate>, CycleError> {
+        if stale::should_skip(&results, Utc::now(), self.config.get_skip_stale_after()) {
+            report::info(&format!("Reading for {} at {} is older than OPENWEATHER_SKIP_STALE_AFTER, skipping.", results.get_location(), results.get_time()));
+            return Ok(None);
+        }
+        self.add_indices(&mut results);
+        self.add_trends(&mut results);
+        let transform_start: Instant = Instant::now();
+        let transformed: Result<Option<PollUpdate>, CycleError> = self.apply_transforms(results);
+        metrics::record_stage("transform", transform_start.elapsed());
+        let mut results: PollUpdate = match transformed? {
+            Some(results) => results,
+            None => return Ok(None),
+        };
+        results.set_health(self.config.get_aqi_standard(results.get_location()).assess(&results));
+        self.reading_hooks.iter().for_each(|hook| hook(&results));
+        self.alerts.evaluate(&results);
+
+        if self.is_skipped_duplicate(&results) {
+            report::info(&format!("Reading for {} at {} was already written, skipping.", results.get_location(), results.get_time()));
+            metrics::increment("pollution_duplicates_skipped_total", &[("location", results.get_location())]);
+            return Ok(None);
+        }
+        Ok(Some(results))
+    }
+    /// The sink stage's work: write an update to every sink, call the write hooks, remember it as written and add it to the in-memory history<br>
+    /// Readings interpolated to fill a gap since the last one in the history are written and recorded along with it
+    async fn deliver(&self, results: PollUpdate) -> Result<PollUpdate, CycleError> {
+        let mut batch: Vec<PollUpdate> = self.interpolate_gap(&results);
+        batch.push(results.clone());
+        let write_start: Instant = Instant::now();
+        self.write_to_sinks(&batch).await?;
+        metrics::record_stage("write", write_start.elapsed());
+        health::record_write();
+        self.write_hooks.iter().for_each(|hook| hook(&results));
+        self.mark_written(&batch);
+
+        batch.into_iter().for_each(|update| self.record(update));
+        if report::is_table() {
+            report::print_table(&self.latest_readings(&results));
+        } else if report::is_plain() && !self.dry_run {
+            report::info(&format!("Successfully written to DB {}", self.config.get_dbname()));
+        }
+        Ok(results)
+    }
+    /// Build readings for the gap between the latest reading in the history for the update's location and the update, if OPENWEATHER_INTERPOLATE_GAPS allows one that long
+    fn interpolate_gap(&self, update: &PollUpdate) -> Vec<PollUpdate> {
+        if self.config.get_interpolate_gaps() == 0 {
+            return Vec::new();
+        }
+        let previous: Option<PollUpdate> = self.readings.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).latest(&update.get_series()).cloned();
+        let filled: Vec<PollUpdate> = match previous {
+            Some(previous) => interpolate::fill(&previous, update, self.config.get_interpolate_gaps()),
+            None => Vec::new(),
+        };
+        if !filled.is_empty() {
+            report::info(&format!("Interpolated {} missing readings for {} before {}.", filled.len(), update.get_location(), update.get_time()));
+        }
+        filled
+    }
+    /// Work out the indices wanted for an update's location, those in OPENWEATHER_INDICES and the index of its set AQI standard, averaging each with the readings in the history for its series
+    fn add_indices(&self, update: &mut PollUpdate) {
+        let wanted: Vec<Index> = self.config.get_indices(update.get_location());
+        if wanted.is_empty() {
+            return;
+        }
+        let recent: Vec<PollUpdate> = self.readings.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).recent(&update.get_series());
+        indices::apply(&wanted, &recent, update);
+    }
+    /// Work out each pollutant's trend over the last OPENWEATHER_TREND_HOURS of the history for an update's location, when set
+    fn add_trends(&self, update: &mut PollUpdate) {
+        let hours: u32 = self.config.get_trend_hours();
+        if hours == 0 {
+            return;
+        }
+        let recent: Vec<PollUpdate> = self.readings.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).recent(&update.get_series());
+        trend::apply(&recent, update, hours);
+    }
+    /// Check the forecast against the alert rules when OPENWEATHER_FORECAST_ALERT_HOURS is set, and write it to the sinks when OPENWEATHER_WRITE_FORECAST is on, if it was last checked over FORECAST_CHECK_INTERVAL ago<br>
+    /// Each forecast hour gets its indices worked out from the history and the forecast hours before it, so category rules can be judged.
+    /// Forecast hours skip the transforms, duplicate checks and history, as they are not readings. Failing to get or write the forecast is printed but does not fail the poll.
+    async fn check_forecast(&self) {
+        let hours: u32 = self.config.get_forecast_alert_hours();
+        let alerting: bool = hours > 0 && !self.alerts.is_empty();
+        if !alerting && !self.config.get_write_forecast() {
+            return;
+        }
+        {
+            let mut checked = self.forecast_checked.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            if checked.is_some_and(|checked| checked.elapsed() < FORECAST_CHECK_INTERVAL) {
+                return;
+            }
+            *checked = Some(Instant::now());
+        }
+        let source: Arc<dyn Source> = Arc::clone(&self.source);
+        let mut forecast: Vec<PollUpdate> = match off_runtime(move || source.fetch_forecast()).await {
+            Ok(forecast) => forecast,
+            Err(e) => {
+                report::warn(&format!("Unable to get the forecast: {}", e));
+                return;
+            },
+        };
+        let mut recent: BTreeMap<String, Vec<PollUpdate>> = BTreeMap::new();
+        for update in forecast.iter_mut() {
+            name_location(update, self.config.get_location());
+            let wanted: Vec<Index> = self.config.get_indices(update.get_location());
+            let series: &mut Vec<PollUpdate> = recent.entry(update.get_series()).or_insert_with_key(|series| self.readings.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).recent(series));
+            indices::apply(&wanted, series, update);
+            series.push(update.clone());
+        }
+        if alerting {
+            self.alerts.evaluate_forecast(&forecast, Utc::now(), hours);
+        }
+        if self.config.get_write_forecast() {
+            match self.write_to_sinks(&forecast).await {
+                Ok(()) => report::info(&format!("Wrote {} forecast hours.", forecast.len())),
+                Err(e) => report::warn(&format!("Unable to write the forecast: {}", e)),
+            }
+        }
+    }
+    /// Run an update through every transform in order, returning None as soon as one drops it
+    fn apply_transforms(&self, update: PollUpdate) -> Result<Option<PollUpdate>, CycleError> {
+        let mut results: PollUpdate = update;
+        for transform in &self.transforms {
+            results = match transform.apply(results).map_err(CycleError::Transform)? {
+                Some(transformed) => {
+                    report::debug(&format!("After transform {}: {}", transform.name(), report::describe_fields(&transformed)));
+                    transformed
+                },
+                None => {
+                    report::info(&format!("Update dropped by transform {}", transform.name()));
+                    return Ok(None);
+                },
+            };
+        }
+        Ok(Some(results))
+    }
+    /// Check if an update has already been written and OPENWEATHER_DUPLICATES says to skip it
+    fn is_skipped_duplicate(&self, update: &PollUpdate) -> bool {
+        self.config.get_duplicates() == DuplicatePolicy::Skip && self.written.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).is_written(update)
+    }
+    /// Write updates to every sink in order, reporting a WriteEvent for each and stopping at the first failure<br>
+    /// With OPENWEATHER_HA_LOCK_FILE set the lease is renewed first, and nothing is written if another instance has become the leader since the updates were polled.
+    async fn write_to_sinks(&self, updates: &[PollUpdate]) -> Result<(), CycleError> {
+        if let Some(lease) = &self.lease {
+            if lease.renew(Utc::now()) != Role::Leader {
+                report::info(&format!("Not writing {} readings as this instance is no longer the leader.", updates.len()));
+                return Ok(());
+            }
+        }
+        for sink in &self.sinks {
+            let sink_start: Instant = Instant::now();
+            let written: Result<usize, PluginError> = sink.write(updates).instrument(tracing::info_span!(target: LOG_TARGET, "write", sink = sink.name(), points = updates.len())).await;
+            let event: WriteEvent = WriteEvent { time: Utc::now(), sink: sink.name().to_string(), points: updates.len(), bytes: *written.as_ref().unwrap_or(&0), latency: sink_start.elapsed(), success: written.is_ok() };
+            self.report_write(&event);
+            written.map_err(CycleError::Write)?;
+            report::debug(&format!("Written to sink {}", sink.name()));
+        }
+        Ok(())
+    }
+    /// Remember updates as written, saving the state file if there is one
+    fn mark_written(&self, updates: &[PollUpdate]) {
+        let mut written = self.written.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        for update in updates {
+            if let Err(e) = written.mark(update) {
+                report::warn(&format!("Unable to save the last written reading to {}: {}", self.config.get_state_file().unwrap_or("the state file"), e));
+            }
+        }
+    }
+    /// Fetch and write every reading between two times that has not been written yet, ex: to fill a gap after the machine was suspended<br>
+    /// Readings go through the same transforms as polled ones but not the reading hooks or alerts, as they are already out of date. Returns how many were written.
+    ///
+    /// # Errors
+    /// Returns CycleError::Fetch if the source cannot fetch past readings, CycleError::Transform if a transform fails and CycleError::Write if a sink fails
+    pub async fn backfill(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<usize, CycleError> {
+        let source: Arc<dyn Source> = Arc::clone(&self.source);
+        let fetched: Vec<PollUpdate> = off_runtime(move || source.fetch_range(start, end)).await.map_err(CycleError::Fetch)?;
+        let mut missing: Vec<PollUpdate> = Vec::new();
+        for mut update in fetched {
+            name_location(&mut update, self.config.get_location());
+            if let Some(transformed) = self.apply_transforms(update)? {
+                if !self.is_skipped_duplicate(&transformed) {
+                    missing.push(transformed);
+                }
+            }
+        }
+        if missing.is_empty() {
+            return Ok(0);
+        }
+        self.write_to_sinks(&missing).await?;
+        self.mark_written(&missing);
+        for update in &missing {
+            self.record(update.clone());
+        }
+        Ok(missing.len())
+    }
+    /// Record a write in the metrics registry, print it as a line of JSON and pass it to the write event hooks
+    fn report_write(&self, event: &WriteEvent) {
+        event.record();
+        report::info(&event.to_json());
+        self.write_event_hooks.iter().for_each(|hook| hook(event));
+    }
+    /// Keep running cycles until shutdown is called or too many fetches fail in a row<br>
+    /// Polls on a fixed interval of the configured timing measured with the monotonic clock, so NTP corrections and DST changes cannot skip or double a poll.
+    /// Polls that were due while a cycle overran are handled as OPENWEATHER_MISSED_TICKS says, and polls inside OPENWEATHER_QUIET_HOURS are skipped.
+    /// With OPENWEATHER_HA_LOCK_FILE set, polls are also skipped while another instance is the leader, and the lock is released when polling stops.
+    /// After a failed fetch it retries after half the timing instead.
+    /// The source, processor and sink stages run alongside each other connected by queues, so a slow sink holds up writes rather than polls until OPENWEATHER_QUEUE_SIZE are waiting, when OPENWEATHER_QUEUE_POLICY applies.
+    /// A failed transform or write stops every stage straight away.
+    /// If the wall clock jumps forward while sleeping, ex: the machine was suspended, it polls straight away and backfills the readings missed since the last write.
+    /// A status line with the outcome and the time of the next poll is printed after every cycle.
+    ///
+    /// # Errors
+    /// Returns the last CycleError::Fetch once the maximum retries are reached, the first CycleError::Fetch that is not retryable, or the first CycleError::Transform or CycleError::Write
+    pub async fn run(&self) -> Result<(), CycleError> {
+        if let Some(lease) = &self.lease {
+            lease.renew(Utc::now());
+        }
+        if self.is_leading() {
+            self.replay_spool().await;
+        }
+        let polled: Queue<Polled> = Queue::new(pipeline::POLLED_QUEUE, self.config.get_queue_size(), QueuePolicy::Block);
+        let to_write: Queue<PollUpdate> = Queue::new(pipeline::WRITE_QUEUE, self.config.get_queue_size(), self.config.get_queue_policy());
+        let stopped_at: OnceLock<time::Instant> = OnceLock::new();
+        let unwritten: Mutex<Vec<PollUpdate>> = Mutex::new(Vec::new());
+        let (_, sourced, processed, written) = tokio::join!(
+            self.heartbeat(&polled),
+            self.source_stage(&polled, &stopped_at),
+            pipeline::connect(pipeline::PROCESSOR_STAGE, &polled, &to_write, |polled| async move { self.process_stage(polled) }),
+            pipeline::drain(pipeline::SINK_STAGE, &to_write, |update| self.sink_stage(update, &stopped_at, &unwritten)),
+        );
+        let unprocessed: usize = polled.take_all().len();
+        if unprocessed > 0 {
+            report::error(&format!("{} polled readings were not processed before the pipeline stopped and are lost.", unprocessed));
+        }
+        let mut unwritten: Vec<PollUpdate> = unwritten.into_inner().unwrap_or_else(|poisoned| poisoned.into_inner());
+        unwritten.extend(to_write.take_all());
+        self.save_unwritten(unwritten);
+        sourced.and(processed).and(written)
+    }
+    /// The source stage of run. Polls on the interval until it stops, then notes when and closes its queue so the later stages can finish.
+    async fn source_stage(&self, polled: &Queue<Polled>, stopped_at: &OnceLock<time::Instant>) -> Result<(), CycleError> {
+        let sourced: Result<(), CycleError> = self.poll_until_stopped(polled).await;
+        let _ = stopped_at.set(time::Instant::now());
+        polled.close();
+        sourced
+    }
+    /// Stops on shutdown, on too many failed fetches or when a later stage fails and closes the queue
+    async fn poll_until_stopped(&self, polled: &Queue<Polled>) -> Result<(), CycleError> {
+        let mut error_count: u8 = 0;
+        let mut polls: u64 = 0;
+        let period: Duration = poll_period(self.config.get_timing());
+        let mut ticker: Interval = time::interval(period);
+        ticker.set_missed_tick_behavior(self.config.get_missed_ticks().into());
+        health::start(self.config.get_retry_backoff().map_or(period, |backoff| period.max(backoff.max)));
+        loop {
+            let (ticked_at, gap_start) = self.wait_for_tick(&mut ticker, polled).await;
+            if self.is_shutting_down() {
+                break;
+            }
+            if polled.is_closed() {
+                return Ok(());
+            }
+            if let Some(window) = quiet::active_window(self.config.get_quiet_hours(), Local::now().time()) {
+                report::info(&format!("Quiet hours {} in effect, skipping this poll.", window));
+                health::record_cycle();
+                continue;
+            }
+            if let Some(Role::Standby(leader)) = self.get_role() {
+                report::info(&format!("Standing by while {} is the leader, skipping this poll.", leader));
+                health::record_cycle();
+                continue;
+            }
+            if let Some(suspended_at) = gap_start {
+                self.fill_gap(suspended_at).await;
+            }
+            let fetch_start: Instant = Instant::now();
+            let fetched: Result<Vec<PollUpdate>, PluginError> = self.fetch().instrument(self.poll_span()).await;
+            pipeline::record_item(pipeline::SOURCE_STAGE, if fetched.is_ok() { Handled::Passed } else { Handled::Failed }, fetch_start.elapsed());
+            match fetched {
+                Ok(updates) => {
+                    // Reset error count if we've had a success
+                    error_count = 0;
+                    health::record_poll();
+                    polls += 1;
+                    let last_poll: bool = self.poll_limit.is_some_and(|limit| polls >= limit);
+                    let next_poll: Option<DateTime<Utc>> = if last_poll { None } else { Some(wall_time(ticked_at + period)) };
+                    for update in updates {
+                        if polled.push(Polled { update, next_poll }).await.is_some() {
+                            // Only happens once the processor stage has stopped
+                            return Ok(());
+                        }
+                    }
+                    self.check_forecast().await;
+                    if last_poll {
+                        report::info(&format!("Finished {} polls, stopping.", polls));
+                        return Ok(());
+                    }
+                },
+                Err(e) => {
+                    // Tick the error count up by one and try to print the error out for later troubleshooting
+                    report::error("Error encountered while grabbing stats.");
+                    error_count += 1;
+                    health::record_failure();
+                    match e.downcast_ref::<ureq::Error>() {
+                        Some(ureq::Error::Status(code, resp)) => report::error(&format!("Status: {}, Text: {}", code, resp.status_text())),
+                        Some(ureq::Error::Transport(trans)) => report::error(&format!("Kind: {}, Message: {}", trans.kind(), trans.message().unwrap_or("N/A"))),
+                        None => report::error(&format!("Message: {}", e)),
+                    };
+                    let failed: CycleError = CycleError::Fetch(e);
+                    self.error_hooks.iter().for_each(|hook| hook(&failed));
+                    // Some errors will be the same on every try, so fail straight away instead of using up the retries
+                    if matches!(&failed, CycleError::Fetch(e) if is_auth_failure(e)) {
+                        report::error("API key rejected by OpenWeatherMap. Check OPENWEATHER_API_KEY. Stopping without retrying.");
+                        report::info(&status_line(self.config.get_location(), Err(&failed), None));
+                        return Err(failed);
+                    }
+                    if !failed.is_retryable() {
+                        report::error("This error will not go away by retrying, check the location and request settings. Stopping without using the remaining retries.");
+                        report::info(&status_line(self.config.get_location(), Err(&failed), None));
+                        return Err(failed);
+                    }
+                    // If we are at our error limit, there is no point in continuing
+                    if self.config.get_maxretry() <= error_count {
+                        report::info(&status_line(self.config.get_location(), Err(&failed), None));
+                        return Err(failed);
+                    }
+                    // Retry sooner, with the regular interval carrying on from the retry
+                    let retry_after: Duration = retry_delay(self.config.get_retry_backoff(), period, error_count);
+                    ticker.reset_after(retry_after);
+                    report::info(&format!("Retrying in {}s after {} failed fetches in a row.", retry_after.as_secs(), error_count));
+                    report::info(&status_line(self.config.get_location(), Err(&failed), Some(wall_time(time::Instant::now() + retry_after))));
+                },
+            }
+        }
+        report::info("Shutdown requested, polling stopped.");
+        Ok(())
+    }
+    /// Renew the lease on the lock file every Lease::renew_every until polling stops, then release it so a standby can take over.
+    /// Does nothing without OPENWEATHER_HA_LOCK_FILE.
+    async fn heartbeat(&self, polled: &Queue<Polled>) {
+        let lease: &Lease = match &self.lease {
+            Some(lease) => lease,
+            None => return,
+        };
+        let mut ticker: Interval = time::interval_at(time::Instant::now() + lease.renew_every(), lease.renew_every());
+        while !polled.is_closed() {
+            tokio::select! {
+                _ = ticker.tick() => {
+                    lease.renew(Utc::now());
+                },
+                _ = time::sleep(SHUTDOWN_CHECK_INTERVAL) => (),
+            }
+        }
+        lease.release();
+    }
+    /// Check if this instance should poll and write: it is the leader, or leader and standby mode is off
+    fn is_leading(&self) -> bool {
+        self.lease.as_ref().is_none_or(|lease| lease.is_leader())
+    }
+    /// The processor stage of run. Processes a polled update, prints the status line and passes the update on if it needs writing.
+    fn process_stage(&self, polled: Polled) -> Result<Option<PollUpdate>, CycleError> {
+        let processed: Result<Option<PollUpdate>, CycleError> = self.process(polled.update);
+        match &processed {
+            Ok(update) => report::info(&status_line(update.as_ref().map_or(self.config.get_location(), PollUpdate::get_location), Ok(update.as_ref()), polled.next_poll)),
+            Err(e) => {
+                self.error_hooks.iter().for_each(|hook| hook(e));
+                report::info(&status_line(self.config.get_location(), Err(e), None));
+            },
+        }
+        processed
+    }
+    /// The sink stage of run. Writes an update, calling the error hooks and keeping it to save if it fails.<br>
+    /// While polling, readings waiting in OPENWEATHER_SPOOL_FILE are written first so they stay in order. If they still cannot be written the update joins them without trying,
+    /// and an update that fails to write is added to the spool, so polling carries on while the sinks are down.
+    /// Once polling has stopped, writes only carry on until OPENWEATHER_DRAIN_TIMEOUT has passed. Updates after that are kept to save without trying.
+    async fn sink_stage(&self, update: PollUpdate, stopped_at: &OnceLock<time::Instant>, unwritten: &Mutex<Vec<PollUpdate>>) -> Result<(), CycleError> {
+        let spooling: bool = stopped_at.get().is_none() && self.config.get_spool_file().is_some() && !self.dry_run;
+        if spooling && !self.replay_spool().await {
+            self.spool_failed(update);
+            return Ok(());
+        }
+        let delivered: Result<PollUpdate, CycleError> = match stopped_at.get() {
+            None => self.deliver(update.clone()).await,
+            Some(stopped_at) => {
+                let deadline: time::Instant = *stopped_at + Duration::from_secs(self.config.get_drain_timeout());
+                let timed: Option<Result<PollUpdate, CycleError>> = if time::Instant::now() < deadline {
+                    time::timeout_at(deadline, self.deliver(update.clone())).await.ok()
+                } else {
+                    None
+                };
+                match timed {
+                    Some(delivered) => delivered,
+                    None => {
+                        unwritten.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push(update);
+                        return Ok(());
+                    },
+                }
+            },
+        };
+        match delivered {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                self.error_hooks.iter().for_each(|hook| hook(&e));
+                health::record_failure();
+                if spooling {
+                    report::warn(&e.to_string());
+                    self.spool_failed(update);
+                    return Ok(());
+                }
+                unwritten.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push(update);
+                Err(e)
+            },
+        }
+    }
+    /// Add an update that could not be written to the spool, to be written once the sinks recover
+    fn spool_failed(&self, update: PollUpdate) {
+        let path: &str = self.config.get_spool_file().unwrap_or_default();
+        match spool::append(Path::new(path), std::slice::from_ref(&update)) {
+            Ok(()) => {
+                metrics::increment("pollution_spooled_total", &[("location", update.get_location())]);
+                report::info(&format!("Saved the reading for {} at {} to {} to write once the sinks recover.", update.get_location(), update.get_time(), path));
+            },
+            Err(e) => report::error(&format!("Unable to save the reading for {} at {} to {}, it is lost: {}", update.get_location(), update.get_time(), path, e)),
+        }
+    }
+    /// Write the readings waiting in the spool, oldest first, removing them from it once they are written<br>
+    /// Returns false if there were readings that could not be written, and true if the spool is now empty or there is none.
+    async fn replay_spool(&self) -> bool {
+        let path: &Path = match self.config.get_spool_file() {
+            Some(path) if !self.dry_run => Path::new(path),
+            _ => return true,
+        };
+        if !path.exists() {
+            return true;
+        }
+        let spooled: spool::Spooled = match spool::read(path) {
+            Ok(spooled) => spooled,
+            Err(e) => {
+                report::warn(&format!("Unable to read spool {}: {}", path.display(), e));
+                return false;
+            },
+        };
+        let replayed: Vec<PollUpdate> = spooled.updates.iter().filter(|update| !self.is_skipped_duplicate(update)).cloned().collect();
+        if !replayed.is_empty() {
+            if let Err(e) = self.write_to_sinks(&replayed).await {
+                report::warn(&format!("Unable to write the readings saved to {}, keeping them: {}", path.display(), e));
+                return false;
+            }
+            report::info(&format!("Wrote {} readings saved to {}.", replayed.len(), path.display()));
+            self.mark_written(&replayed);
+            replayed.into_iter().for_each(|update| self.record(update));
+        }
+        if let Err(e) = spool::remove_written(path, &spooled) {
+            report::warn(&format!("Unable to remove the written readings from {}, they may be written again: {}", path.display(), e));
+        }
+        true
+    }
+    /// Save readings that could not be written to the spool, or warn that they are lost if there is no spool
+    fn save_unwritten(&self, unwritten: Vec<PollUpdate>) {
+        if unwritten.is_empty() {
+            return;
+        }
+        match self.config.get_spool_file().filter(|_| !self.dry_run) {
+            Some(path) => match spool::append(Path::new(path), &unwritten) {
+                Ok(()) => report::info(&format!("Saved {} unwritten readings to {} to write once the sinks recover or on the next start.", unwritten.len(), path)),
+                Err(e) => report::error(&format!("Unable to save {} unwritten readings to {}, they are lost: {}", unwritten.len(), path, e)),
+            },
+            None => report::error(&format!("{} readings were not written and are lost. Set OPENWEATHER_SPOOL_FILE to keep them for the next start.", unwritten.len())),
+        }
+    }
+    /// Ask a running loop to stop. It finishes the cycle it is on and stops polling within a second of sleeping,
+    /// then writes what is queued for up to OPENWEATHER_DRAIN_TIMEOUT seconds before saving the rest to OPENWEATHER_SPOOL_FILE and returning from run.
+    pub fn shutdown(&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+    }
+    /// Get the flag shutdown sets, so something running alongside the client can stop it or see that it is stopping
+    pub fn stop_handle(&self) -> Arc<AtomicBool> {
+        self.stopping.clone()
+    }
+    /// Check if shutdown has been called
+    pub fn is_shutting_down(&self) -> bool {
+        self.stopping.load(Ordering::SeqCst)
+    }
+    /// Wait for the next tick of the poll interval, checking for a shutdown request or a closed queue every SHUTDOWN_CHECK_INTERVAL<br>
+    /// Returns the time the tick was due, and the wall clock time from before the jump if the wall clock jumped forward by more than CLOCK_JUMP_THRESHOLD beyond the time waited.
+    /// A jump ends the wait early and restarts the interval from now.
+    async fn wait_for_tick(&self, ticker: &mut Interval, polled: &Queue<Polled>) -> (time::Instant, Option<DateTime<Utc>>) {
+        while !self.is_shutting_down() && !polled.is_closed() {
+            let wall_before: DateTime<Utc> = Utc::now();
+            let waited_from: time::Instant = time::Instant::now();
+            let ticked: Option<time::Instant> = tokio::select! {
+                ticked_at = ticker.tick() => Some(ticked_at),
+                _ = time::sleep(SHUTDOWN_CHECK_INTERVAL) => None,
+            };
+            if let Some(jump) = clock_jump(wall_before, Utc::now(), waited_from.elapsed()) {
+                report::warn(&format!("Wall clock jumped forward by {}s while sleeping, polling now.", jump.num_seconds()));
+                ticker.reset();
+                return (time::Instant::now(), Some(wall_before));
+            }
+            if let Some(ticked_at) = ticked {
+                return (ticked_at, None);
+            }
+        }
+        (time::Instant::now(), None)
+    }
+    /// Backfill from the last reading written for this location, or from when the clock jumped if nothing has been written. Failures are only logged.
+    async fn fill_gap(&self, suspended_at: DateTime<Utc>) {
+        let since: DateTime<Utc> = self.written.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).last_written(self.config.get_location()).unwrap_or(suspended_at);
+        match self.backfill(since, Utc::now()).await {
+            Ok(count) => report::info(&format!("Backfilled {} readings missed since {}.", count, since)),
+            Err(e) => report::warn(&format!("Unable to backfill readings missed since {}: {}", since, e)),
+        }
+    }
+    /// Read the stored updates for a location between two times from InfluxDB, oldest first. Without an end it reads up to now.
+    ///
+    /// # Errors
+    /// This function passes any errors generated by the underlying influxdb crate
+    pub async fn read_range(&self, location: &str, from: DateTime<Utc>, to: Option<DateTime<Utc>>) -> Result<Vec<PollUpdate>, Error> {
+        influx::read_range(&self.dbclient, &self.config, location, from, to).await
+    }
+    /// Read the mean of every field for a location over each UTC day between two times from InfluxDB, oldest first
+    ///
+    /// # Errors
+    /// This function passes any errors generated by the underlying influxdb crate
+    pub async fn read_daily_averages(&self, location: &str, from: DateTime<Utc>, to: Option<DateTime<Utc>>) -> Result<Vec<DailyAverage>, Error> {
+        influx::read_daily_averages(&self.dbclient, &self.config, location, from, to).await
+    }
+    /// Get every update for a location from a point in time onwards, oldest first<br>
+    /// Served from memory when the history reaches back far enough, otherwise read from InfluxDB with anything newer from memory added on the end
+    ///
+    /// # Errors
+    /// This function passes any errors generated by the underlying influxdb crate when the database has to be queried
+    pub async fn history(&self, location: &str, since: DateTime<Utc>) -> Result<Vec<PollUpdate>, Error> {
+        let (covered, buffered) = {
+            let readings = self.readings.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            (readings.covers(location, since), readings.since(location, since))
+        };
+        if covered {
+            return Ok(buffered);
+        }
+        let stored: Vec<PollUpdate> = influx::read_history(&self.dbclient, &self.config, location, since).await?;
+        Ok(merge_history(stored, buffered))
+    }
+}
+
+/// Load the WASM modules listed in OPENWEATHER_WASM_TRANSFORMS
+#[cfg(feature = "wasm")]
+fn load_wasm_transforms(config: &Config) -> Result<Vec<Box<dyn Transform>>, String> {
+    config.get_wasm_transforms().iter()
+        .map(|path| crate::wasm::WasmTransform::load(path).map(|transform| Box::new(transform) as Box<dyn Transform>))
+        .collect()
+}
+
+/// Without the wasm feature there is nothing to load, so configured modules are an error rather than silently skipped
+#[cfg(not(feature = "wasm"))]
+fn load_wasm_transforms(config: &Config) -> Result<Vec<Box<dyn Transform>>, String> {
+    if config.get_wasm_transforms().is_empty() {
+        Ok(Vec::new())
+    } else {
+        Err("OPENWEATHER_WASM_TRANSFORMS is set but this build does not include the \"wasm\" feature.".to_string())
+    }
+}
+
+/// Compile the scripts listed in OPENWEATHER_SCRIPT_TRANSFORMS
+#[cfg(feature = "scripting")]
+fn load_script_transforms(config: &Config) -> Result<Vec<Box<dyn Transform>>, String> {
+    config.get_script_transforms().iter().enumerate()
+        .map(|(index, source)| crate::scripting::ScriptTransform::new(&format!("script transform {}", index + 1), source).map(|transform| Box::new(transform) as Box<dyn Transform>))
+        .collect()
+}
+
+/// Without the scripting feature there is nothing to run, so configured scripts are an error rather than silently skipped
+#[cfg(not(feature = "scripting"))]
+fn load_script_transforms(config: &Config) -> Result<Vec<Box<dyn Transform>>, String> {
+    if config.get_script_transforms().is_empty() {
+        Ok(Vec::new())
+    } else {
+        Err("OPENWEATHER_SCRIPT_TRANSFORMS is set but this build does not include the \"scripting\" feature.".to_string())
+    }
+}
+
+/// Set an update's location unless the source already named it, ex: after a grid point
+fn name_location(update: &mut PollUpdate, location: &str) {
+    if update.get_location() == "pending" {
+        update.set_location(location);
+    }
+}
+
+/// Add buffered updates newer than anything read from the database, covering writes that have not landed yet
+fn merge_history(mut stored: Vec<PollUpdate>, buffered: Vec<PollUpdate>) -> Vec<PollUpdate> {
+    let newest_stored: Option<DateTime<Utc>> = stored.last().map(|update| update.get_time());
+    stored.extend(buffered.into_iter().filter(|update| match newest_stored {
+        Some(newest) => update.get_time() > newest,
+        None => true,
+    }));
+    stored
+}
+
+/// Turn the configured timing into the poll interval. Zero is raised to a second as an interval cannot be empty.
+fn poll_period(timing: u64) -> Duration {
+    Duration::from_secs(timing.max(1))
+}
+
+/// Work out if the wall clock moved forward more than CLOCK_JUMP_THRESHOLD further than the monotonic clock, which does not count time suspended<br>
+/// Returns how far beyond the time slept it moved. Backwards corrections are ignored, as sleeping runs on the monotonic clock anyway.
+fn clock_jump(wall_before: DateTime<Utc>, wall_after: DateTime<Utc>, slept: Duration) -> Option<chrono::Duration> {
+    let slept: chrono::Duration = chrono::Duration::from_std(slept).ok()?;
+    let beyond: chrono::Duration = (wall_after - wall_before) - slept;
+    if beyond > chrono::Duration::from_std(CLOCK_JUMP_THRESHOLD).ok()? {
+        Some(beyond)
+    } else {
+        None
+    }
+}
+
+/// Work out how long to wait before retrying after failures in a row, using the backoff if one is set or half the poll interval if not
+fn retry_delay(backoff: Option<Backoff>, period: Duration, failures: u8) -> Duration {
+    match backoff {
+        Some(backoff) => backoff.next_delay(u32::from(failures)),
+        None => period / 2,
+    }
+}
+
+/// Turn a point on the monotonic clock into the wall clock time it is expected at
+fn wall_time(instant: time::Instant) -> DateTime<Utc> {
+    Utc::now() + chrono::Duration::from_std(instant.saturating_duration_since(time::Instant::now())).unwrap_or_else(|_| chrono::Duration::zero())
+}
+
+/// Build the status line printed after a cycle, ex: "Status: location=Home aqi=2 write=queued next_poll=2023-11-14T23:13:20Z"<br>
+/// A next_poll of "none" means the loop is about to stop
+fn status_line(location: &str, outcome: Result<Option<&PollUpdate>, &CycleError>, next_poll: Option<DateTime<Utc>>) -> String {
+    let (aqi, write): (String, &str) = match outcome {
+        Ok(Some(update)) => (update.get_aqi().to_string(), "queued"),
+        Ok(None) => ("n/a".to_string(), "dropped"),
+        Err(CycleError::Fetch(_)) => ("n/a".to_string(), "skipped_fetch_failed"),
+        Err(CycleError::Transform(_)) => ("n/a".to_string(), "skipped_transform_failed"),
+        Err(CycleError::Write(_)) => ("n/a".to_string(), "failed"),
+    };
+    let next: String = match next_poll {
+        Some(next_poll) => next_poll.to_rfc3339_opts(SecondsFormat::Secs, true),
+        None => "none".to_string(),
+    };
+    format!("Status: location={} aqi={} write={} next_poll={}", location, aqi, write, next)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_update(seconds: i64) -> PollUpdate {
+        PollUpdate::fixture("Home", seconds)
+    }
+
+    #[test]
+    fn merge_history_appends_newer_buffered() {
+        let merged: Vec<PollUpdate> = merge_history(vec![test_update(100), test_update(200)], vec![test_update(200), test_update(300)]);
+        let times: Vec<i64> = merged.iter().map(|update| update.get_time().timestamp()).collect();
+        assert_eq!(times, vec![100, 200, 300]);
+        assert_eq!(merge_history(Vec::new(), vec![test_update(300)]).len(), 1);
+    }
+
+    #[test]
+    fn fetch_errors_sorted_by_status() {
+        let status = |code: u16| -> PluginError { Box::new(ureq::Error::Status(code, ureq::Response::new(code, "Status", "").unwrap())) };
+        assert!(is_retryable_fetch(&status(500)));
+        assert!(is_retryable_fetch(&status(503)));
+        assert!(is_retryable_fetch(&status(429)));
+        assert!(!is_retryable_fetch(&status(400)));
+        assert!(!is_retryable_fetch(&status(404)));
+        let reset: PluginError = Box::new(ureq::Error::from(std::io::Error::from(std::io::ErrorKind::ConnectionReset)));
+        assert!(is_retryable_fetch(&reset));
+        assert!(is_retryable_fetch(&PluginError::from("source unavailable")));
+        assert!(!CycleError::Write(PluginError::from("refused")).is_retryable());
+    }
+
+    #[test]
+    fn retry_delay_uses_backoff() {
+        let period: Duration = Duration::from_secs(3600);
+        assert_eq!(retry_delay(None, period, 3), Duration::from_secs(1800));
+        let backoff: Backoff = "initial=10s,multiplier=2,max=1m,jitter=0".parse().unwrap();
+        assert_eq!(retry_delay(Some(backoff), period, 1), Duration::from_secs(10));
+        assert_eq!(retry_delay(Some(backoff), period, 3), Duration::from_secs(40));
+        assert_eq!(retry_delay(Some(backoff), period, 5), Duration::from_secs(60));
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn blocking_requests_run_off_runtime() {
+        assert_eq!(off_runtime(|| Ok::<u8, String>(3)).await.unwrap(), 3);
+        assert_eq!(off_runtime(|| Err::<u8, String>("refused".to_string())).await.unwrap_err().to_string(), "refused");
+        let panicked: PluginError = off_runtime(|| -> Result<u8, String> { panic!("source crashed") }).await.unwrap_err();
+        assert!(panicked.to_string().starts_with("Request stopped unexpectedly: "));
+    }
+
+    #[test]
+    fn auth_failures_detected() {
+        let status = |code: u16| -> PluginError { Box::new(ureq::Error::Status(code, ureq::Response::new(code, "Status", "").unwrap())) };
+        assert!(is_auth_failure(&status(401)));
+        assert!(is_auth_failure(&status(403)));
+        assert!(!is_auth_failure(&status(404)));
+        assert!(!is_auth_failure(&PluginError::from("401")));
+        assert!(!is_retryable_fetch(&status(401)));
+    }
+
+    #[test]
+    fn provider_fetch_errors_classified() {
+        use crate::provider::{serve_status, OpenWeatherProvider, ProviderSource};
+        let mut config: Config = Config::default();
+        config.add_loc(crate::Location::Zip(crate::ZipLoc { zip: "10001".to_string(), name: "10001".to_string(), lat: 40.0, lon: -74.0, country: "US".to_string() }));
+        let fetch_all = |status: &str| -> PluginError {
+            ProviderSource::new(Box::new(OpenWeatherProvider::with_api(&config, &serve_status(status))), &config).fetch_all().unwrap_err()
+        };
+        let rejected: PluginError = fetch_all("401 Unauthorized");
+        assert!(is_auth_failure(&rejected) && !is_retryable_fetch(&rejected));
+        let unavailable: PluginError = fetch_all("503 Service Unavailable");
+        assert!(!is_auth_failure(&unavailable) && is_retryable_fetch(&unavailable));
+        assert!(!is_retryable_fetch(&fetch_all("404 Not Found")));
+    }
+
+    #[test]
+    fn poll_period_is_never_zero() {
+        assert_eq!(poll_period(3600), Duration::from_secs(3600));
+        assert_eq!(poll_period(0), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn clock_jump_detects_suspend() {
+        let before: DateTime<Utc> = DateTime::from_timestamp(1700000000, 0).unwrap();
+        assert!(clock_jump(before, before + chrono::Duration::seconds(1), Duration::from_secs(1)).is_none());
+        assert!(clock_jump(before, before + chrono::Duration::seconds(30), Duration::from_secs(1)).is_none());
+        assert!(clock_jump(before, before - chrono::Duration::hours(1), Duration::from_secs(1)).is_none());
+        assert_eq!(clock_jump(before, before + chrono::Duration::hours(3), Duration::from_secs(1)), Some(chrono::Duration::seconds(3 * 3600 - 1)));
+    }
+
+    #[test]
+    fn status_line_shows_outcome_and_next_poll() {
+        let next: DateTime<Utc> = DateTime::from_timestamp(1700003600, 0).unwrap();
+        assert_eq!(status_line("Home", Ok(Some(&test_update(100))), Some(next)), "Status: location=Home aqi=1 write=queued next_poll=2023-11-14T23:13:20Z".to_string());
+        assert_eq!(status_line("Home", Ok(None), Some(next)), "Status: location=Home aqi=n/a write=dropped next_poll=2023-11-14T23:13:20Z".to_string());
+        let failed: CycleError = CycleError::Write("refused".into());
+        assert_eq!(status_line("Home", Err(&failed), None), "Status: location=Home aqi=n/a write=failed next_poll=none".to_string());
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn run_returns_after_shutdown() {
+        let test_client: PollutionClient = PollutionClient::new(Config::default()).unwrap();
+        test_client.shutdown();
+        assert!(test_client.is_shutting_down());
+        assert!(test_client.run().await.is_ok());
+    }
+
+    #[test]
+    fn hooks_are_kept_in_order() {
+        use std::sync::Mutex;
+        let seen: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(Vec::new()));
+        let mut test_client: PollutionClient = PollutionClient::new(Config::default()).unwrap();
+        let reading_seen: Arc<Mutex<Vec<String>>> = seen.clone();
+        test_client.on_reading(move |update| reading_seen.lock().unwrap().push(format!("reading {}", update.get_location())));
+        let error_seen: Arc<Mutex<Vec<String>>> = seen.clone();
+        test_client.on_error(move |e| error_seen.lock().unwrap().push(format!("error {}", matches!(e, CycleError::Write(_)))));
+        test_client.reading_hooks.iter().for_each(|hook| hook(&test_update(100)));
+        test_client.error_hooks.iter().for_each(|hook| hook(&CycleError::Write(Box::new(Error::ConnectionError { error: "refused".to_string() }))));
+        assert_eq!(*seen.lock().unwrap(), vec!["reading Home".to_string(), "error true".to_string()]);
+        assert!(test_client.write_hooks.is_empty());
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn history_served_from_memory() {
+        let test_client: PollutionClient = PollutionClient::new(Config::default()).unwrap();
+        test_client.record(test_update(100));
+        test_client.record(test_update(200));
+        let recent: Vec<PollUpdate> = test_client.history("Home", DateTime::from_timestamp(150, 0).unwrap()).await.unwrap();
+        assert_eq!(recent.len(), 1);
+        assert_eq!(recent[0].get_time().timestamp(), 200);
+    }
+
+    /// Fails every write while down. Each failed write records how many readings the spool at spool_file holds while it is being replayed.
+    struct FlakySink {
+        down: Arc<AtomicBool>,
+        written: Arc<Mutex<Vec<i64>>>,
+        spool_file: Option<std::path::PathBuf>,
+        spooled_while_down: Arc<Mutex<Vec<usize>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl Sink for FlakySink {
+        fn name(&self) -> &str {
+            "flaky"
+        }
+        async fn write(&self, updates: &[PollUpdate]) -> Result<usize, PluginError> {
+            if self.down.load(Ordering::SeqCst) {
+                if let Some(path) = &self.spool_file {
+                    self.spooled_while_down.lock().unwrap().push(spool::read(path).unwrap().updates.len());
+                }
+                return Err("sink is down".into());
+            }
+            self.written.lock().unwrap().extend(updates.iter().map(|update| update.get_time().timestamp()));
+            Ok(0)
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn failed_writes_spooled_and_replayed_in_order() {
+        let path: std::path::PathBuf = std::env::temp_dir().join(format!("pollutionclient_client_spool_test_{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let down: Arc<AtomicBool> = Arc::new(AtomicBool::new(true));
+        let written: Arc<Mutex<Vec<i64>>> = Arc::new(Mutex::new(Vec::new()));
+        let mut registry: Registry = Registry::default();
+        let spooled_while_down: Arc<Mutex<Vec<usize>>> = Arc::new(Mutex::new(Vec::new()));
+        let (sink_down, sink_written, sink_path, sink_spooled) = (down.clone(), written.clone(), path.clone(), spooled_while_down.clone());
+        registry.register_sink("flaky", move |_| Ok(Box::new(FlakySink { down: sink_down.clone(), written: sink_written.clone(), spool_file: Some(sink_path.clone()), spooled_while_down: sink_spooled.clone() })));
+        let config: Config = Config { sinks: vec!["flaky".to_string()], spool_file: Some(path.to_string_lossy().to_string()), ..Config::default() };
+        let test_client: PollutionClient = PollutionClient::with_registry(config, &registry).unwrap();
+        let (stopped_at, unwritten) = (OnceLock::new(), Mutex::new(Vec::new()));
+        assert!(test_client.sink_stage(test_update(100), &stopped_at, &unwritten).await.is_ok());
+        assert!(test_client.sink_stage(test_update(200), &stopped_at, &unwritten).await.is_ok());
+        assert!(test_client.sink_stage(test_update(250), &stopped_at, &unwritten).await.is_ok());
+        assert!(written.lock().unwrap().is_empty());
+        // Replaying during the outage leaves every spooled reading on disk while the write is tried
+        assert_eq!(*spooled_while_down.lock().unwrap(), vec![0, 1, 2]);
+        down.store(false, Ordering::SeqCst);
+        assert!(test_client.sink_stage(test_update(300), &stopped_at, &unwritten).await.is_ok());
+        assert_eq!(*written.lock().unwrap(), vec![100, 200, 250, 300]);
+        assert!(unwritten.lock().unwrap().is_empty());
+        assert!(!path.exists());
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn demoted_leader_stops_writing() {
+        let path: std::path::PathBuf = std::env::temp_dir().join(format!("pollutionclient_client_ha_test_{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let written: Arc<Mutex<Vec<i64>>> = Arc::new(Mutex::new(Vec::new()));
+        let mut registry: Registry = Registry::default();
+        let sink_written: Arc<Mutex<Vec<i64>>> = written.clone();
+        registry.register_sink("flaky", move |_| Ok(Box::new(FlakySink { down: Arc::new(AtomicBool::new(false)), written: sink_written.clone(), spool_file: None, spooled_while_down: Arc::new(Mutex::new(Vec::new())) })));
+        let config: Config = Config { sinks: vec!["flaky".to_string()], ha_lock_file: Some(path.to_string_lossy().to_string()), instance_id: "me".to_string(), ..Config::default() };
+        let test_client: PollutionClient = PollutionClient::with_registry(config, &registry).unwrap();
+        let (stopped_at, unwritten) = (OnceLock::new(), Mutex::new(Vec::new()));
+        assert!(test_client.sink_stage(test_update(100), &stopped_at, &unwritten).await.is_ok());
+        std::fs::write(&path, r#"{"leader":"other","heartbeat":"2023-11-14T22:13:20Z"}"#).unwrap();
+        assert!(test_client.sink_stage(test_update(200), &stopped_at, &unwritten).await.is_ok());
+        assert_eq!(*written.lock().unwrap(), vec![100]);
+        assert_eq!(test_client.lease.as_ref().unwrap().role(), Role::Standby("other".to_string()));
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(format!("{}.claim", path.display()));
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn dry_run_leaves_sinks_and_spool_alone() {
+        let path: std::path::PathBuf = std::env::temp_dir().join(format!("pollutionclient_client_dry_run_test_{}.jsonl", std::process::id()));
+        spool::append(&path, &[test_update(100)]).unwrap();
+        let mut registry: Registry = Registry::default();
+        registry.register_sink("flaky", |_| Ok(Box::new(FlakySink { down: Arc::new(AtomicBool::new(true)), written: Arc::new(Mutex::new(Vec::new())), spool_file: None, spooled_while_down: Arc::new(Mutex::new(Vec::new())) })));
+        let config: Config = Config { sinks: vec!["flaky".to_string()], spool_file: Some(path.to_string_lossy().to_string()), dry_run: Some(DryRunFormat::Json), ..Config::default() };
+        let test_client: PollutionClient = PollutionClient::with_registry(config, &registry).unwrap();
+        assert!(test_client.is_dry_run());
+        assert_eq!(test_client.sinks.iter().map(|sink| sink.name()).collect::<Vec<&str>>(), vec![crate::dryrun::DRY_RUN_SINK]);
+        let (stopped_at, unwritten) = (OnceLock::new(), Mutex::new(Vec::new()));
+        assert!(test_client.sink_stage(test_update(200), &stopped_at, &unwritten).await.is_ok());
+        assert!(test_client.prepare().await.is_ok());
+        assert_eq!(spool::read(&path).unwrap().updates.len(), 1);
+        let _ = std::fs::remove_file(&path);
+    }
+}
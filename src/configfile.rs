@@ -0,0 +1,274 @@
+//! Helpers for reading the TOML configuration file.<br>
+//! Parse errors are turned into messages naming the key, the line, the value found and an example of a valid one.
+//! Unknown keys are found here too, so typos can be warned about or, in strict mode, refused.
+
+use std::ops::Range;
+use crate::deprecated;
+
+/// Every key a configuration file can use, with an example of a valid entry
+pub(crate) const KEY_EXAMPLES: &[(&str, &str)] = &[
+    ("OPENWEATHER_API_KEY", "OPENWEATHER_API_KEY = \"0123456789abcdef\""),
+    ("OPENWEATHER_POLL_ZIP", "OPENWEATHER_POLL_ZIP = \"90210\""),
+    ("OPENWEATHER_POLL_COUNTRY", "OPENWEATHER_POLL_COUNTRY = \"US\""),
+    ("OPENWEATHER_POLL_CITY", "OPENWEATHER_POLL_CITY = \"Portland,OR\""),
+    ("OPENWEATHER_POLL_LAT", "OPENWEATHER_POLL_LAT = 47.6062"),
+    ("OPENWEATHER_POLL_LON", "OPENWEATHER_POLL_LON = -122.3321"),
+    ("OPENWEATHER_POLL_NAME", "OPENWEATHER_POLL_NAME = \"Seattle\""),
+    ("OPENWEATHER_POLL_LOCATIONS", "[[OPENWEATHER_POLL_LOCATIONS]]\nname = \"Home\"\nlat = 34.09\nlon = -118.41\ncountry = \"US\""),
+    ("OPENWEATHER_POLL_TIMING", "OPENWEATHER_POLL_TIMING = 3600"),
+    ("OPENWEATHER_INFLUXDB_NAME", "OPENWEATHER_INFLUXDB_NAME = \"pollution\""),
+    ("OPENWEATHER_INFLUXDB_SERVER", "OPENWEATHER_INFLUXDB_SERVER = \"http://localhost:8086\""),
+    ("OPENWEATHER_INFLUXDB_DBUSER", "OPENWEATHER_INFLUXDB_DBUSER = \"writer\""),
+    ("OPENWEATHER_INFLUXDB_DBPASS", "OPENWEATHER_INFLUXDB_DBPASS = \"secret\""),
+    ("OPENWEATHER_MAX_RETRY", "OPENWEATHER_MAX_RETRY = 3"),
+    ("OPENWEATHER_INFLUXDB_TOKEN", "OPENWEATHER_INFLUXDB_TOKEN = \"my-token\""),
+    ("OPENWEATHER_INFLUXDB_CLIENT_CERT", "OPENWEATHER_INFLUXDB_CLIENT_CERT = \"/etc/pollution/client.pem\""),
+    ("OPENWEATHER_INFLUXDB_CLIENT_KEY", "OPENWEATHER_INFLUXDB_CLIENT_KEY = \"/etc/pollution/client.key\""),
+    ("OPENWEATHER_INFLUXDB_CA_CERT", "OPENWEATHER_INFLUXDB_CA_CERT = \"/etc/pollution/ca.pem\""),
+    ("OPENWEATHER_INFLUXDB_ROUTES", "[[OPENWEATHER_INFLUXDB_ROUTES]]\nmeasurement = \"pollution\"\nretention_policy = \"ninety_days\""),
+    ("OPENWEATHER_INFLUXDB_RETENTION_POLICIES", "[[OPENWEATHER_INFLUXDB_RETENTION_POLICIES]]\nname = \"ninety_days\"\nduration = \"90d\"\ndefault = true"),
+    ("OPENWEATHER_INFLUXDB_CREATE_DB", "OPENWEATHER_INFLUXDB_CREATE_DB = true"),
+    ("OPENWEATHER_INFLUXDB_ORG", "OPENWEATHER_INFLUXDB_ORG = \"home\""),
+    ("OPENWEATHER_INFLUXDB_BUCKET", "OPENWEATHER_INFLUXDB_BUCKET = \"pollution\""),
+    ("OPENWEATHER_INFLUXDB_CREATE_BUCKET", "OPENWEATHER_INFLUXDB_CREATE_BUCKET = true"),
+    ("OPENWEATHER_INFLUXDB_BUCKET_RETENTION", "OPENWEATHER_INFLUXDB_BUCKET_RETENTION = \"90d\""),
+    ("OPENWEATHER_INFLUXDB_DBRP_BUCKET", "OPENWEATHER_INFLUXDB_DBRP_BUCKET = \"pollution\""),
+    ("OPENWEATHER_INFLUXDB_DOWNSAMPLE", "OPENWEATHER_INFLUXDB_DOWNSAMPLE = true"),
+    ("OPENWEATHER_INFLUXDB_DOWNSAMPLE_BUCKET", "OPENWEATHER_INFLUXDB_DOWNSAMPLE_BUCKET = \"pollution_longterm\""),
+    ("OPENWEATHER_INFLUXDB_FIELD_NAMES", "OPENWEATHER_INFLUXDB_FIELD_NAMES = \"pm2_5=pm25,aqi=owm_aqi\""),
+    ("OPENWEATHER_INFLUXDB_PRECISION", "OPENWEATHER_INFLUXDB_PRECISION = \"s\""),
+    ("OPENWEATHER_INFLUXDB_GZIP", "OPENWEATHER_INFLUXDB_GZIP = true"),
+    ("OPENWEATHER_INFLUXDB_VERIFY_WRITES", "OPENWEATHER_INFLUXDB_VERIFY_WRITES = true"),
+    ("OPENWEATHER_INFLUXDB_WRITE_RETRIES", "OPENWEATHER_INFLUXDB_WRITE_RETRIES = 2"),
+    ("OPENWEATHER_SELF_TEST", "OPENWEATHER_SELF_TEST = true"),
+    ("OPENWEATHER_HISTORY_SIZE", "OPENWEATHER_HISTORY_SIZE = 24"),
+    ("OPENWEATHER_HTTP_BIND", "OPENWEATHER_HTTP_BIND = \"0.0.0.0:8080\""),
+    ("OPENWEATHER_SOURCE", "OPENWEATHER_SOURCE = \"openweathermap\""),
+    ("OPENWEATHER_SINKS", "OPENWEATHER_SINKS = [\"influxdb\"]"),
+    ("OPENWEATHER_SINK_HEADERS", "OPENWEATHER_SINK_HEADERS = { influxdb = { \"X-Scope-OrgID\" = \"home\" } }"),
+    ("OPENWEATHER_WASM_TRANSFORMS", "OPENWEATHER_WASM_TRANSFORMS = [\"/etc/pollution/transform.wasm\"]"),
+    ("OPENWEATHER_SCRIPT_TRANSFORMS", "OPENWEATHER_SCRIPT_TRANSFORMS = [\"pm2_5 = pm2_5 * 0.9;\"]"),
+    ("OPENWEATHER_ALERTS", "[[OPENWEATHER_ALERTS]]\nname = \"pm2_5 high\"\nfield = \"pm2_5\"\nabove = 35.0"),
+    ("OPENWEATHER_STATE_FILE", "OPENWEATHER_STATE_FILE = \"/var/lib/pollution/state.json\""),
+    ("OPENWEATHER_DUPLICATES", "OPENWEATHER_DUPLICATES = \"overwrite\""),
+    ("OPENWEATHER_LOCALE", "OPENWEATHER_LOCALE = \"de\""),
+    ("OPENWEATHER_OUTPUT", "OPENWEATHER_OUTPUT = \"auto\""),
+    ("OPENWEATHER_MISSED_TICKS", "OPENWEATHER_MISSED_TICKS = \"delay\""),
+    ("OPENWEATHER_RETRY_BACKOFF", "OPENWEATHER_RETRY_BACKOFF = \"initial=30s,multiplier=2,max=15m,jitter=0.2\""),
+    ("OPENWEATHER_QUEUE_SIZE", "OPENWEATHER_QUEUE_SIZE = 100"),
+    ("OPENWEATHER_QUEUE_POLICY", "OPENWEATHER_QUEUE_POLICY = \"drop-oldest\""),
+    ("OPENWEATHER_DRAIN_TIMEOUT", "OPENWEATHER_DRAIN_TIMEOUT = 30"),
+    ("OPENWEATHER_SPOOL_FILE", "OPENWEATHER_SPOOL_FILE = \"/var/lib/pollution/spool.jsonl\""),
+    ("OPENWEATHER_QUIET_HOURS", "OPENWEATHER_QUIET_HOURS = \"22:00-06:00\""),
+    ("OPENWEATHER_STALE_LIMIT", "OPENWEATHER_STALE_LIMIT = 7200"),
+    ("OPENWEATHER_SKIP_STALE_AFTER", "OPENWEATHER_SKIP_STALE_AFTER = 180"),
+    ("OPENWEATHER_INTERPOLATE_GAPS", "OPENWEATHER_INTERPOLATE_GAPS = 2"),
+    ("OPENWEATHER_CARDINALITY_BUDGET", "OPENWEATHER_CARDINALITY_BUDGET = 1000"),
+    ("OPENWEATHER_CARDINALITY_POLICY", "OPENWEATHER_CARDINALITY_POLICY = \"refuse\""),
+    ("OPENWEATHER_HA_LOCK_FILE", "OPENWEATHER_HA_LOCK_FILE = \"/mnt/shared/pollution.lock\""),
+    ("OPENWEATHER_HA_STALE_AFTER", "OPENWEATHER_HA_STALE_AFTER = 120"),
+    ("OPENWEATHER_INSTANCE_ID", "OPENWEATHER_INSTANCE_ID = \"pollution-a\""),
+    ("OPENWEATHER_GRID_BBOX", "OPENWEATHER_GRID_BBOX = \"47.4,-122.5,47.8,-122.1\""),
+    ("OPENWEATHER_GRID_SPACING_KM", "OPENWEATHER_GRID_SPACING_KM = 5.0"),
+    ("OPENWEATHER_REFERENCE_STATION", "OPENWEATHER_REFERENCE_STATION = \"nearest\""),
+    ("OPENWEATHER_OPENAQ_API_KEY", "OPENWEATHER_OPENAQ_API_KEY = \"your-openaq-key\""),
+    ("OPENWEATHER_EEA_STATION", "OPENWEATHER_EEA_STATION = \"DEBE010\""),
+    ("OPENWEATHER_DEFRA_SITE", "OPENWEATHER_DEFRA_SITE = \"MY1\""),
+    ("OPENWEATHER_WAQI_TOKEN", "OPENWEATHER_WAQI_TOKEN = \"your-waqi-token\""),
+    ("OPENWEATHER_INDICES", "OPENWEATHER_INDICES = [\"aqhi\"]"),
+    ("OPENWEATHER_AQI_STANDARD", "OPENWEATHER_AQI_STANDARD = \"us\""),
+    ("OPENWEATHER_FORECAST_ALERT_HOURS", "OPENWEATHER_FORECAST_ALERT_HOURS = 48"),
+    ("OPENWEATHER_TREND_HOURS", "OPENWEATHER_TREND_HOURS = 3"),
+    ("OPENWEATHER_WRITE_FORECAST", "OPENWEATHER_WRITE_FORECAST = true"),
+    ("OPENWEATHER_WRITE_COLLECTED_AT", "OPENWEATHER_WRITE_COLLECTED_AT = true"),
+    ("OPENWEATHER_PROMETHEUS_BIND", "OPENWEATHER_PROMETHEUS_BIND = \"0.0.0.0:9898\""),
+    ("OPENWEATHER_MQTT_HOST", "OPENWEATHER_MQTT_HOST = \"mqtt://homeassistant.local:1883\""),
+    ("OPENWEATHER_MQTT_TOPIC", "OPENWEATHER_MQTT_TOPIC = \"pollution/{location}\""),
+    ("OPENWEATHER_MQTT_USER", "OPENWEATHER_MQTT_USER = \"pollution\""),
+    ("OPENWEATHER_MQTT_PASS", "OPENWEATHER_MQTT_PASS = \"mqtt-password\""),
+    ("OPENWEATHER_MQTT_DISCOVERY", "OPENWEATHER_MQTT_DISCOVERY = true"),
+    ("OPENWEATHER_SLACK_WEBHOOK", "OPENWEATHER_SLACK_WEBHOOK = \"https://hooks.slack.com/services/T000/B000/XXXX\""),
+    ("OPENWEATHER_SLACK_INTERVAL", "OPENWEATHER_SLACK_INTERVAL = 3600"),
+    ("OPENWEATHER_DRY_RUN", "OPENWEATHER_DRY_RUN = \"json\""),
+    ("OPENWEATHER_CONFIG_STRICT", "OPENWEATHER_CONFIG_STRICT = true"),
+];
+
+/// Keys allowed inside each list of tables
+const TABLE_KEYS: &[(&str, &[&str])] = &[
+    ("OPENWEATHER_POLL_LOCATIONS", &["name", "lat", "lon", "country", "zip", "city"]),
+    ("OPENWEATHER_INFLUXDB_ROUTES", &["measurement", "retention_policy"]),
+    ("OPENWEATHER_INFLUXDB_RETENTION_POLICIES", &["name", "duration", "default"]),
+    ("OPENWEATHER_ALERTS", &["name", "field", "above", "condition", "category"]),
+];
+
+/// Get an example of a valid entry for a top level key, using the current name if the key has been renamed
+pub(crate) fn example_for(key: &str) -> Option<&'static str> {
+    let key: &str = deprecated::current_name(key);
+    KEY_EXAMPLES.iter().find(|(known, _)| *known == key).map(|(_, example)| *example)
+}
+
+/// Find every key in a configuration file that is not recognised, with a suggestion when it looks like a typo<br>
+/// ex: Unknown key OPENWEATHER_POLL_TIMNIG, did you mean OPENWEATHER_POLL_TIMING?
+pub fn unknown_keys(content: &str) -> Vec<String> {
+    let table: toml::Table = match toml::from_str(content) {
+        Ok(table) => table,
+        Err(_) => return Vec::new(),
+    };
+    let known: Vec<&str> = KEY_EXAMPLES.iter().map(|(key, _)| *key).collect();
+    let mut unknown: Vec<String> = Vec::new();
+    for (key, value) in &table {
+        if !known.contains(&deprecated::current_name(key)) {
+            unknown.push(unknown_message(key, &known));
+            continue;
+        }
+        let allowed: &[&str] = match TABLE_KEYS.iter().find(|(table_key, _)| table_key == key) {
+            Some((_, allowed)) => allowed,
+            None => continue,
+        };
+        if let Some(entries) = value.as_array() {
+            for (index, entry) in entries.iter().enumerate() {
+                for nested in entry.as_table().into_iter().flat_map(|entry| entry.keys()) {
+                    if !allowed.contains(&nested.as_str()) {
+                        unknown.push(unknown_message(&format!("{}[{}].{}", key, index + 1, nested), &allowed.iter().map(|allowed| format!("{}[{}].{}", key, index + 1, allowed)).collect::<Vec<String>>()));
+                    }
+                }
+            }
+        }
+    }
+    unknown
+}
+
+/// Build the message for an unknown key, suggesting the closest known key if it is only a few edits away
+fn unknown_message<S: AsRef<str>>(key: &str, known: &[S]) -> String {
+    let closest: Option<(&str, usize)> = known.iter().map(|candidate| (candidate.as_ref(), edit_distance(key, candidate.as_ref()))).min_by_key(|(_, distance)| *distance);
+    match closest {
+        Some((suggestion, distance)) if distance <= 3.max(key.len() / 8) => format!("Unknown key {}, did you mean {}?", key, suggestion),
+        _ => format!("Unknown key {}", key),
+    }
+}
+
+/// Count the single character insertions, removals and swaps needed to turn one string into another
+fn edit_distance(from: &str, to: &str) -> usize {
+    let to_chars: Vec<char> = to.chars().collect();
+    let mut previous: Vec<usize> = (0..=to_chars.len()).collect();
+    for (row, from_char) in from.chars().enumerate() {
+        let mut current: Vec<usize> = vec![row + 1; to_chars.len() + 1];
+        for (column, to_char) in to_chars.iter().enumerate() {
+            let substitution: usize = previous[column] + usize::from(from_char != *to_char);
+            current[column + 1] = substitution.min(previous[column + 1] + 1).min(current[column] + 1);
+        }
+        previous = current;
+    }
+    previous[to_chars.len()]
+}
+
+/// Turn a TOML error into a message naming where in the file it happened<br>
+/// ex: Problem with OPENWEATHER_POLL_TIMING on line 2: invalid type: string "hourly", expected u64
+pub fn describe_error(content: &str, error: &toml::de::Error) -> String {
+    let span: Range<usize> = match error.span() {
+        Some(span) if span.start <= content.len() => span,
+        _ => return error.message().to_string(),
+    };
+    let line_number: usize = content[..span.start].matches('\n').count() + 1;
+    let path: Option<String> = key_path(content, span.start);
+    let mut described: String = match &path {
+        Some(path) => format!("Problem with {} on line {}: {}", path, line_number, error.message()),
+        None => format!("Problem on line {}: {}", line_number, error.message()),
+    };
+    let found: &str = content.get(span).unwrap_or("").trim();
+    if !found.is_empty() && !found.contains('\n') {
+        described.push_str(&format!("\n  Found: {}", found));
+    }
+    let example: Option<&str> = path.as_deref().and_then(|path| example_for(top_level_key(path)));
+    if let Some(example) = example {
+        described.push_str(&format!("\n  Example of a valid entry:\n    {}", example.replace('\n', "\n    ")));
+    }
+    described
+}
+
+/// Work out the key path for a position in the file, ex: OPENWEATHER_INFLUXDB_ROUTES[1].measurement
+fn key_path(content: &str, offset: usize) -> Option<String> {
+    let line_start: usize = content[..offset].rfind('\n').map(|index| index + 1).unwrap_or(0);
+    let line: &str = content[line_start..].lines().next().unwrap_or("");
+    let key: Option<String> = line.split_once('=').map(|(key, _)| key.trim().trim_matches('"').to_string()).filter(|key| !key.is_empty() && !key.starts_with('['));
+    let table: Option<String> = table_header(&content[..line_start]);
+    match (table, key) {
+        (Some(table), Some(key)) => Some(format!("{}.{}", table, key)),
+        (Some(table), None) => Some(table),
+        (None, Some(key)) => Some(key),
+        (None, None) => None,
+    }
+}
+
+/// Find the table the text before a line is in, numbering array tables from 1 so the entry can be found
+fn table_header(before: &str) -> Option<String> {
+    let header: &str = before.lines().rev().map(|line| line.trim()).find(|line| line.starts_with('['))?;
+    if let Some(name) = header.strip_prefix("[[").and_then(|rest| rest.split("]]").next()) {
+        let name: &str = name.trim();
+        let entry: usize = before.lines().filter(|line| line.trim().strip_prefix("[[").and_then(|rest| rest.split("]]").next()).map(|found| found.trim()) == Some(name)).count();
+        return Some(format!("{}[{}]", name, entry));
+    }
+    header.strip_prefix('[').and_then(|rest| rest.split(']').next()).map(|name| name.trim().to_string())
+}
+
+/// Get the first part of a key path, ex: OPENWEATHER_INFLUXDB_ROUTES from OPENWEATHER_INFLUXDB_ROUTES[1].measurement
+fn top_level_key(path: &str) -> &str {
+    path.split(['.', '[']).next().unwrap_or(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ConfigFile;
+
+    fn parse_error(content: &str) -> String {
+        describe_error(content, &toml::from_str::<ConfigFile>(content).err().unwrap())
+    }
+
+    #[test]
+    fn wrong_type_names_key_and_example() {
+        let described: String = parse_error("OPENWEATHER_API_KEY = \"abc\"\nOPENWEATHER_POLL_TIMING = \"hourly\"\n");
+        assert!(described.starts_with("Problem with OPENWEATHER_POLL_TIMING on line 2: invalid type: string \"hourly\", expected u64"));
+        assert!(described.contains("Found: \"hourly\""));
+        assert!(described.contains("OPENWEATHER_POLL_TIMING = 3600"));
+    }
+
+    #[test]
+    fn array_table_entry_is_numbered() {
+        let content: &str = "[[OPENWEATHER_INFLUXDB_ROUTES]]\nmeasurement = \"pollution\"\nretention_policy = \"ninety_days\"\n\n[[OPENWEATHER_INFLUXDB_ROUTES]]\nmeasurement = 5\nretention_policy = \"forever\"\n";
+        let described: String = parse_error(content);
+        assert!(described.starts_with("Problem with OPENWEATHER_INFLUXDB_ROUTES[2].measurement on line 6"));
+        assert!(described.contains("retention_policy = \"ninety_days\""));
+    }
+
+    #[test]
+    fn syntax_error_names_line() {
+        let described: String = parse_error("OPENWEATHER_API_KEY = \"abc\"\nOPENWEATHER_POLL_TIMING = \n");
+        assert!(described.contains("line 2"));
+    }
+
+    #[test]
+    fn every_example_parses() {
+        for (key, example) in KEY_EXAMPLES {
+            assert!(toml::from_str::<ConfigFile>(example).is_ok(), "example for {} does not parse", key);
+        }
+    }
+
+    #[test]
+    fn unknown_keys_suggest_typos() {
+        let content: &str = "OPENWEATHER_POLL_TIMNIG = 600\nSOMETHING_ELSE = 1\n[[OPENWEATHER_ALERTS]]\nname = \"high\"\nfeild = \"pm10\"\nabove = 50.0\n";
+        assert_eq!(unknown_keys(content), vec![
+            "Unknown key OPENWEATHER_ALERTS[1].feild, did you mean OPENWEATHER_ALERTS[1].field?".to_string(),
+            "Unknown key OPENWEATHER_POLL_TIMNIG, did you mean OPENWEATHER_POLL_TIMING?".to_string(),
+            "Unknown key SOMETHING_ELSE".to_string(),
+        ]);
+        assert!(unknown_keys("OPENWEATHER_POLL_TIMING = 600\n").is_empty());
+    }
+
+    #[test]
+    fn edit_distance_counts_changes() {
+        assert_eq!(edit_distance("TIMNG", "TIMING"), 1);
+        assert_eq!(edit_distance("same", "same"), 0);
+        assert_eq!(edit_distance("", "abc"), 3);
+    }
+}
@@ -0,0 +1,154 @@
+//! Checking OPENWEATHER_POLL_COUNTRY against ISO 3166-1 alpha-2 codes before the location is looked up.<br>
+//! OpenWeatherMaps answers an unknown country with a bare 404, so codes are checked here first, full names like "Germany" are turned into their code, and a mistake is answered with the closest matches.
+
+/// Every ISO 3166-1 alpha-2 code with its short English name
+pub const COUNTRIES: &[(&str, &str)] = &[
+    ("AD", "Andorra"), ("AE", "United Arab Emirates"), ("AF", "Afghanistan"), ("AG", "Antigua and Barbuda"), ("AI", "Anguilla"),
+    ("AL", "Albania"), ("AM", "Armenia"), ("AO", "Angola"), ("AQ", "Antarctica"), ("AR", "Argentina"),
+    ("AS", "American Samoa"), ("AT", "Austria"), ("AU", "Australia"), ("AW", "Aruba"), ("AX", "Aland Islands"),
+    ("AZ", "Azerbaijan"), ("BA", "Bosnia and Herzegovina"), ("BB", "Barbados"), ("BD", "Bangladesh"), ("BE", "Belgium"),
+    ("BF", "Burkina Faso"), ("BG", "Bulgaria"), ("BH", "Bahrain"), ("BI", "Burundi"), ("BJ", "Benin"),
+    ("BL", "Saint Barthelemy"), ("BM", "Bermuda"), ("BN", "Brunei Darussalam"), ("BO", "Bolivia"), ("BQ", "Bonaire, Sint Eustatius and Saba"),
+    ("BR", "Brazil"), ("BS", "Bahamas"), ("BT", "Bhutan"), ("BV", "Bouvet Island"), ("BW", "Botswana"),
+    ("BY", "Belarus"), ("BZ", "Belize"), ("CA", "Canada"), ("CC", "Cocos (Keeling) Islands"), ("CD", "Congo, Democratic Republic of the"),
+    ("CF", "Central African Republic"), ("CG", "Congo"), ("CH", "Switzerland"), ("CI", "Cote d'Ivoire"), ("CK", "Cook Islands"),
+    ("CL", "Chile"), ("CM", "Cameroon"), ("CN", "China"), ("CO", "Colombia"), ("CR", "Costa Rica"),
+    ("CU", "Cuba"), ("CV", "Cabo Verde"), ("CW", "Curacao"), ("CX", "Christmas Island"), ("CY", "Cyprus"),
+    ("CZ", "Czechia"), ("DE", "Germany"), ("DJ", "Djibouti"), ("DK", "Denmark"), ("DM", "Dominica"),
+    ("DO", "Dominican Republic"), ("DZ", "Algeria"), ("EC", "Ecuador"), ("EE", "Estonia"), ("EG", "Egypt"),
+    ("EH", "Western Sahara"), ("ER", "Eritrea"), ("ES", "Spain"), ("ET", "Ethiopia"), ("FI", "Finland"),
+    ("FJ", "Fiji"), ("FK", "Falkland Islands"), ("FM", "Micronesia"), ("FO", "Faroe Islands"), ("FR", "France"),
+    ("GA", "Gabon"), ("GB", "United Kingdom"), ("GD", "Grenada"), ("GE", "Georgia"), ("GF", "French Guiana"),
+    ("GG", "Guernsey"), ("GH", "Ghana"), ("GI", "Gibraltar"), ("GL", "Greenland"), ("GM", "Gambia"),
+    ("GN", "Guinea"), ("GP", "Guadeloupe"), ("GQ", "Equatorial Guinea"), ("GR", "Greece"), ("GS", "South Georgia and the South Sandwich Islands"),
+    ("GT", "Guatemala"), ("GU", "Guam"), ("GW", "Guinea-Bissau"), ("GY", "Guyana"), ("HK", "Hong Kong"),
+    ("HM", "Heard Island and McDonald Islands"), ("HN", "Honduras"), ("HR", "Croatia"), ("HT", "Haiti"), ("HU", "Hungary"),
+    ("ID", "Indonesia"), ("IE", "Ireland"), ("IL", "Israel"), ("IM", "Isle of Man"), ("IN", "India"),
+    ("IO", "British Indian Ocean Territory"), ("IQ", "Iraq"), ("IR", "Iran"), ("IS", "Iceland"), ("IT", "Italy"),
+    ("JE", "Jersey"), ("JM", "Jamaica"), ("JO", "Jordan"), ("JP", "Japan"), ("KE", "Kenya"),
+    ("KG", "Kyrgyzstan"), ("KH", "Cambodia"), ("KI", "Kiribati"), ("KM", "Comoros"), ("KN", "Saint Kitts and Nevis"),
+    ("KP", "North Korea"), ("KR", "South Korea"), ("KW", "Kuwait"), ("KY", "Cayman Islands"), ("KZ", "Kazakhstan"),
+    ("LA", "Laos"), ("LB", "Lebanon"), ("LC", "Saint Lucia"), ("LI", "Liechtenstein"), ("LK", "Sri Lanka"),
+    ("LR", "Liberia"), ("LS", "Lesotho"), ("LT", "Lithuania"), ("LU", "Luxembourg"), ("LV", "Latvia"),
+    ("LY", "Libya"), ("MA", "Morocco"), ("MC", "Monaco"), ("MD", "Moldova"), ("ME", "Montenegro"),
+    ("MF", "Saint Martin (French part)"), ("MG", "Madagascar"), ("MH", "Marshall Islands"), ("MK", "North Macedonia"), ("ML", "Mali"),
+    ("MM", "Myanmar"), ("MN", "Mongolia"), ("MO", "Macao"), ("MP", "Northern Mariana Islands"), ("MQ", "Martinique"),
+    ("MR", "Mauritania"), ("MS", "Montserrat"), ("MT", "Malta"), ("MU", "Mauritius"), ("MV", "Maldives"),
+    ("MW", "Malawi"), ("MX", "Mexico"), ("MY", "Malaysia"), ("MZ", "Mozambique"), ("NA", "Namibia"),
+    ("NC", "New Caledonia"), ("NE", "Niger"), ("NF", "Norfolk Island"), ("NG", "Nigeria"), ("NI", "Nicaragua"),
+    ("NL", "Netherlands"), ("NO", "Norway"), ("NP", "Nepal"), ("NR", "Nauru"), ("NU", "Niue"),
+    ("NZ", "New Zealand"), ("OM", "Oman"), ("PA", "Panama"), ("PE", "Peru"), ("PF", "French Polynesia"),
+    ("PG", "Papua New Guinea"), ("PH", "Philippines"), ("PK", "Pakistan"), ("PL", "Poland"), ("PM", "Saint Pierre and Miquelon"),
+    ("PN", "Pitcairn"), ("PR", "Puerto Rico"), ("PS", "Palestine"), ("PT", "Portugal"), ("PW", "Palau"),
+    ("PY", "Paraguay"), ("QA", "Qatar"), ("RE", "Reunion"), ("RO", "Romania"), ("RS", "Serbia"),
+    ("RU", "Russia"), ("RW", "Rwanda"), ("SA", "Saudi Arabia"), ("SB", "Solomon Islands"), ("SC", "Seychelles"),
+    ("SD", "Sudan"), ("SE", "Sweden"), ("SG", "Singapore"), ("SH", "Saint Helena, Ascension and Tristan da Cunha"), ("SI", "Slovenia"),
+    ("SJ", "Svalbard and Jan Mayen"), ("SK", "Slovakia"), ("SL", "Sierra Leone"), ("SM", "San Marino"), ("SN", "Senegal"),
+    ("SO", "Somalia"), ("SR", "Suriname"), ("SS", "South Sudan"), ("ST", "Sao Tome and Principe"), ("SV", "El Salvador"),
+    ("SX", "Sint Maarten (Dutch part)"), ("SY", "Syria"), ("SZ", "Eswatini"), ("TC", "Turks and Caicos Islands"), ("TD", "Chad"),
+    ("TF", "French Southern Territories"), ("TG", "Togo"), ("TH", "Thailand"), ("TJ", "Tajikistan"), ("TK", "Tokelau"),
+    ("TL", "Timor-Leste"), ("TM", "Turkmenistan"), ("TN", "Tunisia"), ("TO", "Tonga"), ("TR", "Turkey"),
+    ("TT", "Trinidad and Tobago"), ("TV", "Tuvalu"), ("TW", "Taiwan"), ("TZ", "Tanzania"), ("UA", "Ukraine"),
+    ("UG", "Uganda"), ("UM", "United States Minor Outlying Islands"), ("US", "United States"), ("UY", "Uruguay"), ("UZ", "Uzbekistan"),
+    ("VA", "Holy See"), ("VC", "Saint Vincent and the Grenadines"), ("VE", "Venezuela"), ("VG", "British Virgin Islands"), ("VI", "U.S. Virgin Islands"),
+    ("VN", "Viet Nam"), ("VU", "Vanuatu"), ("WF", "Wallis and Futuna"), ("WS", "Samoa"), ("YE", "Yemen"),
+    ("YT", "Mayotte"), ("ZA", "South Africa"), ("ZM", "Zambia"), ("ZW", "Zimbabwe"),
+];
+
+/// Other names people commonly use for a country, with its code
+pub const ALIASES: &[(&str, &str)] = &[
+    ("USA", "US"), ("United States of America", "US"), ("America", "US"),
+    ("UK", "GB"), ("Great Britain", "GB"), ("Britain", "GB"), ("England", "GB"), ("Scotland", "GB"), ("Wales", "GB"), ("Northern Ireland", "GB"),
+    ("Czech Republic", "CZ"), ("Holland", "NL"), ("The Netherlands", "NL"), ("Republic of Korea", "KR"), ("Korea", "KR"),
+    ("Vietnam", "VN"), ("Ivory Coast", "CI"), ("Cape Verde", "CV"), ("Swaziland", "SZ"), ("Macedonia", "MK"),
+    ("Burma", "MM"), ("East Timor", "TL"), ("Turkiye", "TR"), ("Russian Federation", "RU"), ("Vatican City", "VA"),
+    ("UAE", "AE"), ("DRC", "CD"), ("Democratic Republic of the Congo", "CD"), ("Republic of the Congo", "CG"),
+];
+
+/// Get the name of a country from its alpha-2 code
+pub fn name(code: &str) -> Option<&'static str> {
+    COUNTRIES.iter().find(|(known, _)| known.eq_ignore_ascii_case(code)).map(|(_, name)| *name)
+}
+
+/// Count the single character edits that turn one string into another
+fn edit_distance(from: &str, to: &str) -> usize {
+    let to_chars: Vec<char> = to.chars().collect();
+    let mut previous: Vec<usize> = (0..=to_chars.len()).collect();
+    for (row, from_char) in from.chars().enumerate() {
+        let mut current: Vec<usize> = vec![row + 1];
+        for (column, to_char) in to_chars.iter().enumerate() {
+            let substitution: usize = previous[column] + usize::from(from_char != *to_char);
+            current.push(substitution.min(previous[column + 1] + 1).min(current[column] + 1));
+        }
+        previous = current;
+    }
+    previous[to_chars.len()]
+}
+
+/// Find up to three countries whose name or code is close to what was given, closest first, written as "Name (CODE)"
+pub fn near_matches(raw_country: &str) -> Vec<String> {
+    let wanted: String = raw_country.trim().to_lowercase();
+    let known: Vec<(&str, &str)> = COUNTRIES.iter().copied().chain(ALIASES.iter().map(|(alias, code)| (*code, *alias))).collect();
+    let mut scored: Vec<(usize, &str)> = known.into_iter()
+        .filter_map(|(code, known_name)| {
+            let lower_name: String = known_name.to_lowercase();
+            let distance: usize = if wanted.len() >= 3 && lower_name.starts_with(&wanted) { 0 } else { edit_distance(&wanted, &lower_name).min(edit_distance(&wanted, &code.to_lowercase())) };
+            (distance <= wanted.len().div_ceil(3).max(1)).then_some((distance, code))
+        })
+        .collect();
+    scored.sort();
+    let mut matches: Vec<String> = Vec::new();
+    for (_, code) in scored {
+        let suggestion: String = format!("{} ({})", name(code).unwrap_or(code), code);
+        if !matches.contains(&suggestion) {
+            matches.push(suggestion);
+        }
+        if matches.len() == 3 {
+            break;
+        }
+    }
+    matches
+}
+
+/// Turn OPENWEATHER_POLL_COUNTRY into an ISO 3166-1 alpha-2 code, accepting a code in any case, a country's name or a common alias, ex: "Germany" gives "DE"
+///
+/// # Errors
+/// Returns a message naming the closest matches if the country is not recognised
+pub fn normalize(raw_country: &str) -> Result<String, String> {
+    let trimmed: &str = raw_country.trim();
+    if let Some((code, _)) = COUNTRIES.iter().find(|(code, known_name)| code.eq_ignore_ascii_case(trimmed) || known_name.eq_ignore_ascii_case(trimmed)) {
+        return Ok(code.to_string());
+    }
+    if let Some((_, code)) = ALIASES.iter().find(|(alias, _)| alias.eq_ignore_ascii_case(trimmed)) {
+        return Ok(code.to_string());
+    }
+    let matches: Vec<String> = near_matches(trimmed);
+    if matches.is_empty() {
+        Err(format!("Unknown country \"{}\" in OPENWEATHER_POLL_COUNTRY. Expected an ISO 3166-1 alpha-2 code, ex: US or DE.", raw_country))
+    } else {
+        Err(format!("Unknown country \"{}\" in OPENWEATHER_POLL_COUNTRY. Did you mean {}?", raw_country, matches.join(", ")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_and_names_normalized() {
+        assert_eq!(normalize("de"), Ok("DE".to_string()));
+        assert_eq!(normalize(" Germany "), Ok("DE".to_string()));
+        assert_eq!(normalize("UK"), Ok("GB".to_string()));
+        assert_eq!(normalize("united states of america"), Ok("US".to_string()));
+        assert_eq!(name("gb"), Some("United Kingdom"));
+        assert!(COUNTRIES.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    }
+
+    #[test]
+    fn unknown_countries_suggest_matches() {
+        assert_eq!(normalize("Germny").unwrap_err(), "Unknown country \"Germny\" in OPENWEATHER_POLL_COUNTRY. Did you mean Germany (DE)?");
+        assert!(normalize("Switzerlnd").unwrap_err().contains("Switzerland (CH)"));
+        assert!(normalize("Atlantis").unwrap_err().ends_with("Expected an ISO 3166-1 alpha-2 code, ex: US or DE."));
+        assert_eq!(near_matches("Korea"), vec!["South Korea (KR)".to_string()]);
+    }
+}
@@ -0,0 +1,155 @@
+//! Tracking of the last reading written for each location, so the same reading is not written twice.<br>
+//! OpenWeatherMaps only updates hourly, so polling more often (or restarting in a crash loop) fetches the same reading again with the same "dt".
+//! When OPENWEATHER_STATE_FILE is set the times are saved there after every write and loaded at startup, so this holds across restarts.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use chrono::{DateTime, Utc};
+use crate::{report, PollUpdate};
+
+/// What to do with a reading that has already been written, set by OPENWEATHER_DUPLICATES
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    /// Do not write it again
+    Skip,
+    /// Write it again, replacing the stored point as it has the same time and tags
+    Overwrite,
+}
+
+impl FromStr for DuplicatePolicy {
+    type Err = String;
+    fn from_str(raw_policy: &str) -> Result<Self, Self::Err> {
+        match raw_policy.trim().to_lowercase().as_str() {
+            "skip" => Ok(DuplicatePolicy::Skip),
+            "overwrite" => Ok(DuplicatePolicy::Overwrite),
+            _ => Err(format!("Unknown duplicate policy \"{}\". Expected skip or overwrite.", raw_policy)),
+        }
+    }
+}
+
+impl fmt::Display for DuplicatePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DuplicatePolicy::Skip => write!(f, "skip"),
+            DuplicatePolicy::Overwrite => write!(f, "overwrite"),
+        }
+    }
+}
+
+/// The time of the newest reading written for each location, optionally saved to a file
+#[derive(Debug)]
+pub struct WrittenState {
+    path: Option<PathBuf>,
+    last_written: BTreeMap<String, DateTime<Utc>>,
+}
+
+/// A WrittenState shared between the polling loop and anything else that needs it
+pub type SharedState = Arc<Mutex<WrittenState>>;
+
+impl WrittenState {
+    /// Start with nothing written and nowhere to save it
+    pub fn in_memory() -> WrittenState {
+        WrittenState { path: None, last_written: BTreeMap::new() }
+    }
+    /// Load the state saved at a path. A missing file starts empty; an unreadable one is reported and also starts empty, as the worst outcome is one repeated write.
+    pub fn load(path: &Path) -> WrittenState {
+        let last_written: BTreeMap<String, DateTime<Utc>> = match std::fs::read_to_string(path) {
+            Ok(content) => match serde_json::from_str(&content) {
+                Ok(saved) => saved,
+                Err(e) => {
+                    report::warn(&format!("Ignoring unreadable state file {}: {}", path.display(), e));
+                    BTreeMap::new()
+                },
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => {
+                report::warn(&format!("Unable to read state file {}: {}", path.display(), e));
+                BTreeMap::new()
+            },
+        };
+        WrittenState { path: Some(path.to_path_buf()), last_written }
+    }
+    /// Load from a path if one is given, otherwise keep the state in memory only
+    pub fn shared(path: Option<&str>) -> SharedState {
+        let state: WrittenState = match path {
+            Some(path) => WrittenState::load(Path::new(path)),
+            None => WrittenState::in_memory(),
+        };
+        Arc::new(Mutex::new(state))
+    }
+    /// Check if a reading at least as new as this one has already been written for its series, kept apart for reference station readings
+    pub fn is_written(&self, update: &PollUpdate) -> bool {
+        match self.last_written.get(&update.get_series()) {
+            Some(last) => update.get_time() <= *last,
+            None => false,
+        }
+    }
+    /// Get the time of the newest reading written for a location
+    pub fn last_written(&self, location: &str) -> Option<DateTime<Utc>> {
+        self.last_written.get(location).copied()
+    }
+    /// Note that a reading has been written and save the state if there is a file for it<br>
+    /// Saving goes through a temporary file and a rename so a crash mid-write cannot leave it half written
+    ///
+    /// # Errors
+    /// Returns any error writing the state file. The state in memory is updated regardless.
+    pub fn mark(&mut self, update: &PollUpdate) -> std::io::Result<()> {
+        let newest: DateTime<Utc> = match self.last_written.get(&update.get_series()) {
+            Some(last) if *last > update.get_time() => *last,
+            _ => update.get_time(),
+        };
+        self.last_written.insert(update.get_series(), newest);
+        match &self.path {
+            Some(path) => {
+                let temporary: PathBuf = path.with_extension("tmp");
+                std::fs::write(&temporary, serde_json::to_string_pretty(&self.last_written)?)?;
+                std::fs::rename(&temporary, path)
+            },
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_update(location: &str, seconds: i64) -> PollUpdate {
+        PollUpdate::fixture(location, seconds)
+    }
+
+    #[test]
+    fn duplicate_policy_from_str() {
+        assert_eq!("Skip".parse::<DuplicatePolicy>(), Ok(DuplicatePolicy::Skip));
+        assert_eq!(" overwrite".parse::<DuplicatePolicy>(), Ok(DuplicatePolicy::Overwrite));
+        assert!("ignore".parse::<DuplicatePolicy>().is_err());
+    }
+
+    #[test]
+    fn written_per_location() {
+        let mut state: WrittenState = WrittenState::in_memory();
+        assert!(!state.is_written(&test_update("Home", 3600)));
+        state.mark(&test_update("Home", 3600)).unwrap();
+        assert!(state.is_written(&test_update("Home", 3600)));
+        assert!(state.is_written(&test_update("Home", 0)));
+        assert!(!state.is_written(&test_update("Home", 7200)));
+        assert!(!state.is_written(&test_update("Work", 3600)));
+        state.mark(&test_update("Home", 0)).unwrap();
+        assert_eq!(state.last_written("Home").unwrap().timestamp(), 3600);
+    }
+
+    #[test]
+    fn state_survives_restart() {
+        let path: PathBuf = std::env::temp_dir().join(format!("pollutionclient_state_test_{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut state: WrittenState = WrittenState::load(&path);
+        assert!(state.last_written("Home").is_none());
+        state.mark(&test_update("Home", 3600)).unwrap();
+        let restarted: WrittenState = WrittenState::load(&path);
+        assert!(restarted.is_written(&test_update("Home", 3600)));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
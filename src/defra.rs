@@ -0,0 +1,182 @@
+//! A source reading the UK's Automatic Urban and Rural Network (AURN), run by DEFRA, for UK deployments reporting against official monitors.<br>
+//! Set OPENWEATHER_SOURCE to "defra" and OPENWEATHER_DEFRA_SITE to the station's site code, ex: "MY1" for London Marylebone Road.
+//! Every poll reads the site's hourly data file for the year from UK-AIR and takes the newest hour with any values, named the way the rest of the client names them.
+//! The file is only downloaded again once it is older than download::REFRESH and has changed, as it grows by an hour at a time.
+
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveTime, Utc};
+use crate::download::{self, DownloadCache};
+use crate::plugin::{PluginError, Source};
+use crate::{geocode, reference, report, Config, PollUpdate};
+
+/// Name of the DEFRA source for OPENWEATHER_SOURCE
+pub const DEFRA_SOURCE: &str = "defra";
+/// Base of UK-AIR's hourly data files for each site and year
+pub const SITE_DATA_URL: &str = "https://uk-air.defra.gov.uk/datastore/data_files/site_data";
+
+/// The end of an hour and its values in µg/m³ by field
+pub type Hour = (DateTime<Utc>, Vec<(&'static str, f32)>);
+
+/// Check an AURN site code and return it in capitals, ex: "my1" becomes "MY1"
+///
+/// # Errors
+/// Returns a message if the code is not 2 to 6 letters and digits
+pub fn parse_site(raw_site: &str) -> Result<String, String> {
+    let site: String = raw_site.trim().to_uppercase();
+    if (2..=6).contains(&site.len()) && site.chars().all(|c| c.is_ascii_alphanumeric()) {
+        Ok(site)
+    } else {
+        Err(format!("Invalid DEFRA site code \"{}\". Expected an AURN site code like MY1 or CLL2.", raw_site))
+    }
+}
+
+/// Build the URL of a site's hourly data for a year, ex: ".../MY1_2024.csv"
+pub fn site_url(site: &str, year: i32) -> String {
+    format!("{}/{}_{}.csv", SITE_DATA_URL, site, year)
+}
+
+/// Get the field a UK-AIR column is written under, ex: "Nitrogen dioxide" is no2, or None for one OpenWeatherMaps does not report<br>
+/// Nitrogen oxides as nitrogen dioxide, the NOx total, is left out as it is not NO2.
+pub fn field_for(column: &str) -> Option<&'static str> {
+    let column: String = column.trim().to_lowercase();
+    match column.as_str() {
+        "carbon monoxide" => Some("co"),
+        "nitric oxide" => Some("no"),
+        "nitrogen dioxide" => Some("no2"),
+        "ozone" => Some("o3"),
+        "sulphur dioxide" => Some("so2"),
+        "ammonia" => Some("nh3"),
+        _ if column.starts_with("pm10") => Some("pm10"),
+        _ if column.starts_with("pm2.5") || column.starts_with("pm<sub>2.5</sub>") => Some("pm2_5"),
+        _ => None,
+    }
+}
+
+/// Turn a UK-AIR unit into one to_micrograms understands, ex: "ugm-3 (TEOM FDMS)" is µg/m³
+fn normalize_unit(unit: &str) -> &str {
+    let unit: &str = unit.trim();
+    if unit.starts_with("ugm-3") {
+        "µg/m³"
+    } else if unit.starts_with("mgm-3") {
+        "mg/m³"
+    } else {
+        unit
+    }
+}
+
+/// Parse the end of an hour as UK-AIR writes it in GMT, ex: "01-03-2024" and "14:00", where "24:00" is midnight at the end of the day
+fn parse_time(date: &str, time: &str) -> Option<DateTime<Utc>> {
+    let date: NaiveDate = NaiveDate::parse_from_str(date.trim(), "%d-%m-%Y").ok()?;
+    if time.trim() == "24:00" {
+        return Some(date.and_time(NaiveTime::MIN).and_utc() + Duration::days(1));
+    }
+    Some(date.and_time(NaiveTime::parse_from_str(time.trim(), "%H:%M").ok()?).and_utc())
+}
+
+/// Find the newest hour with any values in a UK-AIR site data file, with the values in µg/m³ by field<br>
+/// The lines above the header describing the site are skipped, as are values of "No data" and units that cannot be converted.
+///
+/// # Errors
+/// Returns a message if there is no header starting with Date and time
+pub fn latest_values(csv: &str) -> Result<Option<Hour>, String> {
+    let mut lines = csv.lines().skip_while(|line| !line.trim_start_matches(['\u{feff}', '"']).to_lowercase().starts_with("date,"));
+    let header: Vec<String> = match lines.next() {
+        Some(line) => geocode::split_csv_line(line),
+        None => return Err("UK-AIR data has no header starting with Date. The site code may be wrong.".to_string()),
+    };
+    // Each pollutant is followed by its status and unit columns
+    let columns: Vec<(usize, &'static str, Option<usize>)> = header.iter().enumerate()
+        .filter_map(|(index, column)| field_for(column).map(|field| (index, field, header.iter().skip(index + 1).position(|unit| unit.eq_ignore_ascii_case("unit")).map(|offset| index + 1 + offset))))
+        .collect();
+    let mut newest: Option<Hour> = None;
+    for line in lines {
+        let row: Vec<String> = geocode::split_csv_line(line);
+        let time: DateTime<Utc> = match row.first().zip(row.get(1)).and_then(|(date, time)| parse_time(date, time)) {
+            Some(time) => time,
+            None => continue,
+        };
+        let values: Vec<(&'static str, f32)> = columns.iter().filter_map(|(index, field, unit_index)| {
+            let value: f32 = row.get(*index)?.parse::<f32>().ok()?;
+            let unit: &str = unit_index.and_then(|unit_index| row.get(unit_index)).map(|unit| normalize_unit(unit)).unwrap_or("µg/m³");
+            reference::to_micrograms(field, value, unit).filter(|value| *value >= 0.0).map(|value| (*field, value))
+        }).collect();
+        if !values.is_empty() && newest.as_ref().is_none_or(|(newest_time, _)| time > *newest_time) {
+            newest = Some((time, values));
+        }
+    }
+    Ok(newest)
+}
+
+/// The latest official readings for an AURN site from UK-AIR
+pub struct DefraSource {
+    site: String,
+    downloads: DownloadCache<Option<Hour>>,
+}
+
+impl DefraSource {
+    /// Create the source for the site in a Config
+    ///
+    /// # Errors
+    /// Returns a message if OPENWEATHER_DEFRA_SITE is not set
+    pub fn new(config: &Config) -> Result<DefraSource, String> {
+        match config.get_defra_site() {
+            Some(site) => Ok(DefraSource { site: site.to_string(), downloads: DownloadCache::new(download::REFRESH) }),
+            None => Err(format!("OPENWEATHER_SOURCE is {} but OPENWEATHER_DEFRA_SITE is not set.", DEFRA_SOURCE)),
+        }
+    }
+    /// Read the newest hour from a year's data file, or None if the file is missing or has no values yet
+    fn latest_in(&self, year: i32) -> Result<Option<Hour>, PluginError> {
+        Ok(self.downloads.get(&site_url(&self.site, year), |csv| Ok(latest_values(csv)?))?.flatten())
+    }
+}
+
+impl Source for DefraSource {
+    fn name(&self) -> &str {
+        DEFRA_SOURCE
+    }
+    /// Read the newest hour with values for the site, looking in last year's file early in January before this year's has any
+    fn fetch(&self) -> Result<PollUpdate, PluginError> {
+        let year: i32 = Utc::now().year();
+        let latest: Option<Hour> = match self.latest_in(year)? {
+            Some(latest) => Some(latest),
+            None => {
+                report::debug(&format!("No {} data yet for DEFRA site {}, trying {}", year, self.site, year - 1));
+                self.latest_in(year - 1)?
+            },
+        };
+        match latest {
+            Some((time, values)) => Ok(PollUpdate::from_measured(time, &values)),
+            None => Err(format!("UK-AIR has no recent values for site {}. Check OPENWEATHER_DEFRA_SITE.", self.site).into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn site_codes_checked() {
+        assert_eq!(parse_site(" my1 "), Ok("MY1".to_string()));
+        assert!(parse_site("London Marylebone").unwrap_err().contains("Expected an AURN site code"));
+        assert_eq!(site_url("MY1", 2024), "https://uk-air.defra.gov.uk/datastore/data_files/site_data/MY1_2024.csv");
+        assert_eq!(field_for("PM<sub>2.5</sub> particulate matter (Hourly measured)"), Some("pm2_5"));
+        assert_eq!(field_for("Nitrogen oxides as nitrogen dioxide"), None);
+    }
+
+    #[test]
+    fn newest_hour_found() {
+        let csv: &str = "Hourly data from DEFRA's UK-AIR
+Site Name,London Marylebone Road
+Date,time,Nitric oxide,status,unit,Nitrogen dioxide,status,unit,Nitrogen oxides as nitrogen dioxide,status,unit,Carbon monoxide,status,unit,PM10 particulate matter (Hourly measured),status,unit
+01-03-2024,23:00,20.5,V,ugm-3,40.25,V,ugm-3,71.6,V,ugm-3,0.3,V,mgm-3,18.0,V,ugm-3 (TEOM FDMS)
+01-03-2024,24:00,21.0,P,ugm-3,42.0,P,ugm-3,74.2,P,ugm-3,No data,,mgm-3,No data,,ugm-3 (TEOM FDMS)
+02-03-2024,01:00,No data,,ugm-3,No data,,ugm-3,No data,,ugm-3,No data,,mgm-3,No data,,ugm-3 (TEOM FDMS)
+";
+        let (time, values) = latest_values(csv).unwrap().unwrap();
+        assert_eq!(time, DateTime::parse_from_rfc3339("2024-03-02T00:00:00Z").unwrap());
+        assert_eq!(values, vec![("no", 21.0), ("no2", 42.0)]);
+        let (_, earlier) = latest_values(&csv.replace("01-03-2024,24:00", "01-03-2024,22:00")).unwrap().unwrap();
+        assert!(earlier.contains(&("co", 300.0)) && earlier.contains(&("pm10", 18.0)));
+        assert!(latest_values("<html>Not found</html>").is_err());
+    }
+}
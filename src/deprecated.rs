@@ -0,0 +1,92 @@
+//! Compatibility for settings that have been renamed.<br>
+//! Old names are still read from the environment and configuration files, with a warning naming the replacement printed the first time each is seen.
+
+use std::collections::BTreeSet;
+use std::env;
+use std::sync::{Mutex, OnceLock};
+use crate::report;
+
+/// Settings that have been renamed, old name first
+pub const RENAMED_SETTINGS: &[(&str, &str)] = &[];
+
+static WARNED: OnceLock<Mutex<BTreeSet<String>>> = OnceLock::new();
+
+/// Get the current name for a setting, which is the setting itself unless it has been renamed
+pub fn current_name(name: &str) -> &str {
+    renamed_to(RENAMED_SETTINGS, name)
+}
+
+/// Get the current name for a setting from a list of renames, old name first
+fn renamed_to<'a>(renamed: &[(&str, &'static str)], name: &'a str) -> &'a str {
+    renamed.iter().find(|(old, _)| *old == name).map(|(_, new)| *new).unwrap_or(name)
+}
+
+/// Read a setting from the environment, falling back to any old name it had
+///
+/// # Errors
+/// Returns the same error as env::var when neither the setting nor an old name for it is set
+pub fn env_var(name: &str) -> Result<String, env::VarError> {
+    read_renamed(RENAMED_SETTINGS, name, |key| env::var(key))
+}
+
+/// Read a setting with read, falling back to any old name it had in a list of renames
+fn read_renamed(renamed: &[(&str, &str)], name: &str, read: impl Fn(&str) -> Result<String, env::VarError>) -> Result<String, env::VarError> {
+    let current: Result<String, env::VarError> = read(name);
+    if current.is_ok() {
+        return current;
+    }
+    for (old, _) in renamed.iter().filter(|(_, new)| *new == name) {
+        if let Ok(value) = read(old) {
+            warn_once(old, name);
+            return Ok(value);
+        }
+    }
+    current
+}
+
+/// Warn about every old name used as a key in a configuration file
+pub fn warn_renamed_keys<'a, I: IntoIterator<Item = &'a String>>(keys: I) {
+    for key in keys {
+        let replacement: &str = current_name(key);
+        if replacement != key {
+            warn_once(key, replacement);
+        }
+    }
+}
+
+/// Print the deprecation warning for an old name, only the first time it is seen. Returns true if it was printed.
+fn warn_once(old: &str, new: &str) -> bool {
+    let mut warned = WARNED.get_or_init(|| Mutex::new(BTreeSet::new())).lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let first: bool = warned.insert(old.to_string());
+    if first {
+        report::warn(&format!("{} is deprecated and will be removed in a future release. Use {} instead.", old, new));
+    }
+    first
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_RENAMES: &[(&str, &str)] = &[("TEST_OLD_INTERVAL", "TEST_NEW_INTERVAL")];
+
+    #[test]
+    fn current_name_maps_old_names() {
+        assert_eq!(renamed_to(TEST_RENAMES, "TEST_OLD_INTERVAL"), "TEST_NEW_INTERVAL");
+        assert_eq!(renamed_to(TEST_RENAMES, "TEST_NEW_INTERVAL"), "TEST_NEW_INTERVAL");
+        assert_eq!(current_name("OPENWEATHER_API_KEY"), "OPENWEATHER_API_KEY");
+    }
+
+    #[test]
+    fn warning_only_printed_once() {
+        assert!(warn_once("TEST_OLD_SETTING", "TEST_NEW_SETTING"));
+        assert!(!warn_once("TEST_OLD_SETTING", "TEST_NEW_SETTING"));
+    }
+
+    #[test]
+    fn read_falls_back_to_old_name() {
+        let read = |key: &str| if key == "TEST_OLD_INTERVAL" { Ok("900".to_string()) } else { Err(env::VarError::NotPresent) };
+        assert_eq!(read_renamed(TEST_RENAMES, "TEST_NEW_INTERVAL", read).unwrap(), "900".to_string());
+        assert!(read_renamed(TEST_RENAMES, "TEST_NEVER_SET", read).is_err());
+    }
+}
@@ -0,0 +1,131 @@
+//! Data files downloaded from official networks, kept between polls so a file that only changes hourly is not downloaded again on every poll.<br>
+//! A file is checked again once it is older than REFRESH, asking the server to answer 304 Not Modified if its ETag or Last-Modified time has not changed.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use chrono::{DateTime, Utc};
+use crate::plugin::PluginError;
+
+/// How long a downloaded file is used before checking whether it has changed
+pub const REFRESH: chrono::Duration = chrono::Duration::minutes(15);
+
+/// What was read from a file, with what is needed to ask whether it has changed
+struct Download<T> {
+    checked: DateTime<Utc>,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    value: Option<T>,
+}
+
+/// What was read from each downloaded file by its URL
+pub struct DownloadCache<T> {
+    refresh: chrono::Duration,
+    files: Mutex<BTreeMap<String, Download<T>>>,
+}
+
+impl<T: Clone> DownloadCache<T> {
+    /// Create an empty cache, checking files again once they are older than refresh
+    pub fn new(refresh: chrono::Duration) -> DownloadCache<T> {
+        DownloadCache { refresh, files: Mutex::new(BTreeMap::new()) }
+    }
+    /// Get what was read from the file at a URL, only downloading and reading it again once it is older than the refresh and has changed<br>
+    /// Returns None if there is no file at the URL, a 404.
+    ///
+    /// # Errors
+    /// Returns any error downloading the file or from read. Nothing is kept for the URL then, so the next call tries again.
+    pub fn get(&self, url: &str, read: impl FnOnce(&str) -> Result<T, PluginError>) -> Result<Option<T>, PluginError> {
+        let now: DateTime<Utc> = Utc::now();
+        let mut files = self.files.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut request: ureq::Request = ureq::get(url);
+        if let Some(file) = files.get(url) {
+            if now - file.checked < self.refresh {
+                return Ok(file.value.clone());
+            }
+            if let Some(etag) = &file.etag {
+                request = request.set("If-None-Match", etag);
+            }
+            if let Some(last_modified) = &file.last_modified {
+                request = request.set("If-Modified-Since", last_modified);
+            }
+        }
+        let response: ureq::Response = match request.call() {
+            Ok(response) => response,
+            Err(ureq::Error::Status(404, _)) => {
+                files.insert(url.to_string(), Download { checked: now, etag: None, last_modified: None, value: None });
+                return Ok(None);
+            },
+            Err(e) => return Err(Box::new(e)),
+        };
+        if response.status() == 304 {
+            if let Some(file) = files.get_mut(url) {
+                file.checked = now;
+                return Ok(file.value.clone());
+            }
+        }
+        let etag: Option<String> = response.header("ETag").map(str::to_string);
+        let last_modified: Option<String> = response.header("Last-Modified").map(str::to_string);
+        let value: T = read(&response.into_string()?)?;
+        files.insert(url.to_string(), Download { checked: now, etag, last_modified, value: Some(value.clone()) });
+        Ok(Some(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Serve a file with an ETag, answering 304 to requests that already have it, and count the requests
+    fn serve_file(requests: Arc<AtomicUsize>) -> String {
+        let listener: std::net::TcpListener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address: std::net::SocketAddr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                requests.fetch_add(1, Ordering::SeqCst);
+                let mut reader: BufReader<std::net::TcpStream> = BufReader::new(stream.try_clone().unwrap());
+                let mut line: String = String::new();
+                let mut cached: bool = false;
+                while reader.read_line(&mut line).is_ok_and(|read| read > 2) {
+                    cached |= line.to_lowercase().starts_with("if-none-match: \"v1\"");
+                    line.clear();
+                }
+                let response: &str = if cached {
+                    "HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\nConnection: close\r\n\r\n"
+                } else {
+                    "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nContent-Length: 4\r\nConnection: close\r\n\r\n12.5"
+                };
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+        format!("http://{}/DE_PM10.csv", address)
+    }
+
+    fn read_number(body: &str) -> Result<f32, PluginError> {
+        Ok(body.trim().parse::<f32>()?)
+    }
+
+    #[test]
+    fn unchanged_files_not_read_again() {
+        let requests: Arc<AtomicUsize> = Arc::new(AtomicUsize::new(0));
+        let url: String = serve_file(requests.clone());
+        let always: DownloadCache<f32> = DownloadCache::new(chrono::Duration::zero());
+        assert_eq!(always.get(&url, read_number).unwrap(), Some(12.5));
+        assert_eq!(always.get(&url, |_| Err("read again".into())).unwrap(), Some(12.5));
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        let hourly: DownloadCache<f32> = DownloadCache::new(REFRESH);
+        assert_eq!(hourly.get(&url, read_number).unwrap(), Some(12.5));
+        assert_eq!(hourly.get(&url, read_number).unwrap(), Some(12.5));
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn missing_files_and_errors() {
+        let cache: DownloadCache<f32> = DownloadCache::new(REFRESH);
+        assert_eq!(cache.get(&crate::provider::serve_status("404 Not Found"), read_number).unwrap(), None);
+        let failing: String = crate::provider::serve_status("503 Service Unavailable");
+        assert!(cache.get(&failing, read_number).is_err());
+        assert!(cache.get(&failing, read_number).is_err());
+    }
+}
@@ -0,0 +1,123 @@
+//! Dry runs, set by OPENWEATHER_DRY_RUN or the binary's --dry-run flag, fetch and process readings as usual but print what would have been written instead of writing it.<br>
+//! This shows the measurement, tags and fields each reading is stored with, so the layout can be checked before pointing the client at a production database.
+
+use std::fmt;
+use std::str::FromStr;
+use async_trait::async_trait;
+use influxdb::{Query, WriteQuery};
+use crate::plugin::{PluginError, Sink};
+use crate::{Config, PollUpdate, POLLUTION_MEASUREMENT};
+
+/// Name the dry run sink reports its writes under
+pub const DRY_RUN_SINK: &str = "dry-run";
+
+/// How a dry run prints the readings it would have written
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DryRunFormat {
+    /// The InfluxDB line protocol body the influxdb sink would send
+    LineProtocol,
+    /// One JSON reading per line, as saved to OPENWEATHER_SPOOL_FILE
+    Json,
+}
+
+impl FromStr for DryRunFormat {
+    type Err = String;
+    fn from_str(raw_format: &str) -> Result<Self, Self::Err> {
+        match raw_format.trim().to_lowercase().as_str() {
+            "true" | "yes" | "on" | "1" | "line" | "line-protocol" => Ok(DryRunFormat::LineProtocol),
+            "json" => Ok(DryRunFormat::Json),
+            _ => Err(format!("Unknown dry run format \"{}\". Expected true, line or json.", raw_format)),
+        }
+    }
+}
+
+impl fmt::Display for DryRunFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DryRunFormat::LineProtocol => write!(f, "line protocol"),
+            DryRunFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// Check if OPENWEATHER_DRY_RUN turns dry runs off, ex: "false", rather than giving a format
+pub fn is_off(raw_format: &str) -> bool {
+    matches!(raw_format.trim().to_lowercase().as_str(), "" | "false" | "no" | "off" | "0")
+}
+
+/// Render updates as they would have been written, using the Config's precision and field names for line protocol
+///
+/// # Errors
+/// Passes any error from the influxdb crate building the line protocol or from serializing to JSON
+pub fn render(current_config: &Config, updates: &[PollUpdate], format: DryRunFormat) -> Result<String, PluginError> {
+    if updates.is_empty() {
+        return Ok(String::new());
+    }
+    match format {
+        DryRunFormat::LineProtocol => {
+            let queries: Vec<WriteQuery> = updates.iter().map(|update| update.to_named_write_query(POLLUTION_MEASUREMENT, current_config.get_precision(), current_config.get_field_names())).collect();
+            Ok(queries.build().map_err(Box::new)?.get())
+        },
+        DryRunFormat::Json => {
+            let lines: Vec<String> = updates.iter().map(serde_json::to_string).collect::<Result<Vec<String>, serde_json::Error>>()?;
+            Ok(lines.join("\n"))
+        },
+    }
+}
+
+/// Prints updates to the console instead of writing them, standing in for every configured sink during a dry run
+pub struct DryRunSink {
+    config: Config,
+    format: DryRunFormat,
+}
+
+impl DryRunSink {
+    /// Create the sink for a Config, printing in a format
+    pub fn new(config: &Config, format: DryRunFormat) -> DryRunSink {
+        DryRunSink { config: config.clone(), format }
+    }
+}
+
+#[async_trait]
+impl Sink for DryRunSink {
+    fn name(&self) -> &str {
+        DRY_RUN_SINK
+    }
+    async fn write(&self, updates: &[PollUpdate]) -> Result<usize, PluginError> {
+        let rendered: String = render(&self.config, updates, self.format)?;
+        if !rendered.is_empty() {
+            println!("{}", rendered);
+        }
+        Ok(rendered.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_update(location: &str) -> PollUpdate {
+        PollUpdate { aqi: 2, co: Some(201.94), no2: Some(0.77), o3: Some(68.66), so2: Some(0.64), pm2_5: Some(0.5), pm10: Some(0.54), nh3: Some(0.12), ..PollUpdate::fixture(location, 1700000000) }
+    }
+
+    #[test]
+    fn formats_parsed() {
+        assert_eq!("true".parse::<DryRunFormat>(), Ok(DryRunFormat::LineProtocol));
+        assert_eq!(" JSON ".parse::<DryRunFormat>(), Ok(DryRunFormat::Json));
+        assert!("csv".parse::<DryRunFormat>().is_err());
+        assert!(is_off("false") && is_off(" ") && !is_off("json"));
+    }
+
+    #[test]
+    fn renders_what_would_be_written() {
+        let updates: Vec<PollUpdate> = vec![test_update("Home"), test_update("Work")];
+        let lines: String = render(&Config::default(), &updates, DryRunFormat::LineProtocol).unwrap();
+        assert_eq!(lines.lines().count(), 2);
+        assert!(lines.starts_with("pollution,location=Home aqi=2i,co=201.94"));
+        assert!(lines.ends_with("1700000000000000000"));
+        let json: String = render(&Config::default(), &updates, DryRunFormat::Json).unwrap();
+        let parsed: PollUpdate = serde_json::from_str(json.lines().nth(1).unwrap()).unwrap();
+        assert_eq!(parsed.get_location(), "Work");
+        assert_eq!(render(&Config::default(), &[], DryRunFormat::Json).unwrap(), "");
+    }
+}
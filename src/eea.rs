@@ -0,0 +1,155 @@
+//! A source reading the European Environment Agency's up-to-date air quality feed, for EU deployments that must report against official EEA data rather than OpenWeatherMaps' model.<br>
+//! Set OPENWEATHER_SOURCE to "eea" and OPENWEATHER_EEA_STATION to the station's EEA code, ex: "DEBE010". The country's feed is picked from the first two letters of the code.
+//! Every poll reads the latest hourly value of each pollutant the station measures, and pollutants it does not measure are left out of the reading.
+//! The country's files are only downloaded again once they are older than download::REFRESH and have changed, as they are updated hourly.
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use crate::download::{self, DownloadCache};
+use crate::plugin::{PluginError, Source};
+use crate::{country, geocode, report, Config, PollUpdate};
+
+/// Name of the EEA source for OPENWEATHER_SOURCE
+pub const EEA_SOURCE: &str = "eea";
+/// Base of the EEA's up-to-date download service, which has a CSV of the latest values for each country and pollutant
+pub const EEA_LATEST_URL: &str = "https://discomap.eea.europa.eu/map/fme/latest";
+/// Pollutants the EEA publishes, by the name in its file names and the field they are written under
+pub const POLLUTANTS: [(&str, &str); 6] = [("CO", "co"), ("NO2", "no2"), ("O3", "o3"), ("PM10", "pm10"), ("PM2.5", "pm2_5"), ("SO2", "so2")];
+
+/// Check an EEA station code and return it in capitals, ex: "debe010" becomes "DEBE010"
+///
+/// # Errors
+/// Returns a message if the code is not letters and digits or does not start with a known country code
+pub fn parse_station(raw_station: &str) -> Result<String, String> {
+    let station: String = raw_station.trim().to_uppercase();
+    if station.len() < 3 || !station.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(format!("Invalid EEA station code \"{}\". Expected a code like DEBE010.", raw_station));
+    }
+    match country::name(&station[..2]) {
+        Some(_) => Ok(station),
+        None => Err(format!("EEA station code \"{}\" does not start with a known country code, ex: DE for DEBE010.", raw_station)),
+    }
+}
+
+/// Build the URL of the latest values of a pollutant in a country, ex: ".../DE_PM10.csv"
+pub fn latest_url(country_code: &str, pollutant: &str) -> String {
+    format!("{}/{}_{}.csv", EEA_LATEST_URL, country_code, pollutant)
+}
+
+/// Parse the end of a measurement period as the EEA writes it, ex: "2024-03-01 14:00:00+01:00", or RFC 3339
+fn parse_time(raw_time: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_str(raw_time.trim(), "%Y-%m-%d %H:%M:%S%:z").or_else(|_| DateTime::parse_from_rfc3339(raw_time.trim()))
+        .map(|time| time.with_timezone(&Utc)).ok()
+        .or_else(|| NaiveDateTime::parse_from_str(raw_time.trim(), "%Y-%m-%d %H:%M:%S").ok().map(|time| time.and_utc()))
+}
+
+/// Find the newest valid value for a station in one of the EEA's CSV files, with its time and unit<br>
+/// Values the EEA marks as invalid, with a value_validity below 1, are passed over.
+///
+/// # Errors
+/// Returns a message if the header is missing a column this needs
+pub fn latest_value(csv: &str, station: &str) -> Result<Option<(DateTime<Utc>, f32, String)>, String> {
+    let mut lines = csv.lines().filter(|line| !line.trim().is_empty());
+    let header: Vec<String> = match lines.next() {
+        Some(line) => geocode::split_csv_line(line.trim_start_matches('\u{feff}')).into_iter().map(|column| column.to_lowercase()).collect(),
+        None => return Ok(None),
+    };
+    let column = |name: &str| header.iter().position(|column| column == name).ok_or_else(|| format!("EEA data has no {} column.", name));
+    let (station_column, time_column, value_column, unit_column) = (column("station_code")?, column("value_datetime_end")?, column("value_numeric")?, column("value_unit")?);
+    let validity_column: Option<usize> = header.iter().position(|column| column == "value_validity");
+    let mut newest: Option<(DateTime<Utc>, f32, String)> = None;
+    for line in lines {
+        let row: Vec<String> = geocode::split_csv_line(line);
+        let cell = |index: usize| row.get(index).map(String::as_str).unwrap_or_default();
+        if !cell(station_column).eq_ignore_ascii_case(station) || validity_column.is_some_and(|index| cell(index).parse::<i32>().unwrap_or(0) < 1) {
+            continue;
+        }
+        if let (Some(time), Ok(value)) = (parse_time(cell(time_column)), cell(value_column).parse::<f32>()) {
+            if newest.as_ref().is_none_or(|(newest_time, _, _)| time > *newest_time) {
+                newest = Some((time, value, cell(unit_column).to_string()));
+            }
+        }
+    }
+    Ok(newest)
+}
+
+/// The newest value of a pollutant for a station with its time and unit, or None if the station has none
+type Latest = Option<(DateTime<Utc>, f32, String)>;
+
+/// The latest official readings for a station from the EEA
+pub struct EeaSource {
+    station: String,
+    downloads: DownloadCache<Latest>,
+}
+
+impl EeaSource {
+    /// Create the source for the station in a Config
+    ///
+    /// # Errors
+    /// Returns a message if OPENWEATHER_EEA_STATION is not set
+    pub fn new(config: &Config) -> Result<EeaSource, String> {
+        match config.get_eea_station() {
+            Some(station) => Ok(EeaSource { station: station.to_string(), downloads: DownloadCache::new(download::REFRESH) }),
+            None => Err(format!("OPENWEATHER_SOURCE is {} but OPENWEATHER_EEA_STATION is not set.", EEA_SOURCE)),
+        }
+    }
+}
+
+impl Source for EeaSource {
+    fn name(&self) -> &str {
+        EEA_SOURCE
+    }
+    /// Read each pollutant's feed for the station's country and build a reading from the newest values, timestamped with the newest of them<br>
+    /// A pollutant the country does not publish, a 404, is skipped.
+    fn fetch(&self) -> Result<PollUpdate, PluginError> {
+        let mut values: Vec<(&str, f32)> = Vec::new();
+        let mut time: Option<DateTime<Utc>> = None;
+        for (pollutant, field) in POLLUTANTS {
+            let latest: Latest = match self.downloads.get(&latest_url(&self.station[..2], pollutant), |csv| Ok(latest_value(csv, &self.station)?))? {
+                Some(latest) => latest,
+                None => continue,
+            };
+            match latest {
+                Some((measured, value, unit)) => match crate::reference::to_micrograms(field, value, &unit) {
+                    Some(value) => {
+                        values.push((field, value));
+                        time = time.max(Some(measured));
+                    },
+                    None => report::debug(&format!("Skipping {} from EEA station {} in unknown units {}", pollutant, self.station, unit)),
+                },
+                None => report::debug(&format!("EEA has no recent {} for station {}", pollutant, self.station)),
+            }
+        }
+        match time {
+            Some(time) => Ok(PollUpdate::from_measured(time, &values)),
+            None => Err(format!("EEA has no recent values for station {}. Check OPENWEATHER_EEA_STATION.", self.station).into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn station_codes_checked() {
+        assert_eq!(parse_station(" debe010 "), Ok("DEBE010".to_string()));
+        assert!(parse_station("DE-BE").unwrap_err().contains("Expected a code like DEBE010"));
+        assert!(parse_station("XXBE010").unwrap_err().contains("does not start with a known country code"));
+        assert_eq!(latest_url("DE", "PM2.5"), "https://discomap.eea.europa.eu/map/fme/latest/DE_PM2.5.csv");
+    }
+
+    #[test]
+    fn newest_valid_value_found() {
+        let csv: &str = "\u{feff}network_countrycode,station_code,station_name,value_datetime_end,value_numeric,value_validity,value_unit
+DE,DEBE010,Wedding,2024-03-01 13:00:00+01:00,21.5,1,µg/m3
+DE,DEBE010,Wedding,2024-03-01 14:00:00+01:00,23.0,1,µg/m3
+DE,DEBE010,Wedding,2024-03-01 15:00:00+01:00,99.0,-1,µg/m3
+DE,DEBE034,\"Neukölln, Nansenstraße\",2024-03-01 15:00:00+01:00,40.0,1,µg/m3
+";
+        let (time, value, unit) = latest_value(csv, "DEBE010").unwrap().unwrap();
+        assert_eq!(time, DateTime::parse_from_rfc3339("2024-03-01T13:00:00Z").unwrap());
+        assert_eq!((value, unit.as_str()), (23.0, "µg/m3"));
+        assert_eq!(latest_value(csv, "DEBE999").unwrap(), None);
+        assert!(latest_value("station_code,value_numeric\nDEBE010,1", "DEBE010").unwrap_err().contains("no value_datetime_end column"));
+    }
+}
@@ -0,0 +1,72 @@
+//! The error type returned by the library's setup functions, so programs using the crate can handle a bad configuration instead of being stopped by a panic.<br>
+//! The bundled binary prints these and exits, and exits with cli::EXIT_API_KEY_REJECTED when the API key was rejected.
+
+use std::fmt;
+
+/// Reasons setting up a Config or PollutionClient can fail
+#[derive(Debug)]
+pub enum PollutionError {
+    /// A setting is missing, malformed or conflicts with another, or the configuration file cannot be read
+    Config(String),
+    /// The location could not be found or looked up
+    Geocode(String),
+    /// A request to OpenWeatherMaps failed
+    Http(Box<ureq::Error>),
+    /// InfluxDB could not be reached or refused a request
+    Db(influxdb::Error),
+}
+
+impl fmt::Display for PollutionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PollutionError::Config(message) => write!(f, "{}", message),
+            PollutionError::Geocode(message) => write!(f, "{}", message),
+            PollutionError::Http(e) => write!(f, "Unable to reach OpenWeatherMaps: {}", e),
+            PollutionError::Db(e) => write!(f, "Unable to set up InfluxDB: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for PollutionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PollutionError::Http(e) => Some(e.as_ref()),
+            PollutionError::Db(e) => Some(e),
+            PollutionError::Config(_) | PollutionError::Geocode(_) => None,
+        }
+    }
+}
+
+impl From<ureq::Error> for PollutionError {
+    fn from(e: ureq::Error) -> Self {
+        PollutionError::Http(Box::new(e))
+    }
+}
+
+impl From<influxdb::Error> for PollutionError {
+    fn from(e: influxdb::Error) -> Self {
+        PollutionError::Db(e)
+    }
+}
+
+impl PollutionError {
+    /// Check if OpenWeatherMaps rejected the API key, a 401 or 403 status, which retrying or restarting will not fix
+    pub fn is_auth_failure(&self) -> bool {
+        matches!(self, PollutionError::Http(e) if matches!(e.as_ref(), ureq::Error::Status(401 | 403, _)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_and_auth_failures() {
+        let config: PollutionError = PollutionError::Config("OPENWEATHER_POLL_LAT and OPENWEATHER_POLL_LON must be set together.".to_string());
+        assert_eq!(config.to_string(), "OPENWEATHER_POLL_LAT and OPENWEATHER_POLL_LON must be set together.");
+        assert!(!config.is_auth_failure());
+        let db: PollutionError = influxdb::Error::DatabaseError { error: "database not found".to_string() }.into();
+        assert!(db.to_string().starts_with("Unable to set up InfluxDB: "));
+        assert!(std::error::Error::source(&db).is_some());
+    }
+}
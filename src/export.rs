@@ -0,0 +1,138 @@
+//! Exporting stored readings from InfluxDB to a local file, so an archive can be pulled out without writing InfluxQL or Flux.<br>
+//! Run with the binary's export subcommand, ex: `pollutionclient_rs export --from 30d --format csv --output pollution.csv`.
+
+use std::fmt;
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+use chrono::{DateTime, SecondsFormat, Utc};
+use influxdb::{Client, Error};
+use crate::{influx, Config, PollUpdate};
+
+/// Header of CSV exports, in the order fields are written
+pub const CSV_HEADER: &str = "time,location,aqi,co,no,no2,o3,so2,pm2_5,pm10,nh3";
+
+/// File formats readings can be exported as
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// One reading per line under CSV_HEADER
+    Csv,
+    /// A JSON array of readings
+    Json,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+    fn from_str(raw_format: &str) -> Result<Self, Self::Err> {
+        match raw_format.trim().to_lowercase().as_str() {
+            "csv" => Ok(ExportFormat::Csv),
+            "json" => Ok(ExportFormat::Json),
+            _ => Err(format!("Unknown export format \"{}\". Expected csv or json.", raw_format)),
+        }
+    }
+}
+
+impl fmt::Display for ExportFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExportFormat::Csv => write!(f, "csv"),
+            ExportFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// What to export, from the export subcommand's flags
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExportArgs {
+    /// Location to export, or the configured location if None
+    pub location: Option<String>,
+    /// Earliest reading to export
+    pub from: DateTime<Utc>,
+    /// Latest reading to export, or up to now if None
+    pub to: Option<DateTime<Utc>>,
+    /// File format to write
+    pub format: ExportFormat,
+    /// File to write, or the console if None
+    pub output: Option<String>,
+}
+
+/// Quote a CSV value if it holds a comma, quote or line break
+fn csv_value(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Render readings as CSV with a header line, leaving the cells of pollutants that were not measured empty
+pub fn to_csv(updates: &[PollUpdate]) -> String {
+    let mut csv: String = format!("{}\n", CSV_HEADER);
+    for update in updates {
+        let fields: Vec<String> = ["aqi", "co", "no", "no2", "o3", "so2", "pm2_5", "pm10", "nh3"].iter()
+            .map(|field| update.get_field(field).map(|value| value.to_string()).unwrap_or_default())
+            .collect();
+        csv.push_str(&format!("{},{},{}\n", update.get_time().to_rfc3339_opts(SecondsFormat::Secs, true), csv_value(update.get_location()), fields.join(",")));
+    }
+    csv
+}
+
+/// Render readings in a format
+///
+/// # Errors
+/// Returns any error serializing to JSON
+pub fn render(updates: &[PollUpdate], format: ExportFormat) -> Result<String, serde_json::Error> {
+    match format {
+        ExportFormat::Csv => Ok(to_csv(updates)),
+        ExportFormat::Json => serde_json::to_string_pretty(updates),
+    }
+}
+
+/// Read the readings the export asks for from InfluxDB and write them to its output<br>
+/// Returns how many readings were exported
+///
+/// # Errors
+/// Returns a message if the query, rendering or writing the file fails
+pub async fn run(dbclient: &Client, current_config: &Config, export: &ExportArgs) -> Result<usize, String> {
+    let location: &str = export.location.as_deref().unwrap_or(current_config.get_location());
+    let updates: Vec<PollUpdate> = influx::read_range(dbclient, current_config, location, export.from, export.to).await
+        .map_err(|e: Error| format!("Unable to read readings for {} from InfluxDB: {}", location, e))?;
+    let rendered: String = render(&updates, export.format).map_err(|e| format!("Unable to render readings as {}: {}", export.format, e))?;
+    match &export.output {
+        Some(path) => std::fs::write(Path::new(path), rendered).map_err(|e| format!("Unable to write {}: {}", path, e))?,
+        None => std::io::stdout().write_all(rendered.as_bytes()).map_err(|e| format!("Unable to write readings: {}", e))?,
+    };
+    Ok(updates.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_update(location: &str, aqi: i8) -> PollUpdate {
+        PollUpdate { aqi, co: Some(201.5), no2: Some(0.77), o3: Some(68.5), so2: Some(0.5), pm2_5: Some(0.5), pm10: Some(0.75), nh3: Some(0.25), ..PollUpdate::fixture(location, 1700000000) }
+    }
+
+    #[test]
+    fn export_format_from_str() {
+        assert_eq!("CSV".parse::<ExportFormat>(), Ok(ExportFormat::Csv));
+        assert_eq!(" json".parse::<ExportFormat>(), Ok(ExportFormat::Json));
+        assert!("xlsx".parse::<ExportFormat>().is_err());
+    }
+
+    #[test]
+    fn csv_has_header_and_quotes_locations() {
+        let csv: String = to_csv(&[test_update("Home", 2), test_update("Springfield, IL", 3)]);
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], CSV_HEADER);
+        assert_eq!(lines[1], "2023-11-14T22:13:20Z,Home,2,201.5,0,0.77,68.5,0.5,0.5,0.75,0.25");
+        assert!(lines[2].starts_with("2023-11-14T22:13:20Z,\"Springfield, IL\",3,"));
+    }
+
+    #[test]
+    fn json_is_an_array() {
+        let json: String = render(&[test_update("Home", 2)], ExportFormat::Json).unwrap();
+        let parsed: Vec<PollUpdate> = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed[0].get_aqi(), 2);
+    }
+}
@@ -0,0 +1,326 @@
+//! Checks made before and after looking up OPENWEATHER_POLL_ZIP or OPENWEATHER_POLL_CITY with the OpenWeatherMaps geocoding API.<br>
+//! A failed lookup is turned into a message saying what went wrong and how to fix it, ex: a zip code that is not in the country given, instead of a bare HTTP status.
+//!
+//! The binary's geocode subcommand looks up many sites in one run, ex: `pollutionclient_rs geocode --input sites.csv --output locations.toml`, writing them as OPENWEATHER_POLL_LOCATIONS so the file can be used as a configuration file.
+//! The input is CSV with a header naming any of the name, zip, city and country columns. Every site needs a zip or a city, and the country defaults to US.
+//! A single zip code can be looked up with `--zip` instead, ex: `pollutionclient_rs geocode --zip 10115 --country DE`.
+
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use crate::{country, get_coords_city, get_coords_zipcode, report, CityLoc, ZipLoc};
+
+/// Time to wait between lookups, keeping under the 60 calls a minute of OpenWeatherMaps' free plan
+pub const GEOCODE_DELAY: Duration = Duration::from_millis(1100);
+
+/// What to look up, from the geocode subcommand's flags
+#[derive(Clone, Debug, PartialEq)]
+pub struct GeocodeArgs {
+    /// CSV file listing the sites, or None when looking up a single zip code
+    pub input: Option<String>,
+    /// Single zip code to look up instead of a CSV file
+    pub zip: Option<String>,
+    /// ISO 3166-1 alpha-2 code of the zip code's country, defaulting to US
+    pub country: Option<String>,
+    /// File to write the locations to, or the console if None
+    pub output: Option<String>,
+}
+
+/// A site to look up, from one row of the input
+#[derive(Clone, Debug, PartialEq)]
+pub struct Site {
+    /// Name to give the location, defaulting to the city or zip code
+    pub name: String,
+    pub zip: Option<String>,
+    pub city: Option<String>,
+    /// ISO 3166-1 alpha-2 code
+    pub country: String,
+}
+
+/// A site with its coordinates, as written to the locations file and read from OPENWEATHER_POLL_LOCATIONS in a configuration file
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Location {
+    pub name: String,
+    pub lat: f32,
+    pub lon: f32,
+    pub country: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub zip: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub city: Option<String>,
+}
+
+/// The locations file written by the geocode subcommand, which is a configuration file polling every location
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct LocationsFile {
+    #[serde(rename = "OPENWEATHER_POLL_LOCATIONS")]
+    pub locations: Vec<Location>,
+}
+
+/// How a batch lookup went
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GeocodeSummary {
+    /// Sites written to the locations file
+    pub resolved: usize,
+    /// Sites left out as they could not be looked up
+    pub failed: usize,
+}
+
+/// Check a US zip code's format, ex: "90210" or "90210-1234"
+pub fn looks_like_us_zip(zip: &str) -> bool {
+    let digits = |part: &str, length: usize| part.len() == length && part.chars().all(|c| c.is_ascii_digit());
+    match zip.trim().split_once('-') {
+        Some((zip5, plus4)) => digits(zip5, 5) && digits(plus4, 4),
+        None => digits(zip.trim(), 5),
+    }
+}
+
+/// Check the zip code and API key before asking OpenWeatherMaps for the location, so missing settings are reported as such instead of as a rejected request
+///
+/// # Errors
+/// Returns a message naming the setting to fix if the zip code is empty or the API key is not set
+pub fn preflight(zip: &str, apikey: &str) -> Result<(), String> {
+    check_lookup("OPENWEATHER_POLL_ZIP", "zip or post code", zip, apikey)
+}
+
+/// Check the city and API key before asking OpenWeatherMaps for the location, as preflight does for a zip code
+///
+/// # Errors
+/// Returns a message naming the setting to fix if the city is empty or the API key is not set
+pub fn preflight_city(city: &str, apikey: &str) -> Result<(), String> {
+    check_lookup("OPENWEATHER_POLL_CITY", "city, ex: \"Portland,OR\",", city, apikey)
+}
+
+fn check_lookup(setting: &str, what: &str, value: &str, apikey: &str) -> Result<(), String> {
+    if value.trim().is_empty() {
+        return Err(format!("{} is empty. Set it to the {} to poll.", setting, what));
+    }
+    if apikey.trim().is_empty() || apikey == "NOAPISET" {
+        return Err(format!("OPENWEATHER_API_KEY is not set, so the location cannot be looked up. Set it before {} is used.", setting));
+    }
+    Ok(())
+}
+
+/// Explain why looking up a zip code failed and suggest a fix
+pub fn describe_error(geocode_error: &ureq::Error, zip: &str, country_code: &str) -> String {
+    let country_name: &str = country::name(country_code).unwrap_or(country_code);
+    match geocode_error {
+        ureq::Error::Status(401 | 403, _) => "OpenWeatherMap rejected the API key while looking up the location. Check OPENWEATHER_API_KEY, and note that new keys can take a couple of hours to start working.".to_string(),
+        ureq::Error::Status(404, _) if country_code == "US" && !looks_like_us_zip(zip) => format!("Zip code {} was not found in the United States, and does not look like a US zip code. Did you forget OPENWEATHER_POLL_COUNTRY? It defaults to US.", zip),
+        ureq::Error::Status(404, _) => format!("Zip code {} was not found in {} ({}). Check OPENWEATHER_POLL_ZIP, and OPENWEATHER_POLL_COUNTRY if the zip code is in another country.", zip, country_name, country_code),
+        ureq::Error::Status(429, _) => "OpenWeatherMap is rate limiting this API key while looking up the location. Wait a minute and start again, or check the key's plan.".to_string(),
+        ureq::Error::Status(code, _) => format!("OpenWeatherMap answered status {} while looking up zip code {} in {}. It may be having trouble, so try again shortly.", code, zip, country_name),
+        ureq::Error::Transport(transport) => format!("Unable to reach OpenWeatherMap to look up zip code {}: {}. Check the network connection, DNS and any proxy settings.", zip, transport),
+    }
+}
+
+/// Explain why looking up a city failed and suggest a fix
+pub fn describe_city_error(geocode_error: &ureq::Error, city: &str, country_code: &str) -> String {
+    let country_name: &str = country::name(country_code).unwrap_or(country_code);
+    match geocode_error {
+        ureq::Error::Status(401 | 403 | 429, _) => describe_error(geocode_error, city, country_code),
+        ureq::Error::Status(code, _) => format!("OpenWeatherMap answered status {} while looking up city {} in {}. It may be having trouble, so try again shortly.", code, city, country_name),
+        ureq::Error::Transport(transport) => format!("Unable to reach OpenWeatherMap to look up city {}: {}. Check the network connection, DNS and any proxy settings.", city, transport),
+    }
+}
+
+/// Explain that a city has no match in a country and suggest a fix
+pub fn city_not_found(city: &str, country_code: &str) -> String {
+    format!("City {} was not found in {} ({}). Check OPENWEATHER_POLL_CITY, and OPENWEATHER_POLL_COUNTRY if the city is in another country.", city, country::name(country_code).unwrap_or(country_code), country_code)
+}
+
+/// Split a CSV line into its values, allowing quoted values holding commas and doubled quotes
+pub(crate) fn split_csv_line(line: &str) -> Vec<String> {
+    let mut values: Vec<String> = vec![String::new()];
+    let mut quoted: bool = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                values.last_mut().unwrap().push('"');
+            },
+            '"' => quoted = !quoted,
+            ',' if !quoted => values.push(String::new()),
+            _ => values.last_mut().unwrap().push(c),
+        }
+    }
+    values.into_iter().map(|value| value.trim().to_string()).collect()
+}
+
+/// Parse the sites listed in CSV with a header, ex: "name,zip,country" then "Home,90210,US"
+///
+/// # Errors
+/// Returns a message naming the line if the header has an unknown column or none of zip and city, a row has neither, or its country is not recognised
+pub fn parse_sites(csv: &str) -> Result<Vec<Site>, String> {
+    let mut lines = csv.lines().enumerate().filter(|(_, line)| !line.trim().is_empty());
+    let header: Vec<String> = match lines.next() {
+        Some((_, line)) => split_csv_line(line).into_iter().map(|column| column.to_lowercase()).collect(),
+        None => return Ok(Vec::new()),
+    };
+    if let Some(unknown) = header.iter().find(|column| !["name", "zip", "city", "country"].contains(&column.as_str())) {
+        return Err(format!("Unknown column \"{}\" in the sites header. Expected name, zip, city and country.", unknown));
+    }
+    if !header.iter().any(|column| column == "zip" || column == "city") {
+        return Err("The sites header needs a zip or city column.".to_string());
+    }
+    let mut sites: Vec<Site> = Vec::new();
+    for (index, line) in lines {
+        let values: Vec<String> = split_csv_line(line);
+        let column = |name: &str| header.iter().position(|column| column == name).and_then(|position| values.get(position)).filter(|value| !value.is_empty()).cloned();
+        let (zip, city): (Option<String>, Option<String>) = (column("zip"), column("city"));
+        let name: String = match column("name").or_else(|| city.clone()).or_else(|| zip.clone()) {
+            Some(name) => name,
+            None => return Err(format!("Line {} of the sites has neither a zip nor a city.", index + 1)),
+        };
+        let country: String = country::normalize(&column("country").unwrap_or("US".to_string())).map_err(|e| format!("Line {} of the sites: {}", index + 1, e))?;
+        sites.push(Site { name, zip, city, country });
+    }
+    Ok(sites)
+}
+
+/// Build the OpenWeatherMaps direct geocoding URL for a city
+pub fn city_url(city: &str, country_code: &str, apikey: &str) -> String {
+    format!("http://api.openweathermap.org/geo/1.0/direct?q={},{}&limit=1&appid={}", url::form_urlencoded::byte_serialize(city.as_bytes()).collect::<String>(), country_code, apikey)
+}
+
+/// Look up the coordinates of a site, by zip code if it has one and by city otherwise
+///
+/// # Errors
+/// Returns a message explaining why the lookup failed
+pub fn lookup(site: &Site, apikey: &str) -> Result<Location, String> {
+    let (lat, lon): (f32, f32) = match (&site.zip, &site.city) {
+        (Some(zip), _) => {
+            let found: ZipLoc = get_coords_zipcode(zip.clone(), site.country.clone(), apikey.to_string()).map_err(|e| describe_error(&e, zip, &site.country))?;
+            (found.lat, found.lon)
+        },
+        (None, Some(city)) => {
+            let found: Option<CityLoc> = match get_coords_city(city.clone(), site.country.clone(), apikey.to_string()) {
+                Ok(found) => found,
+                Err(e @ ureq::Error::Status(401 | 403, _)) => return Err(describe_error(&e, city, &site.country)),
+                Err(e) => return Err(format!("Unable to look up city {} in {}: {}", city, site.country, e)),
+            };
+            match found {
+                Some(found) => (found.lat, found.lon),
+                None => return Err(format!("City {} was not found in {} ({}).", city, country::name(&site.country).unwrap_or(&site.country), site.country)),
+            }
+        },
+        (None, None) => return Err(format!("Site {} has neither a zip nor a city.", site.name)),
+    };
+    Ok(Location { name: site.name.clone(), lat, lon, country: site.country.clone(), zip: site.zip.clone(), city: site.city.clone() })
+}
+
+/// Work out the sites to look up, the single zip code if one was given or every site in the input otherwise
+///
+/// # Errors
+/// Returns a message if the input cannot be read or parsed
+pub fn sites(geocode: &GeocodeArgs) -> Result<Vec<Site>, String> {
+    if let Some(zip) = &geocode.zip {
+        return Ok(vec![Site { name: zip.trim().to_string(), zip: Some(zip.trim().to_string()), city: None, country: geocode.country.clone().unwrap_or("US".to_string()) }]);
+    }
+    let input: &str = geocode.input.as_deref().unwrap_or_default();
+    let csv: String = std::fs::read_to_string(input).map_err(|e| format!("Unable to read {}: {}", input, e))?;
+    parse_sites(&csv)
+}
+
+/// Look up every site in the input, or the single zip code, waiting GEOCODE_DELAY between lookups, and write the ones found as a locations file<br>
+/// Sites that cannot be looked up are reported and left out.
+///
+/// # Errors
+/// Returns a message if the API key is not set, or the input cannot be read or parsed, or the output cannot be written
+pub fn run(apikey: &str, geocode: &GeocodeArgs) -> Result<GeocodeSummary, String> {
+    let sites: Vec<Site> = sites(geocode)?;
+    let mut summary: GeocodeSummary = GeocodeSummary::default();
+    let mut found: LocationsFile = LocationsFile::default();
+    for (index, site) in sites.iter().enumerate() {
+        preflight(site.zip.as_deref().or(site.city.as_deref()).unwrap_or_default(), apikey)?;
+        if index > 0 {
+            std::thread::sleep(GEOCODE_DELAY);
+        }
+        match lookup(site, apikey) {
+            Ok(location) => {
+                crate::report::info(&format!("Found {} at {}, {}.", location.name, location.lat, location.lon));
+                found.locations.push(location);
+                summary.resolved += 1;
+            },
+            Err(message) => {
+                report::warn(&format!("Skipping {}: {}", site.name, message));
+                summary.failed += 1;
+            },
+        }
+    }
+    let rendered: String = toml::to_string(&found).map_err(|e| format!("Unable to render the locations: {}", e))?;
+    match &geocode.output {
+        Some(path) => std::fs::write(Path::new(path), rendered).map_err(|e| format!("Unable to write {}: {}", path, e))?,
+        None => std::io::stdout().write_all(rendered.as_bytes()).map_err(|e| format!("Unable to write the locations: {}", e))?,
+    };
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(code: u16) -> ureq::Error {
+        ureq::Error::Status(code, ureq::Response::new(code, "", "").unwrap())
+    }
+
+    #[test]
+    fn sites_parsed_from_csv() {
+        let sites: Vec<Site> = parse_sites("Name,Zip,City,Country\nHome,90210,,\n\"Berlin, Mitte\",10115,,Germany\nOffice,,Toronto,ca\n").unwrap();
+        assert_eq!(sites[0], Site { name: "Home".to_string(), zip: Some("90210".to_string()), city: None, country: "US".to_string() });
+        assert_eq!(sites[1].name, "Berlin, Mitte");
+        assert_eq!(sites[1].country, "DE");
+        assert_eq!(sites[2].city, Some("Toronto".to_string()));
+        assert!(parse_sites("name,country\nHome,US").unwrap_err().contains("needs a zip or city column"));
+        assert!(parse_sites("zip,state\n90210,CA").unwrap_err().starts_with("Unknown column \"state\""));
+        assert!(parse_sites("zip,city\n,").unwrap_err().starts_with("Line 2 of the sites has neither"));
+    }
+
+    #[test]
+    fn single_zip_is_a_site() {
+        let geocode: GeocodeArgs = GeocodeArgs { input: None, zip: Some(" 10115 ".to_string()), country: Some("DE".to_string()), output: None };
+        assert_eq!(sites(&geocode).unwrap(), vec![Site { name: "10115".to_string(), zip: Some("10115".to_string()), city: None, country: "DE".to_string() }]);
+        let geocode: GeocodeArgs = GeocodeArgs { input: Some("BigFakeSites.csv".to_string()), zip: None, country: None, output: None };
+        assert!(sites(&geocode).unwrap_err().starts_with("Unable to read BigFakeSites.csv: "));
+    }
+
+    #[test]
+    fn locations_file_round_trips() {
+        let found: LocationsFile = LocationsFile { locations: vec![Location { name: "Home".to_string(), lat: 34.09, lon: -118.41, country: "US".to_string(), zip: Some("90210".to_string()), city: None }] };
+        let rendered: String = toml::to_string(&found).unwrap();
+        assert!(rendered.starts_with("[[OPENWEATHER_POLL_LOCATIONS]]\nname = \"Home\""));
+        assert_eq!(toml::from_str::<LocationsFile>(&rendered).unwrap(), found);
+        assert_eq!(city_url("New York", "US", "key"), "http://api.openweathermap.org/geo/1.0/direct?q=New+York,US&limit=1&appid=key");
+    }
+
+    #[test]
+    fn us_zip_format() {
+        assert!(looks_like_us_zip("90210"));
+        assert!(looks_like_us_zip(" 90210-1234 "));
+        assert!(!looks_like_us_zip("SW1A"));
+        assert!(!looks_like_us_zip("1010"));
+    }
+
+    #[test]
+    fn preflight_names_missing_settings() {
+        assert!(preflight("90210", "abc123").is_ok());
+        assert!(preflight(" ", "abc123").unwrap_err().starts_with("OPENWEATHER_POLL_ZIP is empty"));
+        assert!(preflight("90210", "NOAPISET").unwrap_err().starts_with("OPENWEATHER_API_KEY is not set"));
+        assert_eq!(preflight_city("", "abc123").unwrap_err(), "OPENWEATHER_POLL_CITY is empty. Set it to the city, ex: \"Portland,OR\", to poll.");
+        assert!(preflight_city("Portland,OR", "NOAPISET").unwrap_err().ends_with("Set it before OPENWEATHER_POLL_CITY is used."));
+    }
+
+    #[test]
+    fn errors_suggest_fixes() {
+        assert!(describe_error(&status(404), "SW1A", "US").contains("Did you forget OPENWEATHER_POLL_COUNTRY?"));
+        assert!(describe_error(&status(404), "10115", "US").starts_with("Zip code 10115 was not found in United States (US)."));
+        assert_eq!(describe_error(&status(404), "10115", "DE"), "Zip code 10115 was not found in Germany (DE). Check OPENWEATHER_POLL_ZIP, and OPENWEATHER_POLL_COUNTRY if the zip code is in another country.");
+        assert!(describe_error(&status(401), "90210", "US").contains("Check OPENWEATHER_API_KEY"));
+        assert!(describe_error(&status(502), "90210", "US").starts_with("OpenWeatherMap answered status 502"));
+        assert!(describe_city_error(&status(401), "Portland,OR", "US").contains("Check OPENWEATHER_API_KEY"));
+        assert_eq!(describe_city_error(&status(500), "Lyon", "FR"), "OpenWeatherMap answered status 500 while looking up city Lyon in France. It may be having trouble, so try again shortly.");
+        assert_eq!(city_not_found("Lyon", "DE"), "City Lyon was not found in Germany (DE). Check OPENWEATHER_POLL_CITY, and OPENWEATHER_POLL_COUNTRY if the city is in another country.");
+    }
+}
@@ -0,0 +1,120 @@
+//! Sampling a whole area on a grid instead of a single point, for heatmaps of a metro area.<br>
+//! OPENWEATHER_GRID_BBOX sets the corners of the area and OPENWEATHER_GRID_SPACING_KM how far apart the points are. Every poll fetches pollution at each point.
+//! Each point's readings are written with the location tag set to the area name and the point's cell, ex: "Seattle r02c05", counting rows north and columns east from the south west corner.
+
+use std::fmt;
+use std::str::FromStr;
+
+/// Default distance between grid points in kilometres
+pub const DEFAULT_GRID_SPACING_KM: f64 = 5.0;
+/// Most points a grid may have, as each costs an API call every poll
+pub const MAX_GRID_CELLS: usize = 100;
+/// Kilometres in a degree of latitude, and of longitude at the equator
+const KM_PER_DEGREE: f64 = 111.32;
+
+/// An area between two latitudes and two longitudes, written "south,west,north,east", ex: "47.4,-122.5,47.8,-122.1"
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BoundingBox {
+    pub south: f64,
+    pub west: f64,
+    pub north: f64,
+    pub east: f64,
+}
+
+impl FromStr for BoundingBox {
+    type Err = String;
+    fn from_str(raw_bbox: &str) -> Result<Self, Self::Err> {
+        let corners: Vec<f64> = match raw_bbox.split(',').map(|corner| corner.trim().parse::<f64>()).collect::<Result<Vec<f64>, _>>() {
+            Ok(corners) if corners.len() == 4 => corners,
+            _ => return Err(format!("Unable to understand grid bounding box \"{}\". Expected south,west,north,east in degrees, ex: 47.4,-122.5,47.8,-122.1.", raw_bbox)),
+        };
+        let bbox: BoundingBox = BoundingBox { south: corners[0], west: corners[1], north: corners[2], east: corners[3] };
+        if [bbox.south, bbox.north].iter().any(|lat| !(-90.0..=90.0).contains(lat)) || [bbox.west, bbox.east].iter().any(|lon| !(-180.0..=180.0).contains(lon)) {
+            return Err(format!("Grid bounding box \"{}\" is out of range. Latitudes must be within -90 to 90 and longitudes -180 to 180.", raw_bbox));
+        }
+        if bbox.south >= bbox.north || bbox.west >= bbox.east {
+            return Err(format!("Grid bounding box \"{}\" is empty. South must be below north and west below east.", raw_bbox));
+        }
+        Ok(bbox)
+    }
+}
+
+impl fmt::Display for BoundingBox {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{},{},{},{}", self.south, self.west, self.north, self.east)
+    }
+}
+
+/// One point of the grid, at the centre of its cell
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GridCell {
+    /// Row counted north from 0 at the south edge
+    pub row: usize,
+    /// Column counted east from 0 at the west edge
+    pub column: usize,
+    pub lat: f64,
+    pub lon: f64,
+}
+
+impl GridCell {
+    /// Get the cell's label, ex: "r02c05"
+    pub fn label(&self) -> String {
+        format!("r{:02}c{:02}", self.row, self.column)
+    }
+    /// Get the location name the cell's readings are written under, the area name followed by the label
+    pub fn location(&self, area: &str) -> String {
+        format!("{} {}", area, self.label())
+    }
+}
+
+/// Split an area into cells spacing_km across, with a point at the centre of each<br>
+/// Longitude steps are widened by the latitude of the middle of the area, so cells stay roughly square.
+///
+/// # Errors
+/// Returns a message if the spacing is not above 0 or the grid would have more than MAX_GRID_CELLS points
+pub fn cells(bbox: &BoundingBox, spacing_km: f64) -> Result<Vec<GridCell>, String> {
+    if spacing_km.is_nan() || spacing_km <= 0.0 {
+        return Err(format!("Grid spacing of {} km is invalid. Expected a distance above 0.", spacing_km));
+    }
+    let lat_step: f64 = spacing_km / KM_PER_DEGREE;
+    let lon_step: f64 = spacing_km / (KM_PER_DEGREE * ((bbox.south + bbox.north) / 2.0).to_radians().cos().max(0.01));
+    let rows: usize = ((bbox.north - bbox.south) / lat_step).ceil().max(1.0) as usize;
+    let columns: usize = ((bbox.east - bbox.west) / lon_step).ceil().max(1.0) as usize;
+    if rows.saturating_mul(columns) > MAX_GRID_CELLS {
+        return Err(format!("A grid over {} with {} km spacing has {} points, over the limit of {}. Widen OPENWEATHER_GRID_SPACING_KM or shrink OPENWEATHER_GRID_BBOX.", bbox, spacing_km, rows.saturating_mul(columns), MAX_GRID_CELLS));
+    }
+    let (row_height, column_width): (f64, f64) = ((bbox.north - bbox.south) / rows as f64, (bbox.east - bbox.west) / columns as f64);
+    Ok((0..rows).flat_map(|row| (0..columns).map(move |column| GridCell {
+        row,
+        column,
+        lat: bbox.south + row_height * (row as f64 + 0.5),
+        lon: bbox.west + column_width * (column as f64 + 0.5),
+    })).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounding_box_parsed() {
+        let bbox: BoundingBox = "47.4, -122.5, 47.8, -122.1".parse().unwrap();
+        assert_eq!(bbox, BoundingBox { south: 47.4, west: -122.5, north: 47.8, east: -122.1 });
+        assert_eq!(bbox.to_string(), "47.4,-122.5,47.8,-122.1");
+        assert!("47.4,-122.5,47.8".parse::<BoundingBox>().unwrap_err().contains("Expected south,west,north,east"));
+        assert!("47.8,-122.5,47.4,-122.1".parse::<BoundingBox>().unwrap_err().contains("is empty"));
+        assert!("95,-122.5,96,-122.1".parse::<BoundingBox>().unwrap_err().contains("out of range"));
+    }
+
+    #[test]
+    fn cells_cover_the_box() {
+        let bbox: BoundingBox = BoundingBox { south: 0.0, west: 0.0, north: 0.2, east: 0.2 };
+        let grid: Vec<GridCell> = cells(&bbox, 11.132).unwrap();
+        assert_eq!(grid.len(), 4);
+        assert_eq!(grid[1].label(), "r00c01");
+        assert!((grid[0].lat - 0.05).abs() < 1e-9 && (grid[1].lon - 0.15).abs() < 1e-9);
+        assert_eq!(grid[3].location("Quito"), "Quito r01c01");
+        assert!(cells(&bbox, 0.0).is_err());
+        assert!(cells(&bbox, 0.5).unwrap_err().contains("over the limit of 100"));
+    }
+}
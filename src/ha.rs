@@ -0,0 +1,219 @@
+//! Leader and standby mode for running two or more instances against the same database.<br>
+//! Instances share a lock file, ex: on a network share, holding the name of the leader and the time of its last heartbeat.
+//! Only the leader polls and writes. A standby checks the file on the same schedule the leader renews it, and takes over once the heartbeat has not changed for OPENWEATHER_HA_STALE_AFTER.
+//! That is timed by the standby's own clock, so instances do not need their clocks to agree. Claiming the file is done under an exclusive lock on a .claim file next to it, so two standbys cannot both take over.
+
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use crate::report;
+
+/// Default number of seconds without a heartbeat before a standby takes over
+pub const DEFAULT_STALE_AFTER: u64 = 120;
+
+/// What this instance is doing
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Role {
+    /// Holding the lock, so polling and writing
+    Leader,
+    /// Waiting for the named leader's heartbeat to go stale
+    Standby(String),
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Role::Leader => write!(f, "leader"),
+            Role::Standby(leader) => write!(f, "standby for {}", leader),
+        }
+    }
+}
+
+/// The contents of the lock file
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+struct Heartbeat {
+    leader: String,
+    heartbeat: DateTime<Utc>,
+}
+
+/// This instance's hold on a shared lock file
+#[derive(Debug)]
+pub struct Lease {
+    path: PathBuf,
+    instance: String,
+    stale_after: Duration,
+    role: Mutex<Role>,
+    /// The last heartbeat read from the file and when this instance first saw it
+    observed: Mutex<Option<(Heartbeat, DateTime<Utc>)>>,
+}
+
+/// Name this instance from HOSTNAME, which is the container ID under Docker, or the process ID when it is not set
+pub fn default_instance_id() -> String {
+    match std::env::var("HOSTNAME") {
+        Ok(hostname) if !hostname.trim().is_empty() => hostname.trim().to_string(),
+        _ => format!("pollutionclient-{}", std::process::id()),
+    }
+}
+
+impl Lease {
+    /// Create a lease on a lock file for the named instance. It starts as a standby until renew is called.
+    pub fn new(path: &Path, instance: &str, stale_after: Duration) -> Lease {
+        Lease { path: path.to_path_buf(), instance: instance.to_string(), stale_after, role: Mutex::new(Role::Standby("unknown".to_string())), observed: Mutex::new(None) }
+    }
+    /// Get the role found by the last renew
+    pub fn role(&self) -> Role {
+        self.role.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clone()
+    }
+    /// Check if this instance was the leader at the last renew
+    pub fn is_leader(&self) -> bool {
+        self.role() == Role::Leader
+    }
+    /// How often to renew: three times per stale period, so one missed heartbeat does not hand over the lock
+    pub fn renew_every(&self) -> Duration {
+        (self.stale_after / 3).max(Duration::from_secs(1))
+    }
+    /// Read the lock file and take it if it is free, ours or its heartbeat has not changed for the stale period, writing a new heartbeat. Prints a message when the role changes.<br>
+    /// If the file cannot be read or written this instance becomes a standby, as two leaders are worse than none for a while.
+    pub fn renew(&self, now: DateTime<Utc>) -> Role {
+        let role: Role = match self.claim(now) {
+            Ok(role) => role,
+            Err(e) => {
+                report::warn(&format!("Unable to use lock file {}: {}", self.path.display(), e));
+                Role::Standby("unknown".to_string())
+            },
+        };
+        let mut current = self.role.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if *current != role {
+            match &role {
+                Role::Leader => report::info(&format!("Instance {} is now the leader.", self.instance)),
+                Role::Standby(leader) => report::info(&format!("Instance {} is standing by, {} is the leader.", self.instance, leader)),
+            }
+            *current = role.clone();
+        }
+        role
+    }
+    /// Remove the lock file if this instance holds it, so a standby can take over straight away
+    pub fn release(&self) {
+        if let Ok(Some(current)) = self.read() {
+            if current.leader == self.instance {
+                if let Err(e) = std::fs::remove_file(&self.path) {
+                    report::warn(&format!("Unable to release lock file {}: {}", self.path.display(), e));
+                }
+            }
+        }
+        *self.role.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = Role::Standby("unknown".to_string());
+    }
+    /// Take or keep the lock file while holding the claim lock, so no other instance reads or writes it in between<br>
+    /// If another instance holds the claim lock it is part way through claiming, so this one stands by.
+    fn claim(&self, now: DateTime<Utc>) -> std::io::Result<Role> {
+        let guard: File = OpenOptions::new().create(true).truncate(false).write(true).open(self.claim_path())?;
+        match guard.try_lock() {
+            Ok(()) => (),
+            Err(TryLockError::WouldBlock) => return Ok(Role::Standby(self.read()?.map_or("unknown".to_string(), |current| current.leader))),
+            Err(TryLockError::Error(e)) => return Err(e),
+        }
+        if let Some(current) = self.read()? {
+            if current.leader != self.instance && !self.is_stale(&current, now) {
+                return Ok(Role::Standby(current.leader));
+            }
+        }
+        let heartbeat: Heartbeat = Heartbeat { leader: self.instance.clone(), heartbeat: now };
+        let temporary: PathBuf = self.path.with_extension(format!("{}.tmp", std::process::id()));
+        std::fs::write(&temporary, serde_json::to_string(&heartbeat)?)?;
+        std::fs::rename(&temporary, &self.path)?;
+        Ok(Role::Leader)
+    }
+    /// Check if another instance's heartbeat has stayed the same for the stale period, timed from when this instance first saw it<br>
+    /// The heartbeat's own time is only compared with itself, so a leader whose clock is behind or ahead is not taken over early or late.
+    fn is_stale(&self, current: &Heartbeat, now: DateTime<Utc>) -> bool {
+        let mut observed = self.observed.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let since: DateTime<Utc> = match observed.as_ref() {
+            Some((seen, since)) if seen == current => *since,
+            _ => {
+                *observed = Some((current.clone(), now));
+                now
+            },
+        };
+        (now - since).to_std().unwrap_or(Duration::ZERO) >= self.stale_after
+    }
+    /// Get the file locked while claiming, the lock file's path with .claim added
+    fn claim_path(&self) -> PathBuf {
+        let mut claim_path: OsString = self.path.clone().into_os_string();
+        claim_path.push(".claim");
+        PathBuf::from(claim_path)
+    }
+    fn read(&self) -> std::io::Result<Option<Heartbeat>> {
+        match std::fs::read_to_string(&self.path) {
+            Ok(content) => Ok(serde_json::from_str(&content).ok()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_path(name: &str) -> PathBuf {
+        let path: PathBuf = std::env::temp_dir().join(format!("pollutionclient_ha_{}_{}.json", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn standby_takes_over_stale_lock() {
+        let path: PathBuf = test_path("stale");
+        let first: Lease = Lease::new(&path, "first", Duration::from_secs(60));
+        let second: Lease = Lease::new(&path, "second", Duration::from_secs(60));
+        let start: DateTime<Utc> = DateTime::from_timestamp(1700000000, 0).unwrap();
+        assert_eq!(first.renew(start), Role::Leader);
+        assert_eq!(second.renew(start + chrono::Duration::seconds(30)), Role::Standby("first".to_string()));
+        assert_eq!(first.renew(start + chrono::Duration::seconds(40)), Role::Leader);
+        assert_eq!(second.renew(start + chrono::Duration::seconds(100)), Role::Standby("first".to_string()));
+        assert_eq!(second.renew(start + chrono::Duration::seconds(160)), Role::Leader);
+        assert_eq!(first.renew(start + chrono::Duration::seconds(170)), Role::Standby("second".to_string()));
+        assert!(!first.is_leader());
+        second.release();
+        assert!(!path.exists());
+        let _ = std::fs::remove_file(second.claim_path());
+    }
+
+    #[test]
+    fn leader_clock_does_not_matter() {
+        let path: PathBuf = test_path("clock");
+        let start: DateTime<Utc> = DateTime::from_timestamp(1700000000, 0).unwrap();
+        let behind: Lease = Lease::new(&path, "behind", Duration::from_secs(60));
+        let standby: Lease = Lease::new(&path, "standby", Duration::from_secs(60));
+        assert_eq!(behind.renew(start - chrono::Duration::hours(1)), Role::Leader);
+        assert_eq!(standby.renew(start), Role::Standby("behind".to_string()));
+        assert_eq!(behind.renew(start - chrono::Duration::minutes(59)), Role::Leader);
+        assert_eq!(standby.renew(start + chrono::Duration::seconds(59)), Role::Standby("behind".to_string()));
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(standby.claim_path());
+    }
+
+    #[test]
+    fn claim_in_progress_stands_by() {
+        let path: PathBuf = test_path("claim");
+        let lease: Lease = Lease::new(&path, "me", Duration::from_secs(60));
+        let claiming: File = OpenOptions::new().create(true).truncate(false).write(true).open(lease.claim_path()).unwrap();
+        claiming.lock().unwrap();
+        assert_eq!(lease.renew(DateTime::from_timestamp(1700000000, 0).unwrap()), Role::Standby("unknown".to_string()));
+        claiming.unlock().unwrap();
+        assert_eq!(lease.renew(DateTime::from_timestamp(1700000000, 0).unwrap()), Role::Leader);
+        lease.release();
+        let _ = std::fs::remove_file(lease.claim_path());
+    }
+
+    #[test]
+    fn renew_every_is_a_third_of_stale() {
+        assert_eq!(Lease::new(Path::new("lock"), "me", Duration::from_secs(120)).renew_every(), Duration::from_secs(40));
+        assert_eq!(Lease::new(Path::new("lock"), "me", Duration::from_secs(1)).renew_every(), Duration::from_secs(1));
+    }
+}
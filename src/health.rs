@@ -0,0 +1,170 @@
+//! Liveness and readiness of the polling loop, served on GET /healthz and GET /readyz when OPENWEATHER_HTTP_BIND is set.<br>
+//! The loop records every cycle, successful poll, successful write and failure here, so an orchestrator can restart the client when collection silently stalls.
+
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+/// How many poll intervals can pass without a cycle before the client counts as stalled
+pub const STALL_INTERVALS: u32 = 3;
+
+static HEALTH: OnceLock<Mutex<Health>> = OnceLock::new();
+
+fn health() -> &'static Mutex<Health> {
+    HEALTH.get_or_init(|| Mutex::new(Health::new(Utc::now(), Duration::from_secs(3600))))
+}
+
+/// What has been recorded about the polling loop
+#[derive(Clone, Debug, PartialEq)]
+pub struct Health {
+    started: DateTime<Utc>,
+    stall_after: Duration,
+    last_cycle: Option<DateTime<Utc>>,
+    last_poll: Option<DateTime<Utc>>,
+    last_write: Option<DateTime<Utc>>,
+    error_count: u32,
+}
+
+/// The body of /healthz and /readyz
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct HealthReport {
+    /// "ok", or why the check failed, ex: "stalled"
+    pub status: String,
+    /// When a reading was last fetched successfully
+    pub last_poll: Option<DateTime<Utc>>,
+    /// When a reading was last written successfully
+    pub last_write: Option<DateTime<Utc>>,
+    /// Fetches and writes that have failed since the last success
+    pub error_count: u32,
+}
+
+impl Health {
+    /// Start recording for a loop that is expected to go through a cycle at least every stall_after
+    pub fn new(started: DateTime<Utc>, stall_after: Duration) -> Health {
+        Health { started, stall_after, last_cycle: None, last_poll: None, last_write: None, error_count: 0 }
+    }
+    /// Note a cycle of the loop, including ones skipped on purpose, ex: in quiet hours or on standby
+    pub fn cycle(&mut self, time: DateTime<Utc>) {
+        self.last_cycle = Some(time);
+    }
+    /// Note a successful poll, which also clears the error count
+    pub fn poll(&mut self, time: DateTime<Utc>) {
+        self.cycle(time);
+        self.last_poll = Some(time);
+        self.error_count = 0;
+    }
+    /// Note a successful write, which also clears the error count
+    pub fn write(&mut self, time: DateTime<Utc>) {
+        self.last_write = Some(time);
+        self.error_count = 0;
+    }
+    /// Note a failed fetch or write
+    pub fn failure(&mut self, time: DateTime<Utc>) {
+        self.cycle(time);
+        self.error_count = self.error_count.saturating_add(1);
+    }
+    /// Check if the loop has gone through a cycle recently enough. Until the first cycle, time since starting is used.
+    pub fn is_live(&self, now: DateTime<Utc>) -> bool {
+        let since: DateTime<Utc> = self.last_cycle.unwrap_or(self.started);
+        chrono::Duration::from_std(self.stall_after).is_ok_and(|stall_after| now - since <= stall_after)
+    }
+    /// Check if the client is live, has written a reading and has had no failures since its last success
+    pub fn is_ready(&self, now: DateTime<Utc>) -> bool {
+        self.is_live(now) && self.last_write.is_some() && self.error_count == 0
+    }
+    /// Build the report for a check, with status "ok" when it passed
+    fn report(&self, passed: bool, failed_status: &str) -> HealthReport {
+        HealthReport { status: if passed { "ok".to_string() } else { failed_status.to_string() }, last_poll: self.last_poll, last_write: self.last_write, error_count: self.error_count }
+    }
+    /// Work out the status code and report for /healthz, 503 if the loop has stalled
+    pub fn liveness(&self, now: DateTime<Utc>) -> (u16, HealthReport) {
+        let live: bool = self.is_live(now);
+        (if live { 200 } else { 503 }, self.report(live, "stalled"))
+    }
+    /// Work out the status code and report for /readyz, 503 if the loop has stalled, nothing has been written yet or the latest fetch or write failed
+    pub fn readiness(&self, now: DateTime<Utc>) -> (u16, HealthReport) {
+        let failed_status: &str = if !self.is_live(now) {
+            "stalled"
+        } else if self.error_count > 0 {
+            "failing"
+        } else {
+            "starting"
+        };
+        let ready: bool = self.is_ready(now);
+        (if ready { 200 } else { 503 }, self.report(ready, failed_status))
+    }
+}
+
+/// Run something against the recorded health
+fn with_health<T>(action: impl FnOnce(&mut Health) -> T) -> T {
+    action(&mut health().lock().unwrap_or_else(|poisoned| poisoned.into_inner()))
+}
+
+/// Start recording for a polling loop, counting it as stalled after STALL_INTERVALS of the given interval without a cycle
+pub fn start(interval: Duration) {
+    with_health(|health| *health = Health::new(Utc::now(), interval.saturating_mul(STALL_INTERVALS)));
+}
+
+/// Note a cycle of the polling loop that did not poll
+pub fn record_cycle() {
+    with_health(|health| health.cycle(Utc::now()));
+}
+
+/// Note a successful poll
+pub fn record_poll() {
+    with_health(|health| health.poll(Utc::now()));
+}
+
+/// Note a successful write
+pub fn record_write() {
+    with_health(|health| health.write(Utc::now()));
+}
+
+/// Note a failed fetch or write
+pub fn record_failure() {
+    with_health(|health| health.failure(Utc::now()));
+}
+
+/// Get the status code and report for /healthz
+pub fn liveness() -> (u16, HealthReport) {
+    with_health(|health| health.liveness(Utc::now()))
+}
+
+/// Get the status code and report for /readyz
+pub fn readiness() -> (u16, HealthReport) {
+    with_health(|health| health.readiness(Utc::now()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stalls_without_cycles() {
+        let started: DateTime<Utc> = DateTime::from_timestamp(1700000000, 0).unwrap();
+        let mut health: Health = Health::new(started, Duration::from_secs(3 * 3600));
+        assert_eq!(health.liveness(started + chrono::Duration::hours(2)).0, 200);
+        let (status, report) = health.liveness(started + chrono::Duration::hours(4));
+        assert_eq!((status, report.status.as_str()), (503, "stalled"));
+        health.cycle(started + chrono::Duration::hours(3));
+        assert_eq!(health.liveness(started + chrono::Duration::hours(4)).0, 200);
+    }
+
+    #[test]
+    fn ready_after_write_until_failure() {
+        let started: DateTime<Utc> = DateTime::from_timestamp(1700000000, 0).unwrap();
+        let mut health: Health = Health::new(started, Duration::from_secs(3 * 3600));
+        assert_eq!(health.readiness(started).1.status, "starting");
+        health.poll(started);
+        health.write(started);
+        let (status, report) = health.readiness(started);
+        assert_eq!((status, report.error_count, report.last_write), (200, 0, Some(started)));
+        health.failure(started + chrono::Duration::hours(1));
+        let (status, report) = health.readiness(started + chrono::Duration::hours(1));
+        assert_eq!((status, report.status.as_str(), report.error_count), (503, "failing", 1));
+        assert_eq!(health.liveness(started + chrono::Duration::hours(1)).0, 200);
+        health.poll(started + chrono::Duration::hours(2));
+        assert_eq!(health.readiness(started + chrono::Duration::hours(2)).0, 200);
+    }
+}
@@ -0,0 +1,132 @@
+//! Bounded in-memory store of the most recent readings for each location.<br>
+//! Gives the library API and the HTTP endpoint a short history without having to query the database.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use chrono::{DateTime, Utc};
+use crate::PollUpdate;
+
+/// Default number of readings kept per location, a day of hourly polls
+pub const DEFAULT_HISTORY_SIZE: usize = 24;
+
+/// Ring buffer of recent readings per location. Once a location is full, the oldest reading is dropped for each new one.
+#[derive(Clone, Debug)]
+pub struct ReadingBuffer {
+    capacity: usize,
+    readings: BTreeMap<String, VecDeque<PollUpdate>>,
+}
+
+/// A ReadingBuffer that can be handed to other threads, such as the HTTP endpoint
+pub type SharedBuffer = Arc<Mutex<ReadingBuffer>>;
+
+impl ReadingBuffer {
+    /// Create an empty buffer keeping up to capacity readings per location. A capacity of 0 keeps nothing.
+    pub fn new(capacity: usize) -> ReadingBuffer {
+        ReadingBuffer { capacity, readings: BTreeMap::new() }
+    }
+    /// Create an empty buffer already wrapped for sharing between threads
+    pub fn shared(capacity: usize) -> SharedBuffer {
+        Arc::new(Mutex::new(ReadingBuffer::new(capacity)))
+    }
+    /// Add a reading under its series, which is its location unless it came from a reference station, dropping the oldest one if that series is already full
+    pub fn push(&mut self, update: PollUpdate) {
+        if self.capacity == 0 {
+            return;
+        }
+        let location_readings: &mut VecDeque<PollUpdate> = self.readings.entry(update.get_series()).or_default();
+        while location_readings.len() >= self.capacity {
+            location_readings.pop_front();
+        }
+        location_readings.push_back(update);
+    }
+    /// Get a copy of the readings for a location, oldest first
+    pub fn recent(&self, location: &str) -> Vec<PollUpdate> {
+        match self.readings.get(location) {
+            Some(location_readings) => location_readings.iter().cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+    /// Get a copy of the readings for a location taken at or after a point in time, oldest first
+    pub fn since(&self, location: &str, since: DateTime<Utc>) -> Vec<PollUpdate> {
+        match self.readings.get(location) {
+            Some(location_readings) => location_readings.iter().filter(|update| update.get_time() >= since).cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+    /// Check if the buffer holds everything for a location from a point in time onwards<br>
+    /// This is only true when the oldest kept reading is at or before that point, otherwise older readings may only be in the database
+    pub fn covers(&self, location: &str, since: DateTime<Utc>) -> bool {
+        match self.readings.get(location).and_then(|location_readings| location_readings.front()) {
+            Some(oldest) => oldest.get_time() <= since,
+            None => false,
+        }
+    }
+    /// Get the newest reading for a location
+    pub fn latest(&self, location: &str) -> Option<&PollUpdate> {
+        self.readings.get(location).and_then(|location_readings| location_readings.back())
+    }
+    /// Get every location that has at least one reading
+    pub fn locations(&self) -> Vec<String> {
+        self.readings.keys().cloned().collect()
+    }
+    /// Get a copy of every reading, grouped by location
+    pub fn all(&self) -> BTreeMap<String, Vec<PollUpdate>> {
+        self.readings.iter().map(|(location, location_readings)| (location.clone(), location_readings.iter().cloned().collect())).collect()
+    }
+    /// Get the maximum number of readings kept per location
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_update(location: &str, seconds: i64) -> PollUpdate {
+        PollUpdate::fixture(location, seconds)
+    }
+
+    #[test]
+    fn buffer_drops_oldest_when_full() {
+        let mut buffer: ReadingBuffer = ReadingBuffer::new(2);
+        buffer.push(test_update("Home", 1));
+        buffer.push(test_update("Home", 2));
+        buffer.push(test_update("Home", 3));
+        let recent: Vec<PollUpdate> = buffer.recent("Home");
+        assert_eq!(recent.len(), 2);
+        assert_eq!(recent[0].get_time().timestamp(), 2);
+        assert_eq!(buffer.latest("Home").unwrap().get_time().timestamp(), 3);
+    }
+
+    #[test]
+    fn buffer_keeps_locations_apart() {
+        let mut buffer: ReadingBuffer = ReadingBuffer::new(5);
+        buffer.push(test_update("Home", 1));
+        buffer.push(test_update("Work", 2));
+        assert_eq!(buffer.locations(), vec!["Home".to_string(), "Work".to_string()]);
+        assert_eq!(buffer.recent("Home").len(), 1);
+        assert!(buffer.recent("Elsewhere").is_empty());
+        assert!(buffer.latest("Elsewhere").is_none());
+    }
+
+    #[test]
+    fn buffer_since_and_covers() {
+        let mut buffer: ReadingBuffer = ReadingBuffer::new(5);
+        buffer.push(test_update("Home", 100));
+        buffer.push(test_update("Home", 200));
+        buffer.push(test_update("Home", 300));
+        let window: Vec<PollUpdate> = buffer.since("Home", DateTime::from_timestamp(150, 0).unwrap());
+        assert_eq!(window.len(), 2);
+        assert!(buffer.covers("Home", DateTime::from_timestamp(150, 0).unwrap()));
+        assert!(!buffer.covers("Home", DateTime::from_timestamp(50, 0).unwrap()));
+        assert!(!buffer.covers("Work", DateTime::from_timestamp(150, 0).unwrap()));
+    }
+
+    #[test]
+    fn zero_capacity_keeps_nothing() {
+        let mut buffer: ReadingBuffer = ReadingBuffer::new(0);
+        buffer.push(test_update("Home", 1));
+        assert!(buffer.locations().is_empty());
+    }
+}
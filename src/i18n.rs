@@ -0,0 +1,219 @@
+//! Translations for the words shown to people: pollutant names, AQI categories and alert messages.<br>
+//! The locale is set once from OPENWEATHER_LOCALE when a client is created and used by the Display impls and notifiers from then on.
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+use chrono::{NaiveDate, NaiveDateTime};
+
+/// Languages with translation tables
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Locale {
+    English,
+    Spanish,
+    French,
+    German,
+}
+
+static CURRENT: AtomicU8 = AtomicU8::new(0);
+
+impl Locale {
+    const ALL: [Locale; 4] = [Locale::English, Locale::Spanish, Locale::French, Locale::German];
+
+    fn index(self) -> usize {
+        match self {
+            Locale::English => 0,
+            Locale::Spanish => 1,
+            Locale::French => 2,
+            Locale::German => 3,
+        }
+    }
+}
+
+impl FromStr for Locale {
+    type Err = String;
+    /// Accepts a language code with or without a region, ex: "de", "de-AT" or "de_DE.UTF-8"
+    fn from_str(raw_locale: &str) -> Result<Self, Self::Err> {
+        let language: String = raw_locale.trim().split(['-', '_', '.']).next().unwrap_or("").to_lowercase();
+        match language.as_str() {
+            "en" => Ok(Locale::English),
+            "es" => Ok(Locale::Spanish),
+            "fr" => Ok(Locale::French),
+            "de" => Ok(Locale::German),
+            _ => Err(format!("Unknown locale \"{}\". Expected one of en, es, fr or de.", raw_locale)),
+        }
+    }
+}
+
+impl fmt::Display for Locale {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", ["en", "es", "fr", "de"][self.index()])
+    }
+}
+
+/// Set the locale used for everything shown from now on
+pub fn set_locale(locale: Locale) {
+    CURRENT.store(locale.index() as u8, Ordering::Relaxed);
+}
+
+/// Get the locale currently in use. Defaults to English.
+pub fn current() -> Locale {
+    Locale::ALL.get(CURRENT.load(Ordering::Relaxed) as usize).copied().unwrap_or(Locale::English)
+}
+
+/// Pollutant names by field, in the same order as Locale::index
+const POLLUTANTS: &[(&str, [&str; 4])] = &[
+    ("aqi", ["Air Quality", "Calidad del aire", "Qualité de l'air", "Luftqualität"]),
+    ("co", ["Carbon Monoxide", "Monóxido de carbono", "Monoxyde de carbone", "Kohlenmonoxid"]),
+    ("no", ["Nitrogen Monoxide", "Monóxido de nitrógeno", "Monoxyde d'azote", "Stickstoffmonoxid"]),
+    ("no2", ["Nitrogen Dioxide", "Dióxido de nitrógeno", "Dioxyde d'azote", "Stickstoffdioxid"]),
+    ("o3", ["Ozone", "Ozono", "Ozone", "Ozon"]),
+    ("so2", ["Sulphur Dioxide", "Dióxido de azufre", "Dioxyde de soufre", "Schwefeldioxid"]),
+    ("pm2_5", ["Fine Particulate Matter", "Partículas finas", "Particules fines", "Feinstaub"]),
+    ("pm10", ["Course Particulate Matter", "Partículas gruesas", "Particules grossières", "Grobstaub"]),
+    ("nh3", ["Ammonia", "Amoníaco", "Ammoniac", "Ammoniak"]),
+];
+
+/// OpenWeatherMaps AQI categories from 1 (best) to 5 (worst)
+const CATEGORIES: [[&str; 5]; 4] = [
+    ["Good", "Fair", "Moderate", "Poor", "Very Poor"],
+    ["Buena", "Aceptable", "Moderada", "Mala", "Muy mala"],
+    ["Bon", "Correct", "Moyen", "Médiocre", "Très mauvais"],
+    ["Gut", "Ordentlich", "Mäßig", "Schlecht", "Sehr schlecht"],
+];
+
+/// Get the name of a pollutant or the AQI by its field name. Unknown fields are returned as they are.
+pub fn pollutant_name(locale: Locale, field: &str) -> String {
+    match POLLUTANTS.iter().find(|(known, _)| *known == field) {
+        Some((_, names)) => names[locale.index()].to_string(),
+        None => field.to_string(),
+    }
+}
+
+/// Get the name of an OpenWeatherMaps AQI category, ex: 2 is "Fair" in English
+pub fn aqi_category(locale: Locale, aqi: i8) -> &'static str {
+    match aqi {
+        1..=5 => CATEGORIES[locale.index()][(aqi - 1) as usize],
+        _ => ["Unknown", "Desconocida", "Inconnue", "Unbekannt"][locale.index()],
+    }
+}
+
+/// Get the heading printed before the pollutant breakdown
+pub fn component_heading(locale: Locale) -> &'static str {
+    ["Component breakdown:", "Desglose por componente:", "Détail par composant :", "Aufschlüsselung nach Komponenten:"][locale.index()]
+}
+
+/// Get the heading for the location column of the console table
+pub fn location_label(locale: Locale) -> &'static str {
+    ["Location", "Ubicación", "Lieu", "Ort"][locale.index()]
+}
+
+/// Get the heading for the time column of the console table
+pub fn time_label(locale: Locale) -> &'static str {
+    ["Time", "Hora", "Heure", "Zeit"][locale.index()]
+}
+
+/// Build the message for a threshold alert, ex: "Fine Particulate Matter at Home is 40, above 35"
+pub fn threshold_message(locale: Locale, field: &str, location: &str, value: f32, above: f32) -> String {
+    let name: String = pollutant_name(locale, field);
+    match locale {
+        Locale::English => format!("{} at {} is {}, above {}", name, location, value, above),
+        Locale::Spanish => format!("{} en {} es {}, por encima de {}", name, location, value, above),
+        Locale::French => format!("{} à {} est de {}, au-dessus de {}", name, location, value, above),
+        Locale::German => format!("{} in {} liegt bei {}, über {}", name, location, value, above),
+    }
+}
+
+/// Build the message for a category alert, ex: "Air quality at Home is Unhealthy (aqi_us 160)"
+pub fn category_message(locale: Locale, field: &str, location: &str, value: f32, category: &str) -> String {
+    match locale {
+        Locale::English => format!("Air quality at {} is {} ({} {})", location, category, field, value),
+        Locale::Spanish => format!("La calidad del aire en {} es {} ({} {})", location, category, field, value),
+        Locale::French => format!("La qualité de l'air à {} est {} ({} {})", location, category, field, value),
+        Locale::German => format!("Die Luftqualität in {} ist {} ({} {})", location, category, field, value),
+    }
+}
+
+/// Build the message for a scripted alert condition that matched
+pub fn condition_message(locale: Locale, condition: &str, location: &str) -> String {
+    match locale {
+        Locale::English => format!("\"{}\" matched at {}", condition, location),
+        Locale::Spanish => format!("\"{}\" se cumplió en {}", condition, location),
+        Locale::French => format!("\"{}\" vérifiée à {}", condition, location),
+        Locale::German => format!("\"{}\" trifft in {} zu", condition, location),
+    }
+}
+
+/// Describe when a forecast hour falls relative to today, ex: "tomorrow at 14:00", or its date when it is further off
+pub fn forecast_time(locale: Locale, time: NaiveDateTime, today: NaiveDate) -> String {
+    let clock: String = time.format("%H:%M").to_string();
+    let day: Option<usize> = match (time.date() - today).num_days() {
+        0 => Some(0),
+        1 => Some(1),
+        _ => None,
+    };
+    let date: String = time.format("%Y-%m-%d").to_string();
+    match locale {
+        Locale::English => format!("{} at {}", day.map(|day| ["today", "tomorrow"][day].to_string()).unwrap_or(date), clock),
+        Locale::Spanish => format!("{} a las {}", day.map(|day| ["hoy", "mañana"][day].to_string()).unwrap_or(format!("el {}", date)), clock),
+        Locale::French => format!("{} à {}", day.map(|day| ["aujourd'hui", "demain"][day].to_string()).unwrap_or(format!("le {}", date)), clock),
+        Locale::German => format!("{} um {}", day.map(|day| ["heute", "morgen"][day].to_string()).unwrap_or(format!("den {}", date)), clock),
+    }
+}
+
+/// Build the message for an alert raised from the forecast, ex: "Forecast for tomorrow at 14:00: Air quality at Home is Unhealthy (aqi_us 160)"
+pub fn forecast_message(locale: Locale, when: &str, message: &str) -> String {
+    match locale {
+        Locale::English => format!("Forecast for {}: {}", when, message),
+        Locale::Spanish => format!("Previsión para {}: {}", when, message),
+        Locale::French => format!("Prévision pour {} : {}", when, message),
+        Locale::German => format!("Vorhersage für {}: {}", when, message),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locale_parses_regions() {
+        assert_eq!("de-AT".parse::<Locale>(), Ok(Locale::German));
+        assert_eq!("es_ES.UTF-8".parse::<Locale>(), Ok(Locale::Spanish));
+        assert_eq!(" FR ".parse::<Locale>(), Ok(Locale::French));
+        assert!("xx".parse::<Locale>().is_err());
+        assert_eq!(Locale::German.to_string(), "de".to_string());
+    }
+
+    #[test]
+    fn every_locale_has_names() {
+        for locale in Locale::ALL {
+            for (field, _) in POLLUTANTS {
+                assert!(!pollutant_name(locale, field).is_empty());
+            }
+        }
+        assert_eq!(pollutant_name(Locale::German, "pm2_5"), "Feinstaub".to_string());
+        assert_eq!(pollutant_name(Locale::French, "unknown"), "unknown".to_string());
+    }
+
+    #[test]
+    fn categories_by_aqi() {
+        assert_eq!(aqi_category(Locale::English, 2), "Fair");
+        assert_eq!(aqi_category(Locale::Spanish, 5), "Muy mala");
+        assert_eq!(aqi_category(Locale::German, 0), "Unbekannt");
+    }
+
+    #[test]
+    fn threshold_message_translated() {
+        assert_eq!(threshold_message(Locale::Spanish, "o3", "Madrid", 130.0, 120.0), "Ozono en Madrid es 130, por encima de 120".to_string());
+    }
+
+    #[test]
+    fn forecast_time_relative_to_today() {
+        let today: NaiveDate = NaiveDate::from_ymd_opt(2024, 3, 1).unwrap();
+        let tomorrow: NaiveDateTime = NaiveDate::from_ymd_opt(2024, 3, 2).unwrap().and_hms_opt(14, 0, 0).unwrap();
+        assert_eq!(forecast_time(Locale::English, tomorrow, today), "tomorrow at 14:00".to_string());
+        assert_eq!(forecast_time(Locale::German, today.and_hms_opt(9, 30, 0).unwrap(), today), "heute um 09:30".to_string());
+        let later: NaiveDateTime = NaiveDate::from_ymd_opt(2024, 3, 3).unwrap().and_hms_opt(6, 0, 0).unwrap();
+        assert_eq!(forecast_message(Locale::Spanish, &forecast_time(Locale::Spanish, later, today), "Ozono"), "Previsión para el 2024-03-03 a las 06:00: Ozono".to_string());
+    }
+}
@@ -0,0 +1,159 @@
+//! Importing a station's official history from OpenAQ, so a new deployment can seed its dashboards with years of readings.<br>
+//! Run with the binary's import subcommand, ex: `pollutionclient_rs import --station 8118 --from 2022-01-01`.
+//! Hourly averages for each pollutant the station measures are grouped by hour and written as reference readings, timestamped with the end of the hour, the same way OPENWEATHER_REFERENCE_STATION writes them.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+use chrono::{DateTime, SecondsFormat, Utc};
+use influxdb::{Client, Error};
+use serde::Deserialize;
+use crate::reference::{self, MeasuredAt, ReferenceStation, Sensor, Station, OPENAQ_API};
+use crate::{report, write_batch_to_db, Config, PollUpdate};
+
+/// How many hours are asked for, and how many readings written, at a time
+pub const IMPORT_PAGE_SIZE: usize = 1000;
+/// Time between requests to OpenAQ, to stay within its rate limit of 60 a minute
+pub const IMPORT_DELAY: Duration = Duration::from_millis(1100);
+
+/// What to import, from the import subcommand's flags
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImportArgs {
+    /// Station to import, or OPENWEATHER_REFERENCE_STATION if None, or the nearest if that is not set either
+    pub station: Option<ReferenceStation>,
+    /// Earliest hour to import
+    pub from: DateTime<Utc>,
+    /// Latest hour to import, or up to now if None
+    pub to: Option<DateTime<Utc>>,
+    /// Location to write the readings under, or the configured location if None
+    pub location: Option<String>,
+}
+
+/// How an import went
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ImportSummary {
+    /// Name and ID of the station imported from
+    pub station: String,
+    /// Hourly readings written
+    pub imported: usize,
+}
+
+/// The averaging period of an hourly value
+#[derive(Clone, Debug, Deserialize)]
+struct Period {
+    #[serde(rename = "datetimeTo")]
+    datetime_to: MeasuredAt,
+}
+
+/// One hourly average from a sensor
+#[derive(Clone, Debug, Deserialize)]
+struct Hourly {
+    value: f64,
+    period: Period,
+}
+
+/// Build the OpenAQ URL for a page of a sensor's hourly averages between two times
+pub fn hours_url(sensor_id: u64, from: DateTime<Utc>, to: DateTime<Utc>, page: usize) -> String {
+    format!("{}/sensors/{}/hours?datetime_from={}&datetime_to={}&limit={}&page={}", OPENAQ_API, sensor_id,
+        from.to_rfc3339_opts(SecondsFormat::Secs, true), to.to_rfc3339_opts(SecondsFormat::Secs, true), IMPORT_PAGE_SIZE, page)
+}
+
+/// Add a sensor's hourly averages to the values collected for each hour, in µg/m³<br>
+/// Values in units that cannot be converted, or below 0, are left out.
+fn collect_hours(hours: &mut BTreeMap<DateTime<Utc>, Vec<(&'static str, f32)>>, field: &'static str, sensor: &Sensor, page: &[Hourly]) {
+    for hourly in page {
+        if let Some(value) = reference::to_micrograms(field, hourly.value as f32, &sensor.parameter.units).filter(|value| *value >= 0.0) {
+            hours.entry(hourly.period.datetime_to.utc).or_default().push((field, value));
+        }
+    }
+}
+
+/// Turn the values collected for each hour into reference readings for a location, oldest first
+fn to_readings(hours: BTreeMap<DateTime<Utc>, Vec<(&'static str, f32)>>, location: &str) -> Vec<PollUpdate> {
+    hours.into_iter().map(|(time, values)| {
+        let mut update: PollUpdate = reference::reading(time, &values);
+        update.set_location(location);
+        update
+    }).collect()
+}
+
+/// Fetch every hourly average a station's sensors have between two times, a page at a time, printing progress after each sensor
+///
+/// # Errors
+/// Returns a message if OpenAQ cannot be reached or its answer understood
+async fn fetch_hours(current_config: &Config, station: &Station, from: DateTime<Utc>, to: DateTime<Utc>) -> Result<BTreeMap<DateTime<Utc>, Vec<(&'static str, f32)>>, String> {
+    let apikey: &str = current_config.get_openaq_key().unwrap_or_default();
+    let mut hours: BTreeMap<DateTime<Utc>, Vec<(&'static str, f32)>> = BTreeMap::new();
+    for sensor in &station.sensors {
+        let field: &'static str = match reference::field_for(&sensor.parameter.name) {
+            Some(field) => field,
+            None => continue,
+        };
+        let mut page: usize = 1;
+        loop {
+            tokio::time::sleep(IMPORT_DELAY).await;
+            let hourly: Vec<Hourly> = reference::get_results(&hours_url(sensor.id, from, to, page), apikey)
+                .map_err(|e| format!("Unable to read {} history from {}: {}", sensor.parameter.name, station.label(), e))?;
+            collect_hours(&mut hours, field, sensor, &hourly);
+            if hourly.len() < IMPORT_PAGE_SIZE {
+                break;
+            }
+            page += 1;
+        }
+        report::info(&format!("Read {} history from {}, {} hours so far.", sensor.parameter.name, station.label(), hours.len()));
+    }
+    Ok(hours)
+}
+
+/// Read a station's hourly history for the range the import asks for from OpenAQ and write it to InfluxDB as reference readings
+///
+/// # Errors
+/// Returns a message if the range is empty, the station cannot be found, its history cannot be read or a write fails. Readings written before then stay written.
+pub async fn run(dbclient: &Client, current_config: &Config, import: &ImportArgs) -> Result<ImportSummary, String> {
+    let to: DateTime<Utc> = import.to.unwrap_or_else(Utc::now);
+    if import.from >= to {
+        return Err(format!("import needs --from before --to, got {} and {}.", import.from, to));
+    }
+    let wanted: ReferenceStation = import.station.or(current_config.get_reference_station()).unwrap_or(ReferenceStation::Nearest);
+    let station: Station = reference::find_station(current_config, wanted)?;
+    report::info(&format!("Importing history from {} between {} and {}.", station.label(), import.from, to));
+    let location: &str = import.location.as_deref().unwrap_or(current_config.get_location());
+    let readings: Vec<PollUpdate> = to_readings(fetch_hours(current_config, &station, import.from, to).await?, location);
+    let mut summary: ImportSummary = ImportSummary { station: station.label(), imported: 0 };
+    for batch in readings.chunks(IMPORT_PAGE_SIZE) {
+        write_batch_to_db(dbclient, current_config, batch.to_vec()).await
+            .map_err(|e: Error| format!("Unable to write imported readings to {}: {}", current_config.get_dbname(), e))?;
+        summary.imported += batch.len();
+        report::info(&format!("Imported {} of {} readings so far.", summary.imported, readings.len()));
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hours_url_pages_through_a_sensor() {
+        let from: DateTime<Utc> = DateTime::from_timestamp(1699999200, 0).unwrap();
+        assert_eq!(hours_url(3917, from, from + chrono::Duration::days(1), 2),
+            "https://api.openaq.org/v3/sensors/3917/hours?datetime_from=2023-11-14T22:00:00Z&datetime_to=2023-11-15T22:00:00Z&limit=1000&page=2");
+    }
+
+    #[test]
+    fn hours_grouped_into_readings() {
+        let pm25: Sensor = serde_json::from_str(r#"{"id":1,"parameter":{"name":"pm25","units":"µg/m³"}}"#).unwrap();
+        let no2: Sensor = serde_json::from_str(r#"{"id":2,"parameter":{"name":"no2","units":"ppb"}}"#).unwrap();
+        let page = |json: &str| -> Vec<Hourly> { serde_json::from_str(json).unwrap() };
+        let mut hours: BTreeMap<DateTime<Utc>, Vec<(&'static str, f32)>> = BTreeMap::new();
+        collect_hours(&mut hours, "pm2_5", &pm25, &page(r#"[{"value":30.0,"period":{"datetimeTo":{"utc":"2023-11-14T23:00:00Z"}}},{"value":8.0,"period":{"datetimeTo":{"utc":"2023-11-14T22:00:00Z"}}}]"#));
+        collect_hours(&mut hours, "no2", &no2, &page(r#"[{"value":10.0,"period":{"datetimeTo":{"utc":"2023-11-14T22:00:00Z"}}},{"value":-1.0,"period":{"datetimeTo":{"utc":"2023-11-14T23:00:00Z"}}}]"#));
+        let readings: Vec<PollUpdate> = to_readings(hours, "Seattle");
+        assert_eq!(readings.len(), 2);
+        assert_eq!(readings[0].get_time(), DateTime::from_timestamp(1699999200, 0).unwrap());
+        assert_eq!(readings[0].get_location(), "Seattle");
+        assert!(readings[0].is_reference());
+        assert!((readings[0].get_field("no2").unwrap() - 18.82).abs() < 0.01);
+        assert_eq!(readings[1].get_field("no2"), None);
+        assert_eq!(readings[1].get_aqi(), 3);
+    }
+}
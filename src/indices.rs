@@ -0,0 +1,287 @@
+//! Air quality indices worked out from the pollutant readings, written as extra fields alongside OpenWeatherMaps' own 1 to 5 aqi.<br>
+//! OPENWEATHER_INDICES picks which to write, ex: "aqhi,naqi", along with the index of the OPENWEATHER_AQI_STANDARD set for the location. Each is averaged over the window its standard sets, using the readings kept in memory for the location,
+//! so OPENWEATHER_HISTORY_SIZE needs to cover the longest window. Until it does, the average is taken over whichever readings it holds.
+
+use std::fmt;
+use std::str::FromStr;
+use chrono::Duration;
+use crate::{aqi, reference, PollUpdate};
+
+/// An index that can be written alongside the aqi
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Index {
+    /// Canada's Air Quality Health Index, from 3 hour averages of O3, NO2 and PM2.5, written as aqhi
+    Aqhi,
+    /// India's National Air Quality Index from the CPCB, the worst sub-index of 24 hour averages of PM10, PM2.5, NO2, SO2 and NH3 and 8 hour averages of O3 and CO, written as naqi
+    Naqi,
+    /// China's AQI under HJ 633-2012, the worst individual index of the hourly SO2, NO2, CO and O3 and 24 hour averages of PM10 and PM2.5, written as aqi_cn
+    AqiCn,
+    /// The US EPA's AQI from 0 to 500, the worst sub-index of 24 hour PM2.5 and PM10, 8 hour O3 and CO and hourly NO2 and SO2, written as aqi_us
+    AqiUs,
+    /// The European Environment Agency's European Air Quality Index, the worst level from 1, Good, to 6, Extremely Poor, of 24 hour PM2.5 and PM10 and hourly NO2, O3 and SO2, written as aqi_eu
+    AqiEu,
+    /// The UK's Daily Air Quality Index from 1 to 10, the worst band of 24 hour PM2.5 and PM10, 8 hour O3 and hourly NO2 and SO2, written as daqi
+    Daqi,
+}
+
+/// One band of an index's breakpoint table: the lowest and highest concentration in it and the index values they map to
+pub type Band = (f32, f32, f32, f32);
+
+/// CPCB breakpoints for PM10 over 24 hours in µg/m³, Good through Severe. Severe carries on to where it would reach 500.
+pub const NAQI_PM10: [Band; 6] = [(0.0, 50.0, 0.0, 50.0), (51.0, 100.0, 51.0, 100.0), (101.0, 250.0, 101.0, 200.0), (251.0, 350.0, 201.0, 300.0), (351.0, 430.0, 301.0, 400.0), (430.0, 510.0, 401.0, 500.0)];
+/// CPCB breakpoints for PM2.5 over 24 hours in µg/m³
+pub const NAQI_PM2_5: [Band; 6] = [(0.0, 30.0, 0.0, 50.0), (31.0, 60.0, 51.0, 100.0), (61.0, 90.0, 101.0, 200.0), (91.0, 120.0, 201.0, 300.0), (121.0, 250.0, 301.0, 400.0), (250.0, 380.0, 401.0, 500.0)];
+/// CPCB breakpoints for NO2 over 24 hours in µg/m³
+pub const NAQI_NO2: [Band; 6] = [(0.0, 40.0, 0.0, 50.0), (41.0, 80.0, 51.0, 100.0), (81.0, 180.0, 101.0, 200.0), (181.0, 280.0, 201.0, 300.0), (281.0, 400.0, 301.0, 400.0), (400.0, 520.0, 401.0, 500.0)];
+/// CPCB breakpoints for O3 over 8 hours in µg/m³
+pub const NAQI_O3: [Band; 6] = [(0.0, 50.0, 0.0, 50.0), (51.0, 100.0, 51.0, 100.0), (101.0, 168.0, 101.0, 200.0), (169.0, 208.0, 201.0, 300.0), (209.0, 748.0, 301.0, 400.0), (748.0, 1000.0, 401.0, 500.0)];
+/// CPCB breakpoints for CO over 8 hours in mg/m³
+pub const NAQI_CO: [Band; 6] = [(0.0, 1.0, 0.0, 50.0), (1.1, 2.0, 51.0, 100.0), (2.1, 10.0, 101.0, 200.0), (10.1, 17.0, 201.0, 300.0), (17.1, 34.0, 301.0, 400.0), (34.0, 51.0, 401.0, 500.0)];
+/// CPCB breakpoints for SO2 over 24 hours in µg/m³
+pub const NAQI_SO2: [Band; 6] = [(0.0, 40.0, 0.0, 50.0), (41.0, 80.0, 51.0, 100.0), (81.0, 380.0, 101.0, 200.0), (381.0, 800.0, 201.0, 300.0), (801.0, 1600.0, 301.0, 400.0), (1600.0, 2000.0, 401.0, 500.0)];
+/// CPCB breakpoints for NH3 over 24 hours in µg/m³
+pub const NAQI_NH3: [Band; 6] = [(0.0, 200.0, 0.0, 50.0), (201.0, 400.0, 51.0, 100.0), (401.0, 800.0, 101.0, 200.0), (801.0, 1200.0, 201.0, 300.0), (1201.0, 1800.0, 301.0, 400.0), (1800.0, 2400.0, 401.0, 500.0)];
+/// HJ 633-2012 breakpoints for SO2 over 1 hour in µg/m³, which only goes up to 800. Above that the 24 hour average is used.
+pub const CN_SO2_1H: [Band; 4] = [(0.0, 150.0, 0.0, 50.0), (150.0, 500.0, 50.0, 100.0), (500.0, 650.0, 100.0, 150.0), (650.0, 800.0, 150.0, 200.0)];
+/// HJ 633-2012 breakpoints for SO2 over 24 hours in µg/m³
+pub const CN_SO2_24H: [Band; 7] = [(0.0, 50.0, 0.0, 50.0), (50.0, 150.0, 50.0, 100.0), (150.0, 475.0, 100.0, 150.0), (475.0, 800.0, 150.0, 200.0), (800.0, 1600.0, 200.0, 300.0), (1600.0, 2100.0, 300.0, 400.0), (2100.0, 2620.0, 400.0, 500.0)];
+/// HJ 633-2012 breakpoints for NO2 over 1 hour in µg/m³
+pub const CN_NO2_1H: [Band; 7] = [(0.0, 100.0, 0.0, 50.0), (100.0, 200.0, 50.0, 100.0), (200.0, 700.0, 100.0, 150.0), (700.0, 1200.0, 150.0, 200.0), (1200.0, 2340.0, 200.0, 300.0), (2340.0, 3090.0, 300.0, 400.0), (3090.0, 3840.0, 400.0, 500.0)];
+/// HJ 633-2012 breakpoints for CO over 1 hour in mg/m³
+pub const CN_CO_1H: [Band; 7] = [(0.0, 5.0, 0.0, 50.0), (5.0, 10.0, 50.0, 100.0), (10.0, 35.0, 100.0, 150.0), (35.0, 60.0, 150.0, 200.0), (60.0, 90.0, 200.0, 300.0), (90.0, 120.0, 300.0, 400.0), (120.0, 150.0, 400.0, 500.0)];
+/// HJ 633-2012 breakpoints for O3 over 1 hour in µg/m³
+pub const CN_O3_1H: [Band; 7] = [(0.0, 160.0, 0.0, 50.0), (160.0, 200.0, 50.0, 100.0), (200.0, 300.0, 100.0, 150.0), (300.0, 400.0, 150.0, 200.0), (400.0, 800.0, 200.0, 300.0), (800.0, 1000.0, 300.0, 400.0), (1000.0, 1200.0, 400.0, 500.0)];
+/// HJ 633-2012 breakpoints for PM10 over 24 hours in µg/m³
+pub const CN_PM10_24H: [Band; 7] = [(0.0, 50.0, 0.0, 50.0), (50.0, 150.0, 50.0, 100.0), (150.0, 250.0, 100.0, 150.0), (250.0, 350.0, 150.0, 200.0), (350.0, 420.0, 200.0, 300.0), (420.0, 500.0, 300.0, 400.0), (500.0, 600.0, 400.0, 500.0)];
+/// HJ 633-2012 breakpoints for PM2.5 over 24 hours in µg/m³
+pub const CN_PM2_5_24H: [Band; 7] = [(0.0, 35.0, 0.0, 50.0), (35.0, 75.0, 50.0, 100.0), (75.0, 115.0, 100.0, 150.0), (115.0, 150.0, 150.0, 200.0), (150.0, 250.0, 200.0, 300.0), (250.0, 350.0, 300.0, 400.0), (350.0, 500.0, 400.0, 500.0)];
+/// DEFRA bands for O3 over 8 hours in µg/m³, each mapping to its DAQI from 1 to 10
+pub const DAQI_O3: [Band; 10] = [(0.0, 33.0, 1.0, 1.0), (34.0, 66.0, 2.0, 2.0), (67.0, 100.0, 3.0, 3.0), (101.0, 120.0, 4.0, 4.0), (121.0, 140.0, 5.0, 5.0), (141.0, 160.0, 6.0, 6.0), (161.0, 187.0, 7.0, 7.0), (188.0, 213.0, 8.0, 8.0), (214.0, 240.0, 9.0, 9.0), (241.0, f32::INFINITY, 10.0, 10.0)];
+/// DEFRA bands for NO2 over 1 hour in µg/m³
+pub const DAQI_NO2: [Band; 10] = [(0.0, 67.0, 1.0, 1.0), (68.0, 134.0, 2.0, 2.0), (135.0, 200.0, 3.0, 3.0), (201.0, 267.0, 4.0, 4.0), (268.0, 334.0, 5.0, 5.0), (335.0, 400.0, 6.0, 6.0), (401.0, 467.0, 7.0, 7.0), (468.0, 534.0, 8.0, 8.0), (535.0, 600.0, 9.0, 9.0), (601.0, f32::INFINITY, 10.0, 10.0)];
+/// DEFRA bands for SO2 in µg/m³, set for 15 minutes and used here on hourly values
+pub const DAQI_SO2: [Band; 10] = [(0.0, 88.0, 1.0, 1.0), (89.0, 177.0, 2.0, 2.0), (178.0, 266.0, 3.0, 3.0), (267.0, 354.0, 4.0, 4.0), (355.0, 443.0, 5.0, 5.0), (444.0, 532.0, 6.0, 6.0), (533.0, 710.0, 7.0, 7.0), (711.0, 887.0, 8.0, 8.0), (888.0, 1064.0, 9.0, 9.0), (1065.0, f32::INFINITY, 10.0, 10.0)];
+/// DEFRA bands for PM2.5 over 24 hours in µg/m³
+pub const DAQI_PM2_5: [Band; 10] = [(0.0, 11.0, 1.0, 1.0), (12.0, 23.0, 2.0, 2.0), (24.0, 35.0, 3.0, 3.0), (36.0, 41.0, 4.0, 4.0), (42.0, 47.0, 5.0, 5.0), (48.0, 53.0, 6.0, 6.0), (54.0, 58.0, 7.0, 7.0), (59.0, 64.0, 8.0, 8.0), (65.0, 70.0, 9.0, 9.0), (71.0, f32::INFINITY, 10.0, 10.0)];
+/// DEFRA bands for PM10 over 24 hours in µg/m³
+pub const DAQI_PM10: [Band; 10] = [(0.0, 16.0, 1.0, 1.0), (17.0, 33.0, 2.0, 2.0), (34.0, 50.0, 3.0, 3.0), (51.0, 58.0, 4.0, 4.0), (59.0, 66.0, 5.0, 5.0), (67.0, 75.0, 6.0, 6.0), (76.0, 83.0, 7.0, 7.0), (84.0, 91.0, 8.0, 8.0), (92.0, 100.0, 9.0, 9.0), (101.0, f32::INFINITY, 10.0, 10.0)];
+
+impl Index {
+    /// Every index, in the order they are written
+    pub const ALL: [Index; 6] = [Index::Aqhi, Index::Naqi, Index::AqiCn, Index::AqiUs, Index::AqiEu, Index::Daqi];
+    /// Get the field the index is written under
+    pub fn field(&self) -> &'static str {
+        match self {
+            Index::Aqhi => "aqhi",
+            Index::Naqi => "naqi",
+            Index::AqiCn => "aqi_cn",
+            Index::AqiUs => "aqi_us",
+            Index::AqiEu => "aqi_eu",
+            Index::Daqi => "daqi",
+        }
+    }
+    /// Work out the index for a reading, averaging it with the readings before it for the same location<br>
+    /// Returns None if a pollutant the index needs is missing.
+    pub fn compute(&self, recent: &[PollUpdate], current: &PollUpdate) -> Option<f32> {
+        let average = |field: &str, hours: i64| average(recent, current, field, Duration::hours(hours));
+        let averages = |fields: &[(&'static str, i64)]| -> Vec<(&'static str, f32)> {
+            fields.iter().filter_map(|(field, hours)| average(field, *hours).map(|value| (*field, value))).collect()
+        };
+        match self {
+            Index::Aqhi => Some(aqhi(reference::to_ppb("no2", average("no2", 3)?)?, reference::to_ppb("o3", average("o3", 3)?)?, average("pm2_5", 3)?)),
+            Index::Naqi => naqi(&[
+                ("pm10", average("pm10", 24)), ("pm2_5", average("pm2_5", 24)), ("no2", average("no2", 24)), ("so2", average("so2", 24)),
+                ("nh3", average("nh3", 24)), ("o3", average("o3", 8)), ("co", average("co", 8)),
+            ]),
+            Index::AqiCn => china_aqi(&averages(&[("so2", 1), ("no2", 1), ("co", 1), ("o3", 1)]), &averages(&[("so2", 24), ("pm10", 24), ("pm2_5", 24)])),
+            Index::AqiUs => aqi::us_aqi(&averages(&[("o3", 1), ("so2", 1), ("no2", 1)]), &averages(&[("pm2_5", 24), ("pm10", 24), ("o3", 8), ("co", 8), ("so2", 24)])),
+            Index::AqiEu => aqi::eu_aqi(&averages(&[("no2", 1), ("o3", 1), ("so2", 1), ("pm2_5", 24), ("pm10", 24)])),
+            Index::Daqi => daqi(&averages(&[("no2", 1), ("so2", 1), ("o3", 8), ("pm2_5", 24), ("pm10", 24)])),
+        }
+    }
+}
+
+impl FromStr for Index {
+    type Err = String;
+    fn from_str(raw_index: &str) -> Result<Self, Self::Err> {
+        let trimmed: String = raw_index.trim().to_lowercase();
+        match Index::ALL.iter().find(|index| index.field() == trimmed) {
+            Some(index) => Ok(*index),
+            None => Err(format!("Unknown index \"{}\". Expected one of: {}.", raw_index, Index::ALL.map(|index| index.field()).join(", "))),
+        }
+    }
+}
+
+impl fmt::Display for Index {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.field())
+    }
+}
+
+/// Parse index names separated by commas, ex: "aqhi", leaving out repeats
+///
+/// # Errors
+/// Returns a message naming the first index that is not known
+pub fn parse_indices(raw_indices: &str) -> Result<Vec<Index>, String> {
+    let mut indices: Vec<Index> = Vec::new();
+    for name in raw_indices.split(',').filter(|name| !name.trim().is_empty()) {
+        let index: Index = name.parse()?;
+        if !indices.contains(&index) {
+            indices.push(index);
+        }
+    }
+    Ok(indices)
+}
+
+/// Average a field over the readings in the window ending at the current one, counting the current one<br>
+/// Readings for other series or outside the window are left out.
+pub fn average(recent: &[PollUpdate], current: &PollUpdate, field: &str, window: Duration) -> Option<f32> {
+    let start = current.get_time() - window;
+    let values: Vec<f32> = recent.iter()
+        .filter(|reading| reading.get_series() == current.get_series() && reading.get_time() > start && reading.get_time() < current.get_time())
+        .chain(std::iter::once(current))
+        .filter_map(|reading| reading.get_field(field))
+        .collect();
+    if values.is_empty() { None } else { Some(values.iter().sum::<f32>() / values.len() as f32) }
+}
+
+/// Canada's Air Quality Health Index from NO2 and O3 in ppb and PM2.5 in µg/m³, rounded to a whole number from 1 up<br>
+/// Above 10 is reported as "10+" by Environment Canada and is written as the value itself.
+pub fn aqhi(no2_ppb: f32, o3_ppb: f32, pm2_5: f32) -> f32 {
+    let risk: f32 = (0.000871 * no2_ppb).exp_m1() + (0.000537 * o3_ppb).exp_m1() + (0.000487 * pm2_5).exp_m1();
+    (1000.0 / 10.4 * risk).round().max(1.0)
+}
+
+/// Work out the index value for a concentration from a breakpoint table, on a straight line within the band it falls in<br>
+/// Concentrations between two bands are put in the higher, and above the last band the index is held at its top. Returns None for a negative concentration.
+pub fn sub_index(bands: &[Band], concentration: f32) -> Option<f32> {
+    if concentration < 0.0 {
+        return None;
+    }
+    let (low, high, index_low, index_high) = *bands.iter().find(|(_, high, _, _)| concentration <= *high).or(bands.last())?;
+    Some(index_low + (index_high - index_low) * (concentration.clamp(low, high) - low) / (high - low))
+}
+
+/// India's National Air Quality Index from averaged pollutants in µg/m³ by field, the worst sub-index rounded to a whole number<br>
+/// The CPCB only reports it with at least 3 pollutants, one of them PM10 or PM2.5, so returns None with fewer.
+pub fn naqi(averages: &[(&str, Option<f32>)]) -> Option<f32> {
+    let sub_indices: Vec<(&str, f32)> = averages.iter().filter_map(|(field, average)| {
+        let bands: &[Band] = match *field {
+            "pm10" => &NAQI_PM10,
+            "pm2_5" => &NAQI_PM2_5,
+            "no2" => &NAQI_NO2,
+            "o3" => &NAQI_O3,
+            "co" => return average.and_then(|average| sub_index(&NAQI_CO, average / 1000.0)).map(|value| (*field, value)),
+            "so2" => &NAQI_SO2,
+            "nh3" => &NAQI_NH3,
+            _ => return None,
+        };
+        average.and_then(|average| sub_index(bands, average)).map(|value| (*field, value))
+    }).collect();
+    if sub_indices.len() < 3 || !sub_indices.iter().any(|(field, _)| *field == "pm10" || *field == "pm2_5") {
+        return None;
+    }
+    sub_indices.iter().map(|(_, value)| value.round()).reduce(f32::max)
+}
+
+/// China's AQI under HJ 633-2012 from hourly values and 24 hour averages in µg/m³ by field, the worst individual index rounded up as the standard says<br>
+/// SO2 over 800 µg/m³ in an hour is off the hourly table, so its 24 hour average is used instead. Returns None if none of the pollutants were given.
+pub fn china_aqi(hourly: &[(&str, f32)], daily: &[(&str, f32)]) -> Option<f32> {
+    let value = |values: &[(&str, f32)], field: &str| values.iter().find(|(name, _)| *name == field).map(|(_, value)| *value);
+    let so2: Option<f32> = match value(hourly, "so2") {
+        Some(so2) if so2 <= 800.0 => sub_index(&CN_SO2_1H, so2),
+        _ => value(daily, "so2").and_then(|so2| sub_index(&CN_SO2_24H, so2)),
+    };
+    [
+        so2,
+        value(hourly, "no2").and_then(|no2| sub_index(&CN_NO2_1H, no2)),
+        value(hourly, "co").and_then(|co| sub_index(&CN_CO_1H, co / 1000.0)),
+        value(hourly, "o3").and_then(|o3| sub_index(&CN_O3_1H, o3)),
+        value(daily, "pm10").and_then(|pm10| sub_index(&CN_PM10_24H, pm10)),
+        value(daily, "pm2_5").and_then(|pm2_5| sub_index(&CN_PM2_5_24H, pm2_5)),
+    ].into_iter().flatten().map(f32::ceil).reduce(f32::max)
+}
+
+/// The UK's Daily Air Quality Index from each pollutant's average over its window in µg/m³ by field, the worst band from 1 to 10<br>
+/// Returns None if none of the pollutants were given.
+pub fn daqi(averaged: &[(&str, f32)]) -> Option<f32> {
+    worst_band(averaged, &[("pm2_5", &DAQI_PM2_5), ("pm10", &DAQI_PM10), ("no2", &DAQI_NO2), ("o3", &DAQI_O3), ("so2", &DAQI_SO2)])
+}
+
+/// Find the worst band any of the pollutants falls in, for indices whose bands are levels rather than ranges
+pub fn worst_band(averaged: &[(&str, f32)], tables: &[(&str, &[Band])]) -> Option<f32> {
+    tables.iter()
+        .filter_map(|(field, bands)| averaged.iter().find(|(name, _)| name == field).and_then(|(_, value)| sub_index(bands, *value)))
+        .reduce(f32::max)
+}
+
+/// Work out each index for an update and add it to the update's fields, leaving out any it does not have the pollutants for
+pub fn apply(indices: &[Index], recent: &[PollUpdate], update: &mut PollUpdate) {
+    for index in indices {
+        if let Some(value) = index.compute(recent, update) {
+            update.set_index(index.field(), value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{DateTime, Utc};
+
+    fn test_update(hour: i64, no2: f32, o3: f32, pm2_5: f32) -> PollUpdate {
+        let time: DateTime<Utc> = DateTime::from_timestamp(1700000000 + hour * 3600, 0).unwrap();
+        PollUpdate { location: "Toronto".to_string(), ..PollUpdate::from_measured(time, &[("no2", no2), ("o3", o3), ("pm2_5", pm2_5)]) }
+    }
+
+    #[test]
+    fn indices_parsed() {
+        assert_eq!(parse_indices(" AQHI, aqhi,NAQI,aqi_cn"), Ok(vec![Index::Aqhi, Index::Naqi, Index::AqiCn]));
+        assert!(parse_indices("aqhi,caqi").unwrap_err().starts_with("Unknown index \"caqi\". Expected one of: aqhi, naqi, aqi_cn, aqi_us, aqi_eu, daqi"));
+        assert_eq!(parse_indices(""), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn aqhi_uses_three_hour_averages() {
+        assert_eq!(aqhi(20.0, 30.0, 10.0), 4.0);
+        assert_eq!(aqhi(0.0, 0.0, 0.0), 1.0);
+        let recent: Vec<PollUpdate> = vec![test_update(-5, 500.0, 500.0, 500.0), test_update(-2, 20.0, 40.0, 4.0), test_update(-1, 40.0, 80.0, 8.0)];
+        let mut current: PollUpdate = test_update(0, 60.0, 120.0, 12.0);
+        assert_eq!(average(&recent, &current, "pm2_5", Duration::hours(3)), Some(8.0));
+        apply(&[Index::Aqhi], &recent, &mut current);
+        assert_eq!(current.get_index("aqhi"), Some(aqhi(reference::to_ppb("no2", 40.0).unwrap(), reference::to_ppb("o3", 80.0).unwrap(), 8.0)));
+        assert_eq!(current.get_field("aqhi"), current.get_index("aqhi"));
+    }
+
+    #[test]
+    fn naqi_takes_worst_sub_index() {
+        assert_eq!(sub_index(&NAQI_PM2_5, 60.0), Some(100.0));
+        assert_eq!(sub_index(&NAQI_PM2_5, 30.5), Some(51.0));
+        assert_eq!(sub_index(&NAQI_PM10, 900.0), Some(500.0));
+        assert_eq!(sub_index(&NAQI_PM10, -1.0), None);
+        assert_eq!(naqi(&[("pm10", Some(120.0)), ("pm2_5", Some(45.0)), ("no2", Some(20.0)), ("co", Some(1500.0))]), Some(114.0));
+        assert_eq!(naqi(&[("no2", Some(20.0)), ("o3", Some(20.0)), ("so2", Some(20.0))]), None);
+        assert_eq!(naqi(&[("pm10", Some(120.0)), ("pm2_5", None), ("no2", Some(20.0))]), None);
+        let mut current: PollUpdate = test_update(0, 20.0, 30.0, 45.0);
+        apply(&[Index::Naqi], &[test_update(-30, 0.0, 0.0, 500.0)], &mut current);
+        assert_eq!(current.get_index("naqi"), Some(75.0));
+    }
+
+    #[test]
+    fn china_aqi_rounds_worst_index_up() {
+        assert_eq!(china_aqi(&[("no2", 150.0), ("co", 2000.0)], &[("pm2_5", 55.0)]), Some(75.0));
+        assert_eq!(china_aqi(&[("so2", 900.0)], &[("so2", 100.0)]), Some(75.0));
+        assert_eq!(china_aqi(&[("o3", 161.0)], &[]), Some(52.0));
+        assert_eq!(china_aqi(&[], &[]), None);
+        let mut current: PollUpdate = test_update(0, 20.0, 30.0, 80.0);
+        apply(&[Index::AqiCn], &[test_update(-12, 0.0, 0.0, 30.0)], &mut current);
+        assert_eq!(current.get_index("aqi_cn"), Some(75.0));
+    }
+
+    #[test]
+    fn banded_indices_take_worst_level() {
+        assert_eq!(daqi(&[("pm2_5", 11.5), ("pm10", 16.0)]), Some(2.0));
+        assert_eq!(daqi(&[("no2", 5000.0)]), Some(10.0));
+        assert_eq!(daqi(&[]), None);
+    }
+}
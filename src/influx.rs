@@ -0,0 +1,1187 @@
+//! InfluxDB specific helpers for things the influxdb crate does not expose on its own Client.
+
+use serde::{Deserialize, Serialize};
+use std::{fmt, io::Write, str::FromStr, sync::Arc, time::Duration};
+use flate2::{write::GzEncoder, Compression};
+use chrono::{DateTime, SecondsFormat, Utc};
+use influxdb::{Client, Error, Query, ReadQuery, Timestamp, WriteQuery};
+use crate::plugin::{Headers, INFLUXDB_SINK};
+use crate::{metrics, report, schema, tls, Config, PollUpdate, POLLUTION_MEASUREMENT};
+
+/// Measurement the daily means are written to by the downsampling continuous query or task
+pub const DAILY_MEASUREMENT: &str = "pollution_daily";
+/// Name of the continuous query or task created for downsampling
+pub const DOWNSAMPLE_NAME: &str = "pollution_daily_mean";
+/// InfluxQL condition leaving out hours of the forecast written by OPENWEATHER_WRITE_FORECAST, so only readings are read back or averaged
+const NOT_FORECAST: &str = "\"forecast\" != 'true'";
+
+/// Sends every point of a measurement to a specific retention policy instead of the database default.<br>
+/// On InfluxDB v2 the v1 compatibility API maps the database/retention policy pair to a bucket through its DBRP mapping.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct MeasurementRoute {
+    pub measurement: String,
+    pub retention_policy: String,
+}
+
+/// A retention policy that should exist on the configured database (InfluxDB v1 only)
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct RetentionPolicy {
+    pub name: String,
+    pub duration: String,
+    #[serde(default)]
+    pub default: bool,
+}
+
+/// Result of counting a written point back
+#[derive(Deserialize)]
+struct PointCount {
+    count: u64,
+}
+
+/// Counts of stored points and of those stamped with a schema version, with the newest version, read by stored_schema_version
+#[derive(Deserialize)]
+struct StoredSchema {
+    points: u64,
+    version: Option<i64>,
+}
+
+/// The mean of every field for a location over a day, read back by read_daily_averages
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct DailyAverage {
+    /// Start of the day in UTC
+    pub time: DateTime<Utc>,
+    /// Location the readings were for
+    #[serde(default)]
+    pub location: String,
+    pub aqi: f64,
+    pub co: f64,
+    pub no: f64,
+    pub no2: f64,
+    pub o3: f64,
+    pub so2: f64,
+    pub pm2_5: f64,
+    pub pm10: f64,
+    pub nh3: f64,
+}
+
+/// The only column needed when reading back SHOW DATABASES
+#[derive(Deserialize)]
+struct ExistingDatabase {
+    name: String,
+}
+
+/// The only column needed when reading back SHOW RETENTION POLICIES
+#[derive(Deserialize)]
+struct ExistingPolicy {
+    name: String,
+}
+
+/// The only column needed when reading back SHOW CONTINUOUS QUERIES
+#[derive(Deserialize)]
+struct ExistingContinuousQuery {
+    name: String,
+}
+
+/// Timestamp precision used when writing points
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Precision {
+    Seconds,
+    Milliseconds,
+    Microseconds,
+    Nanoseconds,
+}
+
+impl Precision {
+    /// Drop anything finer than this precision from a time, matching what InfluxDB will store
+    pub fn truncate(&self, time: DateTime<Utc>) -> DateTime<Utc> {
+        let nanos: i64 = match self {
+            Precision::Seconds => 0,
+            Precision::Milliseconds => (time.timestamp_subsec_nanos() / 1_000_000 * 1_000_000) as i64,
+            Precision::Microseconds => (time.timestamp_subsec_nanos() / 1_000 * 1_000) as i64,
+            Precision::Nanoseconds => time.timestamp_subsec_nanos() as i64,
+        };
+        DateTime::from_timestamp(time.timestamp(), nanos as u32).unwrap_or(time)
+    }
+    /// Convert a time into a Timestamp at this precision, dropping anything finer
+    pub fn timestamp(&self, time: DateTime<Utc>) -> Timestamp {
+        match self {
+            Precision::Seconds => Timestamp::Seconds(time.timestamp() as u128),
+            Precision::Milliseconds => Timestamp::Milliseconds(time.timestamp_millis() as u128),
+            Precision::Microseconds => Timestamp::Microseconds(time.timestamp_micros() as u128),
+            Precision::Nanoseconds => Timestamp::Nanoseconds(time.timestamp_nanos_opt().unwrap_or_default() as u128),
+        }
+    }
+}
+
+impl FromStr for Precision {
+    type Err = String;
+    fn from_str(raw_precision: &str) -> Result<Self, Self::Err> {
+        match raw_precision.trim().to_lowercase().as_str() {
+            "s" | "seconds" => Ok(Precision::Seconds),
+            "ms" | "milliseconds" => Ok(Precision::Milliseconds),
+            "us" | "u" | "microseconds" => Ok(Precision::Microseconds),
+            "ns" | "nanoseconds" => Ok(Precision::Nanoseconds),
+            _ => Err(format!("Unknown write precision \"{}\". Expected one of s, ms, us or ns.", raw_precision)),
+        }
+    }
+}
+
+impl fmt::Display for Precision {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let short: &str = match self {
+            Precision::Seconds => "s",
+            Precision::Milliseconds => "ms",
+            Precision::Microseconds => "us",
+            Precision::Nanoseconds => "ns",
+        };
+        write!(f, "{}", short)
+    }
+}
+
+/// InfluxDB v2 organization as returned by /api/v2/orgs
+#[derive(Deserialize)]
+struct Organization {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct OrganizationList {
+    #[serde(default)]
+    orgs: Vec<Organization>,
+}
+
+/// InfluxDB v2 bucket as returned by /api/v2/buckets
+#[derive(Deserialize)]
+struct Bucket {
+    #[serde(default)]
+    id: String,
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct BucketList {
+    #[serde(default)]
+    buckets: Vec<Bucket>,
+}
+
+/// InfluxDB v2 DBRP mapping as returned by /api/v2/dbrps, pointing a v1 database and retention policy at a bucket
+#[derive(Deserialize)]
+struct Dbrp {
+    retention_policy: String,
+}
+
+/// Response body of /api/v2/dbrps
+#[derive(Deserialize)]
+struct DbrpList {
+    #[serde(default)]
+    content: Vec<Dbrp>,
+}
+
+/// Body used to create a DBRP mapping through the InfluxDB v2 API
+#[derive(Serialize)]
+struct NewDbrp {
+    #[serde(rename = "orgID")]
+    org_id: String,
+    #[serde(rename = "bucketID")]
+    bucket_id: String,
+    database: String,
+    retention_policy: String,
+    default: bool,
+}
+
+/// InfluxDB v2 task as returned by /api/v2/tasks
+#[derive(Deserialize)]
+struct Task {
+    name: String,
+}
+
+/// Response body of /api/v2/tasks
+#[derive(Deserialize)]
+struct TaskList {
+    #[serde(default)]
+    tasks: Vec<Task>,
+}
+
+/// Body used to create a task through the InfluxDB v2 API
+#[derive(Serialize)]
+struct NewTask {
+    #[serde(rename = "orgID")]
+    org_id: String,
+    flux: String,
+    status: String,
+}
+
+/// Retention rule in the format the InfluxDB v2 API expects
+#[derive(Serialize)]
+struct RetentionRule {
+    #[serde(rename = "type")]
+    rule_type: String,
+    #[serde(rename = "everySeconds")]
+    every_seconds: u64,
+}
+
+/// Body used to create a bucket through the InfluxDB v2 API
+#[derive(Serialize)]
+struct NewBucket {
+    #[serde(rename = "orgID")]
+    org_id: String,
+    name: String,
+    #[serde(rename = "retentionRules")]
+    retention_rules: Vec<RetentionRule>,
+}
+
+/// Parse routes from the environmental variable format: "measurement=policy,measurement=policy"<br>
+/// Entries missing either side of the "=" are skipped with a message
+pub fn parse_routes(raw_routes: &str) -> Vec<MeasurementRoute> {
+    let mut routes: Vec<MeasurementRoute> = Vec::new();
+    for entry in raw_routes.split(',') {
+        let entry: &str = entry.trim();
+        if entry.is_empty() {
+            continue;
+        }
+        match entry.split_once('=') {
+            Some((measurement, policy)) if !measurement.trim().is_empty() && !policy.trim().is_empty() => {
+                routes.push(MeasurementRoute { measurement: measurement.trim().to_string(), retention_policy: policy.trim().to_string() });
+            },
+            _ => report::warn(&format!("Ignoring malformed InfluxDB route \"{}\". Expected \"measurement=policy\".", entry)),
+        };
+    }
+    routes
+}
+
+/// Parse retention policies from the environmental variable format: "name:duration:default,name:duration"<br>
+/// Entries that are missing a name or have an invalid duration are skipped with a message
+pub fn parse_retention_policies(raw_policies: &str) -> Vec<RetentionPolicy> {
+    let mut policies: Vec<RetentionPolicy> = Vec::new();
+    for entry in raw_policies.split(',') {
+        let entry: &str = entry.trim();
+        if entry.is_empty() {
+            continue;
+        }
+        let parts: Vec<&str> = entry.split(':').map(|part| part.trim()).collect();
+        if parts.len() < 2 || parts.len() > 3 || parts[0].is_empty() || !is_valid_duration(parts[1]) {
+            report::warn(&format!("Ignoring malformed retention policy \"{}\". Expected \"name:duration\" or \"name:duration:default\".", entry));
+            continue;
+        }
+        let is_default: bool = parts.len() == 3 && parts[2].eq_ignore_ascii_case("default");
+        policies.push(RetentionPolicy { name: parts[0].to_string(), duration: parts[1].to_string(), default: is_default });
+    }
+    policies
+}
+
+/// Check that a duration is something InfluxQL will accept, ex: "90d", "1h30m" or "INF"
+pub fn is_valid_duration(duration: &str) -> bool {
+    duration.eq_ignore_ascii_case("inf") || duration_to_nanos(duration).is_some()
+}
+
+/// Check that a duration can be a bucket retention, "INF" or a whole number of seconds InfluxDB v2 can store, ex: "90d"<br>
+/// A retention under a second would be sent as no retention rules, which InfluxDB v2 takes as keep forever.
+pub fn is_valid_bucket_retention(retention: &str) -> bool {
+    retention.eq_ignore_ascii_case("inf") || duration_to_nanos(retention).is_some_and(|nanos| nanos >= 1_000_000_000 && u64::try_from(nanos / 1_000_000_000).is_ok())
+}
+
+/// Convert an InfluxQL duration literal into nanoseconds. Returns None for "INF", anything malformed or anything too long to count in nanoseconds.
+pub fn duration_to_nanos(duration: &str) -> Option<u128> {
+    let mut remaining: &str = duration;
+    if remaining.is_empty() {
+        return None;
+    }
+    let mut total: u128 = 0;
+    while !remaining.is_empty() {
+        let digits_end: usize = remaining.find(|c: char| !c.is_ascii_digit()).unwrap_or(remaining.len());
+        if digits_end == 0 || digits_end == remaining.len() {
+            return None;
+        }
+        let amount: u128 = remaining[..digits_end].parse().ok()?;
+        remaining = &remaining[digits_end..];
+        let unit_end: usize = remaining.find(|c: char| c.is_ascii_digit()).unwrap_or(remaining.len());
+        let multiplier: u128 = match &remaining[..unit_end] {
+            "ns" => 1,
+            "u" | "µ" => 1_000,
+            "ms" => 1_000_000,
+            "s" => 1_000_000_000,
+            "m" => 60_000_000_000,
+            "h" => 3_600_000_000_000,
+            "d" => 86_400_000_000_000,
+            "w" => 604_800_000_000_000,
+            _ => return None,
+        };
+        total = total.checked_add(amount.checked_mul(multiplier)?)?;
+        remaining = &remaining[unit_end..];
+    }
+    Some(total)
+}
+
+/// Wrap a value in single quotes so it can be compared against in InfluxQL
+pub(crate) fn quote_literal(literal: &str) -> String {
+    format!("'{}'", literal.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+/// Wrap an identifier in double quotes so names with spaces or keywords are safe in InfluxQL
+pub(crate) fn quote_identifier(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Generate the InfluxQL to create the given retention policy on a database
+pub fn create_retention_policy_query(database: &str, policy: &RetentionPolicy) -> String {
+    let mut query: String = format!("CREATE RETENTION POLICY {} ON {} DURATION {} REPLICATION 1", quote_identifier(&policy.name), quote_identifier(database), policy.duration);
+    if policy.default {
+        query.push_str(" DEFAULT");
+    }
+    query
+}
+
+/// Look up the ID of an InfluxDB v2 organization by name
+fn find_org_id(agent: &ureq::Agent, server: &str, authorization: &str, org: &str) -> Result<String, Error> {
+    let orgs: OrganizationList = agent.get(&format!("{}/api/v2/orgs", server))
+        .query("org", org)
+        .set("Authorization", authorization)
+        .call().map_err(convert_http_error)?
+        .into_json().map_err(|e| Error::DeserializationError { error: e.to_string() })?;
+    match orgs.orgs.into_iter().next() {
+        Some(found) => Ok(found.id),
+        None => Err(Error::DatabaseError { error: format!("Organization {} was not found", org) }),
+    }
+}
+
+/// Build the InfluxQL continuous query that writes the daily mean of every field for each location into DAILY_MEASUREMENT (InfluxDB v1)<br>
+/// Both measurements follow their routes, so a route for pollution_daily keeps the means in a longer retention policy than the raw readings.
+pub fn downsample_query(current_config: &Config) -> String {
+    let database: String = quote_identifier(&current_config.get_dbname());
+    let means: Vec<String> = schema::FIELDS.iter()
+        .map(|field| format!("mean({}) AS {}", stored_field(current_config, field), stored_field(current_config, field)))
+        .collect();
+    let target: String = match current_config.get_route(DAILY_MEASUREMENT) {
+        Some(route) => format!("{}.{}.{}", database, quote_identifier(&route.retention_policy), quote_identifier(DAILY_MEASUREMENT)),
+        None => format!("{}..{}", database, quote_identifier(DAILY_MEASUREMENT)),
+    };
+    let source: String = match current_config.get_route(POLLUTION_MEASUREMENT) {
+        Some(_) => pollution_measurement_path(current_config),
+        None => format!("{}..{}", database, quote_identifier(POLLUTION_MEASUREMENT)),
+    };
+    format!("CREATE CONTINUOUS QUERY {} ON {} BEGIN SELECT {} INTO {} FROM {} WHERE {} GROUP BY time(1d), \"location\" END",
+        quote_identifier(DOWNSAMPLE_NAME), database, means.join(", "), target, source, NOT_FORECAST)
+}
+
+/// Build the Flux task that writes the daily mean of every field for each location into DAILY_MEASUREMENT in a bucket (InfluxDB v2)
+pub fn downsample_flux(source_bucket: &str, target_bucket: &str, org: &str) -> String {
+    format!("option task = {{name: \"{}\", every: 1d, offset: 5m}}\n\nfrom(bucket: \"{}\")\n    |> range(start: -task.every)\n    |> filter(fn: (r) => r._measurement == \"{}\" and (not exists r.forecast or r.forecast != \"true\"))\n    |> aggregateWindow(every: 1d, fn: mean, createEmpty: false)\n    |> set(key: \"_measurement\", value: \"{}\")\n    |> to(bucket: \"{}\", org: \"{}\")\n",
+        DOWNSAMPLE_NAME, flux_string(source_bucket), POLLUTION_MEASUREMENT, DAILY_MEASUREMENT, flux_string(target_bucket), flux_string(org))
+}
+
+/// Escape a value for use inside a Flux string literal
+fn flux_string(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Create the continuous query (InfluxDB v1) or task (InfluxDB v2) that downsamples raw readings into daily means, if it does not already exist<br>
+/// An existing one is left untouched, so changes to it on the server are kept.
+///
+/// # Errors
+/// This function passes any errors generated by the underlying influxdb crate or the InfluxDB v2 API, and on InfluxDB v2 returns a DatabaseError if OPENWEATHER_INFLUXDB_ORG is not set
+pub async fn ensure_downsampling(dbclient: &Client, current_config: &Config) -> Result<(), Error> {
+    if is_v2(current_config) {
+        return ensure_downsample_task(current_config);
+    }
+    let database: String = current_config.get_dbname();
+    let mut existing_result = dbclient.json_query(ReadQuery::new("SHOW CONTINUOUS QUERIES")).await?;
+    let exists: bool = existing_result.deserialize_next::<ExistingContinuousQuery>()?
+        .series.into_iter()
+        .filter(|series| series.name == database)
+        .flat_map(|series| series.values)
+        .any(|query| query.name == DOWNSAMPLE_NAME);
+    if exists {
+        report::info(&format!("Continuous query {} already exists on {}.", DOWNSAMPLE_NAME, database));
+        return Ok(());
+    }
+    dbclient.query(ReadQuery::new(downsample_query(current_config))).await?;
+    report::info(&format!("Created continuous query {} on {} writing daily means to {}.", DOWNSAMPLE_NAME, database, DAILY_MEASUREMENT));
+    Ok(())
+}
+
+/// The InfluxDB v2 half of ensure_downsampling, creating the task through the HTTP API
+fn ensure_downsample_task(current_config: &Config) -> Result<(), Error> {
+    let token: &str = current_config.token.as_deref().unwrap_or_default();
+    let org: &str = match current_config.get_org() {
+        Some(org) => org,
+        None => return Err(Error::DatabaseError { error: "OPENWEATHER_INFLUXDB_DOWNSAMPLE on InfluxDB v2 requires OPENWEATHER_INFLUXDB_ORG to be set.".to_string() }),
+    };
+    let server: String = current_config.get_dbserver();
+    let authorization: String = format!("Token {}", token);
+    let agent: ureq::Agent = http_agent(current_config);
+    let org_id: String = find_org_id(&agent, &server, &authorization, org)?;
+
+    let tasks: TaskList = agent.get(&format!("{}/api/v2/tasks", server))
+        .query("orgID", &org_id)
+        .query("name", DOWNSAMPLE_NAME)
+        .set("Authorization", &authorization)
+        .call().map_err(convert_http_error)?
+        .into_json().map_err(|e| Error::DeserializationError { error: e.to_string() })?;
+    if tasks.tasks.iter().any(|existing| existing.name == DOWNSAMPLE_NAME) {
+        report::info(&format!("Task {} already exists in {}.", DOWNSAMPLE_NAME, org));
+        return Ok(());
+    }
+
+    let source: String = current_config.get_bucket();
+    let target: String = current_config.get_downsample_bucket().map(str::to_string).unwrap_or_else(|| source.clone());
+    let new_task: NewTask = NewTask { org_id, flux: downsample_flux(&source, &target, org), status: "active".to_string() };
+    agent.post(&format!("{}/api/v2/tasks", server))
+        .set("Authorization", &authorization)
+        .send_json(new_task).map_err(convert_http_error)?;
+    report::info(&format!("Created task {} in {} writing daily means to {} in bucket {}.", DOWNSAMPLE_NAME, org, DAILY_MEASUREMENT, target));
+    Ok(())
+}
+
+/// Build the quoted name of the pollution measurement, fully qualified with the database and retention policy when it is routed
+fn pollution_measurement_path(current_config: &Config) -> String {
+    match current_config.get_route(POLLUTION_MEASUREMENT) {
+        Some(route) => format!("{}.{}.{}", quote_identifier(&current_config.get_dbname()), quote_identifier(&route.retention_policy), quote_identifier(POLLUTION_MEASUREMENT)),
+        None => quote_identifier(POLLUTION_MEASUREMENT),
+    }
+}
+
+/// Build the InfluxQL that counts the point written for an update at a location
+pub fn verify_write_query(current_config: &Config, update: &PollUpdate, location: &str) -> String {
+    let measurement: String = pollution_measurement_path(current_config);
+    let stored_time: DateTime<Utc> = current_config.get_precision().truncate(update.time);
+    format!("SELECT count({}) FROM {} WHERE \"location\" = {} AND time = '{}'", stored_field(current_config, "aqi"), measurement, quote_literal(location), stored_time.to_rfc3339_opts(SecondsFormat::Nanos, true))
+}
+
+/// Query a written update back from the database to confirm it was stored<br>
+/// Returns false when the point is missing, which usually means a field type conflict or a retention policy already dropping the data
+///
+/// # Errors
+/// This function passes any errors generated by the underlying influxdb crate
+pub async fn verify_write(dbclient: &Client, current_config: &Config, update: &PollUpdate, location: &str) -> Result<bool, Error> {
+    let mut result = dbclient.json_query(ReadQuery::new(verify_write_query(current_config, update, location))).await?;
+    let found: bool = result.deserialize_next::<PointCount>()?
+        .series.into_iter()
+        .flat_map(|series| series.values)
+        .any(|point| point.count > 0);
+    Ok(found)
+}
+
+/// Quote the name a field is stored under, which OPENWEATHER_INFLUXDB_FIELD_NAMES may have changed
+fn stored_field(current_config: &Config, field: &str) -> String {
+    quote_identifier(schema::field_name(current_config.get_field_names(), field))
+}
+
+/// Build the InfluxQL that reads back every update for a location from a point in time onwards, oldest first<br>
+/// Renamed fields are read back under their usual names.
+pub fn history_query(current_config: &Config, location: &str, since: DateTime<Utc>) -> String {
+    let fields: Vec<String> = schema::FIELDS.iter()
+        .map(|field| match stored_field(current_config, field) {
+            stored if stored == quote_identifier(field) => stored,
+            stored => format!("{} AS {}", stored, quote_identifier(field)),
+        })
+        .collect();
+    format!("SELECT {}, \"location\" FROM {} WHERE \"location\" = {} AND {} AND time >= '{}' ORDER BY time ASC",
+        fields.join(", "), pollution_measurement_path(current_config), quote_literal(location), NOT_FORECAST, since.to_rfc3339_opts(SecondsFormat::Nanos, true))
+}
+
+/// Build the InfluxQL that counts the stored points and finds the newest schema version stamped on them
+pub fn schema_version_query(current_config: &Config) -> String {
+    format!("SELECT count({}) AS \"points\", last({}) AS \"version\" FROM {}", stored_field(current_config, "aqi"), quote_identifier(schema::SCHEMA_VERSION_FIELD), pollution_measurement_path(current_config))
+}
+
+/// Find the newest schema version of the stored points. Points written before versions were stamped are version 1.<br>
+/// Returns None when nothing has been stored yet
+///
+/// # Errors
+/// This function passes any errors generated by the underlying influxdb crate
+pub async fn stored_schema_version(dbclient: &Client, current_config: &Config) -> Result<Option<i64>, Error> {
+    let stored: Option<StoredSchema> = read_rows(dbclient, schema_version_query(current_config)).await?.into_iter().next();
+    Ok(match stored {
+        Some(StoredSchema { version: Some(version), .. }) => Some(version),
+        Some(StoredSchema { points, version: None }) if points > 0 => Some(1),
+        _ => None,
+    })
+}
+
+/// Run an InfluxQL query and deserialize every row of its first result into a typed struct, across all series
+///
+/// # Errors
+/// This function passes any errors generated by the underlying influxdb crate, including rows that do not match the struct
+pub async fn read_rows<T: serde::de::DeserializeOwned + Send + 'static>(dbclient: &Client, query: String) -> Result<Vec<T>, Error> {
+    let mut result = dbclient.json_query(ReadQuery::new(query)).await?;
+    let rows: Vec<T> = result.deserialize_next::<T>()?
+        .series.into_iter()
+        .flat_map(|series| series.values)
+        .collect();
+    Ok(rows)
+}
+
+/// Read the stored updates for a location from a point in time onwards, oldest first
+///
+/// # Errors
+/// This function passes any errors generated by the underlying influxdb crate
+pub async fn read_history(dbclient: &Client, current_config: &Config, location: &str, since: DateTime<Utc>) -> Result<Vec<PollUpdate>, Error> {
+    read_rows(dbclient, history_query(current_config, location, since)).await
+}
+
+/// Build the InfluxQL that reads back every update for a location between two times, oldest first. Without an end it reads up to now.
+pub fn range_query(current_config: &Config, location: &str, from: DateTime<Utc>, to: Option<DateTime<Utc>>) -> String {
+    let query: String = history_query(current_config, location, from);
+    match to {
+        Some(to) => query.replace(" ORDER BY time ASC", &format!(" AND time <= '{}' ORDER BY time ASC", to.to_rfc3339_opts(SecondsFormat::Nanos, true))),
+        None => query,
+    }
+}
+
+/// Read the stored updates for a location between two times, oldest first
+///
+/// # Errors
+/// This function passes any errors generated by the underlying influxdb crate
+pub async fn read_range(dbclient: &Client, current_config: &Config, location: &str, from: DateTime<Utc>, to: Option<DateTime<Utc>>) -> Result<Vec<PollUpdate>, Error> {
+    read_rows(dbclient, range_query(current_config, location, from, to)).await
+}
+
+/// Build the InfluxQL that averages every field for a location over each UTC day between two times, oldest first. Days without readings are left out.
+pub fn daily_averages_query(current_config: &Config, location: &str, from: DateTime<Utc>, to: Option<DateTime<Utc>>) -> String {
+    let means: Vec<String> = schema::FIELDS.iter()
+        .map(|field| format!("mean({}) AS {}", stored_field(current_config, field), quote_identifier(field)))
+        .collect();
+    let until: String = match to {
+        Some(to) => format!(" AND time <= '{}'", to.to_rfc3339_opts(SecondsFormat::Nanos, true)),
+        None => String::new(),
+    };
+    format!("SELECT {} FROM {} WHERE \"location\" = {} AND {} AND time >= '{}'{} GROUP BY time(1d) fill(none) ORDER BY time ASC",
+        means.join(", "), pollution_measurement_path(current_config), quote_literal(location), NOT_FORECAST, from.to_rfc3339_opts(SecondsFormat::Nanos, true), until)
+}
+
+/// Read the mean of every field for a location over each UTC day between two times, oldest first. Without an end it reads up to now.
+///
+/// # Errors
+/// This function passes any errors generated by the underlying influxdb crate
+pub async fn read_daily_averages(dbclient: &Client, current_config: &Config, location: &str, from: DateTime<Utc>, to: Option<DateTime<Utc>>) -> Result<Vec<DailyAverage>, Error> {
+    let mut averages: Vec<DailyAverage> = read_rows(dbclient, daily_averages_query(current_config, location, from, to)).await?;
+    averages.iter_mut().for_each(|average| average.location = location.to_string());
+    Ok(averages)
+}
+
+/// Run verify_write and report the outcome through the log and the pollution_write_verifications_total metric
+pub async fn report_write_verification(dbclient: &Client, current_config: &Config, update: &PollUpdate, location: &str) {
+    match verify_write(dbclient, current_config, update, location).await {
+        Ok(true) => metrics::increment("pollution_write_verifications_total", &[("result", "found")]),
+        Ok(false) => {
+            report::warn(&format!("Point for {} at {} was written but could not be read back. Check for field type conflicts or retention policies dropping the data.", location, update.time));
+            metrics::increment("pollution_write_verifications_total", &[("result", "missing")]);
+        },
+        Err(e) => {
+            report::warn(&format!("Unable to verify the write for {}: {}", location, e));
+            metrics::increment("pollution_write_verifications_total", &[("result", "error")]);
+        },
+    };
+}
+
+/// Build the HTTP client for the influxdb crate presenting the configured client certificate, trusting the configured CA bundle and sending the influxdb sink's headers<br>
+/// Returns None when none of them is set, so the crate's own client is used
+///
+/// # Errors
+/// Returns a message if the client certificate or CA bundle cannot be loaded or a header cannot be sent
+pub fn http_client(current_config: &Config) -> Result<Option<reqwest::Client>, String> {
+    let identity: Option<tls::ClientIdentity> = tls::identity(current_config)?;
+    let authorities: Vec<rustls::Certificate> = tls::ca_certificates(current_config)?;
+    let headers: &[(String, String)] = current_config.get_sink_headers(INFLUXDB_SINK);
+    if identity.is_none() && authorities.is_empty() && headers.is_empty() {
+        return Ok(None);
+    }
+    let mut builder: reqwest::ClientBuilder = reqwest::Client::builder().use_rustls_tls();
+    if let Some(identity) = &identity {
+        builder = builder.identity(tls::reqwest_identity(identity)?);
+    }
+    for authority in &authorities {
+        builder = builder.add_root_certificate(reqwest::Certificate::from_der(&authority.0).map_err(|e| format!("Unable to trust a certificate in the CA bundle: {}", e))?);
+    }
+    let mut header_map: reqwest::header::HeaderMap = reqwest::header::HeaderMap::new();
+    for (name, value) in headers {
+        let header_name = reqwest::header::HeaderName::from_bytes(name.as_bytes()).map_err(|e| format!("Invalid header name \"{}\": {}", name, e))?;
+        let header_value = reqwest::header::HeaderValue::from_str(value).map_err(|e| format!("Invalid value for header {}: {}", name, e))?;
+        header_map.append(header_name, header_value);
+    }
+    builder.default_headers(header_map).build()
+        .map(Some)
+        .map_err(|e| format!("Unable to build the InfluxDB client: {}", e))
+}
+
+/// Build the HTTP agent for requests to InfluxDB made outside the influxdb crate, ex: the v2 API, presenting the configured client certificate, trusting the configured CA bundle and sending the influxdb sink's headers<br>
+/// The certificate and bundle are checked when the InfluxDB client is built at startup, so a failure here is printed and the agent connects without them.
+pub fn http_agent(current_config: &Config) -> ureq::Agent {
+    let builder: ureq::AgentBuilder = match tls::rustls_config(current_config) {
+        Ok(Some(tls_config)) => ureq::AgentBuilder::new().tls_config(Arc::new(tls_config)),
+        Ok(None) => ureq::AgentBuilder::new(),
+        Err(e) => {
+            report::warn(&format!("{} Connecting without the client certificate and CA bundle.", e));
+            ureq::AgentBuilder::new()
+        },
+    };
+    let headers: Headers = current_config.get_sink_headers(INFLUXDB_SINK).to_vec();
+    if headers.is_empty() {
+        return builder.build();
+    }
+    builder.middleware(HeaderMiddleware(headers)).build()
+}
+
+/// Adds a sink's headers to every request an agent sends
+struct HeaderMiddleware(Headers);
+
+impl ureq::Middleware for HeaderMiddleware {
+    fn handle(&self, request: ureq::Request, next: ureq::MiddlewareNext) -> Result<ureq::Response, ureq::Error> {
+        next.handle(self.0.iter().fold(request, |request, (name, value)| request.set(name, value)))
+    }
+}
+
+/// Check if the Config points at InfluxDB v2: a token without a v1 username
+pub(crate) fn is_v2(current_config: &Config) -> bool {
+    current_config.token.is_some() && current_config.dbuser.is_none()
+}
+
+/// Create the configured database if it is not already on the server<br>
+/// Without this, writes against a missing database fail on every poll. InfluxDB v2 uses buckets instead, so token only configurations are skipped.
+///
+/// # Errors
+/// This function passes any errors generated by the underlying influxdb crate
+pub async fn ensure_database(dbclient: &Client, current_config: &Config) -> Result<(), Error> {
+    if is_v2(current_config) {
+        report::info("Skipping database creation as InfluxDB v2 stores data in buckets.");
+        return Ok(());
+    }
+    let database: String = current_config.get_dbname();
+    let mut existing_result = dbclient.json_query(ReadQuery::new("SHOW DATABASES")).await?;
+    let exists: bool = existing_result.deserialize_next::<ExistingDatabase>()?
+        .series.into_iter()
+        .flat_map(|series| series.values)
+        .any(|existing| existing.name == database);
+    if exists {
+        report::info(&format!("Database {} already exists.", database));
+    } else {
+        dbclient.query(ReadQuery::new(format!("CREATE DATABASE {}", quote_identifier(&database)))).await?;
+        report::info(&format!("Created database {}.", database));
+    }
+    Ok(())
+}
+
+/// Create any retention policies from the Config that do not already exist on the database<br>
+/// Policies that already exist are left untouched. InfluxDB v2 manages retention on buckets instead, so token only configurations are skipped.
+///
+/// # Errors
+/// This function passes any errors generated by the underlying influxdb crate
+pub async fn ensure_retention_policies(dbclient: &Client, current_config: &Config) -> Result<(), Error> {
+    if is_v2(current_config) {
+        report::info("Skipping retention policy creation as InfluxDB v2 manages retention on buckets.");
+        return Ok(());
+    }
+    let database: String = current_config.get_dbname();
+    let show_query: ReadQuery = ReadQuery::new(format!("SHOW RETENTION POLICIES ON {}", quote_identifier(&database)));
+    let mut existing_result = dbclient.json_query(show_query).await?;
+    let existing: Vec<String> = existing_result.deserialize_next::<ExistingPolicy>()?
+        .series.into_iter()
+        .flat_map(|series| series.values)
+        .map(|policy| policy.name)
+        .collect();
+    for policy in current_config.get_retention_policies() {
+        if existing.contains(&policy.name) {
+            report::info(&format!("Retention policy {} already exists on {}.", policy.name, database));
+            continue;
+        }
+        dbclient.query(ReadQuery::new(create_retention_policy_query(&database, policy))).await?;
+        report::info(&format!("Created retention policy {} on {} with a duration of {}.", policy.name, database, policy.duration));
+    }
+    Ok(())
+}
+
+/// Create the bucket named after the configured database if it is not already in the organization<br>
+/// Naming the bucket after the database lets the v1 compatibility write API find it without any extra DBRP setup.
+///
+/// # Errors
+/// HTTP failures are converted into the matching influxdb crate errors. Returns a DatabaseError if the token or organization is missing, as there is no way to create a bucket without them.
+pub fn ensure_bucket(current_config: &Config) -> Result<(), Error> {
+    let token: &str = match &current_config.token {
+        Some(token) => token,
+        None => return Err(Error::DatabaseError { error: "OPENWEATHER_INFLUXDB_CREATE_BUCKET requires OPENWEATHER_INFLUXDB_TOKEN to be set.".to_string() }),
+    };
+    let org: &str = match current_config.get_org() {
+        Some(org) => org,
+        None => return Err(Error::DatabaseError { error: "OPENWEATHER_INFLUXDB_CREATE_BUCKET requires OPENWEATHER_INFLUXDB_ORG to be set.".to_string() }),
+    };
+    let server: String = current_config.get_dbserver();
+    let bucket: String = current_config.get_bucket();
+    let authorization: String = format!("Token {}", token);
+    let agent: ureq::Agent = http_agent(current_config);
+
+    let org_id: String = find_org_id(&agent, &server, &authorization, org)?;
+
+    let buckets: BucketList = agent.get(&format!("{}/api/v2/buckets", server))
+        .query("orgID", &org_id)
+        .query("name", &bucket)
+        .set("Authorization", &authorization)
+        .call().map_err(convert_http_error)?
+        .into_json().map_err(|e| Error::DeserializationError { error: e.to_string() })?;
+    if buckets.buckets.iter().any(|existing| existing.name == bucket) {
+        report::info(&format!("Bucket {} already exists in {}.", bucket, org));
+        return Ok(());
+    }
+
+    let new_bucket: NewBucket = NewBucket { org_id, name: bucket.clone(), retention_rules: bucket_retention_rules(current_config.get_bucket_retention()) };
+    agent.post(&format!("{}/api/v2/buckets", server))
+        .set("Authorization", &authorization)
+        .send_json(new_bucket).map_err(convert_http_error)?;
+    report::info(&format!("Created bucket {} in {} with a retention of {}.", bucket, org, current_config.get_bucket_retention()));
+    Ok(())
+}
+
+/// Every retention policy the v1 compatibility API will be asked for: autogen, which writes without one use, and each route's policy
+pub fn dbrp_policies(current_config: &Config) -> Vec<String> {
+    let mut policies: Vec<String> = vec!["autogen".to_string()];
+    for route in &current_config.routes {
+        if !policies.contains(&route.retention_policy) {
+            policies.push(route.retention_policy.clone());
+        }
+    }
+    policies
+}
+
+/// Explain how to add a missing DBRP mapping by hand, as writes through the v1 compatibility API fail with a 404 without one
+pub fn missing_dbrp_message(database: &str, bucket: &str, policies: &[String]) -> String {
+    format!("InfluxDB v2 has no DBRP mapping for database {} and retention policies {}, and no bucket named {} to map them to, so every write would fail with a 404. Create the bucket, ex: with OPENWEATHER_INFLUXDB_CREATE_BUCKET, set OPENWEATHER_INFLUXDB_DBRP_BUCKET to an existing bucket, or map it by hand with `influx v1 dbrp create --db {} --rp autogen --bucket-id <bucket ID> --default`.",
+        database, policies.join(", "), bucket, database)
+}
+
+/// Check that InfluxDB v2 maps the configured database and each retention policy in use to a bucket for the v1 compatibility API, creating any mapping that is missing<br>
+/// Mappings point at OPENWEATHER_INFLUXDB_DBRP_BUCKET, or the bucket written to. Without OPENWEATHER_INFLUXDB_ORG the check is skipped with a note.
+///
+/// # Errors
+/// Returns a DatabaseError explaining what to create when a mapping is missing and there is no bucket to map it to. HTTP failures are converted into the matching influxdb crate errors.
+pub fn ensure_dbrp(current_config: &Config) -> Result<(), Error> {
+    let org: &str = match current_config.get_org() {
+        Some(org) => org,
+        None => {
+            report::info("Set OPENWEATHER_INFLUXDB_ORG to have the InfluxDB v2 DBRP mapping checked at startup.");
+            return Ok(());
+        },
+    };
+    let server: String = current_config.get_dbserver();
+    let database: String = current_config.get_dbname();
+    let authorization: String = format!("Token {}", current_config.token.as_deref().unwrap_or_default());
+    let agent: ureq::Agent = http_agent(current_config);
+    let org_id: String = find_org_id(&agent, &server, &authorization, org)?;
+
+    let existing: DbrpList = agent.get(&format!("{}/api/v2/dbrps", server))
+        .query("orgID", &org_id)
+        .query("db", &database)
+        .set("Authorization", &authorization)
+        .call().map_err(convert_http_error)?
+        .into_json().map_err(|e| Error::DeserializationError { error: e.to_string() })?;
+    let missing: Vec<String> = dbrp_policies(current_config).into_iter()
+        .filter(|policy| !existing.content.iter().any(|mapping| mapping.retention_policy == *policy))
+        .collect();
+    if missing.is_empty() {
+        report::info(&format!("DBRP mapping for database {} found.", database));
+        return Ok(());
+    }
+
+    let bucket: String = current_config.get_dbrp_bucket().map(str::to_string).unwrap_or_else(|| current_config.get_bucket());
+    let buckets: BucketList = agent.get(&format!("{}/api/v2/buckets", server))
+        .query("orgID", &org_id)
+        .query("name", &bucket)
+        .set("Authorization", &authorization)
+        .call().map_err(convert_http_error)?
+        .into_json().map_err(|e| Error::DeserializationError { error: e.to_string() })?;
+    let bucket_id: String = match buckets.buckets.into_iter().find(|existing| existing.name == bucket) {
+        Some(found) => found.id,
+        None => return Err(Error::DatabaseError { error: missing_dbrp_message(&database, &bucket, &missing) }),
+    };
+    for policy in missing {
+        let new_dbrp: NewDbrp = NewDbrp { org_id: org_id.clone(), bucket_id: bucket_id.clone(), database: database.clone(), default: policy == "autogen", retention_policy: policy.clone() };
+        agent.post(&format!("{}/api/v2/dbrps", server))
+            .set("Authorization", &authorization)
+            .send_json(new_dbrp).map_err(convert_http_error)?;
+        report::info(&format!("Created DBRP mapping for database {} and retention policy {} to bucket {}.", database, policy, bucket));
+    }
+    Ok(())
+}
+
+/// InfluxDB v2 treats an empty list of retention rules as keep forever, so only "INF" gets one.<br>
+/// The retention is expected to have passed is_valid_bucket_retention.
+fn bucket_retention_rules(retention: &str) -> Vec<RetentionRule> {
+    match duration_to_nanos(retention).and_then(|nanos| u64::try_from(nanos / 1_000_000_000).ok()) {
+        Some(seconds) if seconds > 0 => vec![RetentionRule { rule_type: "expire".to_string(), every_seconds: seconds }],
+        _ => Vec::new(),
+    }
+}
+
+/// What InfluxDB answered to a write, with the size of the body that was sent
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct WriteResponse {
+    /// Bytes of the request body as sent, after gzip compression when it is turned on
+    pub bytes: usize,
+    /// Body of InfluxDB's answer, usually empty
+    pub body: String,
+}
+
+/// Write queries straight to the /write endpoint, optionally into a specific retention policy.<br>
+/// The influxdb crate Client has no way to set the "rp" parameter, compress the body or use the InfluxDB v2 write API, so this is used whenever any of them is needed.
+/// When Config::get_native_v2 is true the queries go to the bucket through /api/v2/write instead, and the retention policy is not used.
+/// All queries are expected to share the same precision, which is the case for anything built from the same Config. They are sent with an agent from http_agent, so its connections can be reused.
+///
+/// # Errors
+/// HTTP failures are converted into the matching influxdb crate errors so callers can treat both write paths the same
+pub fn write_queries(agent: &ureq::Agent, current_config: &Config, retention_policy: Option<&str>, queries: Vec<WriteQuery>) -> Result<WriteResponse, Error> {
+    let precision: String = match queries.first() {
+        Some(query) => query.get_precision(),
+        None => return Ok(WriteResponse::default()),
+    };
+    let body: String = queries.build()?.get();
+    let mut request: ureq::Request = if current_config.get_native_v2() {
+        agent.post(&format!("{}/api/v2/write", current_config.get_dbserver()))
+            .query("org", current_config.get_org().unwrap_or_default())
+            .query("bucket", &current_config.get_bucket())
+            .query("precision", &current_config.get_precision().to_string())
+    } else {
+        let request: ureq::Request = agent.post(&format!("{}/write", current_config.get_dbserver()))
+            .query("db", &current_config.get_dbname())
+            .query("precision", &precision);
+        match retention_policy {
+            Some(policy) => request.query("rp", policy),
+            None => request,
+        }
+    };
+    if let (Some(user), Some(pass)) = (&current_config.dbuser, &current_config.dbpass) {
+        request = request.query("u", user).query("p", pass);
+    } else if let Some(token) = &current_config.token {
+        request = request.set("Authorization", &format!("Token {}", token.expose()));
+    }
+    let (bytes, response): (usize, Result<ureq::Response, ureq::Error>) = if current_config.get_gzip() {
+        let compressed: Vec<u8> = gzip_body(&body).map_err(|e| Error::InvalidQueryError { error: format!("Unable to compress write: {}", e) })?;
+        (compressed.len(), request.set("Content-Encoding", "gzip").send_bytes(&compressed))
+    } else {
+        (body.len(), request.send_string(&body))
+    };
+    match response {
+        Ok(response) => Ok(WriteResponse { bytes, body: response.into_string().unwrap_or_default() }),
+        Err(e) => Err(convert_http_error(e)),
+    }
+}
+
+/// Time to wait before retrying a write that may or may not have landed
+const WRITE_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Check if a failed write may still have been stored, ex: the connection dropped or timed out after the body was sent<br>
+/// Only these are worth retrying, as the same points can be sent again safely. Errors InfluxDB answered clearly, like a bad request or auth, are not.
+pub fn is_ambiguous_write_error(write_error: &Error) -> bool {
+    match write_error {
+        Error::ConnectionError { .. } => true,
+        Error::DatabaseError { error } => error.starts_with("Status 5") || error.to_lowercase().contains("timeout") || error.to_lowercase().contains("timed out"),
+        _ => false,
+    }
+}
+
+/// Write several updates in a single request, through write_queries with the agent when the Config needs a retention policy, gzip or the v2 write API, and through the influxdb crate Client otherwise
+///
+/// # Errors
+/// Passes any error from the influxdb crate or write_queries
+pub async fn write_batch(dbclient: &Client, agent: &ureq::Agent, current_config: &Config, pollution: Vec<PollUpdate>) -> Result<WriteResponse, Error> {
+    let dbupdates: Vec<WriteQuery> = pollution.iter().map(|update| update.to_named_write_query(POLLUTION_MEASUREMENT, current_config.get_precision(), current_config.get_field_names())).collect();
+    let route: Option<&MeasurementRoute> = current_config.get_route(POLLUTION_MEASUREMENT);
+    if route.is_some() || current_config.get_gzip() || current_config.get_native_v2() {
+        // write_queries blocks on ureq, so it runs on tokio's blocking threads to keep the other stages going
+        let policy: Option<String> = route.map(|found| found.retention_policy.clone());
+        let write_agent: ureq::Agent = agent.clone();
+        let write_config: Config = current_config.clone();
+        tokio::task::spawn_blocking(move || write_queries(&write_agent, &write_config, policy.as_deref(), dbupdates)).await
+            .map_err(|e| Error::ConnectionError { error: format!("Write stopped unexpectedly: {}", e) })?
+    } else {
+        let bytes: usize = dbupdates.build()?.get().len();
+        Ok(WriteResponse { bytes, body: dbclient.clone().query(dbupdates).await? })
+    }
+}
+
+/// Write updates, retrying up to OPENWEATHER_INFLUXDB_WRITE_RETRIES times when the outcome is ambiguous<br>
+/// Every attempt sends the same points with the same timestamps and tags, so a point that was stored by an earlier attempt is overwritten rather than duplicated
+///
+/// # Errors
+/// Returns the first error that is not ambiguous, or the last error once the retries are used up
+pub async fn write_with_retries(dbclient: &Client, agent: &ureq::Agent, current_config: &Config, updates: &[PollUpdate]) -> Result<WriteResponse, Error> {
+    let mut attempt: u8 = 0;
+    loop {
+        match write_batch(dbclient, agent, current_config, updates.to_vec()).await {
+            Ok(result) => return Ok(result),
+            Err(e) if attempt < current_config.get_write_retries() && is_ambiguous_write_error(&e) => {
+                attempt += 1;
+                report::warn(&format!("Write may not have landed ({}). Sending the same points again, attempt {} of {}.", e, attempt, current_config.get_write_retries()));
+                tokio::time::sleep(WRITE_RETRY_DELAY).await;
+            },
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Check if an error means the server could not be reached at all, so another server should be tried
+pub fn is_connection_error(write_error: &Error) -> bool {
+    matches!(write_error, Error::ConnectionError { .. })
+}
+
+/// Split a Config with fallback servers into one Config per server, the primary first, each with no fallbacks of its own
+pub fn server_configs(current_config: &Config) -> Vec<Config> {
+    current_config.get_dbservers().into_iter().map(|server| {
+        let mut server_config: Config = current_config.clone();
+        server_config.set_dbservers(vec![server]);
+        server_config
+    }).collect()
+}
+
+/// Compress a line protocol body with gzip, which the /write endpoint accepts when Content-Encoding is set
+pub fn gzip_body(body: &str) -> std::io::Result<Vec<u8>> {
+    let mut encoder: GzEncoder<Vec<u8>> = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(body.as_bytes())?;
+    encoder.finish()
+}
+
+/// Turn a ureq error from talking to InfluxDB into the closest influxdb crate error
+pub(crate) fn convert_http_error(http_error: ureq::Error) -> Error {
+    match http_error {
+        ureq::Error::Status(401, _) => Error::AuthenticationError,
+        ureq::Error::Status(403, _) => Error::AuthorizationError,
+        ureq::Error::Status(code, response) => Error::DatabaseError { error: format!("Status {}: {}", code, response.into_string().unwrap_or_default()) },
+        ureq::Error::Transport(transport) => Error::ConnectionError { error: transport.to_string() },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ambiguous_write_errors() {
+        assert!(is_ambiguous_write_error(&Error::ConnectionError { error: "timed out reading response".to_string() }));
+        assert!(is_ambiguous_write_error(&Error::DatabaseError { error: "Status 503: unavailable".to_string() }));
+        assert!(!is_ambiguous_write_error(&Error::DatabaseError { error: "Status 400: partial write: field type conflict".to_string() }));
+        assert!(!is_ambiguous_write_error(&Error::AuthenticationError));
+    }
+
+    #[test]
+    fn server_configs_one_per_server() {
+        let mut test_config: Config = Config::default();
+        test_config.set_dbserver("influx-a,influx-b".to_string()).unwrap();
+        let configs: Vec<Config> = server_configs(&test_config);
+        assert_eq!(configs.iter().map(Config::get_dbserver).collect::<Vec<String>>(), vec!["http://influx-a:8086".to_string(), "http://influx-b:8086".to_string()]);
+        assert!(configs.iter().all(|config| config.get_fallback_servers().is_empty()));
+        assert!(is_connection_error(&Error::ConnectionError { error: "refused".to_string() }));
+        assert!(!is_connection_error(&Error::AuthenticationError));
+    }
+
+    #[test]
+    fn parse_routes_multiple() {
+        let routes: Vec<MeasurementRoute> = parse_routes("pollution=ninety_days, pollution_daily = forever");
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes[0], MeasurementRoute { measurement: "pollution".to_string(), retention_policy: "ninety_days".to_string() });
+        assert_eq!(routes[1], MeasurementRoute { measurement: "pollution_daily".to_string(), retention_policy: "forever".to_string() });
+    }
+
+    #[test]
+    fn parse_routes_skips_malformed() {
+        let routes: Vec<MeasurementRoute> = parse_routes("pollution,=forever,daily=,,weather=autogen");
+        assert_eq!(routes, vec![MeasurementRoute { measurement: "weather".to_string(), retention_policy: "autogen".to_string() }]);
+    }
+
+    #[test]
+    fn parse_retention_policies_with_default() {
+        let policies: Vec<RetentionPolicy> = parse_retention_policies("ninety_days:90d:default, forever:INF");
+        assert_eq!(policies, vec![
+            RetentionPolicy { name: "ninety_days".to_string(), duration: "90d".to_string(), default: true },
+            RetentionPolicy { name: "forever".to_string(), duration: "INF".to_string(), default: false },
+        ]);
+    }
+
+    #[test]
+    fn parse_retention_policies_skips_bad_durations() {
+        let policies: Vec<RetentionPolicy> = parse_retention_policies("broken:ninety,:90d,ok:1h30m");
+        assert_eq!(policies, vec![RetentionPolicy { name: "ok".to_string(), duration: "1h30m".to_string(), default: false }]);
+    }
+
+    #[test]
+    fn duration_validation() {
+        assert!(is_valid_duration("90d"));
+        assert!(is_valid_duration("1w2d"));
+        assert!(is_valid_duration("500ms"));
+        assert!(is_valid_duration("inf"));
+        assert!(!is_valid_duration(""));
+        assert!(!is_valid_duration("90"));
+        assert!(!is_valid_duration("d"));
+        assert!(!is_valid_duration("90days"));
+    }
+
+    #[test]
+    fn precision_parsing() {
+        assert_eq!("s".parse::<Precision>(), Ok(Precision::Seconds));
+        assert_eq!("MS".parse::<Precision>(), Ok(Precision::Milliseconds));
+        assert_eq!("us".parse::<Precision>(), Ok(Precision::Microseconds));
+        assert_eq!("ns".parse::<Precision>(), Ok(Precision::Nanoseconds));
+        assert!("hours".parse::<Precision>().is_err());
+    }
+
+    #[test]
+    fn precision_truncates_timestamp() {
+        let time: DateTime<Utc> = DateTime::from_timestamp(1700000000, 123_456_789).unwrap();
+        assert_eq!(Precision::Seconds.timestamp(time), Timestamp::Seconds(1700000000));
+        assert_eq!(Precision::Milliseconds.timestamp(time), Timestamp::Milliseconds(1700000000123));
+        assert_eq!(Precision::Microseconds.timestamp(time), Timestamp::Microseconds(1700000000123456));
+        assert_eq!(Precision::Nanoseconds.timestamp(time), Timestamp::Nanoseconds(1700000000123456789));
+    }
+
+    #[test]
+    fn precision_truncate_matches_timestamp() {
+        let time: DateTime<Utc> = DateTime::from_timestamp(1700000000, 123_456_789).unwrap();
+        assert_eq!(Precision::Seconds.truncate(time), DateTime::from_timestamp(1700000000, 0).unwrap());
+        assert_eq!(Precision::Milliseconds.truncate(time), DateTime::from_timestamp(1700000000, 123_000_000).unwrap());
+        assert_eq!(Precision::Nanoseconds.truncate(time), time);
+    }
+
+    #[test]
+    fn literal_quoting_escapes() {
+        assert_eq!(quote_literal("O'Fallon"), "'O\\'Fallon'".to_string());
+    }
+
+    #[test]
+    fn duration_to_nanos_adds_units() {
+        assert_eq!(duration_to_nanos("1h30m"), Some(5_400_000_000_000));
+        assert_eq!(duration_to_nanos("90d"), Some(90 * 86_400_000_000_000));
+        assert_eq!(duration_to_nanos("INF"), None);
+        assert_eq!(duration_to_nanos("999999999999999999999999w"), None);
+        assert_eq!(duration_to_nanos("340282366920938463463374607431768211455ns1ns"), None);
+    }
+
+    #[test]
+    fn bucket_retention_at_least_a_second() {
+        assert!(is_valid_bucket_retention("INF"));
+        assert!(is_valid_bucket_retention("1s"));
+        assert!(is_valid_bucket_retention("90d"));
+        assert!(!is_valid_bucket_retention("500ms"));
+        assert!(!is_valid_bucket_retention("0s"));
+        assert!(!is_valid_bucket_retention("90days"));
+    }
+
+    #[test]
+    fn bucket_retention_rules_infinite_is_empty() {
+        assert!(bucket_retention_rules("INF").is_empty());
+        let rules: Vec<RetentionRule> = bucket_retention_rules("30d");
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].every_seconds, 2_592_000);
+        assert_eq!(rules[0].rule_type, "expire".to_string());
+    }
+
+    #[test]
+    fn retention_policy_query_quotes_names() {
+        let policy: RetentionPolicy = RetentionPolicy { name: "ninety days".to_string(), duration: "90d".to_string(), default: true };
+        assert_eq!(create_retention_policy_query("pollution", &policy), "CREATE RETENTION POLICY \"ninety days\" ON \"pollution\" DURATION 90d REPLICATION 1 DEFAULT".to_string());
+    }
+
+    #[test]
+    fn write_reports_bytes_sent() {
+        let mut test_config: Config = Config::default();
+        test_config.set_dbservers(vec![crate::provider::serve_status("204 No Content")]);
+        let queries: Vec<WriteQuery> = vec![PollUpdate::fixture("Home", 1700000000).to_write_query(POLLUTION_MEASUREMENT, Precision::Seconds)];
+        let body: String = queries.build().unwrap().get();
+        let agent: ureq::Agent = http_agent(&test_config);
+        assert_eq!(write_queries(&agent, &test_config, None, queries.clone()).unwrap().bytes, body.len());
+        test_config.gzip = true;
+        assert_eq!(write_queries(&agent, &test_config, None, queries).unwrap().bytes, gzip_body(&body).unwrap().len());
+    }
+
+    #[test]
+    fn gzip_body_round_trips() {
+        use std::io::Read;
+        let body: String = "pollution,location=Test aqi=1i 1700000000\n".repeat(50);
+        let compressed: Vec<u8> = gzip_body(&body).unwrap();
+        assert!(compressed.len() < body.len());
+        let mut decoded: String = String::new();
+        flate2::read::GzDecoder::new(compressed.as_slice()).read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, body);
+    }
+
+    #[test]
+    fn routes_from_config_file() {
+        let content: &str = "OPENWEATHER_API_KEY = \"abc\"\n[[OPENWEATHER_INFLUXDB_ROUTES]]\nmeasurement = \"pollution\"\nretention_policy = \"ninety_days\"\n";
+        let configuration: crate::ConfigFile = toml::from_str(content).unwrap();
+        assert_eq!(configuration.routes, vec![MeasurementRoute { measurement: "pollution".to_string(), retention_policy: "ninety_days".to_string() }]);
+    }
+
+    #[test]
+    fn history_query_uses_route() {
+        let test_config: Config = Config { routes: vec![MeasurementRoute { measurement: "pollution".to_string(), retention_policy: "ninety_days".to_string() }], ..Config::default() };
+        let since: DateTime<Utc> = DateTime::from_timestamp(1700000000, 0).unwrap();
+        let query: String = history_query(&test_config, "O'Fallon", since);
+        assert!(query.contains("FROM \"test\".\"ninety_days\".\"pollution\""));
+        assert!(query.contains("\"location\" = 'O\\'Fallon'"));
+        assert!(query.contains(" AND \"forecast\" != 'true' AND "));
+        assert!(query.ends_with("time >= '2023-11-14T22:13:20.000000000Z' ORDER BY time ASC"));
+    }
+
+    #[test]
+    fn dbrp_policies_include_routes() {
+        assert_eq!(dbrp_policies(&Config::default()), vec!["autogen".to_string()]);
+        let routed: Config = Config { routes: parse_routes("pollution=ninety_days,pollution_daily=forever,alerts=ninety_days"), ..Config::default() };
+        assert_eq!(dbrp_policies(&routed), vec!["autogen".to_string(), "ninety_days".to_string(), "forever".to_string()]);
+        let message: String = missing_dbrp_message("test", "test", &dbrp_policies(&Config::default()));
+        assert!(message.contains("no DBRP mapping for database test and retention policies autogen"));
+        assert!(message.contains("influx v1 dbrp create --db test --rp autogen"));
+        let mappings: DbrpList = serde_json::from_str(r#"{"content":[{"id":"1","database":"test","retention_policy":"autogen","default":true,"bucketID":"2","orgID":"3"}]}"#).unwrap();
+        assert_eq!(mappings.content[0].retention_policy, "autogen");
+    }
+
+    #[test]
+    fn schema_version_query_counts_points() {
+        let test_config: Config = Config { field_names: schema::parse_field_names("aqi=owm_aqi").unwrap(), ..Config::default() };
+        assert_eq!(schema_version_query(&test_config), "SELECT count(\"owm_aqi\") AS \"points\", last(\"schema_version\") AS \"version\" FROM \"pollution\"");
+    }
+
+    #[test]
+    fn renamed_fields_read_back() {
+        let test_config: Config = Config { field_names: schema::parse_field_names("pm2_5=pm25,aqi=owm_aqi").unwrap(), ..Config::default() };
+        let since: DateTime<Utc> = DateTime::from_timestamp(1700000000, 0).unwrap();
+        assert!(history_query(&test_config, "Home", since).starts_with("SELECT \"owm_aqi\" AS \"aqi\", \"co\","));
+        assert!(history_query(&test_config, "Home", since).contains("\"pm25\" AS \"pm2_5\", \"pm10\""));
+        assert!(daily_averages_query(&test_config, "Home", since, None).contains("mean(\"pm25\") AS \"pm2_5\""));
+        assert!(downsample_query(&test_config).contains("mean(\"pm25\") AS \"pm25\""));
+        let update: PollUpdate = serde_json::from_str(r#"{"time":"2023-11-14T22:13:20Z","location":"Home","aqi":2,"co":1.0,"no":0.0,"no2":0.0,"o3":0.0,"so2":0.0,"pm2_5":6.0,"pm10":0.0,"nh3":0.0}"#).unwrap();
+        assert!(verify_write_query(&test_config, &update, "Home").starts_with("SELECT count(\"owm_aqi\")"));
+    }
+
+    #[test]
+    fn daily_averages_query_groups_by_day() {
+        let from: DateTime<Utc> = DateTime::from_timestamp(1700000000, 0).unwrap();
+        let query: String = daily_averages_query(&Config::default(), "Home", from, None);
+        assert!(query.starts_with("SELECT mean(\"aqi\") AS \"aqi\", mean(\"co\") AS \"co\","));
+        assert!(query.contains("FROM \"pollution\" WHERE \"location\" = 'Home' AND \"forecast\" != 'true' AND time >= '2023-11-14T22:13:20.000000000Z' GROUP BY time(1d) fill(none)"));
+        let average: DailyAverage = serde_json::from_str(r#"{"time":"2023-11-14T00:00:00Z","aqi":2.5,"co":1.0,"no":0.0,"no2":3.0,"o3":4.0,"so2":5.0,"pm2_5":6.0,"pm10":7.0,"nh3":8.0}"#).unwrap();
+        assert_eq!(average.aqi, 2.5);
+        assert!(average.location.is_empty());
+    }
+
+    #[test]
+    fn downsample_query_follows_routes() {
+        let plain: String = downsample_query(&Config::default());
+        assert!(plain.starts_with("CREATE CONTINUOUS QUERY \"pollution_daily_mean\" ON \"test\" BEGIN SELECT mean(\"aqi\") AS \"aqi\","));
+        assert!(plain.ends_with("INTO \"test\"..\"pollution_daily\" FROM \"test\"..\"pollution\" WHERE \"forecast\" != 'true' GROUP BY time(1d), \"location\" END"));
+        let routed: Config = Config { routes: parse_routes("pollution=ninety_days,pollution_daily=forever"), ..Config::default() };
+        assert!(downsample_query(&routed).contains("INTO \"test\".\"forever\".\"pollution_daily\" FROM \"test\".\"ninety_days\".\"pollution\""));
+    }
+
+    #[test]
+    fn downsample_flux_writes_daily_measurement() {
+        let flux: String = downsample_flux("raw", "long\"term", "home");
+        assert!(flux.starts_with("option task = {name: \"pollution_daily_mean\", every: 1d, offset: 5m}"));
+        assert!(flux.contains("from(bucket: \"raw\")"));
+        assert!(flux.contains("not exists r.forecast or r.forecast != \"true\""));
+        assert!(flux.contains("set(key: \"_measurement\", value: \"pollution_daily\")"));
+        assert!(flux.contains("to(bucket: \"long\\\"term\", org: \"home\")"));
+    }
+
+    #[test]
+    fn range_query_adds_end() {
+        let from: DateTime<Utc> = DateTime::from_timestamp(1700000000, 0).unwrap();
+        let query: String = range_query(&Config::default(), "Home", from, Some(from + chrono::Duration::days(1)));
+        assert!(query.ends_with("time >= '2023-11-14T22:13:20.000000000Z' AND time <= '2023-11-15T22:13:20.000000000Z' ORDER BY time ASC"));
+        assert_eq!(range_query(&Config::default(), "Home", from, None), history_query(&Config::default(), "Home", from));
+    }
+}
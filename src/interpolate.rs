@@ -0,0 +1,82 @@
+//! Filling short gaps in the hourly readings by drawing a straight line between the readings either side, for downstream averaging that breaks on missing points.<br>
+//! Only gaps of up to OPENWEATHER_INTERPOLATE_GAPS missing readings are filled. Filled readings are marked as interpolated and written with an interpolated=true tag, so they can be told apart from real ones.
+
+use std::collections::BTreeMap;
+use chrono::{DateTime, Utc};
+use crate::PollUpdate;
+
+/// How far apart OpenWeatherMaps readings are expected to be
+pub const READING_STEP: chrono::Duration = chrono::Duration::hours(1);
+
+/// Work out how many readings are missing between two, rounding to the nearest READING_STEP
+pub fn missing_readings(previous: DateTime<Utc>, next: DateTime<Utc>) -> u32 {
+    let steps: i64 = ((next - previous).num_seconds() as f64 / READING_STEP.num_seconds() as f64).round() as i64;
+    u32::try_from(steps - 1).unwrap_or(0)
+}
+
+/// Build the readings missing between two, evenly spaced with every pollutant on a straight line between them<br>
+/// Returns nothing if no readings are missing, more than max_missing are or the two are for different locations. The AQI is rounded to the nearest level, and pollutants and indices only filled where both readings have them.
+pub fn fill(previous: &PollUpdate, next: &PollUpdate, max_missing: u32) -> Vec<PollUpdate> {
+    let missing: u32 = missing_readings(previous.time, next.time);
+    if missing == 0 || missing > max_missing || previous.location != next.location {
+        return Vec::new();
+    }
+    let gap: chrono::Duration = next.time - previous.time;
+    let steps: i32 = missing as i32 + 1;
+    (1..steps).map(|step| {
+        let fraction: f32 = step as f32 / steps as f32;
+        let between = |from: f32, to: f32| from + (to - from) * fraction;
+        PollUpdate {
+            time: previous.time + gap * step / steps,
+            location: next.location.clone(),
+            aqi: between(previous.aqi as f32, next.aqi as f32).round() as i8,
+            co: previous.co.zip(next.co).map(|(from, to)| between(from, to)),
+            no: previous.no.zip(next.no).map(|(from, to)| between(from, to)),
+            no2: previous.no2.zip(next.no2).map(|(from, to)| between(from, to)),
+            o3: previous.o3.zip(next.o3).map(|(from, to)| between(from, to)),
+            so2: previous.so2.zip(next.so2).map(|(from, to)| between(from, to)),
+            pm2_5: previous.pm2_5.zip(next.pm2_5).map(|(from, to)| between(from, to)),
+            pm10: previous.pm10.zip(next.pm10).map(|(from, to)| between(from, to)),
+            nh3: previous.nh3.zip(next.nh3).map(|(from, to)| between(from, to)),
+            interpolated: true,
+            reference: next.reference,
+            forecast: false,
+            collected: None,
+            indices: next.indices.iter()
+                .filter_map(|(index, to)| previous.indices.get(index).map(|from| (index.clone(), between(*from, *to))))
+                .collect(),
+            health: None,
+            trends: BTreeMap::new(),
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_update(hour: i64, aqi: i8, pm2_5: f32) -> PollUpdate {
+        PollUpdate { aqi, pm2_5: Some(pm2_5), ..PollUpdate::fixture("Home", 1700000000 + hour * 3600) }
+    }
+
+    #[test]
+    fn fill_draws_straight_line() {
+        let filled: Vec<PollUpdate> = fill(&test_update(0, 1, 10.0), &test_update(3, 4, 40.0), 2);
+        assert_eq!(filled.len(), 2);
+        assert_eq!(filled[0].get_time(), test_update(1, 1, 0.0).get_time());
+        assert_eq!(filled[1].get_time(), test_update(2, 1, 0.0).get_time());
+        assert_eq!(filled[0].get_field("pm2_5"), Some(20.0));
+        assert_eq!(filled[1].get_aqi(), 3);
+        assert!(filled.iter().all(|update| update.is_interpolated()));
+    }
+
+    #[test]
+    fn fill_skips_long_and_missing_gaps() {
+        assert!(fill(&test_update(0, 1, 10.0), &test_update(1, 1, 10.0), 2).is_empty());
+        assert!(fill(&test_update(0, 1, 10.0), &test_update(4, 1, 10.0), 2).is_empty());
+        let mut elsewhere: PollUpdate = test_update(2, 1, 10.0);
+        elsewhere.set_location("Work");
+        assert!(fill(&test_update(0, 1, 10.0), &elsewhere, 2).is_empty());
+        assert_eq!(missing_readings(test_update(0, 1, 0.0).get_time(), test_update(2, 1, 0.0).get_time() + chrono::Duration::minutes(5)), 1);
+    }
+}
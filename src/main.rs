@@ -1,79 +1,258 @@
 use pollutionclient_rs::*;
-use std::{thread, time::Duration, env};
-use influxdb::{Client, Error};
+use std::env;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use tokio;
 
-// Utilizing tokio as "current_thread" to ensure async function is taken care of. It's okay that it's actually blocking.
+// Utilizing tokio as "current_thread" to ensure async function is taken care of. Blocking requests run on tokio's blocking threads, so polls, writes and signal handling carry on alongside each other.
 #[tokio::main(flavor = "current_thread")]
-async fn main() -> Result<(), Error> {
+async fn main() -> Result<(), plugin::PluginError> {
+    let args: cli::Args = match cli::parse_args(env::args()) {
+        Ok(args) => args,
+        // --help and --version are printed as errors by clap, but exit with code 0
+        Err(e) if !e.use_stderr() => e.exit(),
+        Err(e) => {
+            let _ = e.print();
+            std::process::exit(cli::EXIT_ERROR);
+        },
+    };
+    report::set_verbosity(args.verbosity);
+    // Logging is set up from the environment alone, so loading the configuration can log through it
+    if let Err(message) = report::log_format_from_env().and_then(report::init_logging) {
+        exit_with(&message);
+    }
+    // Exporting or geocoding to the console should print nothing but the readings or locations
+    if args.export.as_ref().is_some_and(|export| export.output.is_none()) || args.geocode.as_ref().is_some_and(|geocode| geocode.output.is_none()) {
+        report::set_verbosity(report::Verbosity::Quiet);
+    }
+    if args.validate {
+        validate_config();
+    }
+    // Geocoding needs only the API key, so the configured locations are not looked up for it, as one that cannot be found should not stop it
+    let offline: bool = args.geocode.is_some();
     // Check to see if FILE_POLL_CONFIG is set, which means there is a config file to be had instead of environmental variables
-    let running_config: Config = match env::var("FILE_POLL_CONFIG") {
+    let loaded: Result<Config, error::PollutionError> = match env::var("FILE_POLL_CONFIG") {
+        Ok(config_file) if offline => Config::unpack_config_file_offline(&config_file),
         Ok(config_file) => Config::unpack_config_file(&config_file),
-        Err(_) => Config::parse_env().unwrap(),
+        Err(_) if offline => Config::parse_env_offline(),
+        Err(_) => Config::parse_env(),
+    };
+    let running_config: Config = match loaded {
+        Ok(config) => config,
+        Err(e) if e.is_auth_failure() => exit_api_key_rejected(),
+        Err(e) => exit_with(&e),
     };
     if running_config.get_key() == "NOAPISET".to_string() {
-        panic!("API key is not set. Unable to proceed.")
+        exit_with(&"API key is not set. Unable to proceed.")
     };
-    if running_config.location_is_set() {
-        println!("Location added: {}", running_config.get_location())
-    } else {
-        panic!("Location not set. Unable to proceed.")
+    // Geocoding needs only the API key, as it is how locations are found in the first place
+    if let Some(geocode) = &args.geocode {
+        match geocode::run(&running_config.get_key(), geocode) {
+            Ok(summary) => report::info(&format!("Found {} locations, skipped {} that could not be looked up.", summary.resolved, summary.failed)),
+            Err(message) => exit_with(&message),
+        }
+        return Ok(());
+    }
+    if let Err(problems) = running_config.validate() {
+        exit_with(&format!("Unable to proceed, the configuration has {} problems:\n- {}", problems.len(), problems.join("\n- ")));
+    }
+    report::info(&format!("Location added: {}", running_config.get_locations().join(", ")));
+    if let Some(bbox) = running_config.get_grid_bbox() {
+        report::info(&format!("Sampling {} grid points over {}, {} km apart.", running_config.get_grid_cells().len(), bbox, running_config.get_grid_spacing()));
+    }
+
+    report::info(&format!("InfluxDB server set to: {}", running_config.get_dbserver()));
+    report::info("If this is incorrect, ensure that OPENWEATHER_INFLUXDB_SERVER is set correctly.");
+    report::info(&format!("InfluxDB name set to {}", running_config.get_dbname()));
+    report::info("If this is incorrect, ensure that OPENWEATHER_INFLUXDB_NAME is set correctly.");
+
+    if let Some(export) = &args.export {
+        match export::run(&database_client(&running_config), &running_config, export).await {
+            Ok(count) => report::info(&format!("Exported {} readings as {}.", count, export.format)),
+            Err(message) => exit_with(&message),
+        }
+        return Ok(());
+    }
+    if let Some(migrate) = &args.migrate {
+        match migrate::run(&database_client(&running_config), &running_config, migrate).await {
+            Ok(summary) => report::info(&format!("Migrated {} points from {} to {}, skipped {} that could not be read.", summary.migrated, migrate.from, migrate.to, summary.skipped)),
+            Err(message) => exit_with(&message),
+        }
+        return Ok(());
+    }
+    if let Some(import) = &args.import {
+        match import::run(&database_client(&running_config), &running_config, import).await {
+            Ok(summary) => report::info(&format!("Imported {} hourly readings from {}.", summary.imported, summary.station)),
+            Err(message) => exit_with(&message),
+        }
+        return Ok(());
+    }
+
+    let mut pollution_client: client::PollutionClient = match client::PollutionClient::new(running_config.clone()) {
+        Ok(pollution_client) => pollution_client,
+        Err(e) => exit_with(&e),
     };
+    if let Some(polls) = args.polls {
+        pollution_client.stop_after_polls(polls);
+    }
+    if let Some(format) = args.dry_run {
+        pollution_client.dry_run(format);
+    }
+    if let Err(e) = pollution_client.prepare().await {
+        exit_with(&e);
+    }
+
+    if let Some(backfill) = &args.backfill {
+        match backfill::run(&pollution_client, backfill).await {
+            Ok(summary) => report::info(&format!("Backfilled {} hourly readings in {} requests.", summary.written, summary.chunks)),
+            Err(message) => exit_with(&message),
+        }
+        return Ok(());
+    }
+
+    if running_config.get_self_test() && !pollution_client.is_dry_run() {
+        if let Err(message) = selftest::run_self_test(pollution_client.get_dbclient(), &running_config).await {
+            exit_with(&message);
+        }
+    }
+    // A single poll for cron does not need the endpoints, the dashboard or signal handling
+    if args.once {
+        return finish(pollution_client.run_once().await.map(|_| ()));
+    }
+    if let Some(bind) = running_config.get_http_bind() {
+        if let Err(message) = api::serve(bind, pollution_client.get_readings()) {
+            exit_with(&message);
+        }
+    }
+
+    if let Some(bind) = running_config.get_prometheus_bind() {
+        start_prometheus(bind);
+    }
+
+    let dashboard: Option<std::thread::JoinHandle<()>> = if args.tui { Some(start_dashboard(&pollution_client)) } else { None };
+    tokio::spawn(stop_on_signal(pollution_client.stop_handle()));
+    if let Some(run_for) = args.run_for {
+        tokio::spawn(stop_after(run_for, pollution_client.stop_handle()));
+    }
+
+    // This keeps going until we hit our error limit, a write or transform fails, the dashboard is closed or we are asked to stop
+    let outcome: Result<(), client::CycleError> = pollution_client.run().await;
+    if let Some(handle) = dashboard {
+        pollution_client.shutdown();
+        let _ = handle.join();
+    }
+    finish(outcome)
+}
+
+/// Turn how polling ended into the binary's result, exiting with the code that suits a failed fetch<br>
+/// Every error is printed through report::error, so it is redacted like the rest of the output.
+fn finish(outcome: Result<(), client::CycleError>) -> Result<(), plugin::PluginError> {
+    match outcome {
+        Ok(()) => Ok(()),
+        Err(e @ client::CycleError::Write(_)) | Err(e @ client::CycleError::Transform(_)) => exit_with(&e),
+        // If we make it out of the loop on fetch errors, we are at our limit or retrying would not help and need to terminate
+        // The client has already said the key was rejected
+        Err(client::CycleError::Fetch(e)) if client::is_auth_failure(&e) => std::process::exit(cli::EXIT_API_KEY_REJECTED),
+        Err(client::CycleError::Fetch(e)) if !client::is_retryable_fetch(&e) => exit_with(&format!("Unable to fetch pollution and retrying will not help: {}", e)),
+        Err(client::CycleError::Fetch(_)) => exit_with(&"Max errors reached! Terminating loop and script."),
+    }
+}
+
+/// Load the configuration without looking up locations or connecting to anything and print every problem with it for the validate subcommand<br>
+/// Exits with code 0 if there are none and cli::EXIT_ERROR otherwise.
+fn validate_config() -> ! {
+    let loaded: Result<Config, error::PollutionError> = match env::var("FILE_POLL_CONFIG") {
+        Ok(config_file) => Config::unpack_config_file_offline(&config_file),
+        Err(_) => Config::parse_env_offline(),
+    };
+    let problems: Vec<String> = match loaded {
+        Ok(config) => config.validate().err().unwrap_or_default(),
+        Err(e) => vec![e.to_string()],
+    };
+    if !problems.is_empty() {
+        exit_with(&format!("The configuration has {} problems:\n- {}", problems.len(), problems.join("\n- ")));
+    }
+    println!("The configuration is valid.");
+    std::process::exit(0);
+}
+
+/// Print why the binary cannot carry on and stop with cli::EXIT_ERROR
+fn exit_with(message: &dyn fmt::Display) -> ! {
+    report::error(&message.to_string());
+    std::process::exit(cli::EXIT_ERROR);
+}
+
+/// Build the InfluxDB client for a subcommand, stopping if the Config cannot make one
+fn database_client(running_config: &Config) -> influxdb::Client {
+    match build_client(running_config) {
+        Ok(dbclient) => dbclient,
+        Err(e) => exit_with(&e),
+    }
+}
+
+/// Stop with a dedicated exit code when OpenWeatherMaps rejects the API key, as retrying or restarting will not fix it
+fn exit_api_key_rejected() -> ! {
+    report::error("API key rejected by OpenWeatherMap. Check OPENWEATHER_API_KEY.");
+    std::process::exit(cli::EXIT_API_KEY_REJECTED);
+}
+
+/// Wait for Ctrl+C or SIGTERM, ex: from docker stop, and ask the client to stop so it writes or spools what is queued before exiting
+async fn stop_on_signal(stopping: Arc<AtomicBool>) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => (),
+                    _ = terminate.recv() => (),
+                }
+            },
+            Err(e) => {
+                report::warn(&format!("Unable to listen for SIGTERM, only Ctrl+C will stop cleanly: {}", e));
+                let _ = tokio::signal::ctrl_c().await;
+            },
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+    report::info("Stop requested, writing queued readings before exiting.");
+    stopping.store(true, Ordering::SeqCst);
+}
+
+/// Ask the client to stop once --run-for has passed, so it exits cleanly with code 0 after writing what is queued
+async fn stop_after(run_for: std::time::Duration, stopping: Arc<AtomicBool>) {
+    tokio::time::sleep(run_for).await;
+    report::info(&format!("Ran for {}s as asked by --run-for, stopping.", run_for.as_secs()));
+    stopping.store(true, Ordering::SeqCst);
+}
+
+/// Hand the terminal to the dashboard, stopping readings and progress messages that would draw over it
+#[cfg(feature = "tui")]
+fn start_dashboard(pollution_client: &client::PollutionClient) -> std::thread::JoinHandle<()> {
+    report::silence();
+    report::set_verbosity(report::Verbosity::Quiet);
+    match tui::start(pollution_client.get_readings(), pollution_client.stop_handle()) {
+        Ok(handle) => handle,
+        Err(message) => exit_with(&message),
+    }
+}
+
+#[cfg(not(feature = "tui"))]
+fn start_dashboard(_pollution_client: &client::PollutionClient) -> std::thread::JoinHandle<()> {
+    exit_with(&"--tui was given but this build does not include the \"tui\" feature.")
+}
+
+/// Serve the latest readings for Prometheus to scrape
+#[cfg(feature = "prometheus")]
+fn start_prometheus(bind: &str) {
+    if let Err(message) = prometheus::serve(bind) {
+        exit_with(&message);
+    }
+}
 
-    let running_coords: [String; 2] = running_config.get_coords();
-    match running_coords[0].parse::<f32>() {
-        Ok(_) => println!("Latitude looks good."),
-        Err(e) => panic!("Latitude looks malformed. {} given but parsing returns: {}", running_coords[0], e),
-    }
-    match running_coords[1].parse::<f32>() {
-        Ok(_) => println!("Longitude looks good."),
-        Err(e) => panic!("Longitude looks malformed. {} given but parsing returns: {}", running_coords[1], e),
-    }
-
-    println!("InfluxDB server set to: {}", running_config.get_dbserver());
-    println!("If this is incorrect, ensure that OPENWEATHER_INFLUXDB_SERVER is set correctly.");
-    println!("InfluxDB name set to {}", running_config.get_dbname());
-    println!("If this is incorrect, ensure that OPENWEATHER_INFLUXDB_NAME is set correctly.");
-
-    let running_client: Client = build_client(&running_config);
-    // This String will need to be updated as OpenWeatherMaps makes updates/changes to their API endpoints
-    let running_url: String = format!("http://api.openweathermap.org/data/2.5/air_pollution?lat={}&lon={}&appid={}", &running_coords[0], &running_coords[1], running_config.get_key());
-
-    let mut error_count: u8 = 0;
-    // This while loop will keep going forever until we hit our error limit
-    while error_count < running_config.get_maxretry() {
-        let response: Result<PollResponse, ureq::Error> = match get_pollution(&running_url) {
-            Ok(res) => Ok(res),
-            Err(e) => Err(e),
-        };
-        // If the response is not an error, unwrap and format it to be placed in the DB then sleep for the set time
-        if response.is_ok() {
-            let unpacked: PollResponse = response.unwrap();
-            let results: PollUpdate = unpacked.unpack();
-
-            write_to_db(&running_client, results, &running_config.get_location()).await?;
-
-            println!("Successfully written to DB {}", running_config.get_dbname());
-            // Reset error count if we've had a success
-            error_count = 0;
-            thread::sleep(Duration::from_secs(running_config.get_timing()));
-        } else {
-            // If the response is anything but Ok, tick the error count up by one and try to print the error out for later troubleshooting
-            println!("Error encountered while grabbing stats.");
-            error_count = error_count + 1;
-            match response.unwrap_err() {
-                ureq::Error::Status(code, resp) => println!("Status: {}, Text: {}", code, resp.status_text()),
-                ureq::Error::Transport(trans) => println!("Kind: {}, Message: {}", trans.kind(), trans.message().unwrap_or("N/A")),
-            };
-            // If we are at our error limit, there is no point in continuing
-            if running_config.get_maxretry() <= error_count {
-                break;
-            } else {
-                // If we are under our error limit, sleep for half of the normal time and then run the loop again
-                thread::sleep(Duration::from_secs(running_config.get_timing() / 2));
-            };
-        } 
-    }
-    // If we make it out of the while loop, we have are at our limit and need to terminate
-    panic!("Max errors reached! Terminating loop and script.");
-}
\ No newline at end of file
+#[cfg(not(feature = "prometheus"))]
+fn start_prometheus(_bind: &str) {
+    exit_with(&"OPENWEATHER_PROMETHEUS_BIND is set but this build does not include the \"prometheus\" feature.")
+}
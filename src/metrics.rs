@@ -0,0 +1,100 @@
+//! Lightweight in-process metrics.<br>
+//! Counters and gauges are kept in a single registry keyed by their series name (name plus labels) so they can be read back by the library or exported later.
+
+use std::collections::BTreeMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use crate::report;
+
+static REGISTRY: OnceLock<Mutex<BTreeMap<String, f64>>> = OnceLock::new();
+
+fn registry() -> &'static Mutex<BTreeMap<String, f64>> {
+    REGISTRY.get_or_init(|| Mutex::new(BTreeMap::new()))
+}
+
+/// Build the series name for a metric, ex: pollution_writes_total{result="ok"}
+pub fn series_name(name: &str, labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return name.to_string();
+    }
+    let rendered: Vec<String> = labels.iter()
+        .map(|(label, value)| format!("{}=\"{}\"", label, value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")))
+        .collect();
+    format!("{}{{{}}}", name, rendered.join(","))
+}
+
+/// Add one to a counter
+pub fn increment(name: &str, labels: &[(&str, &str)]) {
+    add(name, labels, 1.0);
+}
+
+/// Add an amount to a counter
+pub fn add(name: &str, labels: &[(&str, &str)], amount: f64) {
+    let mut metrics = registry().lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    *metrics.entry(series_name(name, labels)).or_insert(0.0) += amount;
+}
+
+/// Set a gauge to a specific value
+pub fn set_gauge(name: &str, labels: &[(&str, &str)], value: f64) {
+    let mut metrics = registry().lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    metrics.insert(series_name(name, labels), value);
+}
+
+/// Get the current value of a series if it has been recorded
+pub fn get(name: &str, labels: &[(&str, &str)]) -> Option<f64> {
+    let metrics = registry().lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    metrics.get(&series_name(name, labels)).copied()
+}
+
+/// Copy out every recorded series, sorted by series name
+pub fn snapshot() -> Vec<(String, f64)> {
+    let metrics = registry().lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    metrics.iter().map(|(series, value)| (series.clone(), *value)).collect()
+}
+
+/// Record how long a stage of the polling cycle took and log it<br>
+/// The latest duration is kept as a gauge, alongside a running total and count so averages can be worked out
+pub fn record_stage(stage: &str, elapsed: Duration) {
+    let seconds: f64 = elapsed.as_secs_f64();
+    set_gauge("pollution_stage_duration_seconds", &[("stage", stage)], seconds);
+    add("pollution_stage_duration_seconds_total", &[("stage", stage)], seconds);
+    increment("pollution_stage_runs_total", &[("stage", stage)]);
+    report::info(&format!("Stage {} took {:.3}s", stage, seconds));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn series_name_renders_labels() {
+        assert_eq!(series_name("plain_total", &[]), "plain_total".to_string());
+        assert_eq!(series_name("writes_total", &[("result", "ok"), ("sink", "influx\"db")]), "writes_total{result=\"ok\",sink=\"influx\\\"db\"}".to_string());
+    }
+
+    #[test]
+    fn counters_accumulate() {
+        increment("test_counter_accumulate_total", &[("kind", "a")]);
+        increment("test_counter_accumulate_total", &[("kind", "a")]);
+        add("test_counter_accumulate_total", &[("kind", "b")], 2.5);
+        assert_eq!(get("test_counter_accumulate_total", &[("kind", "a")]), Some(2.0));
+        assert_eq!(get("test_counter_accumulate_total", &[("kind", "b")]), Some(2.5));
+    }
+
+    #[test]
+    fn record_stage_tracks_latest_and_total() {
+        record_stage("test_stage", Duration::from_millis(500));
+        record_stage("test_stage", Duration::from_millis(250));
+        assert_eq!(get("pollution_stage_duration_seconds", &[("stage", "test_stage")]), Some(0.25));
+        assert_eq!(get("pollution_stage_duration_seconds_total", &[("stage", "test_stage")]), Some(0.75));
+        assert_eq!(get("pollution_stage_runs_total", &[("stage", "test_stage")]), Some(2.0));
+    }
+
+    #[test]
+    fn gauges_overwrite() {
+        set_gauge("test_gauge_overwrite", &[], 5.0);
+        set_gauge("test_gauge_overwrite", &[], 3.0);
+        assert_eq!(get("test_gauge_overwrite", &[]), Some(3.0));
+        assert!(snapshot().contains(&("test_gauge_overwrite".to_string(), 3.0)));
+    }
+}
@@ -0,0 +1,186 @@
+//! Migrating points written in an older schema, ex: under field names from before OPENWEATHER_INFLUXDB_FIELD_NAMES was set or without a location tag, into the current one.<br>
+//! Run with the binary's migrate-schema subcommand, ex: `pollutionclient_rs migrate-schema --to pollution_v2 --field-names pm2_5=pm25`.
+//! Points are read a page at a time, rebuilt as readings and written to a new measurement, and optionally a new database or bucket, so the originals are left untouched.
+
+use std::collections::BTreeMap;
+use chrono::{DateTime, SecondsFormat, Utc};
+use influxdb::{Client, Error, WriteQuery};
+use serde_json::Value;
+use crate::indices::Index;
+use crate::influx::{quote_identifier, quote_literal};
+use crate::schema::{self, FieldNames};
+use crate::{influx, quiet_client, report, Config, PollUpdate, POLLUTION_MEASUREMENT};
+
+/// How many points are read and written at a time
+pub const MIGRATE_PAGE_SIZE: usize = 1000;
+
+/// What to migrate, from the migrate-schema subcommand's flags
+#[derive(Clone, Debug, PartialEq)]
+pub struct MigrateArgs {
+    /// Measurement holding the old points
+    pub from: String,
+    /// Measurement to write the migrated points to
+    pub to: String,
+    /// Database, or bucket on InfluxDB v2, to write to, or the configured one if None
+    pub database: Option<String>,
+    /// Location for points without a location tag, or the configured location if None
+    pub location: Option<String>,
+    /// Earliest point to migrate, or every point if None
+    pub since: Option<DateTime<Utc>>,
+    /// Fields the old points store under other names, written the same way as OPENWEATHER_INFLUXDB_FIELD_NAMES
+    pub field_names: FieldNames,
+}
+
+impl Default for MigrateArgs {
+    fn default() -> Self {
+        MigrateArgs { from: POLLUTION_MEASUREMENT.to_string(), to: String::new(), database: None, location: None, since: None, field_names: FieldNames::new() }
+    }
+}
+
+/// How a migration went
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MigrateSummary {
+    /// Points rewritten in the current schema
+    pub migrated: usize,
+    /// Points left behind as they could not be read as a reading
+    pub skipped: usize,
+}
+
+/// One point as InfluxDB returns it, by column name
+pub type Row = BTreeMap<String, Value>;
+
+/// Build the InfluxQL that reads a page of old points, oldest first
+pub fn page_query(migrate: &MigrateArgs, offset: usize) -> String {
+    let since: String = match migrate.since {
+        Some(since) => format!(" WHERE time >= {}", quote_literal(&since.to_rfc3339_opts(SecondsFormat::Nanos, true))),
+        None => String::new(),
+    };
+    format!("SELECT * FROM {}{} ORDER BY time ASC LIMIT {} OFFSET {}", quote_identifier(&migrate.from), since, MIGRATE_PAGE_SIZE, offset)
+}
+
+/// Get a column as a number, whether InfluxDB returned it as a number or a string
+fn number(row: &Row, column: &str) -> Option<f64> {
+    match row.get(column)? {
+        Value::Number(number) => number.as_f64(),
+        Value::String(text) => text.trim().parse::<f64>().ok(),
+        _ => None,
+    }
+}
+
+/// Rebuild a reading from an old point, following the renames in field_names and every schema version since the one it was written in<br>
+/// Points without a location tag are given default_location, and pollutants the point has no field for are left out.
+///
+/// # Errors
+/// Returns a message if the point has no valid time or aqi field
+pub fn upgrade_row(row: &Row, field_names: &FieldNames, default_location: &str) -> Result<PollUpdate, String> {
+    let time: DateTime<Utc> = match row.get("time").and_then(Value::as_str).and_then(|time| DateTime::parse_from_rfc3339(time).ok()) {
+        Some(time) => time.with_timezone(&Utc),
+        None => return Err(format!("Point has no valid time: {:?}", row.get("time"))),
+    };
+    let version: i64 = number(row, schema::SCHEMA_VERSION_FIELD).map(|version| version as i64).unwrap_or(1);
+    let mut fields: BTreeMap<&str, f64> = BTreeMap::new();
+    for column in row.keys() {
+        let client_name: &str = field_names.iter().find(|(_, stored)| *stored == column).map(|(field, _)| field.as_str()).unwrap_or(column);
+        let current: &str = schema::upgrade_field_name(version, client_name);
+        if let Some(value) = number(row, column).filter(|_| schema::FIELDS.contains(&current) || Index::ALL.iter().any(|index| index.field() == current)) {
+            fields.insert(current, value);
+        }
+    }
+    let field = |name: &str| fields.get(name).copied().ok_or_else(|| format!("Point at {} has no {} field", time, name));
+    let location: String = match row.get("location").and_then(Value::as_str) {
+        Some(location) if !location.is_empty() => location.to_string(),
+        _ => default_location.to_string(),
+    };
+    Ok(PollUpdate {
+        time,
+        location,
+        aqi: field("aqi")?.round() as i8,
+        co: fields.get("co").map(|value| *value as f32),
+        no: fields.get("no").map(|value| *value as f32),
+        no2: fields.get("no2").map(|value| *value as f32),
+        o3: fields.get("o3").map(|value| *value as f32),
+        so2: fields.get("so2").map(|value| *value as f32),
+        pm2_5: fields.get("pm2_5").map(|value| *value as f32),
+        pm10: fields.get("pm10").map(|value| *value as f32),
+        nh3: fields.get("nh3").map(|value| *value as f32),
+        interpolated: row.get("interpolated").and_then(Value::as_str) == Some("true"),
+        reference: row.get("source").and_then(Value::as_str) == Some("reference"),
+        forecast: row.get("forecast").and_then(Value::as_str) == Some("true"),
+        collected: number(row, schema::COLLECTED_AT_FIELD).and_then(|seconds| DateTime::from_timestamp(seconds as i64, 0)),
+        indices: Index::ALL.iter().filter_map(|index| fields.get(index.field()).map(|value| (index.field().to_string(), *value as f32))).collect(),
+        health: None,
+        trends: BTreeMap::new(),
+    })
+}
+
+/// Read every old point the migration asks for from InfluxDB and write it to the new measurement in the current schema, printing progress after each page
+///
+/// # Errors
+/// Returns a message if the target is the same as the source, or reading or writing a page fails. Points migrated before then stay written.
+pub async fn run(dbclient: &Client, current_config: &Config, migrate: &MigrateArgs) -> Result<MigrateSummary, String> {
+    let target_database: String = migrate.database.clone().unwrap_or(current_config.get_dbname());
+    if migrate.to.is_empty() || (migrate.to == migrate.from && target_database == current_config.get_dbname()) {
+        return Err("migrate-schema needs a --to measurement, different from --from unless --database is given.".to_string());
+    }
+    let mut target_config: Config = current_config.clone();
+    target_config.set_dbname(target_database.clone());
+    let target_client: Client = quiet_client(&target_config)?;
+    let default_location: &str = migrate.location.as_deref().unwrap_or(current_config.get_location());
+    let mut summary: MigrateSummary = MigrateSummary::default();
+    let mut offset: usize = 0;
+    loop {
+        let rows: Vec<Row> = influx::read_rows(dbclient, page_query(migrate, offset)).await
+            .map_err(|e: Error| format!("Unable to read points from {}: {}", migrate.from, e))?;
+        let mut queries: Vec<WriteQuery> = Vec::new();
+        for row in &rows {
+            match upgrade_row(row, &migrate.field_names, default_location) {
+                Ok(update) => queries.push(update.to_named_write_query(&migrate.to, current_config.get_precision(), current_config.get_field_names())),
+                Err(e) => {
+                    report::debug(&format!("Skipping point: {}", e));
+                    summary.skipped += 1;
+                },
+            }
+        }
+        let written: usize = queries.len();
+        if !queries.is_empty() {
+            target_client.query(queries).await.map_err(|e: Error| format!("Unable to write migrated points to {} in {}: {}", migrate.to, target_database, e))?;
+        }
+        summary.migrated += written;
+        offset += rows.len();
+        report::info(&format!("Migrated {} points to {} so far, skipped {}.", summary.migrated, migrate.to, summary.skipped));
+        if rows.len() < MIGRATE_PAGE_SIZE {
+            return Ok(summary);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(json: &str) -> Row {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn page_query_reads_in_order() {
+        let migrate: MigrateArgs = MigrateArgs { to: "pollution_v2".to_string(), since: DateTime::from_timestamp(1700000000, 0), ..MigrateArgs::default() };
+        assert_eq!(page_query(&migrate, 2000), "SELECT * FROM \"pollution\" WHERE time >= '2023-11-14T22:13:20.000000000Z' ORDER BY time ASC LIMIT 1000 OFFSET 2000");
+    }
+
+    #[test]
+    fn upgrade_row_follows_renames() {
+        let names: FieldNames = schema::parse_field_names("pm2_5=pm25,aqi=owm_aqi").unwrap();
+        let old: Row = row(r#"{"time":"2023-11-14T22:13:20Z","location":null,"owm_aqi":3,"co":1.5,"no":0,"no2":0,"o3":0,"so2":0,"pm25":12.5,"pm10":0,"nh3":0}"#);
+        let update: PollUpdate = upgrade_row(&old, &names, "Home").unwrap();
+        assert_eq!(update.get_location(), "Home");
+        assert_eq!(update.get_aqi(), 3);
+        assert_eq!(update.get_field("pm2_5"), Some(12.5));
+        assert!(!update.is_interpolated());
+        let missing: Row = row(r#"{"time":"2023-11-14T22:13:20Z","location":"Work","aqi":3}"#);
+        assert_eq!(upgrade_row(&missing, &FieldNames::new(), "Home").unwrap().get_field("co"), None);
+        let no_aqi: Row = row(r#"{"time":"2023-11-14T22:13:20Z","location":"Work","co":1.5}"#);
+        assert_eq!(upgrade_row(&no_aqi, &FieldNames::new(), "Home").unwrap_err(), "Point at 2023-11-14 22:13:20 UTC has no aqi field");
+        assert!(upgrade_row(&row(r#"{"aqi":3}"#), &FieldNames::new(), "Home").is_err());
+    }
+}
@@ -0,0 +1,210 @@
+//! Optional MQTT sink, publishing every reading as JSON so it reaches Home Assistant or anything else on the same broker.<br>
+//! The connection is kept by rumqttc's event loop, started on the first write and reconnecting on its own if the broker goes away.
+//! With OPENWEATHER_MQTT_DISCOVERY on, Home Assistant discovery messages are published for each location before its first reading, so its sensors appear on their own.
+
+use std::collections::BTreeSet;
+use std::sync::Mutex;
+use std::time::Duration;
+use async_trait::async_trait;
+use rumqttc::{AsyncClient, EventLoop, MqttOptions, QoS};
+use serde_json::json;
+use crate::plugin::{PluginError, Sink};
+use crate::trend::POLLUTANTS;
+use crate::{i18n, report, Config, PollUpdate};
+
+/// Name the MQTT sink is registered under
+pub const MQTT_SINK: &str = "mqtt";
+/// Port used when OPENWEATHER_MQTT_HOST does not give one
+pub const DEFAULT_MQTT_PORT: u16 = 1883;
+/// How many publishes can wait for the event loop before a write fails
+const QUEUE_CAPACITY: usize = 64;
+/// How long to wait before polling the event loop again after the connection fails
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// Topic prefix Home Assistant listens for discovery messages under
+pub const DISCOVERY_PREFIX: &str = "homeassistant";
+
+/// Publishes each update to OPENWEATHER_MQTT_TOPIC on the OPENWEATHER_MQTT_HOST broker<br>
+/// Hours of the forecast are not published, as subscribers take the latest message on the topic as the current reading.
+pub struct MqttSink {
+    client: AsyncClient,
+    eventloop: Mutex<Option<EventLoop>>,
+    topic: String,
+    discovery: bool,
+    discovered: Mutex<BTreeSet<String>>,
+}
+
+impl MqttSink {
+    /// Create the sink for the broker in a Config. Nothing is sent until the first write.
+    ///
+    /// # Errors
+    /// Returns a message if OPENWEATHER_MQTT_HOST is not set or cannot be read
+    pub fn new(config: &Config) -> Result<MqttSink, String> {
+        let host: &str = config.get_mqtt_host().ok_or("OPENWEATHER_SINKS includes mqtt but OPENWEATHER_MQTT_HOST is not set.")?;
+        let (hostname, port) = parse_host(host)?;
+        let mut options: MqttOptions = MqttOptions::new(format!("pollutionclient_rs-{}", config.get_instance_id()), hostname, port);
+        options.set_keep_alive(Duration::from_secs(30));
+        if let Some((user, pass)) = config.get_mqtt_credentials() {
+            options.set_credentials(user, pass);
+        }
+        let (client, eventloop) = AsyncClient::new(options, QUEUE_CAPACITY);
+        Ok(MqttSink { client, eventloop: Mutex::new(Some(eventloop)), topic: config.get_mqtt_topic().to_string(),
+            discovery: config.get_mqtt_discovery(), discovered: Mutex::new(BTreeSet::new()) })
+    }
+    /// Start driving the connection the first time this is called
+    fn connect(&self) {
+        let eventloop: Option<EventLoop> = self.eventloop.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).take();
+        if let Some(eventloop) = eventloop {
+            tokio::spawn(drive(eventloop));
+        }
+    }
+    /// Check if discovery messages are still to be sent for a location, marking them as sent
+    fn needs_discovery(&self, location: &str) -> bool {
+        self.discovery && self.discovered.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).insert(location.to_string())
+    }
+}
+
+/// Poll the event loop for as long as the program runs, which sends queued publishes and reconnects when needed
+async fn drive(mut eventloop: EventLoop) {
+    loop {
+        if let Err(e) = eventloop.poll().await {
+            report::warn(&format!("MQTT connection failed, retrying in {}s: {}", RECONNECT_DELAY.as_secs(), e));
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    }
+}
+
+#[async_trait]
+impl Sink for MqttSink {
+    fn name(&self) -> &str {
+        MQTT_SINK
+    }
+    async fn write(&self, updates: &[PollUpdate]) -> Result<usize, PluginError> {
+        self.connect();
+        let mut bytes: usize = 0;
+        for update in updates.iter().filter(|update| !update.is_forecast()) {
+            if self.needs_discovery(update.get_location()) {
+                for (topic, config) in discovery_messages(&self.topic, update.get_location()) {
+                    bytes += config.len();
+                    self.client.publish(topic, QoS::AtLeastOnce, true, config).await?;
+                }
+                report::info(&format!("Published Home Assistant discovery for {}", update.get_location()));
+            }
+            let payload: String = serde_json::to_string(update)?;
+            let topic: String = topic_for(&self.topic, update.get_location());
+            bytes += payload.len();
+            report::debug(&format!("Publishing {} bytes to MQTT topic {}", payload.len(), topic));
+            self.client.try_publish(topic, QoS::AtLeastOnce, true, payload)?;
+        }
+        Ok(bytes)
+    }
+}
+
+/// Split a broker address into its host and port, ex: "mqtt://broker:1883" or "broker"
+///
+/// # Errors
+/// Returns a message if the host is blank or the port is not a number
+pub fn parse_host(raw_host: &str) -> Result<(String, u16), String> {
+    let trimmed: &str = raw_host.trim();
+    let address: &str = trimmed.strip_prefix("mqtt://").or_else(|| trimmed.strip_prefix("tcp://")).unwrap_or(trimmed).trim_end_matches('/');
+    let (host, port) = match address.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse::<u16>().map_err(|_| format!("Invalid port in OPENWEATHER_MQTT_HOST of {}.", raw_host))?),
+        None => (address, DEFAULT_MQTT_PORT),
+    };
+    if host.is_empty() {
+        return Err(format!("No host in OPENWEATHER_MQTT_HOST of {}.", raw_host));
+    }
+    Ok((host.to_string(), port))
+}
+
+/// Fill in the {location} in a topic, in lower case with anything other than letters and digits as underscores
+pub fn topic_for(template: &str, location: &str) -> String {
+    let slug: String = location.trim().to_lowercase().chars().map(|character| if character.is_alphanumeric() { character } else { '_' }).collect();
+    template.replace("{location}", &slug)
+}
+
+/// Get the Home Assistant device class and unit of a field, ex: "pm25" and "µg/m³" for "pm2_5"<br>
+/// Pollutants Home Assistant has no class for in µg/m³, ex: nh3, are left without one.
+fn sensor_class(field: &str) -> (Option<&'static str>, Option<&'static str>) {
+    let class: Option<&'static str> = match field {
+        "aqi" => return (Some("aqi"), None),
+        "pm2_5" => Some("pm25"),
+        "pm10" => Some("pm10"),
+        "o3" => Some("ozone"),
+        "no" => Some("nitrogen_monoxide"),
+        "no2" => Some("nitrogen_dioxide"),
+        "so2" => Some("sulphur_dioxide"),
+        _ => None,
+    };
+    (class, Some("µg/m³"))
+}
+
+/// Build the discovery topic and config of a sensor for the AQI and every pollutant at a location, all reading from its state topic
+pub fn discovery_messages(template: &str, location: &str) -> Vec<(String, String)> {
+    let slug: String = topic_for("{location}", location);
+    let state_topic: String = topic_for(template, location);
+    std::iter::once("aqi").chain(POLLUTANTS).map(|field| {
+        let unique_id: String = format!("pollutionclient_rs_{}_{}", slug, field);
+        let (device_class, unit) = sensor_class(field);
+        let mut config = json!({
+            "name": i18n::pollutant_name(i18n::current(), field),
+            "unique_id": unique_id,
+            "object_id": unique_id,
+            "state_topic": state_topic,
+            "value_template": format!("{{{{ value_json.{} }}}}", field),
+            "state_class": "measurement",
+            "device": {
+                "identifiers": [format!("pollutionclient_rs_{}", slug)],
+                "name": format!("Air quality {}", location),
+                "manufacturer": "OpenWeatherMap",
+            },
+        });
+        if let Some(device_class) = device_class {
+            config["device_class"] = json!(device_class);
+        }
+        if let Some(unit) = unit {
+            config["unit_of_measurement"] = json!(unit);
+        }
+        (format!("{}/sensor/{}/config", DISCOVERY_PREFIX, unique_id), config.to_string())
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_host_forms() {
+        assert_eq!(parse_host("mqtt://homeassistant.local:1884"), Ok(("homeassistant.local".to_string(), 1884)));
+        assert_eq!(parse_host("broker"), Ok(("broker".to_string(), DEFAULT_MQTT_PORT)));
+        assert_eq!(parse_host("tcp://10.0.0.2/"), Ok(("10.0.0.2".to_string(), DEFAULT_MQTT_PORT)));
+        assert!(parse_host("broker:mqtt").is_err());
+        assert!(parse_host("mqtt://:1883").is_err());
+    }
+
+    #[test]
+    fn topic_uses_location() {
+        assert_eq!(topic_for(crate::DEFAULT_MQTT_TOPIC, "Beverly Hills"), "pollution/beverly_hills".to_string());
+        assert_eq!(topic_for("home/air", "Beverly Hills"), "home/air".to_string());
+    }
+
+    #[test]
+    fn discovery_for_each_field() {
+        let messages: Vec<(String, String)> = discovery_messages(crate::DEFAULT_MQTT_TOPIC, "Beverly Hills");
+        assert_eq!(messages.len(), 1 + POLLUTANTS.len());
+        assert_eq!(messages[0].0, "homeassistant/sensor/pollutionclient_rs_beverly_hills_aqi/config".to_string());
+        assert!(!messages[0].1.contains("unit_of_measurement"));
+        let pm2_5: serde_json::Value = serde_json::from_str(&messages.iter().find(|(topic, _)| topic.contains("_pm2_5/")).unwrap().1).unwrap();
+        assert_eq!(pm2_5["device_class"], "pm25");
+        assert_eq!(pm2_5["unit_of_measurement"], "µg/m³");
+        assert_eq!(pm2_5["state_topic"], "pollution/beverly_hills");
+        assert_eq!(pm2_5["value_template"], "{{ value_json.pm2_5 }}");
+        assert_eq!(pm2_5["device"]["identifiers"][0], "pollutionclient_rs_beverly_hills");
+        let nh3: serde_json::Value = serde_json::from_str(&messages.last().unwrap().1).unwrap();
+        assert!(nh3.get("device_class").is_none());
+    }
+
+    #[test]
+    fn sink_needs_host() {
+        assert_eq!(MqttSink::new(&Config::default()).err().unwrap(), "OPENWEATHER_SINKS includes mqtt but OPENWEATHER_MQTT_HOST is not set.");
+    }
+}
@@ -0,0 +1,96 @@
+//! A provider reading official monitors through OpenAQ's v3 API, which gathers government stations from around the world and is free to use with an API key.<br>
+//! Set OPENWEATHER_SOURCE to "openaq" and OPENWEATHER_OPENAQ_API_KEY to poll the closest reference-grade monitor to each location instead of OpenWeatherMaps' model.
+//! Each location's station is found the first time it is polled. Values are converted to µg/m³ and the 1 to 5 AQI is worked out from them, while pollutants the station does not measure are left out of the reading.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use crate::plugin::PluginError;
+use crate::provider::{Place, PollutionProvider};
+use crate::reference::{self, Coordinates, Latest, Station};
+use crate::{report, Config, PollUpdate};
+
+/// Name of the OpenAQ provider for OPENWEATHER_SOURCE
+pub const OPENAQ_SOURCE: &str = "openaq";
+
+/// Latest readings from the closest OpenAQ monitor to each place
+pub struct OpenAqProvider {
+    apikey: String,
+    stations: Mutex<BTreeMap<String, Station>>,
+}
+
+impl OpenAqProvider {
+    /// Create the provider for the OpenAQ API key in a Config
+    ///
+    /// # Errors
+    /// Returns a message if OPENWEATHER_OPENAQ_API_KEY is not set
+    pub fn new(config: &Config) -> Result<OpenAqProvider, String> {
+        match config.get_openaq_key() {
+            Some(apikey) => Ok(OpenAqProvider { apikey: apikey.to_string(), stations: Mutex::new(BTreeMap::new()) }),
+            None => Err(format!("OPENWEATHER_SOURCE is {} but OPENWEATHER_OPENAQ_API_KEY is not set.", OPENAQ_SOURCE)),
+        }
+    }
+    /// Get the station polled for a place, looking up the closest one the first time. A failed lookup is tried again on the next poll.
+    ///
+    /// # Errors
+    /// Returns a message if OpenAQ cannot be reached or has no monitor close enough
+    fn station_for(&self, place: &Place) -> Result<Station, String> {
+        let mut stations = self.stations.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(station) = stations.get(&place.name) {
+            return Ok(station.clone());
+        }
+        let here: Coordinates = Coordinates { latitude: place.lat, longitude: place.lon };
+        let station: Station = reference::find_nearest(&self.apikey, here, &place.name)?;
+        report::info(&format!("Polling OpenAQ station {} for {}, {:.1} km away.", station.label(), place.name, reference::distance_km(station.coordinates, here)));
+        stations.insert(place.name.clone(), station.clone());
+        Ok(station)
+    }
+}
+
+impl PollutionProvider for OpenAqProvider {
+    fn name(&self) -> &str {
+        OPENAQ_SOURCE
+    }
+    fn fetch(&self, place: &Place) -> Result<Vec<PollUpdate>, PluginError> {
+        let station: Station = self.station_for(place)?;
+        let latest: Vec<Latest> = reference::get_results(&reference::latest_url(station.id), &self.apikey)?;
+        Ok(vec![reference::latest_reading(&station, &latest)?])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugin::Registry;
+    use crate::redact::Secret;
+
+    #[test]
+    fn needs_an_api_key() {
+        assert_eq!(OpenAqProvider::new(&Config::default()).err().unwrap(), "OPENWEATHER_SOURCE is openaq but OPENWEATHER_OPENAQ_API_KEY is not set.");
+        let keyed: Config = Config { source: OPENAQ_SOURCE.to_string(), openaq_key: Some(Secret::new("openaq-key".to_string())), ..Config::default() };
+        let provider: OpenAqProvider = OpenAqProvider::new(&keyed).unwrap();
+        assert_eq!(provider.name(), OPENAQ_SOURCE);
+        assert_eq!(Registry::default().build_source(&keyed).unwrap().name(), OPENAQ_SOURCE);
+    }
+
+    #[test]
+    fn latest_values_become_a_reading() {
+        let station: Station = serde_json::from_str(r#"{"id":8118,"name":"Beacon Hill","coordinates":{"latitude":47.5683,"longitude":-122.3081},
+            "sensors":[{"id":1,"parameter":{"name":"pm25","units":"µg/m³"}},{"id":2,"parameter":{"name":"no2","units":"ppb"}}]}"#).unwrap();
+        let latest: Vec<Latest> = serde_json::from_str(r#"[{"datetime":{"utc":"2023-11-14T22:00:00Z"},"value":30.0,"sensorsId":1},{"datetime":{"utc":"2023-11-14T22:00:00Z"},"value":10.0,"sensorsId":2}]"#).unwrap();
+        let update: PollUpdate = reference::latest_reading(&station, &latest).unwrap();
+        assert!(!update.is_reference());
+        assert_eq!((update.get_location(), update.get_aqi(), update.get_field("pm2_5")), ("pending", 3, Some(30.0)));
+        assert!((update.get_field("no2").unwrap() - 18.82).abs() < 0.01);
+    }
+
+    #[test]
+    fn only_reported_parameters_written() {
+        let station: Station = serde_json::from_str(r#"{"id":8118,"name":"Beacon Hill","coordinates":{"latitude":47.5683,"longitude":-122.3081},
+            "sensors":[{"id":1,"parameter":{"name":"pm25","units":"µg/m³"}},{"id":2,"parameter":{"name":"o3","units":"ppm"}}]}"#).unwrap();
+        let latest: Vec<Latest> = serde_json::from_str(r#"[{"datetime":{"utc":"2023-11-14T22:00:00Z"},"value":4.0,"sensorsId":1}]"#).unwrap();
+        let update: PollUpdate = reference::latest_reading(&station, &latest).unwrap();
+        assert_eq!((update.get_aqi(), update.get_field("o3"), update.get_field("no2")), (1, None, None));
+        let line: String = influxdb::Query::build(&update.to_write_query(crate::POLLUTION_MEASUREMENT, crate::influx::Precision::Seconds)).unwrap().get();
+        assert_eq!(line, "pollution,location=pending aqi=1i,pm2_5=4,schema_version=7i 1699999200");
+    }
+}
@@ -0,0 +1,224 @@
+//! The stages of the polling loop and the queues connecting them.<br>
+//! The source stage fetches a reading every poll, the processor stage runs transforms, hooks, alerts and duplicate checks on it, and the sink stage writes it.
+//! Each stage only sees the queue before it and the queue after it, so it can be run and tested on its own with queues filled by hand.
+//! Every stage counts the items it takes in, passes on, filters out and fails on, and how long each took, labelled with the stage name.
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use crate::queue::Queue;
+use crate::{metrics, report, PollUpdate};
+
+/// Name of the stage fetching readings
+pub const SOURCE_STAGE: &str = "source";
+/// Name of the stage transforming and checking readings
+pub const PROCESSOR_STAGE: &str = "processor";
+/// Name of the stage writing readings
+pub const SINK_STAGE: &str = "sink";
+
+/// Name of the queue from the source stage to the processor stage
+pub const POLLED_QUEUE: &str = "polled";
+/// Name of the queue from the processor stage to the sink stage
+pub const WRITE_QUEUE: &str = "write";
+
+/// A reading on its way from the source stage to the processor stage
+#[derive(Clone, Debug)]
+pub struct Polled {
+    /// The reading as fetched, with its location set
+    pub update: PollUpdate,
+    /// When the source stage will poll next, for the status line
+    pub next_poll: Option<DateTime<Utc>>,
+}
+
+/// What became of one item in a stage
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Handled {
+    /// It was passed on to the next stage, or written by the last one
+    Passed,
+    /// The stage decided it should go no further, ex: a transform dropped it or it was already written
+    Filtered,
+    /// The stage failed on it
+    Failed,
+}
+
+/// Record an item going through a stage<br>
+/// Counts it in pollution_pipeline_items_in_total and one of pollution_pipeline_items_out_total, pollution_pipeline_items_filtered_total or pollution_pipeline_errors_total.
+/// The time it took is kept in the pollution_pipeline_latency_seconds gauge and added to pollution_pipeline_latency_seconds_total, so the in total gives the average.
+pub fn record_item(stage: &str, handled: Handled, elapsed: Duration) {
+    let labels: [(&str, &str); 1] = [("stage", stage)];
+    metrics::increment("pollution_pipeline_items_in_total", &labels);
+    match handled {
+        Handled::Passed => metrics::increment("pollution_pipeline_items_out_total", &labels),
+        Handled::Filtered => metrics::increment("pollution_pipeline_items_filtered_total", &labels),
+        Handled::Failed => metrics::increment("pollution_pipeline_errors_total", &labels),
+    };
+    metrics::set_gauge("pollution_pipeline_latency_seconds", &labels, elapsed.as_secs_f64());
+    metrics::add("pollution_pipeline_latency_seconds_total", &labels, elapsed.as_secs_f64());
+}
+
+/// The counts and latest latency recorded for a stage
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct StageStats {
+    /// Items the stage has taken in
+    pub items_in: u64,
+    /// Items passed on to the next stage, or written by the last one
+    pub items_out: u64,
+    /// Items the stage decided should go no further
+    pub filtered: u64,
+    /// Items the stage failed on
+    pub errors: u64,
+    /// How long the latest item took
+    pub latency_seconds: f64,
+}
+
+/// Everything recorded about the pipeline: the stats for each stage and the depth of each queue
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct PipelineStats {
+    /// Stats for each stage by name
+    pub stages: BTreeMap<String, StageStats>,
+    /// Items waiting in each queue by name
+    pub queues: BTreeMap<String, u64>,
+}
+
+/// Read back what has been recorded for a stage. A stage that has seen nothing reports zeros.
+pub fn stage_stats(stage: &str) -> StageStats {
+    let labels: [(&str, &str); 1] = [("stage", stage)];
+    let count = |name: &str| metrics::get(name, &labels).unwrap_or(0.0) as u64;
+    StageStats {
+        items_in: count("pollution_pipeline_items_in_total"),
+        items_out: count("pollution_pipeline_items_out_total"),
+        filtered: count("pollution_pipeline_items_filtered_total"),
+        errors: count("pollution_pipeline_errors_total"),
+        latency_seconds: metrics::get("pollution_pipeline_latency_seconds", &labels).unwrap_or(0.0),
+    }
+}
+
+/// Read back the stats for the source, processor and sink stages and the depth of the queues between them
+pub fn stats() -> PipelineStats {
+    let stages: BTreeMap<String, StageStats> = [SOURCE_STAGE, PROCESSOR_STAGE, SINK_STAGE].iter()
+        .map(|stage| (stage.to_string(), stage_stats(stage)))
+        .collect();
+    let queues: BTreeMap<String, u64> = [POLLED_QUEUE, WRITE_QUEUE].iter()
+        .map(|queue| (queue.to_string(), metrics::get("pollution_queue_depth", &[("queue", queue)]).unwrap_or(0.0) as u64))
+        .collect();
+    PipelineStats { stages, queues }
+}
+
+/// Run a stage between two queues until its input is closed and empty<br>
+/// Every item popped is handled, and anything it returns is pushed to the output, with items the output drops reported on the console.
+/// The output is closed when the stage stops so the next stage can finish. If handling fails the input is closed too, so the stage before stops as well.
+///
+/// # Errors
+/// Returns the first error from handling an item
+pub async fn connect<I, O, E, F, Fut>(stage: &str, input: &Queue<I>, output: &Queue<O>, mut handle: F) -> Result<(), E>
+where
+    F: FnMut(I) -> Fut,
+    Fut: Future<Output = Result<Option<O>, E>>,
+{
+    let connected: Result<(), E> = async {
+        while let Some(item) = input.pop().await {
+            let started: Instant = Instant::now();
+            let handled: Result<Option<O>, E> = handle(item).await;
+            match handled {
+                Ok(Some(handled)) => {
+                    record_item(stage, Handled::Passed, started.elapsed());
+                    if output.push(handled).await.is_some() {
+                        report::warn(&format!("Queue {} is full, dropped an update.", output.name()));
+                    }
+                },
+                Ok(None) => record_item(stage, Handled::Filtered, started.elapsed()),
+                Err(e) => {
+                    record_item(stage, Handled::Failed, started.elapsed());
+                    return Err(e);
+                },
+            }
+        }
+        Ok(())
+    }.await;
+    if connected.is_err() {
+        input.close();
+    }
+    output.close();
+    connected
+}
+
+/// Run the last stage on a queue until it is closed and empty, handling every item popped
+///
+/// # Errors
+/// Returns the first error from handling an item, after closing the queue so the stage before stops
+pub async fn drain<I, E, F, Fut>(stage: &str, input: &Queue<I>, mut handle: F) -> Result<(), E>
+where
+    F: FnMut(I) -> Fut,
+    Fut: Future<Output = Result<(), E>>,
+{
+    while let Some(item) = input.pop().await {
+        let started: Instant = Instant::now();
+        if let Err(e) = handle(item).await {
+            record_item(stage, Handled::Failed, started.elapsed());
+            input.close();
+            return Err(e);
+        }
+        record_item(stage, Handled::Passed, started.elapsed());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::QueuePolicy;
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn connect_passes_kept_items_on() {
+        let input: Queue<u32> = Queue::new("test_in", 8, QueuePolicy::Block);
+        let output: Queue<u32> = Queue::new("test_out", 8, QueuePolicy::Block);
+        for item in 1..=4 {
+            input.push(item).await;
+        }
+        input.close();
+        let connected: Result<(), String> = connect("test_connect", &input, &output, |item| async move { Ok(if item % 2 == 0 { Some(item * 10) } else { None }) }).await;
+        assert!(connected.is_ok());
+        assert!(output.is_closed());
+        assert_eq!(output.pop().await, Some(20));
+        assert_eq!(output.pop().await, Some(40));
+        assert_eq!(output.pop().await, None);
+        assert_eq!(metrics::get("pollution_pipeline_items_in_total", &[("stage", "test_connect")]), Some(4.0));
+        assert_eq!(metrics::get("pollution_pipeline_items_out_total", &[("stage", "test_connect")]), Some(2.0));
+        assert_eq!(metrics::get("pollution_pipeline_items_filtered_total", &[("stage", "test_connect")]), Some(2.0));
+        assert_eq!(stage_stats("test_connect"), StageStats { items_in: 4, items_out: 2, filtered: 2, errors: 0, latency_seconds: stage_stats("test_connect").latency_seconds });
+        assert_eq!(stage_stats("test_unused"), StageStats::default());
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn failed_stage_closes_both_sides() {
+        let input: Queue<u32> = Queue::new("test_in", 8, QueuePolicy::Block);
+        let output: Queue<u32> = Queue::new("test_out", 8, QueuePolicy::Block);
+        input.push(1).await;
+        let connected: Result<(), String> = connect("test_failed", &input, &output, |item| async move { Err(format!("bad item {}", item)) }).await;
+        assert_eq!(connected, Err("bad item 1".to_string()));
+        assert!(input.is_closed());
+        assert!(output.is_closed());
+        assert_eq!(metrics::get("pollution_pipeline_errors_total", &[("stage", "test_failed")]), Some(1.0));
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn drain_stops_at_first_error() {
+        let input: Queue<u32> = Queue::new("test_in", 8, QueuePolicy::Block);
+        for item in 1..=3 {
+            input.push(item).await;
+        }
+        let mut seen: Vec<u32> = Vec::new();
+        let drained: Result<(), String> = drain("test_drain", &input, |item| {
+            seen.push(item);
+            async move { if item == 2 { Err("refused".to_string()) } else { Ok(()) } }
+        }).await;
+        assert!(drained.is_err());
+        assert_eq!(seen, vec![1, 2]);
+        assert!(input.is_closed());
+        assert_eq!(input.depth(), 1);
+        assert_eq!(metrics::get("pollution_pipeline_items_out_total", &[("stage", "test_drain")]), Some(1.0));
+        assert_eq!(metrics::get("pollution_pipeline_errors_total", &[("stage", "test_drain")]), Some(1.0));
+    }
+}
@@ -0,0 +1,383 @@
+//! Sources and sinks that can be swapped by name.<br>
+//! A Source produces updates and a Sink stores them. Both are built from a Config by factories kept in a Registry, so other crates can add their own backends by implementing a trait and registering it at startup.
+//! A Transform sits between them and can change or drop each update.
+//! Data sources that can be asked about any place are easier to add as a provider::PollutionProvider, registered with register_provider, which polls every location for them.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use influxdb::Client;
+use serde::Serialize;
+use crate::defra::{DefraSource, DEFRA_SOURCE};
+use crate::eea::{EeaSource, EEA_SOURCE};
+use crate::openaq::{OpenAqProvider, OPENAQ_SOURCE};
+use crate::provider::{OpenWeatherProvider, PollutionProvider, ProviderSource};
+use crate::waqi::{WaqiProvider, WAQI_SOURCE};
+use crate::{influx, metrics, quiet_client, report, Config, PollUpdate};
+
+/// Name of the built in OpenWeatherMaps source and the default for OPENWEATHER_SOURCE
+pub const OPENWEATHER_SOURCE: &str = "openweathermap";
+/// Name of the built in InfluxDB sink and the default for OPENWEATHER_SINKS
+pub const INFLUXDB_SINK: &str = "influxdb";
+
+/// Extra HTTP headers a sink sends with every request, as name and value pairs in the order given
+pub type Headers = Vec<(String, String)>;
+
+/// Any error a Source or Sink wants to pass back
+pub type PluginError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Produces pollution updates, ex: by calling an API
+pub trait Source: Send + Sync {
+    /// Get the name this source was registered under
+    fn name(&self) -> &str;
+    /// Get the current update. The location is filled in by the client afterwards unless the source sets one.
+    ///
+    /// # Errors
+    /// Any error reaching or understanding the backend
+    fn fetch(&self) -> Result<PollUpdate, PluginError>;
+    /// Get every update for one poll, ex: one for each grid point. Defaults to the single update from fetch.
+    ///
+    /// # Errors
+    /// Any error reaching or understanding the backend
+    fn fetch_all(&self) -> Result<Vec<PollUpdate>, PluginError> {
+        Ok(vec![self.fetch()?])
+    }
+    /// Get every update between two times, oldest first, so gaps can be filled. The location is filled in by the client afterwards unless the source sets one.
+    ///
+    /// # Errors
+    /// Any error reaching or understanding the backend. Sources that cannot look back return an error, which is the default.
+    fn fetch_range(&self, _start: DateTime<Utc>, _end: DateTime<Utc>) -> Result<Vec<PollUpdate>, PluginError> {
+        Err(format!("Source {} cannot fetch past readings", self.name()).into())
+    }
+    /// Get the hourly forecast, oldest first, so alerts can be raised ahead of time. The location is filled in by the client afterwards unless the source sets one.
+    ///
+    /// # Errors
+    /// Any error reaching or understanding the backend. Sources without a forecast return an error, which is the default.
+    fn fetch_forecast(&self) -> Result<Vec<PollUpdate>, PluginError> {
+        Err(format!("Source {} cannot fetch a forecast", self.name()).into())
+    }
+}
+
+/// Stores pollution updates, ex: in a database
+#[async_trait]
+pub trait Sink: Send + Sync {
+    /// Get the name this sink was registered under
+    fn name(&self) -> &str;
+    /// Store updates, returning the number of bytes sent or 0 if the sink cannot tell
+    ///
+    /// # Errors
+    /// Any error reaching or writing to the backend
+    async fn write(&self, updates: &[PollUpdate]) -> Result<usize, PluginError>;
+}
+
+/// What happened when a sink was handed updates, reported after every write so throughput can be tracked
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct WriteEvent {
+    /// When the write finished
+    pub time: DateTime<Utc>,
+    /// Name of the sink written to
+    pub sink: String,
+    /// Number of points handed to the sink
+    pub points: usize,
+    /// Bytes the sink reported sending, 0 on failure
+    pub bytes: usize,
+    /// How long the write took
+    #[serde(rename = "latency_seconds", serialize_with = "serialize_seconds")]
+    pub latency: Duration,
+    /// True if the sink accepted the write
+    pub success: bool,
+}
+
+impl WriteEvent {
+    /// Render the event as a single line of JSON, ex: {"event":"write","sink":"influxdb","points":1,...}
+    pub fn to_json(&self) -> String {
+        let mut event: serde_json::Value = serde_json::to_value(self).unwrap_or_default();
+        if let Some(fields) = event.as_object_mut() {
+            fields.insert("event".to_string(), serde_json::Value::from("write"));
+        }
+        event.to_string()
+    }
+    /// Add the event to the per-sink counters in the metrics registry
+    pub fn record(&self) {
+        let result: &str = if self.success { "ok" } else { "error" };
+        metrics::increment("pollution_sink_writes_total", &[("sink", &self.sink), ("result", result)]);
+        metrics::add("pollution_sink_points_total", &[("sink", &self.sink)], self.points as f64);
+        metrics::add("pollution_sink_bytes_total", &[("sink", &self.sink)], self.bytes as f64);
+        metrics::set_gauge("pollution_sink_write_seconds", &[("sink", &self.sink)], self.latency.as_secs_f64());
+    }
+}
+
+fn serialize_seconds<S: serde::Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+/// Changes or filters each update between the source and the sinks
+pub trait Transform: Send + Sync {
+    /// Get a name for the transform to use in messages
+    fn name(&self) -> &str;
+    /// Return the update to keep, changed or not, or None to drop it so nothing is written this cycle
+    ///
+    /// # Errors
+    /// Any error running the transform
+    fn apply(&self, update: PollUpdate) -> Result<Option<PollUpdate>, PluginError>;
+}
+
+/// Builds a Source from a Config
+pub type SourceFactory = Box<dyn Fn(&Config) -> Result<Box<dyn Source>, String> + Send + Sync>;
+/// Builds a Sink from a Config
+pub type SinkFactory = Box<dyn Fn(&Config) -> Result<Box<dyn Sink>, String> + Send + Sync>;
+
+/// Source and Sink factories by name
+pub struct Registry {
+    sources: BTreeMap<String, SourceFactory>,
+    sinks: BTreeMap<String, SinkFactory>,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        let mut registry: Registry = Registry::empty();
+        registry.register_provider(OPENWEATHER_SOURCE, |config| Ok(Box::new(OpenWeatherProvider::new(config)) as Box<dyn PollutionProvider>));
+        registry.register_provider(OPENAQ_SOURCE, |config| Ok(Box::new(OpenAqProvider::new(config)?) as Box<dyn PollutionProvider>));
+        registry.register_provider(WAQI_SOURCE, |config| Ok(Box::new(WaqiProvider::new(config)?) as Box<dyn PollutionProvider>));
+        registry.register_source(EEA_SOURCE, |config| Ok(Box::new(EeaSource::new(config)?) as Box<dyn Source>));
+        registry.register_source(DEFRA_SOURCE, |config| Ok(Box::new(DefraSource::new(config)?) as Box<dyn Source>));
+        registry.register_sink(INFLUXDB_SINK, |config| Ok(Box::new(InfluxSink::new(config)?) as Box<dyn Sink>));
+        #[cfg(feature = "prometheus")]
+        registry.register_sink(crate::prometheus::PROMETHEUS_SINK, |_| Ok(Box::new(crate::prometheus::PrometheusSink) as Box<dyn Sink>));
+        #[cfg(feature = "mqtt")]
+        registry.register_sink(crate::mqtt::MQTT_SINK, |config| Ok(Box::new(crate::mqtt::MqttSink::new(config)?) as Box<dyn Sink>));
+        registry
+    }
+}
+
+impl Registry {
+    /// Create a registry with nothing in it. Registry::default() comes with the built in source and sink.
+    pub fn empty() -> Registry {
+        Registry { sources: BTreeMap::new(), sinks: BTreeMap::new() }
+    }
+    /// Add a Source factory under a name, replacing any already there
+    pub fn register_source<F: Fn(&Config) -> Result<Box<dyn Source>, String> + Send + Sync + 'static>(&mut self, name: &str, factory: F) {
+        self.sources.insert(name.to_lowercase(), Box::new(factory));
+    }
+    /// Add a PollutionProvider factory under a name, replacing any source already there. It is polled at every location, or every grid point, in the Config.
+    pub fn register_provider<F: Fn(&Config) -> Result<Box<dyn PollutionProvider>, String> + Send + Sync + 'static>(&mut self, name: &str, factory: F) {
+        self.register_source(name, move |config| Ok(Box::new(ProviderSource::new(factory(config)?, config)) as Box<dyn Source>));
+    }
+    /// Add a Sink factory under a name, replacing any already there
+    pub fn register_sink<F: Fn(&Config) -> Result<Box<dyn Sink>, String> + Send + Sync + 'static>(&mut self, name: &str, factory: F) {
+        self.sinks.insert(name.to_lowercase(), Box::new(factory));
+    }
+    /// Get the names of every registered source
+    pub fn source_names(&self) -> Vec<String> {
+        self.sources.keys().cloned().collect()
+    }
+    /// Get the names of every registered sink
+    pub fn sink_names(&self) -> Vec<String> {
+        self.sinks.keys().cloned().collect()
+    }
+    /// Build the source named by OPENWEATHER_SOURCE
+    ///
+    /// # Errors
+    /// Returns a message if the name is not registered or the factory fails
+    pub fn build_source(&self, config: &Config) -> Result<Box<dyn Source>, String> {
+        let name: String = config.get_source().to_lowercase();
+        match self.sources.get(&name) {
+            Some(factory) => factory(config),
+            None => Err(format!("Unknown source \"{}\". Registered sources: {}", name, self.source_names().join(", "))),
+        }
+    }
+    /// Build every sink named by OPENWEATHER_SINKS, in order
+    ///
+    /// # Errors
+    /// Returns a message for the first name that is not registered or whose factory fails
+    pub fn build_sinks(&self, config: &Config) -> Result<Vec<Box<dyn Sink>>, String> {
+        config.get_sinks().iter().map(|name| {
+            let name: String = name.to_lowercase();
+            match self.sinks.get(&name) {
+                Some(factory) => factory(config),
+                None => Err(format!("Unknown sink \"{}\". Registered sinks: {}", name, self.sink_names().join(", "))),
+            }
+        }).collect()
+    }
+}
+
+/// Writes updates to the configured InfluxDB database, retrying ambiguous failures and reading them back afterwards when OPENWEATHER_INFLUXDB_VERIFY_WRITES is on<br>
+/// When OPENWEATHER_INFLUXDB_SERVER lists several servers, a write that cannot reach one is sent to the next, which then takes later writes until it fails in turn.
+pub struct InfluxSink {
+    targets: Vec<(Config, Client, ureq::Agent)>,
+    active: AtomicUsize,
+}
+
+impl InfluxSink {
+    /// Create the sink for the database in a Config, with a client and an HTTP agent for each server, kept so their connections are reused between writes
+    ///
+    /// # Errors
+    /// Returns a message if the TLS settings for a server cannot be loaded, ex: a missing client certificate or CA bundle
+    pub fn new(config: &Config) -> Result<InfluxSink, String> {
+        let targets: Vec<(Config, Client, ureq::Agent)> = influx::server_configs(config).into_iter().map(|server_config| {
+            let dbclient: Client = quiet_client(&server_config)?;
+            let agent: ureq::Agent = influx::http_agent(&server_config);
+            Ok((server_config, dbclient, agent))
+        }).collect::<Result<Vec<(Config, Client, ureq::Agent)>, String>>()?;
+        Ok(InfluxSink { targets, active: AtomicUsize::new(0) })
+    }
+    /// Get the server writes are currently sent to
+    pub fn active_server(&self) -> String {
+        self.targets[self.active.load(Ordering::SeqCst)].0.get_dbserver()
+    }
+}
+
+#[async_trait]
+impl Sink for InfluxSink {
+    fn name(&self) -> &str {
+        INFLUXDB_SINK
+    }
+    async fn write(&self, updates: &[PollUpdate]) -> Result<usize, PluginError> {
+        let first: usize = self.active.load(Ordering::SeqCst);
+        let mut index: usize = first;
+        loop {
+            let (config, dbclient, agent) = &self.targets[index];
+            let next: usize = (index + 1) % self.targets.len();
+            match influx::write_with_retries(dbclient, agent, config, updates).await {
+                Ok(written) => {
+                    self.active.store(index, Ordering::SeqCst);
+                    if config.get_verify_writes() {
+                        for update in updates {
+                            influx::report_write_verification(dbclient, config, update, update.get_location()).await;
+                        }
+                    }
+                    return Ok(written.bytes);
+                },
+                Err(e) if influx::is_connection_error(&e) && next != first => {
+                    report::warn(&format!("Unable to reach InfluxDB at {}: {}. Failing over to {}.", config.get_dbserver(), e, self.targets[next].0.get_dbserver()));
+                    index = next;
+                },
+                Err(e) => return Err(Box::new(e)),
+            }
+        }
+    }
+}
+
+/// Parse a comma separated list of names, ex: "influxdb, console"
+pub fn parse_names(raw_names: &str) -> Vec<String> {
+    raw_names.split(',').map(|name| name.trim().to_lowercase()).filter(|name| !name.is_empty()).collect()
+}
+
+/// Check a header can be sent as given: a name of letters, digits and the usual punctuation, and a value on one line
+///
+/// # Errors
+/// Returns a message naming the header if either part is not allowed
+pub fn check_header(name: &str, value: &str) -> Result<(String, String), String> {
+    let name: &str = name.trim();
+    if name.is_empty() || !name.chars().all(|character| character.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(character)) {
+        return Err(format!("Invalid header name \"{}\".", name));
+    }
+    if value.contains(['\r', '\n']) {
+        return Err(format!("Header {} has a line break in its value.", name));
+    }
+    Ok((name.to_string(), value.trim().to_string()))
+}
+
+/// Parse headers separated by commas, each a name and value separated by a colon, ex: "X-Scope-OrgID: home, X-Proxy-Token: abc"
+///
+/// # Errors
+/// Returns a message naming the first header without a colon or that check_header rejects
+pub fn parse_headers(raw_headers: &str) -> Result<Headers, String> {
+    raw_headers.split(',').map(str::trim).filter(|header| !header.is_empty())
+        .map(|header| match header.split_once(':') {
+            Some((name, value)) => check_header(name, value),
+            None => Err(format!("Unable to understand header \"{}\". Expected Name: value.", header)),
+        })
+        .collect()
+}
+
+/// Get the environmental variable holding a sink's headers, ex: OPENWEATHER_SINK_HEADERS_INFLUXDB
+pub fn headers_variable(sink: &str) -> String {
+    format!("OPENWEATHER_SINK_HEADERS_{}", sink.to_uppercase().replace(|character: char| !character.is_ascii_alphanumeric(), "_"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FixedSource;
+
+    impl Source for FixedSource {
+        fn name(&self) -> &str {
+            "fixed"
+        }
+        fn fetch(&self) -> Result<PollUpdate, PluginError> {
+            Err("nothing to see".into())
+        }
+    }
+
+    #[test]
+    fn parse_names_trims_and_lowercases() {
+        assert_eq!(parse_names(" InfluxDB, console,,"), vec!["influxdb".to_string(), "console".to_string()]);
+    }
+
+    #[test]
+    fn parse_headers_checks_each() {
+        assert_eq!(parse_headers("X-Scope-OrgID: home, X-Proxy-Token:a:b,").unwrap(), vec![("X-Scope-OrgID".to_string(), "home".to_string()), ("X-Proxy-Token".to_string(), "a:b".to_string())]);
+        assert!(parse_headers("X-Tenant").unwrap_err().contains("Expected Name: value"));
+        assert!(parse_headers("Bad Name: x").unwrap_err().starts_with("Invalid header name"));
+        assert!(check_header("X-Tenant", "home\r\nX-Other: y").is_err());
+        assert_eq!(headers_variable("my-sink"), "OPENWEATHER_SINK_HEADERS_MY_SINK");
+    }
+
+    #[test]
+    fn default_registry_has_builtins() {
+        let registry: Registry = Registry::default();
+        assert_eq!(registry.source_names(), vec![DEFRA_SOURCE.to_string(), EEA_SOURCE.to_string(), OPENAQ_SOURCE.to_string(), OPENWEATHER_SOURCE.to_string(), WAQI_SOURCE.to_string()]);
+        let eea_config: Config = Config { source: EEA_SOURCE.to_string(), ..Config::default() };
+        assert_eq!(registry.build_source(&eea_config).err().unwrap(), "OPENWEATHER_SOURCE is eea but OPENWEATHER_EEA_STATION is not set.");
+        assert_eq!(registry.sink_names()[0], INFLUXDB_SINK.to_string());
+        #[cfg(not(any(feature = "prometheus", feature = "mqtt")))]
+        assert_eq!(registry.sink_names().len(), 1);
+        #[cfg(feature = "prometheus")]
+        assert!(registry.sink_names().contains(&crate::prometheus::PROMETHEUS_SINK.to_string()));
+        #[cfg(feature = "mqtt")]
+        assert!(registry.sink_names().contains(&crate::mqtt::MQTT_SINK.to_string()));
+        let sinks: Vec<Box<dyn Sink>> = registry.build_sinks(&Config::default()).unwrap();
+        assert_eq!(sinks[0].name(), INFLUXDB_SINK);
+    }
+
+    #[test]
+    fn registered_source_is_built_by_name() {
+        let mut registry: Registry = Registry::empty();
+        registry.register_source("Fixed", |_| Ok(Box::new(FixedSource) as Box<dyn Source>));
+        let test_config: Config = Config { source: "fixed".to_string(), ..Config::default() };
+        let source: Box<dyn Source> = registry.build_source(&test_config).unwrap();
+        assert_eq!(source.name(), "fixed");
+        assert!(source.fetch().is_err());
+        assert_eq!(source.fetch_range(Utc::now(), Utc::now()).unwrap_err().to_string(), "Source fixed cannot fetch past readings".to_string());
+        assert_eq!(source.fetch_forecast().unwrap_err().to_string(), "Source fixed cannot fetch a forecast".to_string());
+        let missing: Config = Config { source: "other".to_string(), ..Config::default() };
+        assert_eq!(registry.build_source(&missing).err().unwrap(), "Unknown source \"other\". Registered sources: fixed".to_string());
+    }
+
+    #[test]
+    fn write_event_json_and_metrics() {
+        let event: WriteEvent = WriteEvent { time: DateTime::from_timestamp(1700000000, 0).unwrap(), sink: "test_event_sink".to_string(), points: 1, bytes: 120, latency: Duration::from_millis(250), success: true };
+        let json: serde_json::Value = serde_json::from_str(&event.to_json()).unwrap();
+        assert_eq!(json["event"], "write");
+        assert_eq!(json["sink"], "test_event_sink");
+        assert_eq!(json["points"], 1);
+        assert_eq!(json["bytes"], 120);
+        assert_eq!(json["latency_seconds"], 0.25);
+        event.record();
+        event.record();
+        assert_eq!(metrics::get("pollution_sink_bytes_total", &[("sink", "test_event_sink")]), Some(240.0));
+        assert_eq!(metrics::get("pollution_sink_writes_total", &[("sink", "test_event_sink"), ("result", "ok")]), Some(2.0));
+    }
+
+    #[test]
+    fn influx_sink_reports_bad_tls_files() {
+        let mut test_config: Config = Config::default();
+        test_config.set_ca_cert("/nonexistent/ca.pem".to_string());
+        assert!(InfluxSink::new(&test_config).is_err());
+        assert!(Registry::default().build_sinks(&test_config).is_err());
+    }
+}
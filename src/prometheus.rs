@@ -0,0 +1,175 @@
+//! Optional Prometheus exporter, for users who scrape readings with Prometheus rather than store them in InfluxDB.<br>
+//! The "prometheus" sink keeps the latest reading of every location, and serve exposes them as gauges in the text exposition format on GET /metrics along with the client's own metrics.
+
+use std::collections::BTreeMap;
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use async_trait::async_trait;
+use tiny_http::{Header, Response, Server};
+use crate::indices::Index;
+use crate::plugin::{PluginError, Sink};
+use crate::trend::POLLUTANTS;
+use crate::{metrics, report, PollUpdate};
+
+/// Name the Prometheus sink is registered under
+pub const PROMETHEUS_SINK: &str = "prometheus";
+
+static LATEST: OnceLock<Mutex<BTreeMap<String, PollUpdate>>> = OnceLock::new();
+
+fn latest() -> &'static Mutex<BTreeMap<String, PollUpdate>> {
+    LATEST.get_or_init(|| Mutex::new(BTreeMap::new()))
+}
+
+/// Keeps the latest reading of each location for the /metrics endpoint instead of writing it anywhere
+pub struct PrometheusSink;
+
+#[async_trait]
+impl Sink for PrometheusSink {
+    fn name(&self) -> &str {
+        PROMETHEUS_SINK
+    }
+    async fn write(&self, updates: &[PollUpdate]) -> Result<usize, PluginError> {
+        record(updates);
+        Ok(0)
+    }
+}
+
+/// Keep updates as the latest readings of their series, ignoring any older than the one already kept and any hours of the forecast
+pub fn record(updates: &[PollUpdate]) {
+    let mut readings = latest().lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    for update in updates.iter().filter(|update| !update.is_forecast()) {
+        let newer: bool = readings.get(&update.get_series()).is_none_or(|kept| kept.get_time() <= update.get_time());
+        if newer {
+            readings.insert(update.get_series(), update.clone());
+        }
+    }
+}
+
+/// Get the latest reading kept for every series
+pub fn readings() -> Vec<PollUpdate> {
+    latest().lock().unwrap_or_else(|poisoned| poisoned.into_inner()).values().cloned().collect()
+}
+
+/// Labels a reading's gauges carry: its location, and source="reference" for readings from a monitoring station
+fn labels(update: &PollUpdate) -> Vec<(&str, &str)> {
+    let mut labels: Vec<(&str, &str)> = vec![("location", update.get_location())];
+    if update.is_reference() {
+        labels.push(("source", "reference"));
+    }
+    labels
+}
+
+/// Add a metric family's help and type lines and one sample for each series
+fn family(output: &mut String, name: &str, help: &str, samples: &[(String, f64)]) {
+    if samples.is_empty() {
+        return;
+    }
+    output.push_str(&format!("# HELP {} {}\n# TYPE {} gauge\n", name, help, name));
+    for (series, value) in samples {
+        output.push_str(&format!("{} {}\n", series, value));
+    }
+}
+
+/// Render readings as gauges in the Prometheus text exposition format
+pub fn render(readings: &[PollUpdate]) -> String {
+    let mut output: String = String::new();
+    let aqi: Vec<(String, f64)> = readings.iter()
+        .map(|update| (metrics::series_name("pollution_aqi", &labels(update)), update.get_aqi() as f64))
+        .collect();
+    family(&mut output, "pollution_aqi", "OpenWeatherMaps' air quality index, from 1 (good) to 5 (very poor)", &aqi);
+    let components: Vec<(String, f64)> = readings.iter().flat_map(|update| POLLUTANTS.iter().filter_map(move |pollutant| {
+        let mut series_labels: Vec<(&str, &str)> = labels(update);
+        series_labels.push(("pollutant", pollutant));
+        update.get_field(pollutant).map(|value| (metrics::series_name("pollution_concentration_micrograms_per_cubic_meter", &series_labels), value as f64))
+    })).collect();
+    family(&mut output, "pollution_concentration_micrograms_per_cubic_meter", "Concentration of each pollutant in µg/m³", &components);
+    let indices: Vec<(String, f64)> = readings.iter().flat_map(|update| Index::ALL.iter().filter_map(move |index| {
+        let mut series_labels: Vec<(&str, &str)> = labels(update);
+        series_labels.push(("index", index.field()));
+        update.get_index(index.field()).map(|value| (metrics::series_name("pollution_index", &series_labels), value as f64))
+    })).collect();
+    family(&mut output, "pollution_index", "Air quality indices worked out from the reading, ex: aqi_us", &indices);
+    let rates: Vec<(String, f64)> = readings.iter().flat_map(|update| POLLUTANTS.iter().filter_map(move |pollutant| {
+        let mut series_labels: Vec<(&str, &str)> = labels(update);
+        series_labels.push(("pollutant", pollutant));
+        update.get_trend(pollutant).map(|trend| (metrics::series_name("pollution_trend_micrograms_per_cubic_meter_per_hour", &series_labels), trend.rate as f64))
+    })).collect();
+    family(&mut output, "pollution_trend_micrograms_per_cubic_meter_per_hour", "Change in each pollutant over OPENWEATHER_TREND_HOURS in µg/m³ per hour", &rates);
+    let times: Vec<(String, f64)> = readings.iter()
+        .map(|update| (metrics::series_name("pollution_reading_timestamp_seconds", &labels(update)), update.get_time().timestamp() as f64))
+        .collect();
+    family(&mut output, "pollution_reading_timestamp_seconds", "Time of the latest reading as a Unix timestamp", &times);
+    for (series, value) in metrics::snapshot() {
+        output.push_str(&format!("{} {}\n", series, value));
+    }
+    output
+}
+
+/// Start serving the latest readings on the given address, ex: "0.0.0.0:9898"<br>
+/// GET /metrics returns them as gauges, and anything else is not found
+///
+/// # Errors
+/// Returns a message if the address cannot be bound
+pub fn serve(bind: &str) -> Result<thread::JoinHandle<()>, String> {
+    let server: Server = Server::http(bind).map_err(|e| format!("Unable to start Prometheus endpoint on {}: {}", bind, e))?;
+    report::info(&format!("Prometheus endpoint listening on {}", bind));
+    let handle: thread::JoinHandle<()> = thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let (status, body) = if request.method() == &tiny_http::Method::Get {
+                route(request.url())
+            } else {
+                (405, "method not allowed\n".to_string())
+            };
+            let content_type: Header = Header::from_bytes(&b"Content-Type"[..], &b"text/plain; version=0.0.4; charset=utf-8"[..]).unwrap();
+            let response = Response::from_string(body).with_status_code(status).with_header(content_type);
+            if let Err(e) = request.respond(response) {
+                report::warn(&format!("Unable to respond to Prometheus scrape: {}", e));
+            }
+        }
+    });
+    Ok(handle)
+}
+
+/// Work out the status and body for a path
+pub(crate) fn route(url: &str) -> (u16, String) {
+    match url.split('?').next().unwrap_or("") {
+        "/metrics" => (200, render(&readings())),
+        _ => (404, "not found\n".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{DateTime, Utc};
+
+    fn test_update(location: &str, seconds: i64, pm2_5: f32) -> PollUpdate {
+        let mut update: PollUpdate = PollUpdate::from_measured(DateTime::<Utc>::from_timestamp(seconds, 0).unwrap(), &[("pm2_5", pm2_5)]);
+        update.set_location(location);
+        update
+    }
+
+    #[test]
+    fn render_gauges() {
+        let mut update: PollUpdate = test_update("Beverly Hills", 1700000000, 12.5);
+        update.set_index("aqi_us", 52.0);
+        let rendered: String = render(&[update]);
+        assert!(rendered.contains("# TYPE pollution_aqi gauge\npollution_aqi{location=\"Beverly Hills\"} 2\n"));
+        assert!(rendered.contains("pollution_concentration_micrograms_per_cubic_meter{location=\"Beverly Hills\",pollutant=\"pm2_5\"} 12.5\n"));
+        assert!(rendered.contains("pollution_index{location=\"Beverly Hills\",index=\"aqi_us\"} 52\n"));
+        assert!(rendered.contains("pollution_reading_timestamp_seconds{location=\"Beverly Hills\"} 1700000000\n"));
+        assert!(!rendered.contains("pollution_trend"));
+    }
+
+    #[test]
+    fn record_keeps_latest() {
+        record(&[test_update("Prometheus Test", 1700003600, 20.0), test_update("Prometheus Test", 1700000000, 10.0)]);
+        let kept: Vec<PollUpdate> = readings().into_iter().filter(|update| update.get_location() == "Prometheus Test").collect();
+        assert_eq!(kept.len(), 1);
+        assert_eq!(kept[0].get_field("pm2_5"), Some(20.0));
+        let (status, body) = route("/metrics");
+        assert_eq!(status, 200);
+        assert!(body.contains("pollutant=\"pm2_5\"} 20\n"));
+        assert_eq!(route("/other").0, 404);
+    }
+}
@@ -0,0 +1,290 @@
+//! Providers fetch readings for any place they are asked about, so a new data source only has to know how to read its own API.<br>
+//! A ProviderSource turns a provider into a Source polling every location in the Config, or every grid point, naming each reading after its place. Places are fetched a few at a time, and one that fails is left out of the poll rather than failing it.
+//! The scheduler, transforms and sinks only see the Source, so they work the same whichever provider is picked by OPENWEATHER_SOURCE.
+
+use chrono::{DateTime, Utc};
+use crate::grid::GridCell;
+use crate::plugin::{PluginError, Source, OPENWEATHER_SOURCE};
+use crate::{get_pollution, get_pollution_forecast, pollution_forecast_url, pollution_history_url, pollution_url, report, Config, PollUpdate, OPENWEATHER_API};
+
+/// Most places fetched at the same time, so a large grid does not open a connection for every point at once
+pub const CONCURRENT_FETCHES: usize = 4;
+
+/// A place a provider is asked for readings at
+#[derive(Clone, Debug, PartialEq)]
+pub struct Place {
+    /// Location name its readings are written under
+    pub name: String,
+    pub lat: f64,
+    pub lon: f64,
+    /// ISO 3166-1 alpha-2 code, empty if not known
+    pub country: String,
+}
+
+/// Fetches pollution readings for a place from one data source, ex: OpenWeatherMaps
+pub trait PollutionProvider: Send + Sync {
+    /// Get the name this provider is registered under
+    fn name(&self) -> &str;
+    /// Get the latest readings at a place, oldest first. Most providers return one.
+    ///
+    /// # Errors
+    /// Any error reaching or understanding the backend
+    fn fetch(&self, place: &Place) -> Result<Vec<PollUpdate>, PluginError>;
+    /// Get every reading at a place between two times, oldest first, so gaps can be filled
+    ///
+    /// # Errors
+    /// Any error reaching or understanding the backend. Providers that cannot look back return an error, which is the default.
+    fn fetch_range(&self, _place: &Place, _start: DateTime<Utc>, _end: DateTime<Utc>) -> Result<Vec<PollUpdate>, PluginError> {
+        Err(format!("Provider {} cannot fetch past readings", self.name()).into())
+    }
+    /// Get the hourly forecast at a place, oldest first
+    ///
+    /// # Errors
+    /// Any error reaching or understanding the backend. Providers without a forecast return an error, which is the default.
+    fn fetch_forecast(&self, _place: &Place) -> Result<Vec<PollUpdate>, PluginError> {
+        Err(format!("Provider {} cannot fetch a forecast", self.name()).into())
+    }
+}
+
+/// Builds a PollutionProvider from a Config
+pub type ProviderFactory = Box<dyn Fn(&Config) -> Result<Box<dyn PollutionProvider>, String> + Send + Sync>;
+
+/// Get every place a poll covers: each grid point when OPENWEATHER_GRID_BBOX is set, named after the area and its cell, otherwise each location
+pub fn places(config: &Config) -> Vec<Place> {
+    let cells: Vec<GridCell> = config.get_grid_cells();
+    if !cells.is_empty() {
+        let country: String = config.location.as_ref().map(|location| location.country().to_string()).unwrap_or_default();
+        return cells.iter().map(|cell| Place { name: cell.location(config.get_location()), lat: cell.lat, lon: cell.lon, country: country.clone() }).collect();
+    }
+    config.location.iter().chain(config.more_locations.iter())
+        .map(|location| Place { name: location.get_name().to_string(), lat: f64::from(location.lat()), lon: f64::from(location.lon()), country: location.country().to_string() })
+        .collect()
+}
+
+/// Polls a provider at every place in a Config
+pub struct ProviderSource {
+    provider: Box<dyn PollutionProvider>,
+    places: Vec<Place>,
+}
+
+impl ProviderSource {
+    /// Create the source for a provider, polling the places in a Config
+    pub fn new(provider: Box<dyn PollutionProvider>, config: &Config) -> ProviderSource {
+        ProviderSource { provider, places: places(config) }
+    }
+    /// Get the places polled, in order
+    pub fn get_places(&self) -> &[Place] {
+        &self.places
+    }
+    /// Fetch from every place, up to CONCURRENT_FETCHES at a time, naming each reading after its place<br>
+    /// A place that fails is reported and left out, so one bad place does not hold up the rest of the poll.
+    ///
+    /// # Errors
+    /// Returns the first place's error if every place failed
+    fn fetch_each<F: Fn(&Place) -> Result<Vec<PollUpdate>, PluginError> + Sync>(&self, fetch_at: F) -> Result<Vec<PollUpdate>, PluginError> {
+        if self.places.is_empty() {
+            return Err(format!("Provider {} has no location to poll", self.provider.name()).into());
+        }
+        let fetch_at: &F = &fetch_at;
+        let results: Vec<Result<Vec<PollUpdate>, PluginError>> = self.places.chunks(CONCURRENT_FETCHES).flat_map(|chunk| {
+            std::thread::scope(|scope| {
+                let handles: Vec<_> = chunk.iter().map(|place| scope.spawn(move || fetch_at(place))).collect();
+                handles.into_iter().map(|handle| handle.join().unwrap_or_else(|_| Err("the fetch panicked".into()))).collect::<Vec<_>>()
+            })
+        }).collect();
+        let mut updates: Vec<PollUpdate> = Vec::new();
+        let mut failures: Vec<PluginError> = Vec::new();
+        for (place, result) in self.places.iter().zip(results) {
+            match result {
+                Ok(mut place_updates) => {
+                    place_updates.iter_mut().for_each(|update| update.set_location(&place.name));
+                    updates.extend(place_updates);
+                },
+                Err(e) => {
+                    if self.places.len() > 1 {
+                        report::warn(&format!("Unable to fetch {} from {}: {}", place.name, self.provider.name(), e));
+                    }
+                    failures.push(e);
+                },
+            }
+        }
+        if failures.len() == self.places.len() {
+            return Err(failures.remove(0));
+        }
+        Ok(updates)
+    }
+}
+
+impl Source for ProviderSource {
+    fn name(&self) -> &str {
+        self.provider.name()
+    }
+    /// Get the newest reading at the first place
+    fn fetch(&self) -> Result<PollUpdate, PluginError> {
+        let place: &Place = self.places.first().ok_or_else(|| format!("Provider {} has no location to poll", self.provider.name()))?;
+        let mut update: PollUpdate = self.provider.fetch(place)?.pop().ok_or_else(|| format!("Provider {} returned no readings for {}", self.provider.name(), place.name))?;
+        update.set_location(&place.name);
+        Ok(update)
+    }
+    fn fetch_all(&self) -> Result<Vec<PollUpdate>, PluginError> {
+        self.fetch_each(|place| self.provider.fetch(place))
+    }
+    fn fetch_range(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<PollUpdate>, PluginError> {
+        let mut updates: Vec<PollUpdate> = self.fetch_each(|place| self.provider.fetch_range(place, start, end))?;
+        updates.sort_by_key(PollUpdate::get_time);
+        Ok(updates)
+    }
+    fn fetch_forecast(&self) -> Result<Vec<PollUpdate>, PluginError> {
+        let mut updates: Vec<PollUpdate> = self.fetch_each(|place| self.provider.fetch_forecast(place))?;
+        updates.sort_by_key(PollUpdate::get_time);
+        Ok(updates)
+    }
+}
+
+/// Current air pollution from OpenWeatherMaps, with past readings from its history endpoint and the hourly forecast
+pub struct OpenWeatherProvider {
+    config: Config,
+    api: String,
+}
+
+impl OpenWeatherProvider {
+    /// Create the provider for the API key in a Config
+    pub fn new(config: &Config) -> OpenWeatherProvider {
+        OpenWeatherProvider { config: config.clone(), api: OPENWEATHER_API.to_string() }
+    }
+    /// Create the provider sending its requests to another server, ex: a local one answering with a chosen status
+    #[cfg(test)]
+    pub(crate) fn with_api(config: &Config, api: &str) -> OpenWeatherProvider {
+        OpenWeatherProvider { config: config.clone(), api: api.to_string() }
+    }
+    /// Point a URL built for OPENWEATHER_API at the provider's server
+    fn on_api(&self, url: String) -> String {
+        url.replacen(OPENWEATHER_API, &self.api, 1)
+    }
+}
+
+impl PollutionProvider for OpenWeatherProvider {
+    fn name(&self) -> &str {
+        OPENWEATHER_SOURCE
+    }
+    fn fetch(&self, place: &Place) -> Result<Vec<PollUpdate>, PluginError> {
+        Ok(vec![get_pollution(&self.on_api(pollution_url(&self.config.at_coords(place.lat, place.lon))))?.unpack()?])
+    }
+    fn fetch_range(&self, place: &Place, start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Vec<PollUpdate>, PluginError> {
+        Ok(get_pollution(&self.on_api(pollution_history_url(&self.config.at_coords(place.lat, place.lon), start, end)))?.unpack_all())
+    }
+    fn fetch_forecast(&self, place: &Place) -> Result<Vec<PollUpdate>, PluginError> {
+        Ok(get_pollution_forecast(&self.on_api(pollution_forecast_url(&self.config.at_coords(place.lat, place.lon))))?.unpack())
+    }
+}
+
+/// Answer every request on a local port with an HTTP status and no body, for tests of how failed requests are handled<br>
+/// Returns the server's address as a base URL, ex: "http://127.0.0.1:40123".
+#[cfg(test)]
+pub(crate) fn serve_status(status: &str) -> String {
+    use std::io::{BufRead, BufReader, Write};
+    let listener: std::net::TcpListener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address: std::net::SocketAddr = listener.local_addr().unwrap();
+    let response: String = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status);
+    std::thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            // Read the request up to the blank line ending its headers before answering
+            let mut reader: BufReader<std::net::TcpStream> = BufReader::new(stream.try_clone().unwrap());
+            let mut line: String = String::new();
+            while reader.read_line(&mut line).is_ok_and(|read| read > 2) {
+                line.clear();
+            }
+            let _ = stream.write_all(response.as_bytes());
+        }
+    });
+    format!("http://{}", address)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::grid::BoundingBox;
+    use crate::{Location, ZipLoc};
+
+    /// Returns a reading at the place's latitude, or fails for places named "down" and grid points in the second column
+    struct LatitudeProvider;
+
+    impl PollutionProvider for LatitudeProvider {
+        fn name(&self) -> &str {
+            "latitude"
+        }
+        fn fetch(&self, place: &Place) -> Result<Vec<PollUpdate>, PluginError> {
+            if place.name == "down" || place.name.ends_with("c01") {
+                return Err("unreachable".into());
+            }
+            Ok(vec![PollUpdate { co: Some(place.lat as f32), ..PollUpdate::fixture("pending", 1700000000) }])
+        }
+        fn fetch_forecast(&self, place: &Place) -> Result<Vec<PollUpdate>, PluginError> {
+            let mut hours: Vec<PollUpdate> = self.fetch(place)?;
+            hours.insert(0, PollUpdate { forecast: true, ..PollUpdate::fixture("pending", 1700003600 - place.lat as i64) });
+            Ok(hours)
+        }
+    }
+
+    fn test_config(zips: &[&str]) -> Config {
+        let mut test_config: Config = Config::default();
+        for (index, zip) in zips.iter().enumerate() {
+            test_config.add_loc(Location::Zip(ZipLoc { zip: zip.to_string(), name: zip.to_string(), lat: 40.0 + index as f32, lon: -74.0, country: "US".to_string() }));
+        }
+        test_config
+    }
+
+    #[test]
+    fn places_cover_locations_or_grid() {
+        let several: Vec<Place> = places(&test_config(&["10001", "10002"]));
+        assert_eq!(several.iter().map(|place| (place.name.as_str(), place.lat)).collect::<Vec<(&str, f64)>>(), vec![("10001", 40.0), ("10002", 41.0)]);
+        let grid: Config = Config { grid_bbox: Some(BoundingBox { south: 40.0, west: -74.1, north: 40.1, east: -74.0 }), grid_spacing: 10.0, ..test_config(&["Area"]) };
+        let grid_places: Vec<Place> = places(&grid);
+        assert_eq!(grid_places.len(), grid.get_grid_cells().len());
+        assert_eq!((grid_places[0].name.as_str(), grid_places[0].country.as_str()), ("Area r00c00", "US"));
+        assert!(places(&Config::default()).is_empty());
+    }
+
+    #[test]
+    fn rejected_key_reaches_the_client() {
+        let provider: OpenWeatherProvider = OpenWeatherProvider::with_api(&test_config(&["10001"]), &serve_status("401 Unauthorized"));
+        let place: Place = places(&test_config(&["10001"])).remove(0);
+        let rejected: PluginError = provider.fetch(&place).unwrap_err();
+        assert!(matches!(rejected.downcast_ref::<ureq::Error>(), Some(ureq::Error::Status(401, _))));
+        assert!(crate::client::is_auth_failure(&rejected));
+        assert!(crate::client::is_auth_failure(&provider.fetch_forecast(&place).unwrap_err()));
+        assert!(crate::client::is_auth_failure(&provider.fetch_range(&place, Utc::now(), Utc::now()).unwrap_err()));
+    }
+
+    #[test]
+    fn grid_points_that_fail_are_skipped() {
+        let grid: Config = Config { grid_bbox: Some(BoundingBox { south: 40.0, west: -74.1, north: 40.1, east: -74.0 }), grid_spacing: 5.0, ..test_config(&["Area"]) };
+        let updates: Vec<PollUpdate> = ProviderSource::new(Box::new(LatitudeProvider), &grid).fetch_all().unwrap();
+        let reachable: Vec<String> = places(&grid).into_iter().map(|place| place.name).filter(|name| !name.ends_with("c01")).collect();
+        assert!(reachable.len() < grid.get_grid_cells().len());
+        assert_eq!(updates.iter().map(|update| update.get_location().to_string()).collect::<Vec<String>>(), reachable);
+    }
+
+    #[test]
+    fn locations_that_fail_are_skipped() {
+        let source: ProviderSource = ProviderSource::new(Box::new(LatitudeProvider), &test_config(&["10001", "down", "10003"]));
+        let hours: Vec<PollUpdate> = source.fetch_forecast().unwrap();
+        assert_eq!(hours.iter().map(|update| (update.get_location(), update.is_forecast())).collect::<Vec<(&str, bool)>>(),
+            vec![("10001", false), ("10003", false), ("10003", true), ("10001", true)]);
+        assert!(ProviderSource::new(Box::new(LatitudeProvider), &test_config(&["down"])).fetch_forecast().is_err());
+    }
+
+    #[test]
+    fn source_names_readings_after_places() {
+        let source: ProviderSource = ProviderSource::new(Box::new(LatitudeProvider), &test_config(&["10001", "10002"]));
+        assert_eq!(source.name(), "latitude");
+        let updates: Vec<PollUpdate> = source.fetch_all().unwrap();
+        assert_eq!(updates.iter().map(|update| (update.get_location(), update.co)).collect::<Vec<(&str, Option<f32>)>>(), vec![("10001", Some(40.0)), ("10002", Some(41.0))]);
+        assert_eq!(source.fetch().unwrap().get_location(), "10001");
+        assert_eq!(source.fetch_range(Utc::now(), Utc::now()).unwrap_err().to_string(), "Provider latitude cannot fetch past readings");
+        let partial: Vec<PollUpdate> = ProviderSource::new(Box::new(LatitudeProvider), &test_config(&["10001", "down", "10003"])).fetch_all().unwrap();
+        assert_eq!(partial.iter().map(PollUpdate::get_location).collect::<Vec<&str>>(), vec!["10001", "10003"]);
+        assert_eq!(ProviderSource::new(Box::new(LatitudeProvider), &test_config(&["down", "down"])).fetch_all().unwrap_err().to_string(), "unreachable");
+        assert_eq!(ProviderSource::new(Box::new(LatitudeProvider), &Config::default()).fetch_all().unwrap_err().to_string(), "Provider latitude has no location to poll");
+    }
+}
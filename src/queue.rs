@@ -0,0 +1,217 @@
+//! Bounded queues connecting the stages of the pipeline, so a slow stage delays the ones after it instead of the ones before.<br>
+//! When a queue is full its policy decides whether the stage feeding it waits for room or an item is dropped, so memory cannot grow without limit.
+//! The depth of each queue is kept in the pollution_queue_depth gauge and drops are counted in pollution_queue_dropped_total, both labelled with the queue name.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use tokio::sync::Notify;
+use crate::metrics;
+
+/// Default number of items that can wait in a queue
+pub const DEFAULT_QUEUE_SIZE: usize = 100;
+
+/// What to do with a new item when a queue is full, set for the write queue by OPENWEATHER_QUEUE_POLICY
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueuePolicy {
+    /// Wait for the next stage to make room, holding up the stage feeding the queue
+    Block,
+    /// Drop the oldest waiting item to make room
+    DropOldest,
+    /// Drop the new item
+    DropNewest,
+}
+
+impl FromStr for QueuePolicy {
+    type Err = String;
+    fn from_str(raw_policy: &str) -> Result<Self, Self::Err> {
+        match raw_policy.trim().to_lowercase().replace('_', "-").as_str() {
+            "block" => Ok(QueuePolicy::Block),
+            "drop-oldest" => Ok(QueuePolicy::DropOldest),
+            "drop-newest" => Ok(QueuePolicy::DropNewest),
+            _ => Err(format!("Unknown queue policy \"{}\". Expected block, drop-oldest or drop-newest.", raw_policy)),
+        }
+    }
+}
+
+impl fmt::Display for QueuePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QueuePolicy::Block => write!(f, "block"),
+            QueuePolicy::DropOldest => write!(f, "drop-oldest"),
+            QueuePolicy::DropNewest => write!(f, "drop-newest"),
+        }
+    }
+}
+
+/// Items waiting between two stages, shared by the one stage pushing and the one stage popping
+#[derive(Debug)]
+pub struct Queue<T> {
+    name: &'static str,
+    capacity: usize,
+    policy: QueuePolicy,
+    items: Mutex<VecDeque<T>>,
+    closed: AtomicBool,
+    filled: Notify,
+    freed: Notify,
+}
+
+impl<T> Queue<T> {
+    /// Create an empty queue holding up to capacity items, named for its metrics. A capacity of zero is raised to one so an item can always be handed over.
+    pub fn new(name: &'static str, capacity: usize, policy: QueuePolicy) -> Queue<T> {
+        Queue { name, capacity: capacity.max(1), policy, items: Mutex::new(VecDeque::new()), closed: AtomicBool::new(false), filled: Notify::new(), freed: Notify::new() }
+    }
+    /// Get the name the queue's metrics are labelled with
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+    /// Add an item, applying the policy if the queue is full<br>
+    /// Returns the item that was dropped to keep within capacity, if any. An item pushed after close is returned as dropped.
+    pub async fn push(&self, item: T) -> Option<T> {
+        loop {
+            if self.is_closed() {
+                return Some(item);
+            }
+            {
+                let mut items = self.lock();
+                if items.len() < self.capacity {
+                    items.push_back(item);
+                    self.publish_depth(items.len());
+                    self.filled.notify_one();
+                    return None;
+                }
+                match self.policy {
+                    QueuePolicy::Block => (),
+                    QueuePolicy::DropOldest => {
+                        let dropped: Option<T> = items.pop_front();
+                        items.push_back(item);
+                        self.record_drop();
+                        self.filled.notify_one();
+                        return dropped;
+                    },
+                    QueuePolicy::DropNewest => {
+                        self.record_drop();
+                        return Some(item);
+                    },
+                }
+            }
+            self.freed.notified().await;
+        }
+    }
+    /// Take the oldest item, waiting for one if the queue is empty<br>
+    /// Returns None once the queue has been closed and everything in it taken
+    pub async fn pop(&self) -> Option<T> {
+        loop {
+            {
+                let mut items = self.lock();
+                if let Some(item) = items.pop_front() {
+                    self.publish_depth(items.len());
+                    self.freed.notify_one();
+                    return Some(item);
+                }
+            }
+            if self.is_closed() {
+                return None;
+            }
+            self.filled.notified().await;
+        }
+    }
+    /// Stop taking new items. Items already queued can still be popped.
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.filled.notify_one();
+        self.freed.notify_one();
+    }
+    /// Take every item still waiting, ex: to save them once the stage after has stopped
+    pub fn take_all(&self) -> Vec<T> {
+        let mut items = self.lock();
+        let taken: Vec<T> = items.drain(..).collect();
+        self.publish_depth(0);
+        self.freed.notify_one();
+        taken
+    }
+    /// Check if close has been called
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+    /// Get how many items are waiting
+    pub fn depth(&self) -> usize {
+        self.lock().len()
+    }
+    fn lock(&self) -> std::sync::MutexGuard<'_, VecDeque<T>> {
+        self.items.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+    fn publish_depth(&self, depth: usize) {
+        metrics::set_gauge("pollution_queue_depth", &[("queue", self.name)], depth as f64);
+    }
+    fn record_drop(&self) {
+        metrics::increment("pollution_queue_dropped_total", &[("queue", self.name), ("policy", &self.policy.to_string())]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PollUpdate;
+
+    fn test_update(seconds: i64) -> PollUpdate {
+        PollUpdate::fixture("Home", seconds)
+    }
+
+    #[test]
+    fn queue_policy_from_str() {
+        assert_eq!("Block".parse::<QueuePolicy>(), Ok(QueuePolicy::Block));
+        assert_eq!("drop_oldest".parse::<QueuePolicy>(), Ok(QueuePolicy::DropOldest));
+        assert_eq!(" drop-newest".parse::<QueuePolicy>(), Ok(QueuePolicy::DropNewest));
+        assert!("drop".parse::<QueuePolicy>().is_err());
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn drop_oldest_keeps_newest() {
+        let queue: Queue<PollUpdate> = Queue::new("test", 2, QueuePolicy::DropOldest);
+        assert!(queue.push(test_update(1)).await.is_none());
+        assert!(queue.push(test_update(2)).await.is_none());
+        assert_eq!(queue.push(test_update(3)).await.unwrap().get_time().timestamp(), 1);
+        assert_eq!(queue.depth(), 2);
+        assert_eq!(queue.pop().await.unwrap().get_time().timestamp(), 2);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn drop_newest_keeps_queued() {
+        let queue: Queue<PollUpdate> = Queue::new("test", 1, QueuePolicy::DropNewest);
+        assert!(queue.push(test_update(1)).await.is_none());
+        assert_eq!(queue.push(test_update(2)).await.unwrap().get_time().timestamp(), 2);
+        assert_eq!(queue.pop().await.unwrap().get_time().timestamp(), 1);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn block_waits_for_room() {
+        let queue: Queue<PollUpdate> = Queue::new("test", 1, QueuePolicy::Block);
+        queue.push(test_update(1)).await;
+        let (pushed, popped) = tokio::join!(queue.push(test_update(2)), queue.pop());
+        assert!(pushed.is_none());
+        assert_eq!(popped.unwrap().get_time().timestamp(), 1);
+        assert_eq!(queue.depth(), 1);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn close_drains_then_ends() {
+        let queue: Queue<PollUpdate> = Queue::new("test", 4, QueuePolicy::Block);
+        queue.push(test_update(1)).await;
+        queue.close();
+        assert!(queue.push(test_update(2)).await.is_some());
+        assert_eq!(queue.pop().await.unwrap().get_time().timestamp(), 1);
+        assert!(queue.pop().await.is_none());
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn take_all_empties() {
+        let queue: Queue<PollUpdate> = Queue::new("test", 4, QueuePolicy::Block);
+        queue.push(test_update(1)).await;
+        queue.push(test_update(2)).await;
+        assert_eq!(queue.take_all().len(), 2);
+        assert_eq!(queue.depth(), 0);
+    }
+}
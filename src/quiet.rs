@@ -0,0 +1,108 @@
+//! Quiet hours: windows of local time when polling, and so alerting, is suspended, ex: overnight to save API calls.<br>
+//! Windows are written "HH:MM-HH:MM" and may run past midnight, ex: "22:00-06:00". The start is inside the window and the end is not.
+
+use std::fmt;
+use std::str::FromStr;
+use chrono::NaiveTime;
+
+/// A daily window of local time when nothing is polled
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QuietWindow {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl QuietWindow {
+    /// Create a window from its start and end. A window that ends before it starts runs past midnight.
+    pub fn new(start: NaiveTime, end: NaiveTime) -> QuietWindow {
+        QuietWindow { start, end }
+    }
+    /// Get the time the window starts
+    pub fn get_start(&self) -> NaiveTime {
+        self.start
+    }
+    /// Get the time the window ends
+    pub fn get_end(&self) -> NaiveTime {
+        self.end
+    }
+    /// Check if a time of day falls inside the window
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+impl FromStr for QuietWindow {
+    type Err = String;
+    fn from_str(raw_window: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid quiet hours \"{}\". Expected a start and end like 22:00-06:00.", raw_window.trim());
+        let (start, end) = raw_window.trim().split_once('-').ok_or_else(invalid)?;
+        let start: NaiveTime = NaiveTime::parse_from_str(start.trim(), "%H:%M").map_err(|_| invalid())?;
+        let end: NaiveTime = NaiveTime::parse_from_str(end.trim(), "%H:%M").map_err(|_| invalid())?;
+        if start == end {
+            return Err(format!("Quiet hours \"{}\" start and end at the same time.", raw_window.trim()));
+        }
+        Ok(QuietWindow { start, end })
+    }
+}
+
+impl fmt::Display for QuietWindow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.start.format("%H:%M"), self.end.format("%H:%M"))
+    }
+}
+
+/// Parse windows separated by commas, ex: "22:00-06:00,12:00-13:00"
+///
+/// # Errors
+/// Returns a message naming the first window that cannot be read
+pub fn parse_windows(raw_windows: &str) -> Result<Vec<QuietWindow>, String> {
+    raw_windows.split(',').filter(|window| !window.trim().is_empty()).map(|window| window.parse::<QuietWindow>()).collect()
+}
+
+/// Find the window a time of day falls inside, if any
+pub fn active_window(windows: &[QuietWindow], time: NaiveTime) -> Option<QuietWindow> {
+    windows.iter().find(|window| window.contains(time)).copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(hour: u32, minute: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn window_from_str() {
+        assert_eq!("08:00-17:30".parse::<QuietWindow>(), Ok(QuietWindow::new(at(8, 0), at(17, 30))));
+        assert_eq!(" 22:00 - 06:00 ".parse::<QuietWindow>().unwrap().to_string(), "22:00-06:00".to_string());
+        assert!("22:00".parse::<QuietWindow>().is_err());
+        assert!("25:00-06:00".parse::<QuietWindow>().is_err());
+        assert!("06:00-06:00".parse::<QuietWindow>().is_err());
+    }
+
+    #[test]
+    fn window_contains_same_day_and_overnight() {
+        let lunch: QuietWindow = QuietWindow::new(at(12, 0), at(13, 0));
+        assert!(lunch.contains(at(12, 0)));
+        assert!(!lunch.contains(at(13, 0)));
+        let night: QuietWindow = QuietWindow::new(at(22, 0), at(6, 0));
+        assert!(night.contains(at(23, 59)));
+        assert!(night.contains(at(0, 30)));
+        assert!(!night.contains(at(6, 0)));
+        assert!(!night.contains(at(12, 0)));
+    }
+
+    #[test]
+    fn active_window_checks_every_window() {
+        let windows: Vec<QuietWindow> = parse_windows("22:00-06:00, 12:00-13:00").unwrap();
+        assert_eq!(active_window(&windows, at(12, 15)), Some(QuietWindow::new(at(12, 0), at(13, 0))));
+        assert_eq!(active_window(&windows, at(9, 0)), None);
+        assert!(parse_windows("22:00-06:00,noon").is_err());
+        assert!(parse_windows("").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,366 @@
+//! Readings from the nearest official monitoring station, written alongside OpenWeatherMaps' modelled ones so the two can be compared.<br>
+//! OPENWEATHER_REFERENCE_STATION turns this on. "nearest" finds the closest reference-grade monitor to the configured location in OpenAQ's station metadata, or an OpenAQ location ID pins a station.
+//! Every poll fetches the station's latest values and writes them under the configured location with a source=reference tag, so a dashboard can plot model against monitor.
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use crate::{report, Config, PollUpdate};
+
+/// Base of OpenAQ's v3 API
+pub const OPENAQ_API: &str = "https://api.openaq.org/v3";
+/// Furthest a station may be from the configured location, the most OpenAQ allows in a search
+pub const SEARCH_RADIUS_M: u32 = 25000;
+/// Litres a mole of gas takes up at 25°C, for turning ppm and ppb into µg/m³
+const MOLAR_VOLUME: f32 = 24.45;
+
+/// Which station to compare against, set by OPENWEATHER_REFERENCE_STATION
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReferenceStation {
+    /// The closest reference-grade monitor to the configured location
+    Nearest,
+    /// A station by its OpenAQ location ID
+    Id(u64),
+}
+
+impl FromStr for ReferenceStation {
+    type Err = String;
+    fn from_str(raw_station: &str) -> Result<Self, Self::Err> {
+        let trimmed: &str = raw_station.trim();
+        if trimmed.eq_ignore_ascii_case("nearest") {
+            return Ok(ReferenceStation::Nearest);
+        }
+        match trimmed.parse::<u64>() {
+            Ok(id) => Ok(ReferenceStation::Id(id)),
+            Err(_) => Err(format!("Unknown reference station \"{}\". Expected nearest or an OpenAQ location ID, ex: 8118.", raw_station)),
+        }
+    }
+}
+
+impl fmt::Display for ReferenceStation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReferenceStation::Nearest => write!(f, "nearest"),
+            ReferenceStation::Id(id) => write!(f, "{}", id),
+        }
+    }
+}
+
+/// OpenAQ wraps every answer in a list of results
+#[derive(Deserialize)]
+struct Results<T> {
+    results: Vec<T>,
+}
+
+/// Where a station is
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub struct Coordinates {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+/// What a sensor measures and in which units, ex: "pm25" in "µg/m³"
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Parameter {
+    pub name: String,
+    pub units: String,
+}
+
+/// One instrument at a station
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Sensor {
+    pub id: u64,
+    pub parameter: Parameter,
+}
+
+/// A monitoring station from OpenAQ's metadata
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Station {
+    pub id: u64,
+    #[serde(default)]
+    pub name: Option<String>,
+    pub coordinates: Coordinates,
+    #[serde(default)]
+    pub sensors: Vec<Sensor>,
+}
+
+impl Station {
+    /// Get the station's name, or its ID if it has none
+    pub fn label(&self) -> String {
+        match &self.name {
+            Some(name) if !name.trim().is_empty() => format!("{} ({})", name, self.id),
+            _ => format!("OpenAQ location {}", self.id),
+        }
+    }
+}
+
+/// When a value was measured
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct MeasuredAt {
+    pub(crate) utc: DateTime<Utc>,
+}
+
+/// The latest value from one sensor
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct Latest {
+    datetime: MeasuredAt,
+    value: f64,
+    #[serde(rename = "sensorsId")]
+    sensors_id: u64,
+}
+
+/// Distance between two points in kilometres, along the surface of the Earth
+pub fn distance_km(from: Coordinates, to: Coordinates) -> f64 {
+    let (lat_from, lat_to): (f64, f64) = (from.latitude.to_radians(), to.latitude.to_radians());
+    let half_lat: f64 = (lat_to - lat_from) / 2.0;
+    let half_lon: f64 = (to.longitude - from.longitude).to_radians() / 2.0;
+    let a: f64 = half_lat.sin().powi(2) + lat_from.cos() * lat_to.cos() * half_lon.sin().powi(2);
+    2.0 * 6371.0 * a.sqrt().asin()
+}
+
+/// Pick the closest of a list of stations to a point
+pub fn nearest(stations: Vec<Station>, to: Coordinates) -> Option<Station> {
+    stations.into_iter().min_by(|first, second| distance_km(first.coordinates, to).total_cmp(&distance_km(second.coordinates, to)))
+}
+
+/// Build the OpenAQ URL listing reference-grade monitors within SEARCH_RADIUS_M of a point
+pub fn stations_url(lat: &str, lon: &str) -> String {
+    format!("{}/locations?coordinates={},{}&radius={}&monitor=true&limit=100", OPENAQ_API, lat, lon, SEARCH_RADIUS_M)
+}
+
+/// Build the OpenAQ URL for a single station
+pub fn station_url(id: u64) -> String {
+    format!("{}/locations/{}", OPENAQ_API, id)
+}
+
+/// Build the OpenAQ URL for a station's latest values
+pub fn latest_url(id: u64) -> String {
+    format!("{}/locations/{}/latest", OPENAQ_API, id)
+}
+
+/// Get the field a pollutant is written under, or None for one OpenWeatherMaps does not report
+pub(crate) fn field_for(parameter: &str) -> Option<&'static str> {
+    match parameter {
+        "co" => Some("co"),
+        "no" => Some("no"),
+        "no2" => Some("no2"),
+        "o3" => Some("o3"),
+        "so2" => Some("so2"),
+        "pm25" => Some("pm2_5"),
+        "pm10" => Some("pm10"),
+        "nh3" => Some("nh3"),
+        _ => None,
+    }
+}
+
+/// Grams per mole of the gases stations can report by volume
+fn molecular_weight(field: &str) -> Option<f32> {
+    match field {
+        "co" => Some(28.01),
+        "no" => Some(30.01),
+        "no2" => Some(46.01),
+        "o3" => Some(48.00),
+        "so2" => Some(64.07),
+        "nh3" => Some(17.03),
+        _ => None,
+    }
+}
+
+/// Turn a value into µg/m³, the unit OpenWeatherMaps uses for every pollutant, or None if the unit is not understood
+pub fn to_micrograms(field: &str, value: f32, units: &str) -> Option<f32> {
+    match units.trim().to_lowercase().as_str() {
+        "µg/m³" | "μg/m³" | "ug/m3" | "µg/m3" => Some(value),
+        "mg/m³" | "mg/m3" => Some(value * 1000.0),
+        "ppm" => molecular_weight(field).map(|weight| value * weight * 1000.0 / MOLAR_VOLUME),
+        "ppb" => molecular_weight(field).map(|weight| value * weight / MOLAR_VOLUME),
+        _ => None,
+    }
+}
+
+/// Turn a gas in µg/m³ into ppb at 25°C, the unit most official indices are defined in, or None if it is not a gas with a known molecular weight
+pub fn to_ppb(field: &str, micrograms: f32) -> Option<f32> {
+    molecular_weight(field).map(|weight| micrograms * MOLAR_VOLUME / weight)
+}
+
+/// Work out OpenWeatherMaps' 1 to 5 Air Quality Index from pollutants in µg/m³, using the worst of them<br>
+/// Returns 0 if none of the pollutants the index is based on were measured.
+pub fn openweather_index(values: &[(&str, f32)]) -> i8 {
+    values.iter().map(|(field, value)| {
+        let bands: [f32; 4] = match *field {
+            "so2" => [20.0, 80.0, 250.0, 350.0],
+            "no2" => [40.0, 70.0, 150.0, 200.0],
+            "pm10" => [20.0, 50.0, 100.0, 200.0],
+            "pm2_5" => [10.0, 25.0, 50.0, 75.0],
+            "o3" => [60.0, 100.0, 140.0, 180.0],
+            "co" => [4400.0, 9400.0, 12400.0, 15400.0],
+            _ => return 0,
+        };
+        1 + bands.iter().filter(|band| *value >= **band).count() as i8
+    }).max().unwrap_or(0)
+}
+
+/// Build a reference reading from a station's latest values, taking the newest time among them
+///
+/// # Errors
+/// Returns a message if none of the values are for a pollutant this client records
+fn build_update(station: &Station, latest: &[Latest]) -> Result<PollUpdate, String> {
+    Ok(PollUpdate { reference: true, ..latest_reading(station, latest)? })
+}
+
+/// Build a reading from a station's latest values in µg/m³, taking the newest time among them, with its location left as "pending"
+///
+/// # Errors
+/// Returns a message if none of the values are for a pollutant this client records
+pub(crate) fn latest_reading(station: &Station, latest: &[Latest]) -> Result<PollUpdate, String> {
+    let mut values: Vec<(&str, f32)> = Vec::new();
+    let mut time: Option<DateTime<Utc>> = None;
+    for reading in latest {
+        let parameter: &Parameter = match station.sensors.iter().find(|sensor| sensor.id == reading.sensors_id) {
+            Some(sensor) => &sensor.parameter,
+            None => continue,
+        };
+        let field: &str = match field_for(&parameter.name) {
+            Some(field) => field,
+            None => continue,
+        };
+        match to_micrograms(field, reading.value as f32, &parameter.units) {
+            Some(value) if value >= 0.0 => {
+                values.push((field, value));
+                time = time.max(Some(reading.datetime.utc));
+            },
+            Some(_) => report::debug(&format!("Skipping negative {} from {}", parameter.name, station.label())),
+            None => report::debug(&format!("Skipping {} from {} in unknown units {}", parameter.name, station.label(), parameter.units)),
+        }
+    }
+    let time: DateTime<Utc> = time.ok_or_else(|| format!("{} has no recent values for any pollutant this client records.", station.label()))?;
+    Ok(PollUpdate::from_measured(time, &values))
+}
+
+/// Build a reference reading from pollutants in µg/m³, with its location left as "pending"
+pub(crate) fn reading(time: DateTime<Utc>, values: &[(&str, f32)]) -> PollUpdate {
+    PollUpdate { reference: true, ..PollUpdate::from_measured(time, values) }
+}
+
+/// Fetch a list of results from OpenAQ with the configured API key
+pub(crate) fn get_results<T: serde::de::DeserializeOwned>(url: &str, apikey: &str) -> Result<Vec<T>, String> {
+    let response: Results<T> = match ureq::get(url).set("X-API-Key", apikey).call() {
+        Ok(response) => response.into_json().map_err(|e| format!("Unable to understand OpenAQ's answer: {}", e))?,
+        Err(ureq::Error::Status(401 | 403, _)) => return Err("OpenAQ rejected the API key. Check OPENWEATHER_OPENAQ_API_KEY.".to_string()),
+        Err(ureq::Error::Status(code, _)) => return Err(format!("OpenAQ answered status {}.", code)),
+        Err(ureq::Error::Transport(transport)) => return Err(format!("Unable to reach OpenAQ: {}", transport)),
+    };
+    Ok(response.results)
+}
+
+/// Look up the station a Config asks for
+///
+/// # Errors
+/// Returns a message if OpenAQ cannot be reached or has no matching station
+pub fn find_station(current_config: &Config, wanted: ReferenceStation) -> Result<Station, String> {
+    let apikey: &str = current_config.get_openaq_key().unwrap_or_default();
+    match wanted {
+        ReferenceStation::Id(id) => get_results::<Station>(&station_url(id), apikey)?.into_iter().next()
+            .ok_or_else(|| format!("OpenAQ has no location with ID {}.", id)),
+        ReferenceStation::Nearest => {
+            let coords: [String; 2] = current_config.get_coords();
+            let here: Coordinates = Coordinates { latitude: coords[0].parse().unwrap_or_default(), longitude: coords[1].parse().unwrap_or_default() };
+            find_nearest(apikey, here, current_config.get_location())
+        },
+    }
+}
+
+/// Look up the closest reference-grade monitor to a point within SEARCH_RADIUS_M, naming the location in the message if there is none
+///
+/// # Errors
+/// Returns a message if OpenAQ cannot be reached or has no monitor close enough
+pub fn find_nearest(apikey: &str, here: Coordinates, location: &str) -> Result<Station, String> {
+    nearest(get_results::<Station>(&stations_url(&here.latitude.to_string(), &here.longitude.to_string()), apikey)?, here)
+        .ok_or_else(|| format!("No reference monitor within {} km of {}.", SEARCH_RADIUS_M / 1000, location))
+}
+
+/// Fetches the latest values from the reference station each poll, finding the station the first time it is needed
+#[derive(Debug)]
+pub struct ReferenceMonitor {
+    config: Config,
+    wanted: ReferenceStation,
+    station: Mutex<Option<Station>>,
+}
+
+impl ReferenceMonitor {
+    /// Create the monitor for the station a Config names, or None if OPENWEATHER_REFERENCE_STATION is not set
+    pub fn from_config(current_config: &Config) -> Option<ReferenceMonitor> {
+        current_config.get_reference_station().map(|wanted| ReferenceMonitor { config: current_config.clone(), wanted, station: Mutex::new(None) })
+    }
+    /// Get the station's latest values as a reference reading, with its location left as "pending"<br>
+    /// A failed station lookup is tried again on the next call.
+    ///
+    /// # Errors
+    /// Returns a message if the station cannot be found or its values cannot be fetched
+    pub fn fetch(&self) -> Result<PollUpdate, String> {
+        let mut found = self.station.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if found.is_none() {
+            let station: Station = find_station(&self.config, self.wanted)?;
+            let here: [String; 2] = self.config.get_coords();
+            let here: Coordinates = Coordinates { latitude: here[0].parse().unwrap_or_default(), longitude: here[1].parse().unwrap_or_default() };
+            report::info(&format!("Comparing with reference station {}, {:.1} km away.", station.label(), distance_km(station.coordinates, here)));
+            *found = Some(station);
+        }
+        let station: &Station = found.as_ref().unwrap();
+        let latest: Vec<Latest> = get_results(&latest_url(station.id), self.config.get_openaq_key().unwrap_or_default())?;
+        build_update(station, &latest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_station() -> Station {
+        serde_json::from_str(r#"{"id":8118,"name":"Beacon Hill","coordinates":{"latitude":47.5683,"longitude":-122.3081},
+            "sensors":[{"id":1,"parameter":{"name":"pm25","units":"µg/m³"}},{"id":2,"parameter":{"name":"o3","units":"ppm"}},{"id":3,"parameter":{"name":"temperature","units":"c"}}]}"#).unwrap()
+    }
+
+    #[test]
+    fn reference_station_parsed() {
+        assert_eq!(" Nearest ".parse::<ReferenceStation>(), Ok(ReferenceStation::Nearest));
+        assert_eq!("8118".parse::<ReferenceStation>(), Ok(ReferenceStation::Id(8118)));
+        assert!("closest".parse::<ReferenceStation>().unwrap_err().contains("Expected nearest or an OpenAQ location ID"));
+    }
+
+    #[test]
+    fn nearest_station_chosen() {
+        let here: Coordinates = Coordinates { latitude: 47.6062, longitude: -122.3321 };
+        let far: Station = Station { id: 1, coordinates: Coordinates { latitude: 47.9, longitude: -122.2 }, ..test_station() };
+        assert_eq!(nearest(vec![far, test_station()], here).map(|station| station.id), Some(8118));
+        assert!((distance_km(here, test_station().coordinates) - 4.6).abs() < 0.2);
+        assert_eq!(nearest(Vec::new(), here), None);
+    }
+
+    #[test]
+    fn latest_values_become_a_reference_reading() {
+        let latest: Vec<Latest> = serde_json::from_str(r#"[{"datetime":{"utc":"2023-11-14T22:00:00Z"},"value":12.5,"sensorsId":1},
+            {"datetime":{"utc":"2023-11-14T21:00:00Z"},"value":0.05,"sensorsId":2},{"datetime":{"utc":"2023-11-14T22:00:00Z"},"value":8.0,"sensorsId":3}]"#).unwrap();
+        let update: PollUpdate = build_update(&test_station(), &latest).unwrap();
+        assert!(update.is_reference());
+        assert_eq!(update.get_time(), DateTime::from_timestamp(1699999200, 0).unwrap());
+        assert_eq!(update.get_field("pm2_5"), Some(12.5));
+        assert!((update.get_field("o3").unwrap() - 98.16).abs() < 0.01);
+        assert_eq!(update.get_field("no2"), None);
+        assert_eq!(update.get_aqi(), 2);
+        assert!(build_update(&test_station(), &[]).unwrap_err().contains("Beacon Hill (8118) has no recent values"));
+    }
+
+    #[test]
+    fn units_converted_to_micrograms() {
+        assert_eq!(to_micrograms("pm10", 20.0, "µg/m³"), Some(20.0));
+        assert!((to_micrograms("no2", 10.0, "ppb").unwrap() - 18.82).abs() < 0.01);
+        assert_eq!(to_micrograms("pm10", 20.0, "ppm"), None);
+        assert_eq!(to_micrograms("co", 1.0, "furlongs"), None);
+        assert!((to_ppb("no2", 18.82).unwrap() - 10.0).abs() < 0.01);
+        assert_eq!(to_ppb("pm2_5", 10.0), None);
+        assert_eq!(openweather_index(&[("pm2_5", 80.0), ("o3", 10.0)]), 5);
+        assert_eq!(openweather_index(&[("nh3", 80.0)]), 0);
+    }
+}
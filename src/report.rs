@@ -0,0 +1,372 @@
+//! How readings are shown on the console.<br>
+//! Plain output prints each response as it arrives. Table output prints the latest reading for every location as an aligned table,
+//! colored green, yellow or red by AQI category when writing to a terminal. Nothing is printed while the dashboard is showing.
+//! Separately, the verbosity set by --quiet and --verbose decides how much else reaches the console. Warnings and errors are always printed.
+//!
+//! Progress, warnings and errors are tracing events under the LOG_TARGET target, with any secrets in them redacted. Until a tracing subscriber is set, by init_logging or a program embedding this crate, they are printed as plain lines instead.
+//! init_logging sets one up that filters by RUST_LOG, ex: RUST_LOG=debug, and formats lines as OPENWEATHER_LOG_FORMAT asks, including JSON for log shippers like Loki or ELK.
+
+use std::env;
+use std::fmt;
+use std::io::IsTerminal;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use tracing_subscriber::EnvFilter;
+use crate::i18n::{self, Locale};
+use crate::indices::Index;
+use crate::redact::redact;
+use crate::trend;
+use crate::PollUpdate;
+
+/// The ways readings can be shown, set by OPENWEATHER_OUTPUT
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputMode {
+    /// The raw response printed as it arrives
+    Plain,
+    /// A table of the latest reading per location
+    Table,
+    /// Table on an interactive terminal, plain otherwise
+    Auto,
+}
+
+impl FromStr for OutputMode {
+    type Err = String;
+    fn from_str(raw_mode: &str) -> Result<Self, Self::Err> {
+        match raw_mode.trim().to_lowercase().as_str() {
+            "plain" => Ok(OutputMode::Plain),
+            "table" => Ok(OutputMode::Table),
+            "auto" => Ok(OutputMode::Auto),
+            _ => Err(format!("Unknown output mode \"{}\". Expected one of plain, table or auto.", raw_mode)),
+        }
+    }
+}
+
+impl fmt::Display for OutputMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OutputMode::Plain => write!(f, "plain"),
+            OutputMode::Table => write!(f, "table"),
+            OutputMode::Auto => write!(f, "auto"),
+        }
+    }
+}
+
+/// How log lines are formatted, set by OPENWEATHER_LOG_FORMAT
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    /// The message alone, as the console has always shown it
+    Plain,
+    /// The time, level, target and any span fields before each message
+    Text,
+    /// One JSON object per line with the time, level, target, message and span fields
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+    fn from_str(raw_format: &str) -> Result<Self, Self::Err> {
+        match raw_format.trim().to_lowercase().as_str() {
+            "plain" => Ok(LogFormat::Plain),
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Unknown log format \"{}\". Expected one of plain, text or json.", raw_format)),
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LogFormat::Plain => write!(f, "plain"),
+            LogFormat::Text => write!(f, "text"),
+            LogFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// Target of every event logged through this module, so RUST_LOG can name it, ex: RUST_LOG=pollutionclient_rs=debug
+pub const LOG_TARGET: &str = "pollutionclient_rs";
+
+static FILTERED_BY_ENV: AtomicBool = AtomicBool::new(false);
+
+/// Work out the log format from OPENWEATHER_LOG_FORMAT and RUST_LOG, both read from the environment as logging starts before any configuration file is read<br>
+/// Defaults to plain, or to text when RUST_LOG is set so its levels and targets can be seen.
+///
+/// # Errors
+/// Returns a message if OPENWEATHER_LOG_FORMAT is not a known format
+pub fn log_format_from_env() -> Result<LogFormat, String> {
+    match env::var("OPENWEATHER_LOG_FORMAT") {
+        Ok(raw_format) => raw_format.parse::<LogFormat>(),
+        Err(_) if env::var("RUST_LOG").is_ok_and(|filter| !filter.trim().is_empty()) => Ok(LogFormat::Text),
+        Err(_) => Ok(LogFormat::Plain),
+    }
+}
+
+/// Send progress, warnings and errors to a tracing subscriber writing to stdout in a format<br>
+/// When RUST_LOG is set it decides what is logged in place of --quiet and --verbose. Otherwise every event allowed by the verbosity is logged.
+/// Plain without RUST_LOG needs no subscriber, so none is set and messages keep being printed as they are.
+///
+/// # Errors
+/// Returns a message if RUST_LOG cannot be parsed or a subscriber has already been set
+pub fn init_logging(format: LogFormat) -> Result<(), String> {
+    let rust_log: Option<String> = env::var("RUST_LOG").ok().filter(|directives| !directives.trim().is_empty());
+    if format == LogFormat::Plain && rust_log.is_none() {
+        return Ok(());
+    }
+    let filter: EnvFilter = match rust_log {
+        Some(directives) => {
+            FILTERED_BY_ENV.store(true, Ordering::Relaxed);
+            EnvFilter::try_new(directives.trim()).map_err(|e| format!("Unable to use RUST_LOG of {}: {}", directives, e))?
+        },
+        None => EnvFilter::new(format!("warn,{}=debug", LOG_TARGET)),
+    };
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter).with_writer(std::io::stdout);
+    let installed = match format {
+        LogFormat::Plain => subscriber.without_time().with_level(false).with_target(false).with_ansi(false).try_init(),
+        LogFormat::Text => subscriber.try_init(),
+        LogFormat::Json => subscriber.json().flatten_event(true).with_current_span(true).with_span_list(false).try_init(),
+    };
+    installed.map_err(|e| format!("Unable to set up logging: {}", e))
+}
+
+/// Check if events go to a tracing subscriber rather than being printed
+fn logging() -> bool {
+    tracing::dispatcher::has_been_set()
+}
+
+/// Check if a message at a verbosity should be logged, leaving it to RUST_LOG when that is set
+fn allowed(needed: Verbosity) -> bool {
+    (logging() && FILTERED_BY_ENV.load(Ordering::Relaxed)) || verbosity() >= needed
+}
+
+/// How much is printed to the console, from only warnings and errors up to every field of every update
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+}
+
+static VERBOSITY: AtomicU8 = AtomicU8::new(1);
+
+/// Set how much is printed from now on
+pub fn set_verbosity(new_verbosity: Verbosity) {
+    let level: u8 = match new_verbosity {
+        Verbosity::Quiet => 0,
+        Verbosity::Normal => 1,
+        Verbosity::Verbose => 2,
+    };
+    VERBOSITY.store(level, Ordering::Relaxed);
+}
+
+/// Get how much is being printed. Defaults to Normal.
+pub fn verbosity() -> Verbosity {
+    match VERBOSITY.load(Ordering::Relaxed) {
+        0 => Verbosity::Quiet,
+        2 => Verbosity::Verbose,
+        _ => Verbosity::Normal,
+    }
+}
+
+/// Log a progress message unless running quietly
+pub fn info(message: &str) {
+    if !allowed(Verbosity::Normal) {
+        return;
+    }
+    if logging() {
+        tracing::info!(target: LOG_TARGET, "{}", redact(message));
+    } else {
+        println!("{}", redact(message));
+    }
+}
+
+/// Log a detail message only when running verbosely
+pub fn debug(message: &str) {
+    if !allowed(Verbosity::Verbose) {
+        return;
+    }
+    if logging() {
+        tracing::debug!(target: LOG_TARGET, "{}", redact(message));
+    } else {
+        println!("{}", redact(message));
+    }
+}
+
+/// Log a warning, ex: a setting being ignored or a reading that could not be saved, whatever the verbosity
+pub fn warn(message: &str) {
+    if logging() {
+        tracing::warn!(target: LOG_TARGET, "{}", redact(message));
+    } else {
+        println!("{}", redact(message));
+    }
+}
+
+/// Log an error that stops something from working, ex: readings being lost, whatever the verbosity
+pub fn error(message: &str) {
+    if logging() {
+        tracing::error!(target: LOG_TARGET, "{}", redact(message));
+    } else {
+        println!("{}", redact(message));
+    }
+}
+
+/// List every field of an update on one line, ex: "location=Home aqi=2 co=201.94 ..."
+pub fn describe_fields(update: &PollUpdate) -> String {
+    let mut fields: Vec<String> = FIELDS.iter().filter_map(|field| update.get_field(field).map(|value| format!("{}={}", field, value))).collect();
+    fields.extend(Index::ALL.iter().filter_map(|index| update.get_index(index.field()).map(|value| format!("{}={}", index, value))));
+    fields.extend(trend::POLLUTANTS.iter().filter_map(|pollutant| update.get_trend(pollutant).map(|trend| format!("{}={} {}={}", trend::rate_field(pollutant), trend.rate, trend::direction_field(pollutant), trend.direction))));
+    let source: &str = if update.is_reference() { " source=reference" } else { "" };
+    format!("location={}{} time={} {}", update.get_location(), source, update.get_time().to_rfc3339(), fields.join(" "))
+}
+
+/// Every field of an update in the order they are written
+const FIELDS: [&str; 9] = ["aqi", "co", "no", "no2", "o3", "so2", "pm2_5", "pm10", "nh3"];
+
+const PLAIN: u8 = 0;
+const TABLE: u8 = 1;
+const SILENT: u8 = 2;
+
+static SHOWING: AtomicU8 = AtomicU8::new(PLAIN);
+
+/// Set how readings are shown from now on, resolving Auto by checking if stdout is a terminal<br>
+/// Has no effect once silence has been called
+pub fn set_mode(mode: OutputMode) {
+    let showing: u8 = match mode {
+        OutputMode::Plain => PLAIN,
+        OutputMode::Table => TABLE,
+        OutputMode::Auto if std::io::stdout().is_terminal() => TABLE,
+        OutputMode::Auto => PLAIN,
+    };
+    let _ = SHOWING.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |current| (current != SILENT).then_some(showing));
+}
+
+/// Stop printing readings at all, ex: while the dashboard has the terminal
+pub fn silence() {
+    SHOWING.store(SILENT, Ordering::Relaxed);
+}
+
+/// Check if readings are being shown as a table rather than printed as they arrive. Always false when running quietly.
+pub fn is_table() -> bool {
+    SHOWING.load(Ordering::Relaxed) == TABLE && verbosity() != Verbosity::Quiet
+}
+
+/// Check if readings are printed as they arrive. Always false when running quietly.
+pub fn is_plain() -> bool {
+    SHOWING.load(Ordering::Relaxed) == PLAIN && verbosity() != Verbosity::Quiet
+}
+
+/// Fields shown in the table after the location, time and AQI, with their headings
+const COLUMNS: [(&str, &str); 8] = [("pm2_5", "PM2.5"), ("pm10", "PM10"), ("o3", "O3"), ("no2", "NO2"), ("so2", "SO2"), ("co", "CO"), ("nh3", "NH3"), ("no", "NO")];
+
+const GREEN: &str = "\x1b[32m";
+const YELLOW: &str = "\x1b[33m";
+const RED: &str = "\x1b[31m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+/// Get the color for an AQI: green for good or fair, yellow for moderate and red for poor or worse
+fn aqi_color(aqi: i8) -> &'static str {
+    match aqi {
+        i8::MIN..=2 => GREEN,
+        3 => YELLOW,
+        _ => RED,
+    }
+}
+
+/// Lay out readings as a table with one row per reading, amounts in μg/m3<br>
+/// Colors are ANSI escape codes, added outside the padding so columns stay aligned
+pub fn render_table(readings: &[PollUpdate], locale: Locale, color: bool) -> String {
+    let mut headings: Vec<String> = vec![i18n::location_label(locale).to_string(), i18n::time_label(locale).to_string(), "AQI".to_string()];
+    headings.extend(COLUMNS.iter().map(|(_, heading)| heading.to_string()));
+    let rows: Vec<Vec<String>> = readings.iter().map(|reading| {
+        let mut row: Vec<String> = vec![reading.get_location().to_string(), reading.get_time().format("%Y-%m-%d %H:%M").to_string(), format!("{} {}", reading.get_aqi(), i18n::aqi_category(locale, reading.get_aqi()))];
+        row.extend(COLUMNS.iter().map(|(field, _)| reading.get_field(field).map(|value| format!("{:.1}", value)).unwrap_or_else(|| "-".to_string())));
+        row
+    }).collect();
+    let widths: Vec<usize> = (0..headings.len()).map(|column| {
+        rows.iter().map(|row| row[column].chars().count()).chain(std::iter::once(headings[column].chars().count())).max().unwrap_or(0)
+    }).collect();
+
+    let mut table: String = String::new();
+    let heading_line: String = pad_row(&headings, &widths);
+    if color {
+        table.push_str(&format!("{}{}{}\n", BOLD, heading_line, RESET));
+    } else {
+        table.push_str(&format!("{}\n", heading_line));
+    }
+    table.push_str(&format!("{}\n", widths.iter().map(|width| "-".repeat(*width)).collect::<Vec<String>>().join("-+-")));
+    for (reading, row) in readings.iter().zip(rows.iter()) {
+        let line: String = pad_row(row, &widths);
+        if color {
+            table.push_str(&format!("{}{}{}\n", aqi_color(reading.get_aqi()), line, RESET));
+        } else {
+            table.push_str(&format!("{}\n", line));
+        }
+    }
+    table
+}
+
+/// Pad each cell to its column width, text to the left and numbers to the right
+fn pad_row(cells: &[String], widths: &[usize]) -> String {
+    cells.iter().zip(widths).enumerate().map(|(column, (cell, width))| {
+        if column < 3 {
+            format!("{:<width$}", cell, width = width)
+        } else {
+            format!("{:>width$}", cell, width = width)
+        }
+    }).collect::<Vec<String>>().join(" | ")
+}
+
+/// Print the table for a set of readings, colored only when stdout is a terminal
+pub fn print_table(readings: &[PollUpdate]) {
+    print!("{}", render_table(readings, i18n::current(), std::io::stdout().is_terminal()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_update(location: &str, aqi: i8, pm2_5: f32) -> PollUpdate {
+        PollUpdate { aqi, co: Some(201.94), no2: Some(0.77), o3: Some(68.66), so2: Some(0.64), pm2_5: Some(pm2_5), pm10: Some(0.54), nh3: Some(0.12), ..PollUpdate::fixture(location, 1700000000) }
+    }
+
+    #[test]
+    fn output_mode_from_str() {
+        assert_eq!("Table".parse::<OutputMode>(), Ok(OutputMode::Table));
+        assert_eq!(" auto ".parse::<OutputMode>(), Ok(OutputMode::Auto));
+        assert!("fancy".parse::<OutputMode>().is_err());
+    }
+
+    #[test]
+    fn log_format_from_str() {
+        assert_eq!(" JSON ".parse::<LogFormat>(), Ok(LogFormat::Json));
+        assert_eq!("text".parse::<LogFormat>().unwrap().to_string(), "text");
+        assert!("logfmt".parse::<LogFormat>().unwrap_err().starts_with("Unknown log format \"logfmt\""));
+    }
+
+    #[test]
+    fn describe_fields_lists_everything() {
+        assert_eq!(describe_fields(&test_update("Home", 2, 12.5)), "location=Home time=2023-11-14T22:13:20+00:00 aqi=2 co=201.94 no=0 no2=0.77 o3=68.66 so2=0.64 pm2_5=12.5 pm10=0.54 nh3=0.12".to_string());
+    }
+
+    #[test]
+    fn table_columns_align() {
+        let table: String = render_table(&[test_update("Home", 1, 0.5), test_update("Grandmas House", 4, 120.25)], Locale::English, false);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("Location       | Time             | AQI"));
+        assert!(lines[2].starts_with("Home           | 2023-11-14 22:13 | 1 Good"));
+        assert!(lines[3].contains("4 Poor | 120.2 |"));
+        assert!(lines.iter().all(|line| line.chars().count() == lines[0].chars().count()));
+    }
+
+    #[test]
+    fn table_colors_by_aqi() {
+        let table: String = render_table(&[test_update("Home", 2, 5.0), test_update("Work", 3, 30.0), test_update("Mill", 5, 300.0)], Locale::English, true);
+        let lines: Vec<&str> = table.lines().collect();
+        assert!(lines[0].starts_with(BOLD));
+        assert!(lines[2].starts_with(GREEN));
+        assert!(lines[3].starts_with(YELLOW));
+        assert!(lines[4].starts_with(RED) && lines[4].ends_with(RESET));
+    }
+}
@@ -0,0 +1,186 @@
+//! How the polling loop catches up when a cycle runs longer than the poll interval, ex: a slow database or a backfill in progress.<br>
+//! Also how long it waits before retrying after a failed fetch, set by OPENWEATHER_RETRY_BACKOFF.
+
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+use tokio::time::MissedTickBehavior;
+use crate::cli;
+
+/// Delay before the first retry when OPENWEATHER_RETRY_BACKOFF does not give one
+pub const DEFAULT_BACKOFF_INITIAL: Duration = Duration::from_secs(30);
+/// How much the delay grows with each failure in a row when OPENWEATHER_RETRY_BACKOFF does not say
+pub const DEFAULT_BACKOFF_MULTIPLIER: f64 = 2.0;
+/// Longest delay between retries when OPENWEATHER_RETRY_BACKOFF does not give one
+pub const DEFAULT_BACKOFF_MAX: Duration = Duration::from_secs(900);
+/// Fraction of the delay added or taken away at random when OPENWEATHER_RETRY_BACKOFF does not give one
+pub const DEFAULT_BACKOFF_JITTER: f64 = 0.2;
+
+/// What to do with polls that were due while a cycle overran, set by OPENWEATHER_MISSED_TICKS
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MissedTickPolicy {
+    /// Poll once straight away, then carry on at the next time on the original schedule
+    Skip,
+    /// Poll once straight away, then wait a full interval from then, shifting the schedule
+    Delay,
+    /// Poll once for every missed interval as fast as possible until caught up
+    Burst,
+}
+
+impl FromStr for MissedTickPolicy {
+    type Err = String;
+    fn from_str(raw_policy: &str) -> Result<Self, Self::Err> {
+        match raw_policy.trim().to_lowercase().as_str() {
+            "skip" => Ok(MissedTickPolicy::Skip),
+            "delay" => Ok(MissedTickPolicy::Delay),
+            "burst" => Ok(MissedTickPolicy::Burst),
+            _ => Err(format!("Unknown missed tick policy \"{}\". Expected skip, delay or burst.", raw_policy)),
+        }
+    }
+}
+
+impl fmt::Display for MissedTickPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MissedTickPolicy::Skip => write!(f, "skip"),
+            MissedTickPolicy::Delay => write!(f, "delay"),
+            MissedTickPolicy::Burst => write!(f, "burst"),
+        }
+    }
+}
+
+impl From<MissedTickPolicy> for MissedTickBehavior {
+    fn from(policy: MissedTickPolicy) -> Self {
+        match policy {
+            MissedTickPolicy::Skip => MissedTickBehavior::Skip,
+            MissedTickPolicy::Delay => MissedTickBehavior::Delay,
+            MissedTickPolicy::Burst => MissedTickBehavior::Burst,
+        }
+    }
+}
+
+/// How long to wait before retrying after failed fetches, growing with each failure in a row, set by OPENWEATHER_RETRY_BACKOFF<br>
+/// Written as comma separated settings, ex: "initial=30s,multiplier=2,max=15m,jitter=0.2". Any left out take their defaults.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Backoff {
+    /// Delay before the first retry
+    pub initial: Duration,
+    /// How much the delay is multiplied by for each further failure in a row, at least 1
+    pub multiplier: f64,
+    /// Longest delay, however many failures there have been
+    pub max: Duration,
+    /// Fraction of the delay, from 0 to 1, randomly added or taken away so clients that failed together do not retry together
+    pub jitter: f64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff { initial: DEFAULT_BACKOFF_INITIAL, multiplier: DEFAULT_BACKOFF_MULTIPLIER, max: DEFAULT_BACKOFF_MAX, jitter: DEFAULT_BACKOFF_JITTER }
+    }
+}
+
+impl FromStr for Backoff {
+    type Err = String;
+    fn from_str(raw_backoff: &str) -> Result<Self, Self::Err> {
+        let mut backoff: Backoff = Backoff::default();
+        for setting in raw_backoff.split(',').map(str::trim).filter(|setting| !setting.is_empty()) {
+            let (key, value) = setting.split_once('=')
+                .ok_or_else(|| format!("Invalid retry backoff setting \"{}\". Expected key=value, ex: initial=30s.", setting))?;
+            let value: &str = value.trim();
+            match key.trim().to_lowercase().as_str() {
+                "initial" => backoff.initial = cli::parse_duration(value)?,
+                "max" => backoff.max = cli::parse_duration(value)?,
+                "multiplier" => backoff.multiplier = match value.parse::<f64>() {
+                    Ok(multiplier) if multiplier >= 1.0 && multiplier.is_finite() => multiplier,
+                    _ => return Err(format!("Invalid retry backoff multiplier \"{}\". Expected a number of at least 1.", value)),
+                },
+                "jitter" => backoff.jitter = match value.parse::<f64>() {
+                    Ok(jitter) if (0.0..=1.0).contains(&jitter) => jitter,
+                    _ => return Err(format!("Invalid retry backoff jitter \"{}\". Expected a number from 0 to 1.", value)),
+                },
+                other => return Err(format!("Unknown retry backoff setting \"{}\". Expected initial, multiplier, max or jitter.", other)),
+            }
+        }
+        if backoff.initial > backoff.max {
+            return Err(format!("Invalid retry backoff \"{}\". The initial delay is longer than the max.", raw_backoff));
+        }
+        Ok(backoff)
+    }
+}
+
+impl fmt::Display for Backoff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "initial={}s,multiplier={},max={}s,jitter={}", self.initial.as_secs_f64(), self.multiplier, self.max.as_secs_f64(), self.jitter)
+    }
+}
+
+impl Backoff {
+    /// Work out the delay after a number of failures in a row, starting at 1<br>
+    /// sample is a number from 0 to 1 picking where in the jitter range the delay lands, 0.5 being no jitter.
+    pub fn delay(&self, failures: u32, sample: f64) -> Duration {
+        let exponent: i32 = i32::try_from(failures.saturating_sub(1)).unwrap_or(i32::MAX);
+        let base: f64 = (self.initial.as_secs_f64() * self.multiplier.powi(exponent)).min(self.max.as_secs_f64());
+        let jittered: f64 = base * (1.0 + self.jitter * (2.0 * sample.clamp(0.0, 1.0) - 1.0));
+        Duration::from_secs_f64(jittered.clamp(0.0, self.max.as_secs_f64()))
+    }
+    /// Work out the delay after a number of failures in a row with a random amount of jitter
+    pub fn next_delay(&self, failures: u32) -> Duration {
+        self.delay(failures, random_sample())
+    }
+}
+
+/// Pick a number from 0 to 1, random enough to spread out retries without pulling in a random number crate
+fn random_sample() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(|since| since.as_nanos()).unwrap_or(0));
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missed_tick_policy_from_str() {
+        assert_eq!("Skip".parse::<MissedTickPolicy>(), Ok(MissedTickPolicy::Skip));
+        assert_eq!(" delay ".parse::<MissedTickPolicy>(), Ok(MissedTickPolicy::Delay));
+        assert_eq!("BURST".parse::<MissedTickPolicy>(), Ok(MissedTickPolicy::Burst));
+        assert!("catch-up".parse::<MissedTickPolicy>().is_err());
+    }
+
+    #[test]
+    fn missed_tick_policy_round_trips() {
+        for policy in [MissedTickPolicy::Skip, MissedTickPolicy::Delay, MissedTickPolicy::Burst] {
+            assert_eq!(policy.to_string().parse::<MissedTickPolicy>(), Ok(policy));
+        }
+        assert_eq!(MissedTickBehavior::from(MissedTickPolicy::Delay), MissedTickBehavior::Delay);
+    }
+
+    #[test]
+    fn backoff_from_str() {
+        assert_eq!("".parse::<Backoff>(), Ok(Backoff::default()));
+        let backoff: Backoff = "initial=10s, multiplier=3,max=5m".parse().unwrap();
+        assert_eq!(backoff, Backoff { initial: Duration::from_secs(10), multiplier: 3.0, max: Duration::from_secs(300), jitter: DEFAULT_BACKOFF_JITTER });
+        assert_eq!(backoff.to_string().parse::<Backoff>(), Ok(backoff));
+        assert!("multiplier=0.5".parse::<Backoff>().is_err());
+        assert!("jitter=2".parse::<Backoff>().is_err());
+        assert!("initial=1h,max=10m".parse::<Backoff>().is_err());
+        assert!("retries=5".parse::<Backoff>().is_err());
+        assert!("initial".parse::<Backoff>().is_err());
+    }
+
+    #[test]
+    fn backoff_grows_and_caps() {
+        let backoff: Backoff = "initial=30s,multiplier=2,max=5m,jitter=0.2".parse().unwrap();
+        assert_eq!(backoff.delay(1, 0.5), Duration::from_secs(30));
+        assert_eq!(backoff.delay(3, 0.5), Duration::from_secs(120));
+        assert_eq!(backoff.delay(10, 0.5), Duration::from_secs(300));
+        assert_eq!(backoff.delay(1, 0.0), Duration::from_secs(24));
+        assert_eq!(backoff.delay(1, 1.0), Duration::from_secs(36));
+        assert_eq!(backoff.delay(10, 1.0), Duration::from_secs(300));
+        let delay: Duration = backoff.next_delay(2);
+        assert!(delay >= Duration::from_secs(48) && delay <= Duration::from_secs(72));
+    }
+}
@@ -0,0 +1,153 @@
+//! How readings are laid out in InfluxDB, so the client can write into a schema another collector has already created.<br>
+//! OPENWEATHER_INFLUXDB_FIELD_NAMES renames fields on the way in and out, ex: "pm2_5=pm25,aqi=owm_aqi" writes PM2.5 as pm25 and reads pm25 back as pm2_5, so existing dashboards keep working.
+//!
+//! Every point is stamped with a schema_version field so dashboards can branch on the layout they were written in.
+//! VERSIONS records what each version changed. Adding or renaming a field means adding a version to it and raising SCHEMA_VERSION, so older points can still be read and migrated.
+
+use std::collections::BTreeMap;
+
+/// Every field written for a reading, by the name the client uses for it
+pub const FIELDS: [&str; 9] = ["aqi", "co", "no", "no2", "o3", "so2", "pm2_5", "pm10", "nh3"];
+
+/// Renamed fields, from the client's name to the name stored in InfluxDB
+pub type FieldNames = BTreeMap<String, String>;
+
+/// Field every point is stamped with its schema version in
+pub const SCHEMA_VERSION_FIELD: &str = "schema_version";
+/// Field holding when the client fetched a reading, as a Unix timestamp, when OPENWEATHER_WRITE_COLLECTED_AT is on
+pub const COLLECTED_AT_FIELD: &str = "collected_at";
+/// Version of the layout this client writes, the last entry in VERSIONS
+pub const SCHEMA_VERSION: i64 = 7;
+
+/// One version of the layout points are written in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SchemaVersion {
+    /// Number stamped in the schema_version field. Points from before stamping are version 1.
+    pub version: i64,
+    /// Fields holding readings, not counting schema_version
+    pub fields: &'static [&'static str],
+    /// Tags points can carry
+    pub tags: &'static [&'static str],
+    /// Fields renamed by this version, from the old name to the new one
+    pub renamed: &'static [(&'static str, &'static str)],
+    /// What changed from the version before
+    pub changes: &'static str,
+}
+
+/// Every schema version, oldest first
+pub const VERSIONS: &[SchemaVersion] = &[
+    SchemaVersion { version: 1, fields: &FIELDS, tags: &["location"], renamed: &[], changes: "The location tag and a field for the AQI and each pollutant." },
+    SchemaVersion { version: 2, fields: &FIELDS, tags: &["location", "interpolated"], renamed: &[], changes: "Adds the schema_version field, and the interpolated tag on readings filled in by OPENWEATHER_INTERPOLATE_GAPS." },
+    SchemaVersion { version: 3, fields: &FIELDS, tags: &["location", "interpolated", "source"], renamed: &[], changes: "Adds the source tag, set to reference on readings from the official monitoring station found by OPENWEATHER_REFERENCE_STATION." },
+    SchemaVersion { version: 4, fields: &FIELDS, tags: &["location", "interpolated", "source"], renamed: &[], changes: "Adds a field for each index set in OPENWEATHER_INDICES, ex: aqhi, on readings it could be worked out for." },
+    SchemaVersion { version: 5, fields: &FIELDS, tags: &["location", "interpolated", "source"], renamed: &[], changes: "Adds a {pollutant}_rate and {pollutant}_trend field for each pollutant when OPENWEATHER_TREND_HOURS is set, ex: pm2_5_rate and pm2_5_trend." },
+    SchemaVersion { version: 6, fields: &FIELDS, tags: &["location", "interpolated", "source", "forecast"], renamed: &[], changes: "Adds the forecast tag, set to true on hours of the forecast written by OPENWEATHER_WRITE_FORECAST." },
+    SchemaVersion { version: 7, fields: &FIELDS, tags: &["location", "interpolated", "source", "forecast"], renamed: &[], changes: "Adds the collected_at field when OPENWEATHER_WRITE_COLLECTED_AT is on, holding when the client fetched the reading." },
+];
+
+/// Look up a schema version in VERSIONS
+pub fn version(number: i64) -> Option<&'static SchemaVersion> {
+    VERSIONS.iter().find(|known| known.version == number)
+}
+
+/// Get the current name of a field written in an older schema version, following every rename made since
+pub fn upgrade_field_name(from_version: i64, field: &str) -> &str {
+    VERSIONS.iter()
+        .filter(|later| later.version > from_version)
+        .flat_map(|later| later.renamed.iter())
+        .fold(field, |field, (old, new)| if *old == field { new } else { field })
+}
+
+/// Compare the newest schema version found in stored points with the one this client writes<br>
+/// Returns a message to print if they differ, or None if they match or nothing has been stamped yet
+pub fn compatibility(stored: Option<i64>) -> Option<String> {
+    match stored {
+        None => None,
+        Some(stored) if stored == SCHEMA_VERSION => None,
+        Some(stored) if stored > SCHEMA_VERSION => Some(format!("Stored points use schema version {}, newer than the version {} this client writes. New points will be written in the older layout, so update this client to match.", stored, SCHEMA_VERSION)),
+        Some(stored) => match version(stored) {
+            Some(_) => {
+                let changes: Vec<&str> = VERSIONS.iter().filter(|later| later.version > stored).map(|later| later.changes).collect();
+                Some(format!("Stored points use schema version {} and new points will use version {}. {}", stored, SCHEMA_VERSION, changes.join(" ")))
+            },
+            None => Some(format!("Stored points use schema version {}, which this client does not recognise.", stored)),
+        },
+    }
+}
+
+/// Get the name a field is stored under, which is its own name unless it has been renamed
+pub fn field_name<'a>(names: &'a FieldNames, field: &'a str) -> &'a str {
+    names.get(field).map(String::as_str).unwrap_or(field)
+}
+
+/// Parse field renames separated by commas, ex: "pm2_5=pm25,aqi=owm_aqi"
+///
+/// # Errors
+/// Returns a message naming the problem if a rename is not written field=name, renames a field that does not exist, or would store two fields under the same name
+pub fn parse_field_names(raw_names: &str) -> Result<FieldNames, String> {
+    let mut names: FieldNames = FieldNames::new();
+    for pair in raw_names.split(',').map(str::trim).filter(|pair| !pair.is_empty()) {
+        let (field, name) = match pair.split_once('=') {
+            Some((field, name)) if !name.trim().is_empty() => (field.trim(), name.trim()),
+            _ => return Err(format!("Unable to understand field rename \"{}\". Expected field=name, ex: pm2_5=pm25.", pair)),
+        };
+        if !FIELDS.contains(&field) {
+            return Err(format!("Unknown field \"{}\" in field rename \"{}\". Expected one of {}.", field, pair, FIELDS.join(", ")));
+        }
+        if name == "time" {
+            return Err(format!("Unable to rename {} to time, which InfluxDB reserves for the timestamp.", field));
+        }
+        if name == SCHEMA_VERSION_FIELD {
+            return Err(format!("Unable to rename {} to {}, which holds the schema version of every point.", field, SCHEMA_VERSION_FIELD));
+        }
+        names.insert(field.to_string(), name.to_string());
+    }
+    for (index, field) in FIELDS.iter().enumerate() {
+        let stored: &str = field_name(&names, field);
+        if let Some(other) = FIELDS[index + 1..].iter().find(|other| field_name(&names, other) == stored) {
+            return Err(format!("Fields {} and {} would both be stored as {}.", field, other, stored));
+        }
+    }
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn field_names_parsed() {
+        let names: FieldNames = parse_field_names("pm2_5=pm25, aqi = owm_aqi,").unwrap();
+        assert_eq!(field_name(&names, "pm2_5"), "pm25");
+        assert_eq!(field_name(&names, "aqi"), "owm_aqi");
+        assert_eq!(field_name(&names, "co"), "co");
+        assert!(parse_field_names("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn bad_field_names_rejected() {
+        assert!(parse_field_names("pm2_5").unwrap_err().contains("Expected field=name"));
+        assert!(parse_field_names("pm1=pm_one").unwrap_err().contains("Unknown field \"pm1\""));
+        assert!(parse_field_names("aqi=time").is_err());
+        assert!(parse_field_names("aqi=schema_version").is_err());
+        assert_eq!(parse_field_names("pm2_5=pm10").unwrap_err(), "Fields pm2_5 and pm10 would both be stored as pm10.");
+        assert!(parse_field_names("pm2_5=pm10,pm10=pm_10").is_ok());
+    }
+
+    #[test]
+    fn versions_are_in_order() {
+        assert_eq!(VERSIONS.last().map(|last| last.version), Some(SCHEMA_VERSION));
+        assert!(VERSIONS.windows(2).all(|pair| pair[1].version == pair[0].version + 1));
+        assert_eq!(upgrade_field_name(1, "pm2_5"), "pm2_5");
+        assert_eq!(version(1).map(|found| found.tags), Some(&["location"][..]));
+    }
+
+    #[test]
+    fn compatibility_messages() {
+        assert_eq!(compatibility(None), None);
+        assert_eq!(compatibility(Some(SCHEMA_VERSION)), None);
+        assert!(compatibility(Some(1)).unwrap().contains("Adds the schema_version field"));
+        assert!(compatibility(Some(SCHEMA_VERSION + 1)).unwrap().starts_with("Stored points use schema version 8, newer"));
+        assert!(compatibility(Some(0)).unwrap().contains("does not recognise"));
+    }
+}
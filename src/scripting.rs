@@ -0,0 +1,177 @@
+//! rhai scripts for alert conditions and transforms, only built with the "scripting" feature.<br>
+//! Every script sees the update as variables: location, aqi, co, no, no2, o3, so2, pm2_5, pm10 and nh3.
+//! A pollutant that was not measured is the unit value (), ex: `if no2 != () { ... }`.
+//! A transform can change any of them and keeps the update unless it returns false. A condition must return true or false.
+
+use rhai::{Dynamic, Engine, Scope, AST};
+use crate::plugin::{PluginError, Transform};
+use crate::PollUpdate;
+
+/// A compiled script ready to run against updates
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    source: String,
+}
+
+impl Script {
+    /// Compile a script
+    ///
+    /// # Errors
+    /// Returns the parse error as a message
+    pub fn compile(source: &str) -> Result<Script, String> {
+        let engine: Engine = Engine::new();
+        let ast: AST = engine.compile(source).map_err(|e| e.to_string())?;
+        Ok(Script { engine, ast, source: source.to_string() })
+    }
+    /// Get the text the script was compiled from
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+    /// Run the script as a condition
+    ///
+    /// # Errors
+    /// Returns any error running the script, or if it does not return true or false
+    pub fn matches(&self, update: &PollUpdate) -> Result<bool, PluginError> {
+        let mut scope: Scope = update_scope(update);
+        let result: Dynamic = self.engine.eval_ast_with_scope::<Dynamic>(&mut scope, &self.ast)?;
+        result.as_bool().map_err(|type_name| format!("condition returned {} instead of true or false", type_name).into())
+    }
+    /// Run the script as a transform, returning the changed update or None if the script returned false
+    ///
+    /// # Errors
+    /// Returns any error running the script, or if it sets a field to something that is not a number
+    pub fn transform(&self, update: PollUpdate) -> Result<Option<PollUpdate>, PluginError> {
+        let mut scope: Scope = update_scope(&update);
+        let result: Dynamic = self.engine.eval_ast_with_scope::<Dynamic>(&mut scope, &self.ast)?;
+        if let Ok(false) = result.as_bool() {
+            return Ok(None);
+        }
+        let mut transformed: PollUpdate = update;
+        transformed.location = scope.get_value::<String>("location").unwrap_or(transformed.location);
+        transformed.aqi = i8::try_from(read_number(&scope, "aqi")?.round() as i64)?;
+        transformed.co = read_pollutant(&scope, "co")?;
+        transformed.no = read_pollutant(&scope, "no")?;
+        transformed.no2 = read_pollutant(&scope, "no2")?;
+        transformed.o3 = read_pollutant(&scope, "o3")?;
+        transformed.so2 = read_pollutant(&scope, "so2")?;
+        transformed.pm2_5 = read_pollutant(&scope, "pm2_5")?;
+        transformed.pm10 = read_pollutant(&scope, "pm10")?;
+        transformed.nh3 = read_pollutant(&scope, "nh3")?;
+        Ok(Some(transformed))
+    }
+}
+
+/// A script run as a Transform
+pub struct ScriptTransform {
+    name: String,
+    script: Script,
+}
+
+impl ScriptTransform {
+    /// Compile a transform script, naming it by its position in OPENWEATHER_SCRIPT_TRANSFORMS
+    ///
+    /// # Errors
+    /// Returns a message if the script cannot be compiled
+    pub fn new(name: &str, source: &str) -> Result<ScriptTransform, String> {
+        let script: Script = Script::compile(source).map_err(|e| format!("Unable to compile {}: {}", name, e))?;
+        Ok(ScriptTransform { name: name.to_string(), script })
+    }
+}
+
+impl Transform for ScriptTransform {
+    fn name(&self) -> &str {
+        &self.name
+    }
+    fn apply(&self, update: PollUpdate) -> Result<Option<PollUpdate>, PluginError> {
+        self.script.transform(update)
+    }
+}
+
+/// Put the fields of an update in a scope for a script to use
+fn update_scope(update: &PollUpdate) -> Scope<'static> {
+    let mut scope: Scope = Scope::new();
+    scope.push("location", update.location.clone());
+    scope.push("aqi", update.aqi as i64);
+    push_pollutant(&mut scope, "co", update.co);
+    push_pollutant(&mut scope, "no", update.no);
+    push_pollutant(&mut scope, "no2", update.no2);
+    push_pollutant(&mut scope, "o3", update.o3);
+    push_pollutant(&mut scope, "so2", update.so2);
+    push_pollutant(&mut scope, "pm2_5", update.pm2_5);
+    push_pollutant(&mut scope, "pm10", update.pm10);
+    push_pollutant(&mut scope, "nh3", update.nh3);
+    scope
+}
+
+/// Put a pollutant in a scope as a number, or as () if it was not measured
+fn push_pollutant(scope: &mut Scope, name: &str, value: Option<f32>) {
+    match value {
+        Some(value) => scope.push(name.to_string(), value as f64),
+        None => scope.push(name.to_string(), ()),
+    };
+}
+
+/// Read a pollutant back out of a scope, leaving it out of the update if the script left it or set it to ()
+fn read_pollutant(scope: &Scope, name: &str) -> Result<Option<f32>, PluginError> {
+    match scope.get_value::<Dynamic>(name) {
+        Some(value) if value.is_unit() => Ok(None),
+        _ => Ok(Some(read_number(scope, name)? as f32)),
+    }
+}
+
+/// Read a field back out of a scope, accepting whole numbers as well as decimals
+fn read_number(scope: &Scope, name: &str) -> Result<f64, PluginError> {
+    let value: Dynamic = scope.get_value::<Dynamic>(name).ok_or(format!("{} was removed by the script", name))?;
+    if let Ok(float) = value.as_float() {
+        return Ok(float);
+    }
+    match value.as_int() {
+        Ok(int) => Ok(int as f64),
+        Err(type_name) => Err(format!("{} was set to {} instead of a number", name, type_name).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_update() -> PollUpdate {
+        PollUpdate { aqi: 2, co: Some(200.0), no: Some(0.5), no2: Some(10.0), o3: Some(60.0), so2: Some(1.0), pm2_5: Some(12.5), pm10: Some(20.0), ..PollUpdate::fixture("Home", 1700000000) }
+    }
+
+    #[test]
+    fn condition_sees_fields() {
+        assert!(Script::compile("pm2_5 > 10.0 && aqi == 2").unwrap().matches(&test_update()).unwrap());
+        assert!(!Script::compile("location == \"Work\"").unwrap().matches(&test_update()).unwrap());
+        assert!(Script::compile("pm2_5 + 1").unwrap().matches(&test_update()).is_err());
+    }
+
+    #[test]
+    fn transform_changes_fields() {
+        let transformed: PollUpdate = Script::compile("pm2_5 = pm2_5 * 2.0; nh3 = 3; aqi = 4;").unwrap().transform(test_update()).unwrap().unwrap();
+        assert_eq!(transformed.pm2_5, Some(25.0));
+        assert_eq!(transformed.nh3, Some(3.0));
+        assert_eq!(transformed.aqi, 4);
+        assert_eq!(transformed.co, Some(200.0));
+    }
+
+    #[test]
+    fn unmeasured_pollutants_are_unit() {
+        let update: PollUpdate = PollUpdate { no2: None, ..test_update() };
+        assert!(Script::compile("no2 == ()").unwrap().matches(&update).unwrap());
+        assert_eq!(Script::compile("pm2_5 = 1.0;").unwrap().transform(update.clone()).unwrap().unwrap().no2, None);
+        assert_eq!(Script::compile("no2 = 4.0;").unwrap().transform(update).unwrap().unwrap().no2, Some(4.0));
+    }
+
+    #[test]
+    fn transform_returning_false_drops() {
+        assert!(Script::compile("pm10 < 5.0").unwrap().transform(test_update()).unwrap().is_none());
+        assert!(Script::compile("pm10 > 5.0").unwrap().transform(test_update()).unwrap().is_some());
+    }
+
+    #[test]
+    fn invalid_script_is_reported() {
+        assert!(ScriptTransform::new("script transform 1", "pm2_5 = ").is_err());
+    }
+}
@@ -0,0 +1,51 @@
+//! Optional startup self-test that runs every stage of a polling cycle once before the loop starts,
+//! so a broken API key, location or database is reported straight away instead of after the first sleep.
+
+use influxdb::{Client, ReadQuery, WriteQuery};
+use crate::{get_coords_zipcode, get_pollution, pollution_url, report, Config, Location, PollResponse, PollUpdate, ZipLoc};
+use crate::influx::quote_identifier;
+
+/// Measurement the self-test point is written to, kept apart from real readings
+pub const SELF_TEST_MEASUREMENT: &str = "selftest";
+
+/// Geocode the configured location when it was given by zipcode, fetch pollution once and write a tagged point to the self-test measurement<br>
+/// The point is deleted afterwards when the server allows it, otherwise it stays in its own measurement tagged selftest=true.
+///
+/// # Errors
+/// Returns a message naming the stage that failed along with the underlying error
+pub async fn run_self_test(dbclient: &Client, current_config: &Config) -> Result<(), String> {
+    report::info("Running startup self-test.");
+
+    match &current_config.location {
+        Some(Location::Zip(location)) => {
+            let geocoded: ZipLoc = match get_coords_zipcode(location.zip.clone(), location.country.clone(), current_config.get_key()) {
+                Ok(loc) => loc,
+                Err(e) => return Err(format!("Self-test failed at the geocode stage: {}. Check OPENWEATHER_API_KEY, OPENWEATHER_POLL_ZIP and OPENWEATHER_POLL_COUNTRY.", e)),
+            };
+            report::info(&format!("Self-test geocode passed: {}", geocoded));
+        },
+        Some(location) => report::info(&format!("Self-test geocode skipped, as the location was not given by zipcode: {}", location)),
+        None => return Err("Self-test failed at the geocode stage: no location is configured. Check OPENWEATHER_POLL_ZIP, or OPENWEATHER_POLL_LAT and OPENWEATHER_POLL_LON.".to_string()),
+    };
+
+    let response: PollResponse = match get_pollution(&pollution_url(current_config)) {
+        Ok(res) => res,
+        Err(e) => return Err(format!("Self-test failed at the fetch stage: {}. Check OPENWEATHER_API_KEY and that the key has been activated.", e)),
+    };
+    let mut update: PollUpdate = response.unpack().map_err(|e| format!("Self-test failed at the fetch stage: {}", e))?;
+    update.location = current_config.get_location().to_string();
+    report::info("Self-test fetch passed.");
+
+    let test_point: WriteQuery = update.to_named_write_query(SELF_TEST_MEASUREMENT, current_config.get_precision(), current_config.get_field_names()).add_tag("selftest", "true");
+    if let Err(e) = dbclient.query(test_point).await {
+        return Err(format!("Self-test failed at the write stage: {}. Check OPENWEATHER_INFLUXDB_SERVER, OPENWEATHER_INFLUXDB_NAME and the credentials.", e));
+    }
+    report::info("Self-test write passed.");
+
+    match dbclient.query(ReadQuery::new(format!("DELETE FROM {}", quote_identifier(SELF_TEST_MEASUREMENT)))).await {
+        Ok(_) => report::info("Self-test point removed."),
+        Err(e) => report::warn(&format!("Self-test point could not be removed ({}). It remains in the {} measurement tagged selftest=true.", e, SELF_TEST_MEASUREMENT)),
+    };
+    report::info("Self-test complete.");
+    Ok(())
+}
@@ -0,0 +1,112 @@
+//! Optional Slack notifier, posting alerts to an incoming webhook set by OPENWEATHER_SLACK_WEBHOOK.<br>
+//! Each rule can only post once every OPENWEATHER_SLACK_INTERVAL seconds for a location, so a reading hovering around a threshold does not flood the channel.
+//! Posts run on their own thread, so a slow or unreachable Slack never holds up polling.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::thread;
+use chrono::{DateTime, Duration, Utc};
+use serde_json::json;
+use crate::alerts::{Alert, Notifier};
+use crate::plugin::PluginError;
+use crate::{metrics, report};
+
+/// Default number of seconds before the same rule can post again for a location
+pub const DEFAULT_SLACK_INTERVAL: u64 = 3600;
+
+/// Posts alerts to a Slack incoming webhook
+pub struct SlackNotifier {
+    webhook: String,
+    interval: Duration,
+    last_sent: Mutex<BTreeMap<(String, String), DateTime<Utc>>>,
+}
+
+impl SlackNotifier {
+    /// Create the notifier for a webhook URL, posting each rule at most once every interval seconds for a location
+    pub fn new(webhook: &str, interval: u64) -> SlackNotifier {
+        SlackNotifier { webhook: webhook.trim().to_string(), interval: Duration::seconds(i64::try_from(interval).unwrap_or(i64::MAX)), last_sent: Mutex::new(BTreeMap::new()) }
+    }
+    /// Check if an alert may be posted at a time, noting it as sent if so
+    fn allow(&self, alert: &Alert, now: DateTime<Utc>) -> bool {
+        let mut last_sent = self.last_sent.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let key: (String, String) = (alert.rule.clone(), alert.location.clone());
+        if last_sent.get(&key).is_some_and(|sent| now - *sent < self.interval) {
+            return false;
+        }
+        last_sent.insert(key, now);
+        true
+    }
+}
+
+impl Notifier for SlackNotifier {
+    fn name(&self) -> &str {
+        "slack"
+    }
+    fn notify(&self, alert: &Alert) -> Result<(), PluginError> {
+        if !self.allow(alert, Utc::now()) {
+            report::info(&format!("Alert {} for {} was posted to Slack less than {}s ago, not posting it again.", alert.rule, alert.location, self.interval.num_seconds()));
+            metrics::increment("pollution_alerts_rate_limited_total", &[("notifier", "slack")]);
+            return Ok(());
+        }
+        let payload: serde_json::Value = message(alert);
+        let webhook: String = self.webhook.clone();
+        let rule: String = alert.rule.clone();
+        thread::spawn(move || {
+            if let Err(e) = ureq::post(&webhook).send_json(payload) {
+                report::warn(&format!("Notifier slack was unable to send alert {}: {}", rule, e));
+            }
+        });
+        Ok(())
+    }
+}
+
+/// Build the webhook payload for an alert, with the location, field, value and AQI category it has
+pub fn message(alert: &Alert) -> serde_json::Value {
+    let mut details: Vec<String> = vec![format!("*Location:* {}", alert.location)];
+    if let Some(field) = &alert.field {
+        match alert.value {
+            Some(value) => details.push(format!("*{}:* {}", field, value)),
+            None => details.push(format!("*Field:* {}", field)),
+        }
+    }
+    if let Some(category) = &alert.category {
+        details.push(format!("*Category:* {}", category));
+    }
+    details.push(format!("*Time:* {}", alert.time.format("%Y-%m-%d %H:%M UTC")));
+    let text: String = format!(":warning: *{}*\n{}", alert.rule, alert.message);
+    json!({
+        "text": format!("{}: {}", alert.rule, alert.message),
+        "blocks": [
+            { "type": "section", "text": { "type": "mrkdwn", "text": text } },
+            { "type": "context", "elements": [{ "type": "mrkdwn", "text": details.join("  |  ") }] },
+        ],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_alert(location: &str) -> Alert {
+        Alert { rule: "pm2_5>35".to_string(), location: location.to_string(), time: DateTime::from_timestamp(1700000000, 0).unwrap(),
+            message: "Fine Particulate Matter at Home is 40, above 35".to_string(), field: Some("pm2_5".to_string()), value: Some(40.0), category: Some("Fair".to_string()) }
+    }
+
+    #[test]
+    fn message_has_details() {
+        let payload: serde_json::Value = message(&test_alert("Home"));
+        assert_eq!(payload["text"], "pm2_5>35: Fine Particulate Matter at Home is 40, above 35");
+        assert_eq!(payload["blocks"][0]["text"]["text"], ":warning: *pm2_5>35*\nFine Particulate Matter at Home is 40, above 35");
+        assert_eq!(payload["blocks"][1]["elements"][0]["text"], "*Location:* Home  |  *pm2_5:* 40  |  *Category:* Fair  |  *Time:* 2023-11-14 22:13 UTC");
+    }
+
+    #[test]
+    fn rate_limited_per_rule_and_location() {
+        let notifier: SlackNotifier = SlackNotifier::new("https://hooks.slack.com/services/T0/B0/X", 600);
+        let now: DateTime<Utc> = DateTime::from_timestamp(1700000000, 0).unwrap();
+        assert!(notifier.allow(&test_alert("Home"), now));
+        assert!(!notifier.allow(&test_alert("Home"), now + Duration::seconds(599)));
+        assert!(notifier.allow(&test_alert("Work"), now + Duration::seconds(1)));
+        assert!(notifier.allow(&test_alert("Home"), now + Duration::seconds(600)));
+    }
+}
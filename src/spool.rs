@@ -0,0 +1,107 @@
+//! Readings that could not be written, saved to disk so they are written once the sinks recover or on the next start.<br>
+//! The spool is a file of one JSON update per line set by OPENWEATHER_SPOOL_FILE. New readings are appended, and the file is only removed once what was read from it has been written, so a crash while replaying loses nothing.
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use crate::{report, PollUpdate};
+
+/// Default number of seconds to keep writing queued readings after shutdown is requested
+pub const DEFAULT_DRAIN_TIMEOUT: u64 = 30;
+
+/// Add updates to the end of the spool, creating it if needed
+///
+/// # Errors
+/// Returns any error opening or writing the file
+pub fn append(path: &Path, updates: &[PollUpdate]) -> std::io::Result<()> {
+    if updates.is_empty() {
+        return Ok(());
+    }
+    let mut lines: String = String::new();
+    for update in updates {
+        lines.push_str(&serde_json::to_string(update)?);
+        lines.push('\n');
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(lines.as_bytes())?;
+    file.sync_all()
+}
+
+/// Readings read from the spool, along with how much of the file they were read from
+pub struct Spooled {
+    pub updates: Vec<PollUpdate>,
+    length: u64,
+}
+
+/// Read every update from the spool, leaving the file as it is until they are written. A missing spool is empty.<br>
+/// Lines that cannot be read are reported and skipped, so one damaged line does not hold up the rest.
+///
+/// # Errors
+/// Returns any error reading the file
+pub fn read(path: &Path) -> std::io::Result<Spooled> {
+    let content: String = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Spooled { updates: Vec::new(), length: 0 }),
+        Err(e) => return Err(e),
+    };
+    let mut updates: Vec<PollUpdate> = Vec::new();
+    for line in content.lines().filter(|line| !line.trim().is_empty()) {
+        match serde_json::from_str::<PollUpdate>(line) {
+            Ok(update) => updates.push(update),
+            Err(e) => report::warn(&format!("Skipping unreadable line in spool {}: {}", path.display(), e)),
+        }
+    }
+    Ok(Spooled { updates, length: content.len() as u64 })
+}
+
+/// Remove what was read from the spool once it has been written<br>
+/// Readings appended since it was read are kept by writing them to a file beside it and renaming that over the spool, so the spool is never left without a reading that has not been written.
+///
+/// # Errors
+/// Returns any error reading, writing or removing the files
+pub fn remove_written(path: &Path, spooled: &Spooled) -> std::io::Result<()> {
+    let content: Vec<u8> = match std::fs::read(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let remainder: &[u8] = content.get(spooled.length as usize..).unwrap_or_default();
+    if remainder.is_empty() {
+        return std::fs::remove_file(path);
+    }
+    let mut temporary: std::ffi::OsString = path.as_os_str().to_os_string();
+    temporary.push(".tmp");
+    let mut file = OpenOptions::new().create(true).write(true).truncate(true).open(&temporary)?;
+    file.write_all(remainder)?;
+    file.sync_all()?;
+    std::fs::rename(&temporary, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn test_update(seconds: i64) -> PollUpdate {
+        PollUpdate::fixture("Home", seconds)
+    }
+
+    #[test]
+    fn spool_round_trips_and_empties() {
+        let path: PathBuf = std::env::temp_dir().join(format!("pollutionclient_spool_test_{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        assert!(read(&path).unwrap().updates.is_empty());
+        append(&path, &[test_update(100)]).unwrap();
+        append(&path, &[test_update(200), test_update(300)]).unwrap();
+        let spooled: Spooled = read(&path).unwrap();
+        assert_eq!(spooled.updates.iter().map(|update| update.get_time().timestamp()).collect::<Vec<i64>>(), vec![100, 200, 300]);
+        // Nothing is lost until the readings are written
+        assert_eq!(read(&path).unwrap().updates.len(), 3);
+        append(&path, &[test_update(400)]).unwrap();
+        remove_written(&path, &spooled).unwrap();
+        assert_eq!(read(&path).unwrap().updates.iter().map(|update| update.get_time().timestamp()).collect::<Vec<i64>>(), vec![400]);
+        remove_written(&path, &read(&path).unwrap()).unwrap();
+        assert!(!path.exists());
+        remove_written(&path, &spooled).unwrap();
+    }
+}
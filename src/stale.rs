@@ -0,0 +1,68 @@
+//! Spotting readings OpenWeatherMaps has stopped updating, as its model occasionally returns the same hour for a long time.<br>
+//! The age of each fetched reading is its dt compared to now. It is kept in the pollution_reading_age_seconds gauge, and readings older than OPENWEATHER_STALE_LIMIT are warned about and counted in pollution_stale_readings_total, both labelled with the location.
+//! Readings older than OPENWEATHER_SKIP_STALE_AFTER minutes are dropped rather than written, counted in pollution_stale_skipped_total.
+
+use chrono::{DateTime, Utc};
+use crate::{metrics, report, PollUpdate};
+
+/// Default number of seconds a reading can be behind now before it is stale, two of OpenWeatherMaps' hourly updates
+pub const DEFAULT_STALE_LIMIT: u64 = 7200;
+
+/// How far behind now a reading's timestamp is. A reading from the future has an age of zero.
+pub fn age(update: &PollUpdate, now: DateTime<Utc>) -> chrono::Duration {
+    (now - update.get_time()).max(chrono::Duration::zero())
+}
+
+/// Check if a reading is older than the limit in seconds, recording its age and printing a warning if it is stale
+pub fn check(update: &PollUpdate, now: DateTime<Utc>, limit: u64) -> bool {
+    let age: chrono::Duration = age(update, now);
+    let labels: [(&str, &str); 1] = [("location", update.get_location())];
+    metrics::set_gauge("pollution_reading_age_seconds", &labels, age.num_seconds() as f64);
+    if age.num_seconds() <= i64::try_from(limit).unwrap_or(i64::MAX) {
+        return false;
+    }
+    metrics::increment("pollution_stale_readings_total", &labels);
+    report::warn(&format!("Reading for {} is from {}, {} minutes old. OpenWeatherMaps may have stopped updating.", update.get_location(), update.get_time(), age.num_minutes()));
+    true
+}
+
+/// Check if a reading is older than the number of minutes after which it should not be written, counting it in pollution_stale_skipped_total if so
+pub fn should_skip(update: &PollUpdate, now: DateTime<Utc>, skip_after_minutes: Option<u64>) -> bool {
+    let skip: bool = match skip_after_minutes {
+        Some(minutes) => age(update, now).num_minutes() > i64::try_from(minutes).unwrap_or(i64::MAX),
+        None => false,
+    };
+    if skip {
+        metrics::increment("pollution_stale_skipped_total", &[("location", update.get_location())]);
+    }
+    skip
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_update(seconds: i64) -> PollUpdate {
+        PollUpdate::fixture("StaleTest", seconds)
+    }
+
+    #[test]
+    fn check_counts_stale_readings() {
+        let now: DateTime<Utc> = DateTime::from_timestamp(1700010000, 0).unwrap();
+        assert!(!check(&test_update(1700010000 - 3600), now, DEFAULT_STALE_LIMIT));
+        assert_eq!(metrics::get("pollution_reading_age_seconds", &[("location", "StaleTest")]), Some(3600.0));
+        assert!(check(&test_update(1700010000 - 3 * 3600), now, DEFAULT_STALE_LIMIT));
+        assert_eq!(metrics::get("pollution_stale_readings_total", &[("location", "StaleTest")]), Some(1.0));
+        assert_eq!(age(&test_update(1700010600), now), chrono::Duration::zero());
+    }
+
+    #[test]
+    fn should_skip_only_when_set() {
+        let now: DateTime<Utc> = DateTime::from_timestamp(1700010000, 0).unwrap();
+        let old: PollUpdate = test_update(1700010000 - 4 * 3600);
+        assert!(!should_skip(&old, now, None));
+        assert!(!should_skip(&old, now, Some(300)));
+        assert!(should_skip(&old, now, Some(180)));
+        assert!(!should_skip(&test_update(1700010000 - 600), now, Some(180)));
+    }
+}
@@ -0,0 +1,284 @@
+//! The AQI standard a location is reported against, which picks the composite index written for it and the categories alerts use.<br>
+//! OPENWEATHER_AQI_STANDARD sets it for every location, ex: "us" for the EPA's AQI, or for single locations by name, ex: "Home=us,Toronto=ca".
+//! Without it a location uses its country's own standard, ex: "ca" for a Canadian zip code, falling back to OpenWeatherMaps' 1 to 5 scale where there is none.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use serde::{Deserialize, Serialize};
+use crate::indices::Index;
+use crate::PollUpdate;
+
+/// Countries reporting the European Air Quality Index: the EU along with the other EEA members and Switzerland
+const EAQI_COUNTRIES: [&str; 31] = ["AT", "BE", "BG", "CH", "CY", "CZ", "DE", "DK", "EE", "ES", "FI", "FR", "GR", "HR", "HU", "IE", "IS", "IT", "LI", "LT", "LU", "LV", "MT", "NL", "NO", "PL", "PT", "RO", "SE", "SI", "SK"];
+
+/// The category a reading falls in under an AQI standard and the standard's health advice for it, ex: for displays that show guidance alongside the reading
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Health {
+    /// Code of the standard, ex: "us"
+    pub standard: String,
+    /// Name of the category, ex: "Unhealthy for Sensitive Groups"
+    pub category: String,
+    /// What the standard advises for the category, ex: "Sensitive groups should limit prolonged outdoor exertion."
+    pub advice: String,
+}
+
+/// An AQI standard readings can be reported against
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AqiStandard {
+    /// The US EPA's AQI, written as aqi_us
+    Us,
+    /// The European Air Quality Index, written as aqi_eu
+    Eu,
+    /// The UK's Daily Air Quality Index, written as daqi
+    Uk,
+    /// Canada's Air Quality Health Index, written as aqhi
+    Ca,
+    /// India's National Air Quality Index, written as naqi
+    In,
+    /// China's AQI under HJ 633-2012, written as aqi_cn
+    Cn,
+    /// OpenWeatherMaps' own 1 to 5 scale, the aqi field
+    Owm,
+}
+
+impl AqiStandard {
+    /// Every standard, in the order they are listed in messages
+    pub const ALL: [AqiStandard; 7] = [AqiStandard::Us, AqiStandard::Eu, AqiStandard::Uk, AqiStandard::Ca, AqiStandard::In, AqiStandard::Cn, AqiStandard::Owm];
+    /// Get the name the standard is set by, ex: "us"
+    pub fn code(&self) -> &'static str {
+        match self {
+            AqiStandard::Us => "us",
+            AqiStandard::Eu => "eu",
+            AqiStandard::Uk => "uk",
+            AqiStandard::Ca => "ca",
+            AqiStandard::In => "in",
+            AqiStandard::Cn => "cn",
+            AqiStandard::Owm => "owm",
+        }
+    }
+    /// Get the index worked out for the standard, or None for OpenWeatherMaps' own
+    pub fn index(&self) -> Option<Index> {
+        match self {
+            AqiStandard::Us => Some(Index::AqiUs),
+            AqiStandard::Eu => Some(Index::AqiEu),
+            AqiStandard::Uk => Some(Index::Daqi),
+            AqiStandard::Ca => Some(Index::Aqhi),
+            AqiStandard::In => Some(Index::Naqi),
+            AqiStandard::Cn => Some(Index::AqiCn),
+            AqiStandard::Owm => None,
+        }
+    }
+    /// Get the field the standard's index is written under, ex: "aqi_us"
+    pub fn field(&self) -> &'static str {
+        self.index().map(|index| index.field()).unwrap_or("aqi")
+    }
+    /// Get the standard a country reports against by its ISO 3166-1 alpha-2 code, or OpenWeatherMaps' own if it has none here
+    pub fn for_country(code: &str) -> AqiStandard {
+        match code.trim().to_uppercase().as_str() {
+            "US" => AqiStandard::Us,
+            "GB" => AqiStandard::Uk,
+            "CA" => AqiStandard::Ca,
+            "IN" => AqiStandard::In,
+            "CN" => AqiStandard::Cn,
+            code if EAQI_COUNTRIES.contains(&code) => AqiStandard::Eu,
+            _ => AqiStandard::Owm,
+        }
+    }
+    /// Get the upper bound and name of each of the standard's categories, best first
+    pub fn categories(&self) -> &'static [(f32, &'static str)] {
+        match self {
+            AqiStandard::Us => &[(50.0, "Good"), (100.0, "Moderate"), (150.0, "Unhealthy for Sensitive Groups"), (200.0, "Unhealthy"), (300.0, "Very Unhealthy"), (f32::INFINITY, "Hazardous")],
+            AqiStandard::Eu => &[(1.0, "Good"), (2.0, "Fair"), (3.0, "Moderate"), (4.0, "Poor"), (5.0, "Very Poor"), (f32::INFINITY, "Extremely Poor")],
+            AqiStandard::Uk => &[(3.0, "Low"), (6.0, "Moderate"), (9.0, "High"), (f32::INFINITY, "Very High")],
+            AqiStandard::Ca => &[(3.0, "Low Risk"), (6.0, "Moderate Risk"), (10.0, "High Risk"), (f32::INFINITY, "Very High Risk")],
+            AqiStandard::In => &[(50.0, "Good"), (100.0, "Satisfactory"), (200.0, "Moderate"), (300.0, "Poor"), (400.0, "Very Poor"), (f32::INFINITY, "Severe")],
+            AqiStandard::Cn => &[(50.0, "Excellent"), (100.0, "Good"), (150.0, "Lightly Polluted"), (200.0, "Moderately Polluted"), (300.0, "Heavily Polluted"), (f32::INFINITY, "Severely Polluted")],
+            AqiStandard::Owm => &[(1.0, "Good"), (2.0, "Fair"), (3.0, "Moderate"), (4.0, "Poor"), (f32::INFINITY, "Very Poor")],
+        }
+    }
+    /// Get the standard's index for an update, or None if it could not be worked out
+    pub fn value(&self, update: &PollUpdate) -> Option<f32> {
+        update.get_field(self.field())
+    }
+    /// Find the category a value of the standard's index falls in, as its position from 0 for the best and its name
+    pub fn category(&self, value: f32) -> (usize, &'static str) {
+        let categories: &[(f32, &str)] = self.categories();
+        let level: usize = categories.iter().position(|(upper, _)| value <= *upper).unwrap_or(categories.len() - 1);
+        (level, categories[level].1)
+    }
+    /// Get the health advice the standard gives for a category by its position from 0 for the best, based on the wording of the agency behind it
+    pub fn advice(&self, level: usize) -> &'static str {
+        let advice: &[&str] = match self {
+            AqiStandard::Us => &[
+                "Air quality is satisfactory, and air pollution poses little or no risk.",
+                "Unusually sensitive people should consider limiting prolonged or heavy outdoor exertion.",
+                "Sensitive groups should limit prolonged outdoor exertion.",
+                "Everyone should limit prolonged outdoor exertion, and sensitive groups should avoid it.",
+                "Everyone should avoid prolonged outdoor exertion, and sensitive groups should stay indoors.",
+                "Everyone should avoid all outdoor physical activity.",
+            ],
+            AqiStandard::Eu => &[
+                "The air quality is good. Enjoy your usual outdoor activities.",
+                "Enjoy your usual outdoor activities.",
+                "Sensitive groups should consider reducing intense outdoor activities if they experience symptoms.",
+                "Everyone should consider reducing intense outdoor activities, and sensitive groups should reduce physical activities outdoors.",
+                "Everyone should reduce physical activities outdoors, and sensitive groups should avoid them.",
+                "Everyone should avoid physical activities outdoors.",
+            ],
+            AqiStandard::Uk => &[
+                "Enjoy your usual outdoor activities.",
+                "Sensitive groups who experience symptoms should consider reducing strenuous physical activity, particularly outdoors.",
+                "Anyone experiencing discomfort should consider reducing activity, particularly outdoors, and sensitive groups should reduce strenuous physical exertion.",
+                "Everyone should reduce physical exertion, particularly outdoors, especially if experiencing symptoms.",
+            ],
+            AqiStandard::Ca => &[
+                "Ideal air quality for outdoor activities.",
+                "No need to modify your usual outdoor activities unless you experience symptoms. People at risk should consider reducing strenuous activity outdoors.",
+                "Consider reducing or rescheduling strenuous activities outdoors if you experience symptoms. People at risk should reduce strenuous activity outdoors.",
+                "Reduce or reschedule strenuous activities outdoors, especially if you experience symptoms. People at risk should avoid strenuous activity outdoors.",
+            ],
+            AqiStandard::In => &[
+                "Minimal impact.",
+                "May cause minor breathing discomfort to sensitive people.",
+                "May cause breathing discomfort to people with lung disease such as asthma, and discomfort to people with heart disease, children and older adults.",
+                "May cause breathing discomfort to people on prolonged exposure, and discomfort to people with heart disease.",
+                "May cause respiratory illness on prolonged exposure, more so in people with lung and heart disease.",
+                "May cause respiratory effects even in healthy people, and serious health impacts on people with lung or heart disease, even during light physical activity.",
+            ],
+            AqiStandard::Cn => &[
+                "Air quality is satisfactory. Everyone can carry on outdoor activities as normal.",
+                "Unusually sensitive people should reduce outdoor activities.",
+                "Children, older adults and people with heart or respiratory disease should reduce prolonged, high-intensity outdoor exercise.",
+                "Children, older adults and people with heart or respiratory disease should avoid prolonged, high-intensity outdoor exercise, and everyone else should reduce outdoor exercise.",
+                "Children, older adults and people with heart or lung disease should stay indoors and stop outdoor exercise, and everyone else should reduce outdoor activities.",
+                "Children, older adults and the sick should stay indoors and avoid physical exertion, and everyone else should avoid outdoor activities.",
+            ],
+            AqiStandard::Owm => &[
+                "Enjoy your usual outdoor activities.",
+                "Enjoy your usual outdoor activities.",
+                "Sensitive groups should consider reducing intense outdoor activities.",
+                "Sensitive groups should reduce outdoor activities, and everyone else should consider reducing intense ones.",
+                "Everyone should reduce outdoor activities, and sensitive groups should avoid them.",
+            ],
+        };
+        advice[level.min(advice.len() - 1)]
+    }
+    /// Work out the category and health advice for an update, or None if the standard's index could not be worked out for it<br>
+    /// OpenWeatherMaps' aqi of 0, meaning it was not given, counts as not worked out.
+    pub fn assess(&self, update: &PollUpdate) -> Option<Health> {
+        let value: f32 = self.value(update).filter(|value| *self != AqiStandard::Owm || *value > 0.0)?;
+        let (level, category) = self.category(value);
+        Some(Health { standard: self.code().to_string(), category: category.to_string(), advice: self.advice(level).to_string() })
+    }
+    /// Find the position of one of the standard's categories by its name, ignoring case, ex: "unhealthy" is 3 for "us"
+    pub fn category_level(&self, name: &str) -> Option<usize> {
+        self.categories().iter().position(|(_, category)| category.eq_ignore_ascii_case(name.trim()))
+    }
+}
+
+impl FromStr for AqiStandard {
+    type Err = String;
+    fn from_str(raw_standard: &str) -> Result<Self, Self::Err> {
+        let trimmed: String = raw_standard.trim().to_lowercase();
+        match AqiStandard::ALL.iter().find(|standard| standard.code() == trimmed) {
+            Some(standard) => Ok(*standard),
+            None => Err(format!("Unknown AQI standard \"{}\". Expected one of: {}.", raw_standard, AqiStandard::ALL.map(|standard| standard.code()).join(", "))),
+        }
+    }
+}
+
+/// Parse OPENWEATHER_AQI_STANDARD into the standard for every location and those for single locations by name<br>
+/// It is comma separated, with a bare standard for every location and location=standard for one, ex: "eu,Toronto=ca".
+///
+/// # Errors
+/// Returns a message if a standard is unknown, a location name is empty or more than one standard is given for every location
+pub fn parse_standards(raw_standards: &str) -> Result<(Option<AqiStandard>, BTreeMap<String, AqiStandard>), String> {
+    let mut every: Option<AqiStandard> = None;
+    let mut locations: BTreeMap<String, AqiStandard> = BTreeMap::new();
+    for entry in raw_standards.split(',').filter(|entry| !entry.trim().is_empty()) {
+        match entry.rsplit_once('=') {
+            Some((location, _)) if location.trim().is_empty() => return Err(format!("AQI standard \"{}\" has no location name before the =.", entry.trim())),
+            Some((location, standard)) => {
+                locations.insert(location.trim().to_string(), standard.parse()?);
+            },
+            None if every.is_some() => return Err(format!("AQI standard \"{}\" is the second for every location. Name the location it is for, ex: \"Home={}\".", entry.trim(), entry.trim())),
+            None => every = Some(entry.parse()?),
+        }
+    }
+    Ok((every, locations))
+}
+
+impl fmt::Display for AqiStandard {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.code())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Config, Location};
+
+    #[test]
+    fn standards_parsed_and_picked_by_country() {
+        assert_eq!(" US ".parse::<AqiStandard>(), Ok(AqiStandard::Us));
+        assert!("epa".parse::<AqiStandard>().unwrap_err().ends_with("Expected one of: us, eu, uk, ca, in, cn, owm."));
+        assert_eq!(AqiStandard::for_country("de"), AqiStandard::Eu);
+        assert_eq!(AqiStandard::for_country("GB"), AqiStandard::Uk);
+        assert_eq!(AqiStandard::for_country("BR"), AqiStandard::Owm);
+        assert_eq!(AqiStandard::Ca.field(), "aqhi");
+        assert_eq!(AqiStandard::Owm.field(), "aqi");
+    }
+
+    #[test]
+    fn standards_parsed_per_location() {
+        let (every, locations) = parse_standards("eu, Toronto=CA ,Delhi = in").unwrap();
+        assert_eq!(every, Some(AqiStandard::Eu));
+        assert_eq!(locations, BTreeMap::from([("Toronto".to_string(), AqiStandard::Ca), ("Delhi".to_string(), AqiStandard::In)]));
+        assert_eq!(parse_standards("us").unwrap(), (Some(AqiStandard::Us), BTreeMap::new()));
+        assert!(parse_standards("us,eu").is_err());
+        assert!(parse_standards("=us").is_err());
+        assert!(parse_standards("Home=epa").unwrap_err().starts_with("Unknown AQI standard"));
+    }
+
+    #[test]
+    fn categories_found() {
+        assert_eq!(AqiStandard::Us.category(151.0), (3, "Unhealthy"));
+        assert_eq!(AqiStandard::Us.category(600.0), (5, "Hazardous"));
+        assert_eq!(AqiStandard::Ca.category(4.0), (1, "Moderate Risk"));
+        assert_eq!(AqiStandard::Owm.category(2.0), (1, "Fair"));
+        assert_eq!(AqiStandard::Us.category_level("unhealthy"), Some(3));
+        assert_eq!(AqiStandard::Uk.category_level("Unhealthy"), None);
+    }
+
+    #[test]
+    fn location_picks_standard() {
+        let toronto: Location = Location::Coords { name: "Toronto".to_string(), lat: 43.6, lon: -79.4, country: "CA".to_string() };
+        let delhi: Location = Location::Coords { name: "Delhi".to_string(), lat: 28.6, lon: 77.2, country: "IN".to_string() };
+        let mut config: Config = Config { location: Some(toronto), more_locations: vec![delhi], ..Config::default() };
+        assert_eq!(config.get_aqi_standard("Toronto"), AqiStandard::Ca);
+        assert_eq!(config.get_aqi_standard("Delhi"), AqiStandard::In);
+        assert_eq!(config.get_aqi_standard("Toronto r00c01"), AqiStandard::Ca);
+        assert!(config.get_indices("Toronto").is_empty());
+        config.indices = vec![Index::Naqi, Index::Aqhi];
+        config.aqi_standard = Some(AqiStandard::Us);
+        config.location_standards = BTreeMap::from([("Delhi".to_string(), AqiStandard::Cn)]);
+        assert_eq!(config.get_aqi_standard("Toronto"), AqiStandard::Us);
+        assert_eq!(config.get_indices("Toronto"), vec![Index::Naqi, Index::Aqhi, Index::AqiUs]);
+        assert_eq!(config.get_aqi_standard("Delhi"), AqiStandard::Cn);
+        assert_eq!(config.get_indices("Delhi"), vec![Index::Naqi, Index::Aqhi, Index::AqiCn]);
+        assert_eq!(Config::default().get_aqi_standard("Home"), AqiStandard::Owm);
+    }
+
+    #[test]
+    fn health_advice_follows_category() {
+        let mut update: PollUpdate = PollUpdate::from_measured(chrono::DateTime::from_timestamp(1700000000, 0).unwrap(), &[("pm2_5", 40.0)]);
+        assert_eq!(AqiStandard::Us.assess(&update), None);
+        update.set_index("aqi_us", 120.0);
+        let health: Health = AqiStandard::Us.assess(&update).unwrap();
+        assert_eq!((health.standard.as_str(), health.category.as_str()), ("us", "Unhealthy for Sensitive Groups"));
+        assert_eq!(health.advice, "Sensitive groups should limit prolonged outdoor exertion.");
+        assert_eq!(AqiStandard::Owm.assess(&PollUpdate::from_measured(update.get_time(), &[])), None);
+    }
+}
@@ -0,0 +1,155 @@
+//! Mutual TLS for InfluxDB deployments that require a client certificate as well as, or instead of, a token.<br>
+//! OPENWEATHER_INFLUXDB_CLIENT_CERT and OPENWEATHER_INFLUXDB_CLIENT_KEY name PEM files holding the certificate chain and its private key.
+//! They are presented by the influxdb crate's client and by the HTTP agent used for the v2 API and direct writes, both built in the influx module, so every request to the server carries them.
+//! OPENWEATHER_INFLUXDB_CA_CERT names a PEM bundle of certificate authorities both trust alongside the usual web roots, for servers with a certificate from a private CA.
+
+use std::io::BufReader;
+use crate::Config;
+
+/// A client certificate chain and its private key, read from PEM files
+#[derive(Clone, Debug)]
+pub struct ClientIdentity {
+    certs: Vec<rustls::Certificate>,
+    key: rustls::PrivateKey,
+    pem: Vec<u8>,
+}
+
+impl ClientIdentity {
+    /// Get the number of certificates in the chain
+    pub fn chain_length(&self) -> usize {
+        self.certs.len()
+    }
+}
+
+/// Read a certificate chain and private key from PEM files
+///
+/// # Errors
+/// Returns a message naming the file if either cannot be read, the certificate file holds no certificates or the key file holds no private key
+pub fn load_identity(cert_path: &str, key_path: &str) -> Result<ClientIdentity, String> {
+    let cert_pem: Vec<u8> = std::fs::read(cert_path).map_err(|e| format!("Unable to read client certificate {}: {}", cert_path, e))?;
+    let key_pem: Vec<u8> = std::fs::read(key_path).map_err(|e| format!("Unable to read client key {}: {}", key_path, e))?;
+    let certs: Vec<rustls::Certificate> = rustls_pemfile::certs(&mut BufReader::new(cert_pem.as_slice()))
+        .map_err(|e| format!("Unable to parse client certificate {}: {}", cert_path, e))?
+        .into_iter().map(rustls::Certificate).collect();
+    if certs.is_empty() {
+        return Err(format!("No PEM certificates found in client certificate {}.", cert_path));
+    }
+    let key: rustls::PrivateKey = rustls_pemfile::read_all(&mut BufReader::new(key_pem.as_slice()))
+        .map_err(|e| format!("Unable to parse client key {}: {}", key_path, e))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key) | rustls_pemfile::Item::RSAKey(key) | rustls_pemfile::Item::ECKey(key) => Some(rustls::PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| format!("No PEM private key found in client key {}.", key_path))?;
+    let mut pem: Vec<u8> = cert_pem;
+    pem.push(b'\n');
+    pem.extend(key_pem);
+    Ok(ClientIdentity { certs, key, pem })
+}
+
+/// Load the client identity the Config names, if any
+///
+/// # Errors
+/// Returns a message if only one of the certificate and key is set, or either cannot be loaded
+pub fn identity(current_config: &Config) -> Result<Option<ClientIdentity>, String> {
+    match (current_config.get_client_cert(), current_config.get_client_key()) {
+        (Some(cert_path), Some(key_path)) => load_identity(cert_path, key_path).map(Some),
+        (None, None) => Ok(None),
+        _ => Err("OPENWEATHER_INFLUXDB_CLIENT_CERT and OPENWEATHER_INFLUXDB_CLIENT_KEY must be set together.".to_string()),
+    }
+}
+
+/// Read the certificate authorities in a PEM bundle
+///
+/// # Errors
+/// Returns a message naming the file if it cannot be read or holds no certificates
+pub fn load_ca_bundle(ca_path: &str) -> Result<Vec<rustls::Certificate>, String> {
+    let ca_pem: Vec<u8> = std::fs::read(ca_path).map_err(|e| format!("Unable to read CA bundle {}: {}", ca_path, e))?;
+    let certs: Vec<rustls::Certificate> = rustls_pemfile::certs(&mut BufReader::new(ca_pem.as_slice()))
+        .map_err(|e| format!("Unable to parse CA bundle {}: {}", ca_path, e))?
+        .into_iter().map(rustls::Certificate).collect();
+    if certs.is_empty() {
+        return Err(format!("No PEM certificates found in CA bundle {}.", ca_path));
+    }
+    Ok(certs)
+}
+
+/// Load the certificate authorities the Config names to trust, or nothing if none are set
+///
+/// # Errors
+/// Returns a message if the bundle cannot be loaded
+pub fn ca_certificates(current_config: &Config) -> Result<Vec<rustls::Certificate>, String> {
+    match current_config.get_ca_cert() {
+        Some(ca_path) => load_ca_bundle(ca_path),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Turn a client identity into the form reqwest presents it in
+///
+/// # Errors
+/// Returns a message if reqwest cannot use the certificate and key together
+pub fn reqwest_identity(loaded: &ClientIdentity) -> Result<reqwest::Identity, String> {
+    reqwest::Identity::from_pem(&loaded.pem).map_err(|e| format!("Unable to use client certificate: {}", e))
+}
+
+/// Build the rustls configuration presenting the configured client certificate and trusting the usual web roots along with any configured CA bundle, or None if neither is set
+///
+/// # Errors
+/// Returns a message if the identity or CA bundle cannot be loaded, or rustls rejects either
+pub fn rustls_config(current_config: &Config) -> Result<Option<rustls::ClientConfig>, String> {
+    let loaded: Option<ClientIdentity> = identity(current_config)?;
+    let authorities: Vec<rustls::Certificate> = ca_certificates(current_config)?;
+    if loaded.is_none() && authorities.is_empty() {
+        return Ok(None);
+    }
+    let mut roots: rustls::RootCertStore = rustls::RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+        rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(anchor.subject, anchor.spki, anchor.name_constraints)
+    }));
+    for authority in &authorities {
+        roots.add(authority).map_err(|e| format!("Unable to trust a certificate in the CA bundle: {}", e))?;
+    }
+    let builder: rustls::ConfigBuilder<rustls::ClientConfig, rustls::client::WantsTransparencyPolicyOrClientCert> = rustls::ClientConfig::builder().with_safe_defaults().with_root_certificates(roots);
+    match loaded {
+        Some(loaded) => builder.with_client_auth_cert(loaded.certs, loaded.key)
+            .map(Some)
+            .map_err(|e| format!("Unable to use client certificate: {}", e)),
+        None => Ok(Some(builder.with_no_client_auth())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identity_needs_both_files() {
+        assert!(identity(&Config::default()).unwrap().is_none());
+        let cert_only: Config = Config { client_cert: Some("client.pem".to_string()), ..Config::default() };
+        assert!(identity(&cert_only).unwrap_err().contains("must be set together"));
+        assert!(rustls_config(&Config::default()).unwrap().is_none());
+    }
+
+    #[test]
+    fn ca_bundle_reports_bad_files() {
+        assert!(ca_certificates(&Config::default()).unwrap().is_empty());
+        let path: std::path::PathBuf = std::env::temp_dir().join(format!("pollutionclient_ca_test_{}.pem", std::process::id()));
+        std::fs::write(&path, "not a certificate").unwrap();
+        let with_bundle: Config = Config { ca_cert: Some(path.to_str().unwrap().to_string()), ..Config::default() };
+        assert!(rustls_config(&with_bundle).unwrap_err().starts_with("No PEM certificates found in CA bundle"));
+        assert!(load_ca_bundle("/nonexistent/ca.pem").unwrap_err().starts_with("Unable to read CA bundle /nonexistent/ca.pem"));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn load_identity_reports_bad_files() {
+        let path: std::path::PathBuf = std::env::temp_dir().join(format!("pollutionclient_tls_test_{}.pem", std::process::id()));
+        std::fs::write(&path, "not a certificate").unwrap();
+        let path_text: &str = path.to_str().unwrap();
+        assert!(load_identity("/nonexistent/client.pem", path_text).unwrap_err().starts_with("Unable to read client certificate /nonexistent/client.pem"));
+        assert!(load_identity(path_text, path_text).unwrap_err().starts_with("No PEM certificates found"));
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,131 @@
+//! Short-term trends for each pollutant, so dashboards and alerts can react to air that is getting worse quickly before any threshold is crossed.<br>
+//! A trend is the slope of a least squares line through the readings of the last OPENWEATHER_TREND_HOURS hours in the in-memory history and the current one, in µg/m³ per hour.
+//! It is written as a {pollutant}_rate field holding the slope and a {pollutant}_trend field holding rising, falling or steady, ex: pm2_5_rate and pm2_5_trend.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use crate::PollUpdate;
+
+/// Every pollutant a trend is worked out for
+pub const POLLUTANTS: [&str; 8] = ["co", "no", "no2", "o3", "so2", "pm2_5", "pm10", "nh3"];
+/// Share of a pollutant's average over the window it can change by each hour and still be steady
+pub const STEADY_SHARE: f32 = 0.05;
+/// Smallest change in µg/m³ per hour that is not steady, so pollutants near zero do not flip between rising and falling
+pub const STEADY_FLOOR: f32 = 0.1;
+
+/// Which way a pollutant is heading
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Rising,
+    Falling,
+    Steady,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Direction::Rising => write!(f, "rising"),
+            Direction::Falling => write!(f, "falling"),
+            Direction::Steady => write!(f, "steady"),
+        }
+    }
+}
+
+/// The trend of one pollutant
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Trend {
+    pub direction: Direction,
+    /// Change in µg/m³ per hour
+    pub rate: f32,
+}
+
+/// Get the field a pollutant's rate is written in, ex: "pm2_5_rate"
+pub fn rate_field(pollutant: &str) -> String {
+    format!("{}_rate", pollutant)
+}
+
+/// Get the field a pollutant's direction is written in, ex: "pm2_5_trend"
+pub fn direction_field(pollutant: &str) -> String {
+    format!("{}_trend", pollutant)
+}
+
+/// Work out the trend of every pollutant over the readings in the last hours before the current one, oldest first<br>
+/// Returns nothing unless there is at least one earlier reading in the window to compare with. A pollutant only gets a trend if the current reading measured it, and readings that did not are left out of its line.
+pub fn compute(recent: &[PollUpdate], current: &PollUpdate, hours: u32) -> BTreeMap<String, Trend> {
+    let since: DateTime<Utc> = current.get_time() - chrono::Duration::hours(hours as i64);
+    let mut window: Vec<&PollUpdate> = recent.iter().filter(|update| update.get_time() >= since && update.get_time() < current.get_time()).collect();
+    if window.is_empty() {
+        return BTreeMap::new();
+    }
+    window.push(current);
+    let start: DateTime<Utc> = window[0].get_time();
+    POLLUTANTS.iter().filter(|pollutant| current.get_field(pollutant).is_some()).filter_map(|pollutant| {
+        let (elapsed, values): (Vec<f32>, Vec<f32>) = window.iter()
+            .filter_map(|update| update.get_field(pollutant).map(|value| ((update.get_time() - start).num_seconds() as f32 / 3600.0, value)))
+            .unzip();
+        slope(&elapsed, &values).map(|rate| (pollutant.to_string(), Trend { direction: direction(rate, mean(&values)), rate }))
+    }).collect()
+}
+
+/// Least squares slope of values against hours, or None if they were all read at the same time
+fn slope(hours: &[f32], values: &[f32]) -> Option<f32> {
+    let mean_hours: f32 = mean(hours);
+    let mean_value: f32 = mean(values);
+    let spread: f32 = hours.iter().map(|hour| (hour - mean_hours).powi(2)).sum();
+    if spread == 0.0 {
+        return None;
+    }
+    Some(hours.iter().zip(values).map(|(hour, value)| (hour - mean_hours) * (value - mean_value)).sum::<f32>() / spread)
+}
+
+fn mean(values: &[f32]) -> f32 {
+    values.iter().sum::<f32>() / values.len() as f32
+}
+
+/// Decide the direction of a rate of change for a pollutant averaging the given level
+fn direction(rate: f32, average: f32) -> Direction {
+    if rate.abs() < (STEADY_SHARE * average.abs()).max(STEADY_FLOOR) {
+        Direction::Steady
+    } else if rate > 0.0 {
+        Direction::Rising
+    } else {
+        Direction::Falling
+    }
+}
+
+/// Work out the trends for an update and set them on it
+pub fn apply(recent: &[PollUpdate], update: &mut PollUpdate, hours: u32) {
+    let trends: BTreeMap<String, Trend> = compute(recent, update, hours);
+    update.set_trends(trends);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_update(hour: i64, pm2_5: f32, o3: f32) -> PollUpdate {
+        let time: DateTime<Utc> = DateTime::from_timestamp(1700000000 + hour * 3600, 0).unwrap();
+        PollUpdate::from_measured(time, &[("pm2_5", pm2_5), ("o3", o3)])
+    }
+
+    #[test]
+    fn rising_falling_and_steady() {
+        let recent: Vec<PollUpdate> = vec![test_update(0, 10.0, 80.0), test_update(1, 14.0, 70.0), test_update(2, 18.0, 60.0)];
+        let trends: BTreeMap<String, Trend> = compute(&recent, &test_update(3, 22.0, 50.0), 3);
+        assert_eq!(trends["pm2_5"], Trend { direction: Direction::Rising, rate: 4.0 });
+        assert_eq!(trends["o3"], Trend { direction: Direction::Falling, rate: -10.0 });
+        assert!(!trends.contains_key("co"));
+    }
+
+    #[test]
+    fn window_limits_readings() {
+        let recent: Vec<PollUpdate> = vec![test_update(0, 100.0, 0.0), test_update(2, 20.0, 0.0)];
+        let trends: BTreeMap<String, Trend> = compute(&recent, &test_update(3, 20.5, 0.0), 1);
+        assert_eq!(trends["pm2_5"].direction, Direction::Steady);
+        assert!(compute(&recent, &test_update(5, 20.0, 0.0), 1).is_empty());
+        assert!(compute(&[], &test_update(5, 20.0, 0.0), 3).is_empty());
+    }
+}
@@ -0,0 +1,155 @@
+//! A full screen dashboard of the latest readings, only built with the "tui" feature.<br>
+//! Every location gets an AQI gauge and sparklines of its recent PM2.5, PM10, O3 and NO2 readings from the in-memory history.
+//! The screen is redrawn every second and pressing q or Esc stops polling.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Style};
+use ratatui::widgets::{Block, Borders, Gauge, Paragraph, Sparkline};
+use ratatui::{DefaultTerminal, Frame};
+use crate::history::SharedBuffer;
+use crate::i18n::{self, Locale};
+use crate::{report, PollUpdate};
+
+/// Fields drawn as sparklines under each gauge, with their headings
+const SPARKLINES: [(&str, &str); 4] = [("pm2_5", "PM2.5"), ("pm10", "PM10"), ("o3", "O3"), ("no2", "NO2")];
+
+/// Start the dashboard on its own thread, taking over the terminal until stopping is set<br>
+/// Quitting from the dashboard sets stopping so the polling loop ends too. Join the handle to be sure the terminal has been restored.
+///
+/// # Errors
+/// Returns a message if the thread cannot be started
+pub fn start(readings: SharedBuffer, stopping: Arc<AtomicBool>) -> Result<JoinHandle<()>, String> {
+    thread::Builder::new().name("tui".to_string()).spawn(move || {
+        let mut terminal: DefaultTerminal = ratatui::init();
+        let result: std::io::Result<()> = run(&mut terminal, &readings, &stopping);
+        ratatui::restore();
+        if let Err(e) = result {
+            report::warn(&format!("Dashboard stopped: {}", e));
+        }
+        stopping.store(true, Ordering::SeqCst);
+    }).map_err(|e| format!("Unable to start the dashboard: {}", e))
+}
+
+/// Redraw every second until stopping is set or q is pressed
+fn run(terminal: &mut DefaultTerminal, readings: &SharedBuffer, stopping: &AtomicBool) -> std::io::Result<()> {
+    let mut drawn: usize = usize::MAX;
+    while !stopping.load(Ordering::SeqCst) {
+        let snapshot: Vec<Vec<PollUpdate>> = readings.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).all().into_values().collect();
+        let count: usize = snapshot.iter().map(|location_readings| location_readings.len()).sum();
+        // Anything printed by the polling loop lands on the screen too, so start from a clean one whenever a reading arrives
+        if count != drawn {
+            terminal.clear()?;
+            drawn = count;
+        }
+        terminal.draw(|frame| draw(frame, &snapshot, i18n::current()))?;
+        if event::poll(Duration::from_secs(1))? {
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press && matches!(key.code, KeyCode::Char('q') | KeyCode::Esc) {
+                    break;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Draw a panel for each location, oldest reading first in each list
+fn draw(frame: &mut Frame, locations: &[Vec<PollUpdate>], locale: Locale) {
+    let [title_area, body] = Layout::vertical([Constraint::Length(1), Constraint::Min(0)]).areas(frame.area());
+    frame.render_widget(Paragraph::new("PollutionClient_RS  (q to quit)"), title_area);
+    if locations.is_empty() {
+        frame.render_widget(Paragraph::new("Waiting for the first reading..."), body);
+        return;
+    }
+    let panels = Layout::vertical(locations.iter().map(|_| Constraint::Ratio(1, locations.len() as u32))).split(body);
+    for (location_readings, area) in locations.iter().zip(panels.iter()) {
+        if let Some(latest) = location_readings.last() {
+            draw_location(frame, *area, location_readings, latest, locale);
+        }
+    }
+}
+
+/// Draw the gauge and sparklines for one location
+fn draw_location(frame: &mut Frame, area: Rect, location_readings: &[PollUpdate], latest: &PollUpdate, locale: Locale) {
+    let title: String = format!(" {} - {} ", latest.get_location(), latest.get_time().format("%Y-%m-%d %H:%M"));
+    let block: Block = Block::default().borders(Borders::ALL).title(title);
+    let inner: Rect = block.inner(area);
+    frame.render_widget(block, area);
+
+    let [gauge_area, sparkline_area] = Layout::vertical([Constraint::Length(1), Constraint::Min(0)]).areas(inner);
+    let gauge: Gauge = Gauge::default()
+        .gauge_style(Style::default().fg(aqi_color(latest.get_aqi())))
+        .ratio(aqi_ratio(latest.get_aqi()))
+        .label(format!("AQI {} {}", latest.get_aqi(), i18n::aqi_category(locale, latest.get_aqi())));
+    frame.render_widget(gauge, gauge_area);
+
+    let columns = Layout::horizontal(SPARKLINES.iter().map(|_| Constraint::Ratio(1, SPARKLINES.len() as u32))).split(sparkline_area);
+    for ((field, heading), column) in SPARKLINES.iter().zip(columns.iter()) {
+        let current: String = latest.get_field(field).map(|value| format!("{:.1}", value)).unwrap_or_else(|| "-".to_string());
+        let data: Vec<u64> = sparkline_data(location_readings, field);
+        let sparkline: Sparkline = Sparkline::default()
+            .block(Block::default().title(format!("{} {}", heading, current)))
+            .data(&data)
+            .style(Style::default().fg(Color::Cyan));
+        frame.render_widget(sparkline, *column);
+    }
+}
+
+/// Get how full the AQI gauge should be, from a fifth for good to full for very poor
+fn aqi_ratio(aqi: i8) -> f64 {
+    (aqi.clamp(0, 5) as f64) / 5.0
+}
+
+/// Get the gauge color for an AQI, matching the table output
+fn aqi_color(aqi: i8) -> Color {
+    match aqi {
+        i8::MIN..=2 => Color::Green,
+        3 => Color::Yellow,
+        _ => Color::Red,
+    }
+}
+
+/// Get the values of a field for a sparkline, rounded to whole μg/m3 and skipping readings that did not measure it
+fn sparkline_data(location_readings: &[PollUpdate], field: &str) -> Vec<u64> {
+    location_readings.iter().filter_map(|reading| reading.get_field(field)).map(|value| value.max(0.0).round() as u64).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ratatui::backend::TestBackend;
+    use ratatui::Terminal;
+
+    fn test_update(location: &str, aqi: i8, pm2_5: f32) -> PollUpdate {
+        PollUpdate { aqi, co: Some(200.0), no2: Some(5.0), o3: Some(60.0), so2: Some(1.0), pm2_5: Some(pm2_5), pm10: Some(8.0), ..PollUpdate::fixture(location, 1700000000) }
+    }
+
+    #[test]
+    fn sparkline_data_rounds() {
+        let readings: Vec<PollUpdate> = vec![test_update("Home", 1, 2.4), test_update("Home", 2, 12.6), test_update("Home", 2, -1.0)];
+        assert_eq!(sparkline_data(&readings, "pm2_5"), vec![2, 13, 0]);
+    }
+
+    #[test]
+    fn aqi_ratio_clamped() {
+        assert_eq!(aqi_ratio(1), 0.2);
+        assert_eq!(aqi_ratio(9), 1.0);
+        assert_eq!(aqi_ratio(-1), 0.0);
+    }
+
+    #[test]
+    fn draws_each_location() {
+        let mut terminal: Terminal<TestBackend> = Terminal::new(TestBackend::new(80, 20)).unwrap();
+        let locations: Vec<Vec<PollUpdate>> = vec![vec![test_update("Home", 2, 10.0)], vec![test_update("Work", 4, 80.0)]];
+        terminal.draw(|frame| draw(frame, &locations, Locale::English)).unwrap();
+        let screen: String = terminal.backend().buffer().content().iter().map(|cell| cell.symbol()).collect();
+        assert!(screen.contains("Home - 2023-11-14 22:13"));
+        assert!(screen.contains("AQI 4 Poor"));
+        assert!(screen.contains("PM2.5 80.0"));
+    }
+}
@@ -0,0 +1,155 @@
+//! A provider reading the World Air Quality Index project's feed at aqicn.org, which gathers stations from around the world behind a free API token.<br>
+//! Set OPENWEATHER_SOURCE to "waqi" and OPENWEATHER_WAQI_TOKEN to poll the closest WAQI station to each location instead of OpenWeatherMaps' model.
+//! WAQI publishes a US EPA sub-index for each pollutant rather than its concentration, so no pollutant fields are written and the 1 to 5 aqi is left at 0.
+//! The station's own AQI is written as waqi and each sub-index as waqi_ followed by the pollutant, ex: waqi_pm2_5.
+
+use std::collections::BTreeMap;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use crate::plugin::PluginError;
+use crate::provider::{Place, PollutionProvider};
+use crate::{report, Config, PollUpdate};
+
+/// Name of the WAQI provider for OPENWEATHER_SOURCE
+pub const WAQI_SOURCE: &str = "waqi";
+
+/// The WAQI API
+pub const WAQI_API: &str = "https://api.waqi.info";
+
+/// What WAQI answers with when the token is not recognised
+const INVALID_TOKEN: &str = "Invalid key";
+
+/// Each WAQI sub-index with the pollutant it is written under, after waqi_
+const SUB_INDICES: [(&str, &str); 6] = [("pm25", "pm2_5"), ("pm10", "pm10"), ("o3", "o3"), ("no2", "no2"), ("so2", "so2"), ("co", "co")];
+
+/// WAQI's answer, with the station in data if status is "ok" and the reason it failed otherwise
+#[derive(Debug, Deserialize)]
+struct Feed {
+    status: String,
+    data: serde_json::Value,
+}
+
+#[derive(Debug, Deserialize)]
+struct City {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Measured {
+    iso: DateTime<Utc>,
+}
+
+/// One sub-index or weather value
+#[derive(Debug, Deserialize)]
+struct SubIndex {
+    v: f32,
+}
+
+/// A station's latest readings from WAQI's feed
+#[derive(Debug, Deserialize)]
+struct Station {
+    /// The station's AQI, or "-" when it has none
+    aqi: serde_json::Value,
+    idx: u64,
+    city: City,
+    iaqi: BTreeMap<String, SubIndex>,
+    time: Measured,
+}
+
+/// Build the URL of the feed for the closest station to a point
+pub fn feed_url(lat: f64, lon: f64, token: &str) -> String {
+    format!("{}/feed/geo:{};{}/?token={}", WAQI_API, lat, lon, token)
+}
+
+/// Build a reading from a station's AQI and sub-indices, with its location left as "pending"
+///
+/// # Errors
+/// Returns a message if the station has no sub-index for a pollutant this client records
+fn station_reading(station: &Station) -> Result<PollUpdate, String> {
+    let mut sub_indices: Vec<(String, f32)> = Vec::new();
+    for (name, field) in SUB_INDICES {
+        match station.iaqi.get(name).map(|sub_index| sub_index.v) {
+            Some(sub_index) if sub_index >= 0.0 => sub_indices.push((format!("{}_{}", WAQI_SOURCE, field), sub_index)),
+            Some(_) => report::debug(&format!("Skipping negative {} sub-index from WAQI station {}", name, station.city.name)),
+            None => (),
+        }
+    }
+    if sub_indices.is_empty() {
+        return Err(format!("WAQI station {} has no recent values for any pollutant this client records.", station.city.name));
+    }
+    let mut update: PollUpdate = PollUpdate::from_measured(station.time.iso, &[]);
+    if let Some(aqi) = station.aqi.as_f64() {
+        update.set_index(WAQI_SOURCE, aqi as f32);
+    }
+    sub_indices.iter().for_each(|(index, value)| update.set_index(index, *value));
+    Ok(update)
+}
+
+/// Latest readings from the closest WAQI station to each place
+pub struct WaqiProvider {
+    token: String,
+}
+
+impl WaqiProvider {
+    /// Create the provider for the WAQI token in a Config
+    ///
+    /// # Errors
+    /// Returns a message if OPENWEATHER_WAQI_TOKEN is not set
+    pub fn new(config: &Config) -> Result<WaqiProvider, String> {
+        match config.get_waqi_token() {
+            Some(token) => Ok(WaqiProvider { token: token.to_string() }),
+            None => Err(format!("OPENWEATHER_SOURCE is {} but OPENWEATHER_WAQI_TOKEN is not set.", WAQI_SOURCE)),
+        }
+    }
+}
+
+impl PollutionProvider for WaqiProvider {
+    fn name(&self) -> &str {
+        WAQI_SOURCE
+    }
+    /// Read the closest station's feed. WAQI answers a bad token or an unknown place with a status of "error" rather than an HTTP error.
+    fn fetch(&self, place: &Place) -> Result<Vec<PollUpdate>, PluginError> {
+        let feed: Feed = match ureq::get(&feed_url(place.lat, place.lon, &self.token)).call() {
+            Ok(response) => response.into_json()?,
+            Err(e) => return Err(Box::new(e)),
+        };
+        if feed.status != "ok" {
+            return match feed.data.as_str() {
+                Some(INVALID_TOKEN) => Err("WAQI rejected the token. Check OPENWEATHER_WAQI_TOKEN.".into()),
+                Some(reason) => Err(format!("WAQI has no reading for {}: {}", place.name, reason).into()),
+                None => Err(format!("WAQI has no reading for {}, its status was {}.", place.name, feed.status).into()),
+            };
+        }
+        let station: Station = serde_json::from_value(feed.data)?;
+        report::debug(&format!("Read WAQI station {} ({}) for {}", station.city.name, station.idx, place.name));
+        Ok(vec![station_reading(&station)?])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugin::Registry;
+    use crate::redact::{self, Secret};
+
+    #[test]
+    fn needs_a_token() {
+        assert_eq!(WaqiProvider::new(&Config::default()).err().unwrap(), "OPENWEATHER_SOURCE is waqi but OPENWEATHER_WAQI_TOKEN is not set.");
+        let keyed: Config = Config { source: WAQI_SOURCE.to_string(), waqi_token: Some(Secret::new("waqi-token".to_string())), ..Config::default() };
+        assert_eq!(Registry::default().build_source(&keyed).unwrap().name(), WAQI_SOURCE);
+        assert_eq!(feed_url(47.6, -122.3, "abc"), "https://api.waqi.info/feed/geo:47.6;-122.3/?token=abc");
+        assert_eq!(redact::redact(&feed_url(47.6, -122.3, "abc")), "https://api.waqi.info/feed/geo:47.6;-122.3/?token=[REDACTED]");
+    }
+
+    #[test]
+    fn sub_indices_become_a_reading() {
+        let feed: Feed = serde_json::from_str(r#"{"status":"ok","data":{"aqi":56,"idx":1451,"city":{"name":"Seattle-10th & Weller","geo":[47.5972,-122.3197]},
+            "iaqi":{"pm25":{"v":56},"no2":{"v":8.2},"t":{"v":12.5}},"time":{"s":"2023-11-14 14:00:00","tz":"-08:00","iso":"2023-11-14T14:00:00-08:00"}}}"#).unwrap();
+        let update: PollUpdate = station_reading(&serde_json::from_value(feed.data).unwrap()).unwrap();
+        assert_eq!(update.get_time(), DateTime::from_timestamp(1699999200, 0).unwrap());
+        assert_eq!((update.get_field("pm2_5"), update.get_field("no2"), update.get_field("o3"), update.get_aqi()), (None, None, None, 0));
+        assert_eq!((update.get_index("waqi"), update.get_index("waqi_pm2_5"), update.get_index("waqi_no2")), (Some(56.0), Some(56.0), Some(8.2)));
+        let empty: Station = serde_json::from_str(r#"{"aqi":"-","idx":1,"city":{"name":"Quiet"},"iaqi":{"h":{"v":80}},"time":{"iso":"2023-11-14T14:00:00Z"}}"#).unwrap();
+        assert!(station_reading(&empty).unwrap_err().contains("Quiet has no recent values"));
+    }
+}
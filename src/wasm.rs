@@ -0,0 +1,125 @@
+//! Transforms loaded from WASM modules, only built with the "wasm" feature.<br>
+//! Each update is written into the module's memory as JSON and the module hands back the JSON of the update to keep, or nothing to drop it.
+
+use std::sync::Mutex;
+use wasmtime::{Engine, Instance, Linker, Memory, Module, Store, TypedFunc};
+use crate::plugin::{PluginError, Transform};
+use crate::{report, PollUpdate};
+
+/// A loaded WASM module and the functions used to call it
+pub struct WasmTransform {
+    name: String,
+    runtime: Mutex<WasmRuntime>,
+}
+
+/// Everything that needs the Store, kept together behind one lock
+struct WasmRuntime {
+    store: Store<()>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    transform: TypedFunc<(i32, i32), i64>,
+}
+
+impl WasmTransform {
+    /// Load and instantiate a module from a path
+    ///
+    /// # Errors
+    /// Returns a message if the module cannot be read, compiled or instantiated, or is missing memory, alloc or transform
+    pub fn load(path: &str) -> Result<WasmTransform, String> {
+        let engine: Engine = Engine::default();
+        let module: Module = Module::from_file(&engine, path).map_err(|e| format!("Unable to load WASM transform {}: {}", path, e))?;
+        WasmTransform::from_module(path, &engine, &module)
+    }
+    /// Instantiate an already compiled module under a name
+    ///
+    /// # Errors
+    /// Returns a message if the module cannot be instantiated or is missing memory, alloc or transform
+    pub fn from_module(name: &str, engine: &Engine, module: &Module) -> Result<WasmTransform, String> {
+        let mut store: Store<()> = Store::new(engine, ());
+        let instance: Instance = Linker::new(engine).instantiate(&mut store, module).map_err(|e| format!("Unable to start WASM transform {}: {}", name, e))?;
+        let memory: Memory = instance.get_memory(&mut store, "memory").ok_or(format!("WASM transform {} does not export \"memory\"", name))?;
+        let alloc: TypedFunc<i32, i32> = instance.get_typed_func::<i32, i32>(&mut store, "alloc").map_err(|e| format!("WASM transform {} needs \"alloc(i32) -> i32\": {}", name, e))?;
+        let transform: TypedFunc<(i32, i32), i64> = instance.get_typed_func::<(i32, i32), i64>(&mut store, "transform").map_err(|e| format!("WASM transform {} needs \"transform(i32, i32) -> i64\": {}", name, e))?;
+        report::info(&format!("Loaded WASM transform {}", name));
+        Ok(WasmTransform { name: name.to_string(), runtime: Mutex::new(WasmRuntime { store, memory, alloc, transform }) })
+    }
+}
+
+impl Transform for WasmTransform {
+    fn name(&self) -> &str {
+        &self.name
+    }
+    fn apply(&self, update: PollUpdate) -> Result<Option<PollUpdate>, PluginError> {
+        let input: Vec<u8> = serde_json::to_vec(&update)?;
+        let mut runtime = self.runtime.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let WasmRuntime { store, memory, alloc, transform } = &mut *runtime;
+        let input_len: i32 = i32::try_from(input.len())?;
+        let input_ptr: i32 = alloc.call(&mut *store, input_len)?;
+        memory.write(&mut *store, input_ptr as u32 as usize, &input)?;
+        let (output_ptr, output_len) = unpack_result(transform.call(&mut *store, (input_ptr, input_len))?);
+        if output_len == 0 {
+            return Ok(None);
+        }
+        let mut output: Vec<u8> = vec![0; output_len];
+        memory.read(&*store, output_ptr, &mut output)?;
+        Ok(Some(serde_json::from_slice::<PollUpdate>(&output)?))
+    }
+}
+
+/// Split the i64 returned by transform into the output pointer (high 32 bits) and length (low 32 bits)
+fn unpack_result(packed: i64) -> (usize, usize) {
+    let packed: u64 = packed as u64;
+    ((packed >> 32) as usize, (packed & 0xFFFF_FFFF) as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ECHO_WAT: &str = r#"(module
+        (memory (export "memory") 1)
+        (func (export "alloc") (param i32) (result i32) i32.const 1024)
+        (func (export "transform") (param i32 i32) (result i64)
+            (i64.or (i64.shl (i64.extend_i32_u (local.get 0)) (i64.const 32)) (i64.extend_i32_u (local.get 1)))))"#;
+
+    const DROP_WAT: &str = r#"(module
+        (memory (export "memory") 1)
+        (func (export "alloc") (param i32) (result i32) i32.const 1024)
+        (func (export "transform") (param i32 i32) (result i64) i64.const 0))"#;
+
+    fn test_transform(wat: &str) -> WasmTransform {
+        let engine: Engine = Engine::default();
+        let module: Module = Module::new(&engine, wat).unwrap();
+        WasmTransform::from_module("test", &engine, &module).unwrap()
+    }
+
+    fn test_update() -> PollUpdate {
+        PollUpdate { aqi: 3, co: Some(1.0), no: Some(2.0), no2: Some(3.0), o3: Some(4.0), so2: Some(5.0), pm2_5: Some(6.0), pm10: Some(7.0), nh3: Some(8.0), ..PollUpdate::fixture("Home", 1700000000) }
+    }
+
+    #[test]
+    fn echo_module_keeps_update() {
+        let kept: PollUpdate = test_transform(ECHO_WAT).apply(test_update()).unwrap().unwrap();
+        assert_eq!(kept.get_aqi(), 3);
+        assert_eq!(kept.get_field("pm2_5"), Some(6.0));
+        assert_eq!(kept.get_location(), "Home");
+    }
+
+    #[test]
+    fn drop_module_drops_update() {
+        assert!(test_transform(DROP_WAT).apply(test_update()).unwrap().is_none());
+    }
+
+    #[test]
+    fn missing_exports_are_reported() {
+        let engine: Engine = Engine::default();
+        let module: Module = Module::new(&engine, "(module (memory (export \"memory\") 1))").unwrap();
+        assert!(WasmTransform::from_module("empty", &engine, &module).err().unwrap().contains("alloc"));
+    }
+
+    #[test]
+    fn unpack_result_splits_pointer_and_length() {
+        assert_eq!(unpack_result((1024_i64 << 32) | 57), (1024, 57));
+        assert_eq!(unpack_result(0), (0, 0));
+    }
+}